@@ -0,0 +1,72 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use jobserver::Client;
+use std::thread;
+
+/// Clients to compare: plain ones, which go through the pipe for every
+/// token, and ones with read-ahead, which only do so when they run out.
+fn clients(limit: usize) -> Vec<(&'static str, Client)> {
+    let mut clients = vec![("pipe", Client::new(limit).unwrap())];
+    if cfg!(unix) {
+        let client = Client::new(limit).unwrap();
+        client.set_read_ahead(limit).unwrap();
+        clients.push(("read-ahead", client));
+    }
+    clients.push(("counting", Client::counting(limit)));
+    clients
+}
+
+/// A single token acquired and released over and over, as by a process
+/// running one short job after the other.
+fn acquire_release(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acquire_release");
+    for (name, client) in clients(4) {
+        group.bench_function(name, |b| b.iter(|| drop(client.acquire().unwrap())));
+    }
+    group.finish();
+}
+
+/// All tokens acquired at once and then released, as when spawning a batch
+/// of jobs.
+fn acquire_burst(c: &mut Criterion) {
+    let mut group = c.benchmark_group("acquire_burst");
+    for (name, client) in clients(8) {
+        group.bench_with_input(BenchmarkId::new(name, 8), &client, |b, client| {
+            b.iter(|| {
+                let tokens: Vec<_> = (0..8).map(|_| client.acquire().unwrap()).collect();
+                drop(tokens);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Several threads acquiring and releasing tokens concurrently, through
+/// clones sharing one file descriptor or through clones with their own.
+fn threads(c: &mut Criterion) {
+    const THREADS: usize = 4;
+    let mut group = c.benchmark_group("threads");
+    let client = Client::new(2).unwrap();
+    let shared = vec![client.clone(); THREADS];
+    let per_thread = (0..THREADS)
+        .map(|_| client.try_clone_for_thread().unwrap())
+        .collect::<Vec<_>>();
+    for (name, clients) in [("shared", shared), ("per-thread", per_thread)] {
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    for client in &clients {
+                        s.spawn(move || {
+                            for _ in 0..100 {
+                                drop(client.acquire().unwrap());
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, acquire_release, acquire_burst, threads);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|var: &str| {
+    let flags = jobserver::parse_makeflags(var);
+    // Whatever was found has to be consistent.
+    assert_eq!(flags.auth().is_some(), flags.protocol().is_some());
+    assert_eq!(flags.auth().is_some(), flags.transport().is_some());
+});
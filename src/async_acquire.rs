@@ -0,0 +1,44 @@
+use std::io;
+use std::os::unix::io::BorrowedFd;
+use std::time::Duration;
+
+use async_io::{Async, Timer};
+
+use crate::{Acquired, Backend, Client};
+
+/// How often a paused client checks whether it was resumed.
+const PAUSE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Acquires a token from `client`, waiting for its read end to become
+/// readable on the `async-io` reactor, see [`Client::acquire_async_io`].
+pub(crate) async fn acquire(client: &Client) -> io::Result<Acquired> {
+    let backend = client.backend();
+    let imp = match backend.base() {
+        Backend::Os(c) => c,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-process jobservers have no file descriptor to wait on",
+            ))
+        }
+    };
+    // Released tokens have to go through the pipe to wake this up.
+    let _polling = imp.poller();
+    let fd = imp.read_fd();
+    // Registering a duplicate with the reactor leaves the jobserver's own file
+    // descriptor alone, and `new_nonblocking` doesn't change the blocking mode
+    // of the pipe, which is shared with other processes.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    let fd = Async::new_nonblocking(fd)?;
+    loop {
+        if let Some(token) = client.try_acquire()? {
+            return Ok(token);
+        }
+        // The pipe may well be readable while paused, so don't wait on it.
+        if client.is_paused() {
+            Timer::after(PAUSE_INTERVAL).await;
+        } else {
+            fd.readable().await?;
+        }
+    }
+}
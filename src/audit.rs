@@ -0,0 +1,86 @@
+use std::fmt;
+
+/// The number of tokens found in a jobserver compared with the number it
+/// should have, returned by [`Client::audit`] and
+/// [`Client::audit_and_repair`].
+///
+/// The [`Display`](fmt::Display) implementation gives a one-line summary
+/// suitable for logging, such as `recovered 3 leaked jobserver token(s)`.
+///
+/// [`Client::audit`]: crate::Client::audit
+/// [`Client::audit_and_repair`]: crate::Client::audit_and_repair
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditReport {
+    expected: usize,
+    found: usize,
+    held: usize,
+    repaired: bool,
+}
+
+impl AuditReport {
+    pub(crate) fn new(expected: usize, found: usize, held: usize) -> AuditReport {
+        AuditReport {
+            expected,
+            found,
+            held,
+            repaired: false,
+        }
+    }
+
+    pub(crate) fn set_repaired(&mut self) {
+        self.repaired = true;
+    }
+
+    /// Returns the total number of tokens the jobserver should have.
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+
+    /// Returns the number of tokens which were available in the jobserver.
+    pub fn found(&self) -> usize {
+        self.found
+    }
+
+    /// Returns the number of tokens held through the audited client at the
+    /// time, which are counted as in use rather than missing.
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Returns the number of tokens missing from the jobserver, typically
+    /// leaked by a process which exited abnormally while holding them.
+    pub fn leaked(&self) -> usize {
+        self.expected.saturating_sub(self.found + self.held)
+    }
+
+    /// Returns the number of tokens the jobserver has on top of those it
+    /// should have, typically released more than once.
+    pub fn excess(&self) -> usize {
+        (self.found + self.held).saturating_sub(self.expected)
+    }
+
+    /// Returns whether the jobserver has exactly the tokens it should have.
+    pub fn is_consistent(&self) -> bool {
+        self.found + self.held == self.expected
+    }
+
+    /// Returns whether the number of tokens was corrected, see
+    /// [`Client::audit_and_repair`](crate::Client::audit_and_repair).
+    pub fn repaired(&self) -> bool {
+        self.repaired
+    }
+}
+
+impl fmt::Display for AuditReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.leaked() > 0 {
+            let verb = if self.repaired { "recovered" } else { "found" };
+            write!(f, "{} {} leaked jobserver token(s)", verb, self.leaked())
+        } else if self.excess() > 0 {
+            let verb = if self.repaired { "removed" } else { "found" };
+            write!(f, "{} {} excess jobserver token(s)", verb, self.excess())
+        } else {
+            write!(f, "all {} jobserver token(s) accounted for", self.expected)
+        }
+    }
+}
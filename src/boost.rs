@@ -0,0 +1,54 @@
+use std::io;
+use std::mem;
+
+use crate::Client;
+
+/// Extra tokens added to a jobserver for a while, returned by
+/// [`Client::boost`].
+///
+/// The tokens are removed from the jobserver again when the guard is dropped,
+/// including while unwinding from a panic, so a temporary boost never turns
+/// into a permanently larger jobserver. Removing them waits for as many
+/// tokens to be free as were added, so dropping the guard blocks while the
+/// jobserver's tokens are all held. Use [`BoostGuard::end`] instead of
+/// dropping the guard to find out whether removing the tokens failed.
+#[derive(Debug)]
+#[must_use = "the extra tokens are removed again right away if the guard is dropped"]
+pub struct BoostGuard {
+    client: Client,
+    /// Number of tokens added which haven't been removed yet.
+    extra: usize,
+}
+
+impl BoostGuard {
+    pub(crate) fn new(client: Client, extra: usize) -> BoostGuard {
+        BoostGuard { client, extra }
+    }
+
+    /// Returns the number of tokens added to the jobserver by this guard.
+    pub fn extra(&self) -> usize {
+        self.extra
+    }
+
+    /// Removes the extra tokens from the jobserver, like dropping the guard
+    /// does, and reports whether that worked.
+    ///
+    /// # Errors
+    ///
+    /// Returns any I/O error which happens while acquiring the tokens to
+    /// remove. Tokens which couldn't be removed are left in the jobserver.
+    pub fn end(mut self) -> io::Result<()> {
+        self.remove()
+    }
+
+    fn remove(&mut self) -> io::Result<()> {
+        let extra = mem::take(&mut self.extra);
+        self.client.remove_tokens(extra)
+    }
+}
+
+impl Drop for BoostGuard {
+    fn drop(&mut self) {
+        drop(self.remove());
+    }
+}
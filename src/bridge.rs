@@ -0,0 +1,193 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Acquired, Client};
+
+/// How often the downstream jobserver is checked for tokens running out or
+/// piling up.
+const INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long surplus tokens are left idle downstream before they're returned
+/// upstream.
+const IDLE: Duration = Duration::from_millis(100);
+
+/// Forwards tokens from one jobserver to another on a background thread.
+///
+/// The bridge acquires tokens from `upstream`, such as the jobserver of an
+/// outer `make`, and releases them into `downstream`, a jobserver for inner
+/// workers which only ever see the tokens the bridge hands them. Whenever all
+/// tokens forwarded so far are in use downstream the bridge acquires another
+/// one upstream, so consumers waiting downstream get a token as soon as one
+/// is free upstream. Tokens which are released downstream and left unused
+/// for a while are taken back and released upstream again, keeping one idle
+/// token downstream for the next consumer.
+///
+/// To limit the workers to fewer tokens than the outer jobserver has, cap
+/// the upstream client with [`Client::capped`]: the bridge never holds more
+/// upstream tokens than the cap allows.
+///
+/// The downstream jobserver is usually created for the bridge with no tokens
+/// of its own, with [`Client::new`] and a limit of zero. Taking idle tokens
+/// back requires [`Client::try_acquire`] to be supported downstream, without
+/// it forwarded tokens stay downstream until the bridge is stopped.
+///
+/// # Examples
+///
+/// ```
+/// use jobserver::{Client, JobserverBridge};
+///
+/// let outer = Client::new(8).unwrap();
+/// let inner = Client::new(0).unwrap();
+/// let bridge = JobserverBridge::new(outer.clone().capped(2), inner.clone()).unwrap();
+///
+/// // Workers configured with `inner` run at most two jobs at once.
+/// let token = inner.acquire().unwrap();
+/// drop(token);
+/// bridge.stop().unwrap();
+/// ```
+#[derive(Debug)]
+pub struct JobserverBridge {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    stop: AtomicBool,
+    forwarded: AtomicUsize,
+}
+
+impl JobserverBridge {
+    /// Starts forwarding tokens from `upstream` to `downstream`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the background thread can't be spawned.
+    pub fn new(upstream: Client, downstream: Client) -> io::Result<JobserverBridge> {
+        let shared = Arc::new(Shared::default());
+        let shared2 = shared.clone();
+        let thread = thread::Builder::new()
+            .name("jobserver-bridge".to_string())
+            .spawn(move || run(&upstream, &downstream, &shared2))?;
+        Ok(JobserverBridge {
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the number of upstream tokens currently forwarded downstream,
+    /// whether they're in use there or not.
+    pub fn forwarded(&self) -> usize {
+        self.shared.forwarded.load(Ordering::SeqCst)
+    }
+
+    /// Stops forwarding tokens and waits for the background thread to exit.
+    ///
+    /// Forwarded tokens which are idle downstream are taken back, and every
+    /// upstream token the bridge holds is released. Tokens still in use
+    /// downstream are released upstream right away, so workers should be
+    /// done before the bridge is stopped.
+    ///
+    /// Dropping the bridge stops it the same way, ignoring errors.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error which made the background thread give up forwarding
+    /// tokens, if any.
+    pub fn stop(mut self) -> io::Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.shared.stop.store(true, Ordering::SeqCst);
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "jobserver bridge thread panicked",
+                ))
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for JobserverBridge {
+    fn drop(&mut self) {
+        drop(self.shutdown());
+    }
+}
+
+fn run(upstream: &Client, downstream: &Client, shared: &Shared) -> io::Result<()> {
+    let mut held = Vec::new();
+    let ret = forward(upstream, downstream, shared, &mut held);
+    // Take back what's idle downstream, the rest is still in use.
+    while !held.is_empty() {
+        match downstream.try_acquire() {
+            Ok(Some(token)) => {
+                token.drop_without_releasing();
+                held.pop();
+            }
+            _ => break,
+        }
+    }
+    drop(held);
+    shared.forwarded.store(0, Ordering::SeqCst);
+    ret
+}
+
+fn forward(
+    upstream: &Client,
+    downstream: &Client,
+    shared: &Shared,
+    held: &mut Vec<Acquired>,
+) -> io::Result<()> {
+    let mut idle_since = None;
+    while !shared.stop.load(Ordering::SeqCst) {
+        let available = downstream.available()?;
+        if available == 0 {
+            idle_since = None;
+            // Wait in short steps to notice being stopped.
+            match upstream.acquire_timeout(INTERVAL) {
+                Ok(token) => {
+                    // Count the token before consumers downstream can see it.
+                    held.push(token);
+                    shared.forwarded.store(held.len(), Ordering::SeqCst);
+                    if let Err(e) = downstream.release_raw() {
+                        held.pop();
+                        shared.forwarded.store(held.len(), Ordering::SeqCst);
+                        return Err(e);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            continue;
+        }
+
+        if available > 1 && !held.is_empty() {
+            let since = *idle_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= IDLE {
+                match downstream.try_acquire() {
+                    Ok(Some(token)) => {
+                        token.drop_without_releasing();
+                        held.pop();
+                        shared.forwarded.store(held.len(), Ordering::SeqCst);
+                        idle_since = None;
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        } else {
+            idle_since = None;
+        }
+        thread::sleep(INTERVAL);
+    }
+    Ok(())
+}
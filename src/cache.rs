@@ -0,0 +1,51 @@
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The number of tokens available from a jobserver as last queried, see
+/// [`crate::Client::available_cached`].
+#[derive(Debug, Default)]
+pub(crate) struct AvailableCache {
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Bumped whenever the cache is invalidated, so that a query which raced
+    /// with an acquisition or release doesn't store its stale result.
+    generation: u64,
+    value: Option<(Instant, usize)>,
+}
+
+impl AvailableCache {
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the cached number if it was queried at most `max_age` ago.
+    pub(crate) fn get(&self, max_age: Duration) -> Option<usize> {
+        match self.lock().value {
+            Some((at, available)) if at.elapsed() <= max_age => Some(available),
+            _ => None,
+        }
+    }
+
+    /// Queries the number with `query` and caches it.
+    pub(crate) fn refresh(&self, query: impl FnOnce() -> io::Result<usize>) -> io::Result<usize> {
+        let generation = self.lock().generation;
+        let at = Instant::now();
+        let available = query()?;
+        let mut state = self.lock();
+        if state.generation == generation {
+            state.value = Some((at, available));
+        }
+        Ok(available)
+    }
+
+    /// Forgets the cached number, as this process changed it.
+    pub(crate) fn invalidate(&self) {
+        let mut state = self.lock();
+        state.generation += 1;
+        state.value = None;
+    }
+}
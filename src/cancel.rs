@@ -0,0 +1,144 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{counting, imp, Backend};
+
+/// How often backends which can't be woken up directly are checked for
+/// cancellation while waiting for a token.
+const INTERVAL: Duration = Duration::from_millis(10);
+
+/// A handle to cancel waiting for tokens, returned by
+/// [`Client::with_cancellation`](crate::Client::with_cancellation).
+///
+/// Calling [`CancelHandle::cancel`], from any thread, makes every blocking
+/// acquisition of the client it was created with fail with
+/// [`io::ErrorKind::Interrupted`], both those already waiting and any made
+/// later. Clones of the handle cancel the same client.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    signal: Arc<Signal>,
+}
+
+impl CancelHandle {
+    /// Cancels all current and future blocking acquisitions of the client.
+    ///
+    /// Threads blocked in [`Client::acquire`](crate::Client::acquire) and
+    /// similar methods return an error of kind
+    /// [`io::ErrorKind::Interrupted`] promptly. Tokens already held aren't
+    /// affected and are released as usual when dropped, and
+    /// [`Client::try_acquire`](crate::Client::try_acquire) keeps working as
+    /// it never blocks. Cancelling more than once has no further effect.
+    pub fn cancel(&self) {
+        if self.signal.cancelled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Leave the byte in the pipe, so that every waiter sees it readable.
+        #[cfg(unix)]
+        drop(io::Write::write(&mut &self.signal.pipe.1, &[0]));
+    }
+
+    /// Returns whether [`CancelHandle::cancel`] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.signal.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The state shared by a [`CancelHandle`] and the backend it cancels.
+#[derive(Debug)]
+pub(crate) struct Signal {
+    cancelled: AtomicBool,
+    /// A self-pipe which becomes readable once cancelled, so that waiting for
+    /// a token from a pipe can be woken up without a signal.
+    #[cfg(unix)]
+    pipe: (std::fs::File, std::fs::File),
+}
+
+pub(crate) fn new() -> io::Result<(Arc<Signal>, CancelHandle)> {
+    let signal = Arc::new(Signal {
+        cancelled: AtomicBool::new(false),
+        #[cfg(unix)]
+        pipe: imp::self_pipe()?,
+    });
+    let handle = CancelHandle {
+        signal: signal.clone(),
+    };
+    Ok((signal, handle))
+}
+
+impl Signal {
+    fn check(&self) -> io::Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(cancelled());
+        }
+        Ok(())
+    }
+
+    /// Acquires a token from `inner`, failing once cancelled.
+    pub(crate) fn acquire(&self, inner: &Backend) -> io::Result<Option<imp::Acquired>> {
+        self.check()?;
+        #[cfg(unix)]
+        if let Backend::Os(c) = inner {
+            return match c.acquire_allow_wakeup(&self.pipe.0)? {
+                Some(acquired) => Ok(Some(acquired)),
+                None => Err(cancelled()),
+            };
+        }
+        // Anything else is waited for in slices, checking for cancellation
+        // in between.
+        loop {
+            if let Some(acquired) = inner.acquire_timeout(INTERVAL)? {
+                return Ok(acquired);
+            }
+            self.check()?;
+        }
+    }
+
+    /// Like `acquire`, returning `None` if no token turned up within
+    /// `timeout`.
+    pub(crate) fn acquire_timeout(
+        &self,
+        inner: &Backend,
+        timeout: Duration,
+    ) -> io::Result<Option<Option<imp::Acquired>>> {
+        let start = Instant::now();
+        loop {
+            self.check()?;
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if let Some(acquired) = inner.acquire_timeout(remaining.min(INTERVAL))? {
+                return Ok(Some(acquired));
+            }
+            if remaining <= INTERVAL {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Waits for room under `cap` for up to `timeout`, or for as long as it
+    /// takes if `None`, failing once cancelled.
+    pub(crate) fn acquire_cap(
+        &self,
+        cap: &counting::Client,
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<()>> {
+        let start = Instant::now();
+        loop {
+            self.check()?;
+            let remaining = timeout.map_or(INTERVAL, |t| t.saturating_sub(start.elapsed()));
+            if let Some(()) = cap.acquire_timeout(remaining.min(INTERVAL))? {
+                return Ok(Some(()));
+            }
+            if timeout.is_some() && remaining <= INTERVAL {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Interrupted,
+        "waiting for a jobserver token was cancelled",
+    )
+}
@@ -0,0 +1,62 @@
+use std::io;
+use std::sync::Arc;
+use std::thread::{Builder, JoinHandle};
+
+use crate::counting;
+
+#[derive(Debug)]
+pub struct Helper {
+    thread: JoinHandle<()>,
+    cap: counting::Client,
+}
+
+/// Spawns a helper thread for a client created with [`crate::Client::capped`].
+///
+/// This thread only waits for room under the cap, and then hands the request
+/// on to a regular helper thread for the underlying jobserver. Waiting for the
+/// cap first means that no jobserver token is held while blocked on it.
+pub(crate) fn spawn_helper(
+    client: crate::Client,
+    state: Arc<crate::HelperState>,
+    mut f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
+    signals: bool,
+) -> io::Result<Helper> {
+    let (inner, cap) = match &*client.backend() {
+        crate::Backend::Capped { inner, cap } => (inner.clone(), cap.clone()),
+        _ => unreachable!(),
+    };
+    let uncapped = crate::Client::pinned(inner, client.state.clone());
+    let cap2 = cap.clone();
+    let helper = uncapped.spawn_helper_thread(
+        move |acquired| match acquired {
+            Ok(acquired) => f(Ok(acquired.recap(&client))),
+            Err(e) => {
+                drop(cap2.release());
+                f(Err(e))
+            }
+        },
+        signals,
+    )?;
+
+    let cap2 = cap.clone();
+    let thread = Builder::new().spawn(move || {
+        state.for_each_request(|state| {
+            if cap2.acquire_allow_interrupts(state).is_some() {
+                helper.request_token();
+            }
+        });
+        // Dropping `helper` here shuts down the helper thread for the
+        // underlying jobserver as well.
+    })?;
+
+    Ok(Helper { thread, cap })
+}
+
+impl Helper {
+    pub fn join(self) {
+        // Wake the helper thread if it's blocked waiting for room under the
+        // cap, the producer is already flagged as done at this point.
+        self.cap.interrupt();
+        drop(self.thread.join());
+    }
+}
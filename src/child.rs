@@ -0,0 +1,75 @@
+use std::io;
+use std::process::{Child, ExitStatus};
+
+use crate::Acquired;
+
+/// A child process holding a jobserver token, returned by
+/// [`Client::spawn_limited`].
+///
+/// The token is released once the child process has been reaped through
+/// [`LimitedChild::wait`] or [`LimitedChild::try_wait`].
+///
+/// Dropping a `LimitedChild` releases the token as well, even if the child
+/// process is still running, much like dropping a [`Child`] doesn't wait for
+/// the process to exit. Wait for the child process first to stay within the
+/// jobserver's limit.
+///
+/// [`Client::spawn_limited`]: crate::Client::spawn_limited
+#[derive(Debug)]
+pub struct LimitedChild {
+    child: Child,
+    token: Option<Acquired>,
+}
+
+impl LimitedChild {
+    pub(crate) fn new(child: Child, token: Acquired) -> LimitedChild {
+        LimitedChild {
+            child,
+            token: Some(token),
+        }
+    }
+
+    /// Returns the OS-assigned process identifier of the child process.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Returns the underlying [`Child`], for example to access its standard
+    /// I/O handles.
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Forces the child process to exit, see [`Child::kill`].
+    ///
+    /// The token is still held until the child process is reaped.
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+
+    /// Waits for the child process to exit, and then releases its token.
+    ///
+    /// See [`Child::wait`] for details.
+    pub fn wait(&mut self) -> io::Result<ExitStatus> {
+        let status = self.child.wait()?;
+        self.token = None;
+        Ok(status)
+    }
+
+    /// Releases the token if the child process has exited, without blocking.
+    ///
+    /// See [`Child::try_wait`] for details.
+    pub fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        let status = self.child.try_wait()?;
+        if status.is_some() {
+            self.token = None;
+        }
+        Ok(status)
+    }
+
+    /// Returns whether the token is still held, that is whether the child
+    /// process hasn't been reaped yet.
+    pub fn holds_token(&self) -> bool {
+        self.token.is_some()
+    }
+}
@@ -0,0 +1,46 @@
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+use std::process::Command;
+
+/// A builder for child processes which a jobserver can be passed on to, see
+/// [`Client::configure_generic`].
+///
+/// This is implemented for [`std::process::Command`], and can be implemented
+/// for other process builders, such as `tokio::process::Command` or a custom
+/// sandboxing spawner, to give the processes they spawn access to a
+/// jobserver.
+///
+/// [`Client::configure_generic`]: crate::Client::configure_generic
+pub trait ConfigurableCommand {
+    /// Sets the environment variable `key` to `value` for the child process.
+    fn env(&mut self, key: &OsStr, value: &OsStr);
+
+    /// Makes the file descriptor `fd` of this process available to the child
+    /// process under the same number.
+    ///
+    /// The jobserver passes the numbers of the file descriptors of its pipe
+    /// to the child through the environment, so they must not be closed or
+    /// renumbered when the child is spawned. For [`std::process::Command`]
+    /// this clears the close-on-exec flag of `fd` in the child.
+    ///
+    /// This may be called more than once for the same `fd` if a command is
+    /// configured more than once, which must be harmless.
+    #[cfg(unix)]
+    fn inherit_fd(&mut self, fd: RawFd);
+}
+
+impl ConfigurableCommand for Command {
+    fn env(&mut self, key: &OsStr, value: &OsStr) {
+        Command::env(self, key, value);
+    }
+
+    #[cfg(unix)]
+    fn inherit_fd(&mut self, fd: RawFd) {
+        use std::os::unix::process::CommandExt;
+
+        unsafe {
+            self.pre_exec(move || crate::imp::set_cloexec(fd, false));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+#[cfg(feature = "testing")]
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::Semaphore;
+
+/// A purely in-process jobserver, see [`crate::Client::counting`].
+///
+/// This is a plain counting semaphore which never touches the OS, so it can't
+/// be inherited by child processes. The counting itself is done by a
+/// [`Semaphore`], with a condition variable on top to sleep while no tokens
+/// are available.
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    tokens: Semaphore,
+    /// Held while checking for a token before waiting on `cvar`, and while
+    /// notifying it, so that no release is missed in between.
+    lock: Mutex<()>,
+    cvar: Condvar,
+    /// Errors queued up with [`Client::fail_next_acquire`].
+    #[cfg(feature = "testing")]
+    failures: Mutex<VecDeque<io::ErrorKind>>,
+}
+
+impl Client {
+    pub fn new(limit: usize) -> Client {
+        Client {
+            inner: Arc::new(Inner {
+                tokens: Semaphore::new(limit),
+                lock: Mutex::new(()),
+                cvar: Condvar::new(),
+                #[cfg(feature = "testing")]
+                failures: Mutex::default(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ()> {
+        self.inner.lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Makes the next call to `acquire` or `try_acquire` fail with `kind`,
+    /// after any failures queued up before.
+    #[cfg(feature = "testing")]
+    pub fn fail_next_acquire(&self, kind: io::ErrorKind) {
+        self.inner
+            .failures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_back(kind);
+    }
+
+    fn injected_failure(&self) -> io::Result<()> {
+        #[cfg(feature = "testing")]
+        {
+            let next = self
+                .inner
+                .failures
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front();
+            if let Some(kind) = next {
+                return Err(io::Error::new(kind, "injected acquire failure"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn acquire(&self) -> io::Result<()> {
+        self.injected_failure()?;
+        if self.inner.tokens.try_take() {
+            return Ok(());
+        }
+        let mut lock = self.lock();
+        while !self.inner.tokens.try_take() {
+            lock = self
+                .inner
+                .cvar
+                .wait(lock)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        Ok(())
+    }
+
+    /// Block waiting for a token for up to `timeout`, returning `None` if
+    /// none turned up in time.
+    pub fn acquire_timeout(&self, timeout: Duration) -> io::Result<Option<()>> {
+        self.injected_failure()?;
+        if self.inner.tokens.try_take() {
+            return Ok(Some(()));
+        }
+        let start = Instant::now();
+        let mut lock = self.lock();
+        while !self.inner.tokens.try_take() {
+            let remaining = timeout.saturating_sub(start.elapsed());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            lock = self
+                .inner
+                .cvar
+                .wait_timeout(lock, remaining)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+        Ok(Some(()))
+    }
+
+    /// Block waiting for a token, returning `None` if the helper thread
+    /// owning `state` was asked to shut down in the meantime.
+    pub(crate) fn acquire_allow_interrupts(&self, state: &crate::HelperState) -> Option<()> {
+        let mut lock = self.lock();
+        while !self.inner.tokens.try_take() {
+            if state.lock().producer_done {
+                return None;
+            }
+            lock = self
+                .inner
+                .cvar
+                .wait(lock)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        Some(())
+    }
+
+    pub fn try_acquire(&self) -> io::Result<Option<()>> {
+        self.injected_failure()?;
+        Ok(self.inner.tokens.try_take().then_some(()))
+    }
+
+    pub fn release(&self) -> io::Result<()> {
+        self.inner.tokens.put();
+        drop(self.lock());
+        self.inner.cvar.notify_one();
+        Ok(())
+    }
+
+    pub fn available(&self) -> io::Result<usize> {
+        Ok(self.inner.tokens.available())
+    }
+
+    /// Wakes up everyone blocked in `acquire_allow_interrupts` so they can
+    /// re-check whether they've been asked to shut down.
+    pub(crate) fn interrupt(&self) {
+        let _lock = self.lock();
+        self.inner.cvar.notify_all();
+    }
+}
+
+#[derive(Debug)]
+pub struct Helper {
+    thread: JoinHandle<()>,
+    client: crate::Client,
+}
+
+pub(crate) fn spawn_helper(
+    client: crate::Client,
+    state: Arc<crate::HelperState>,
+    mut f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
+) -> io::Result<Helper> {
+    let client2 = client.clone();
+    let thread = Builder::new().spawn(move || {
+        let backend = client2.backend();
+        let counting = backend.counting().unwrap();
+        state.for_each_request(|helper| {
+            if counting.acquire_allow_interrupts(helper).is_some() {
+                f(Ok(crate::Acquired::new(&client2, None)));
+            }
+        });
+    })?;
+
+    Ok(Helper { thread, client })
+}
+
+impl Helper {
+    pub fn join(self) {
+        // The producer is already flagged as done at this point, so all that's
+        // left is to wake the helper thread if it's blocked waiting for a
+        // token.
+        self.client.backend().counting().unwrap().interrupt();
+        drop(self.thread.join());
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+
+/// The threads holding and waiting for tokens through a client created with
+/// [`crate::Client::new_deadlock_checked`].
+#[derive(Debug, Default)]
+pub(crate) struct Holders {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// Number of tokens held by each thread.
+    held: HashMap<ThreadId, usize>,
+    /// Threads blocked in `Client::acquire` without a timeout.
+    waiting: HashSet<ThreadId>,
+}
+
+impl Holders {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers the current thread as blocked waiting for a token until the
+    /// returned guard is dropped.
+    ///
+    /// Fails instead if this process already holds all `limit` tokens and
+    /// every thread holding one is the current thread or already blocked
+    /// waiting itself, in which case none of them can ever be released.
+    pub(crate) fn wait(&self, limit: usize) -> io::Result<Waiting<'_>> {
+        let thread = thread::current().id();
+        let mut inner = self.lock();
+        let total = inner.held.values().sum::<usize>();
+        let stuck = inner
+            .held
+            .keys()
+            .all(|holder| *holder == thread || inner.waiting.contains(holder));
+        if total > 0 && total >= limit && stuck {
+            let held = inner.held.get(&thread).copied().unwrap_or(0);
+            let msg = if held >= total {
+                format!(
+                    "acquiring a jobserver token would deadlock: the current \
+                     thread already holds all {} tokens",
+                    limit
+                )
+            } else {
+                format!(
+                    "acquiring a jobserver token would deadlock: this process \
+                     holds all {} tokens and every thread holding one is \
+                     waiting for another",
+                    limit
+                )
+            };
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+        inner.waiting.insert(thread);
+        Ok(Waiting {
+            holders: self,
+            thread,
+        })
+    }
+}
+
+/// Registers a thread as blocked waiting for a token until dropped.
+#[derive(Debug)]
+pub(crate) struct Waiting<'a> {
+    holders: &'a Holders,
+    thread: ThreadId,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.holders.lock().waiting.remove(&self.thread);
+    }
+}
+
+/// Counts a [`crate::Acquired`] token towards the thread which acquired it
+/// until it's dropped.
+#[derive(Debug)]
+pub(crate) struct HolderGuard {
+    thread: ThreadId,
+    state: Arc<crate::ClientState>,
+}
+
+impl HolderGuard {
+    /// Returns `None` if the client owning `state` doesn't check for
+    /// deadlocks.
+    pub(crate) fn new(state: &Arc<crate::ClientState>) -> Option<HolderGuard> {
+        let holders = state.holders.as_ref()?;
+        let thread = thread::current().id();
+        *holders.lock().held.entry(thread).or_insert(0) += 1;
+        Some(HolderGuard {
+            thread,
+            state: state.clone(),
+        })
+    }
+}
+
+impl Drop for HolderGuard {
+    fn drop(&mut self) {
+        let holders = self.state.holders.as_ref().unwrap();
+        let mut inner = holders.lock();
+        if let Some(count) = inner.held.get_mut(&self.thread) {
+            *count -= 1;
+            if *count == 0 {
+                inner.held.remove(&self.thread);
+            }
+        }
+    }
+}
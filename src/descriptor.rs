@@ -0,0 +1,77 @@
+/// The information needed to connect to a jobserver again, returned by
+/// [`Client::descriptor`].
+///
+/// Unlike a [`Client`] itself this is plain data: with the `serde` feature
+/// enabled it can be serialized, for example to hand a job's environment to
+/// another process, and turned back into a client with
+/// [`Client::from_descriptor`].
+///
+/// Only named jobservers, that is fifos on Unix and semaphores on Windows,
+/// can be connected to by any process knowing the descriptor. Anonymous pipes
+/// are described by the numbers of their file descriptors, which only refer
+/// to the jobserver in processes which inherited them.
+///
+/// [`Client`]: crate::Client
+/// [`Client::descriptor`]: crate::Client::descriptor
+/// [`Client::from_descriptor`]: crate::Client::from_descriptor
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientDescriptor {
+    kind: TransportKind,
+    auth: String,
+    limit: Option<usize>,
+}
+
+/// The kind of OS object a jobserver is shared through, see
+/// [`ClientDescriptor::kind`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub enum TransportKind {
+    /// An anonymous pipe on Unix, `--jobserver-auth=R,W`.
+    Pipe,
+    /// A named fifo on Unix, `--jobserver-auth=fifo:PATH`.
+    Fifo,
+    /// A named semaphore on Windows.
+    Semaphore,
+}
+
+impl TransportKind {
+    /// Returns the kind of jobserver `auth` describes on this platform.
+    pub(crate) fn of(auth: &str) -> TransportKind {
+        if cfg!(windows) {
+            TransportKind::Semaphore
+        } else if auth.starts_with("fifo:") {
+            TransportKind::Fifo
+        } else {
+            TransportKind::Pipe
+        }
+    }
+}
+
+impl ClientDescriptor {
+    pub(crate) fn new(auth: String, limit: Option<usize>) -> ClientDescriptor {
+        ClientDescriptor {
+            kind: TransportKind::of(&auth),
+            auth,
+            limit,
+        }
+    }
+
+    /// Returns the kind of OS object the jobserver is shared through.
+    pub fn kind(&self) -> TransportKind {
+        self.kind
+    }
+
+    /// Returns the jobserver auth string, as passed to child processes in
+    /// `--jobserver-auth=`.
+    pub fn auth(&self) -> &str {
+        &self.auth
+    }
+
+    /// Returns the total number of tokens in the jobserver, if known, see
+    /// [`Client::total_tokens`](crate::Client::total_tokens).
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
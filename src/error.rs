@@ -24,6 +24,11 @@ pub enum FromEnvErrorKind {
     NoJobserver,
     /// Cannot parse jobserver environment variable value, incorrect format.
     CannotParse,
+    /// The jobserver environment variable value is not valid UTF-8.
+    ///
+    /// This is only reported on platforms where the jobserver auth has to be
+    /// valid UTF-8; on Unix the raw bytes of the value are used instead.
+    NotUtf8,
     /// Cannot open path or name from the jobserver environment variable value.
     CannotOpenPath,
     /// Cannot open file descriptor from the jobserver environment variable value.
@@ -33,11 +38,62 @@ pub enum FromEnvErrorKind {
     /// ([GNU `make` manual: POSIX Jobserver Interaction](https://www.gnu.org/software/make/manual/make.html#POSIX-Jobserver)).
     NegativeFd,
     /// File descriptor from the jobserver environment variable value is not a pipe.
-    NotAPipe,
+    NotAPipe {
+        /// What the file descriptor refers to instead.
+        actual: FdKind,
+    },
     /// Jobserver inheritance is not supported on this platform.
+    ///
+    /// This is returned on every platform other than Unix and Windows (such
+    /// as `wasm32`), where no jobserver can be shared between processes. A
+    /// [`Client::counting`] jobserver can be used to limit parallelism within
+    /// the process instead.
+    ///
+    /// [`Client::counting`]: crate::Client::counting
     Unsupported,
 }
 
+/// The type of file a file descriptor refers to, as reported in
+/// [`FromEnvErrorKind::NotAPipe`].
+///
+/// This is determined with `fstat` on Unix, and only ever reported there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FdKind {
+    /// A pipe or a named fifo.
+    Fifo,
+    /// A socket.
+    Socket,
+    /// A regular file.
+    Regular,
+    /// A directory.
+    Directory,
+    /// A character device, such as a terminal or `/dev/null`.
+    CharDevice,
+    /// A block device.
+    BlockDevice,
+    /// A symbolic link.
+    Symlink,
+    /// The type couldn't be determined, because `fstat` failed or returned a
+    /// type not listed here.
+    Unknown,
+}
+
+impl std::fmt::Display for FdKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            FdKind::Fifo => "fifo",
+            FdKind::Socket => "socket",
+            FdKind::Regular => "regular file",
+            FdKind::Directory => "directory",
+            FdKind::CharDevice => "character device",
+            FdKind::BlockDevice => "block device",
+            FdKind::Symlink => "symbolic link",
+            FdKind::Unknown => "file of unknown type",
+        })
+    }
+}
+
 impl FromEnvError {
     /// Get the error kind.
     pub fn kind(&self) -> FromEnvErrorKind {
@@ -45,13 +101,49 @@ impl FromEnvError {
             FromEnvErrorInner::NoEnvVar => FromEnvErrorKind::NoEnvVar,
             FromEnvErrorInner::NoJobserver => FromEnvErrorKind::NoJobserver,
             FromEnvErrorInner::CannotParse(_) => FromEnvErrorKind::CannotParse,
+            FromEnvErrorInner::NotUtf8 => FromEnvErrorKind::NotUtf8,
             FromEnvErrorInner::CannotOpenPath(..) => FromEnvErrorKind::CannotOpenPath,
             FromEnvErrorInner::CannotOpenFd(..) => FromEnvErrorKind::CannotOpenFd,
             FromEnvErrorInner::NegativeFd(..) => FromEnvErrorKind::NegativeFd,
-            FromEnvErrorInner::NotAPipe(..) => FromEnvErrorKind::NotAPipe,
+            FromEnvErrorInner::NotAPipe(_, actual, _) => FromEnvErrorKind::NotAPipe { actual },
             FromEnvErrorInner::Unsupported => FromEnvErrorKind::Unsupported,
         }
     }
+
+    /// Returns `true` if there is no environment variable describing a
+    /// jobserver, see [`FromEnvErrorKind::NoEnvVar`].
+    pub fn is_no_env_var(&self) -> bool {
+        matches!(self.inner, FromEnvErrorInner::NoEnvVar)
+    }
+
+    /// Returns `true` if the environment variable doesn't describe a
+    /// jobserver, see [`FromEnvErrorKind::NoJobserver`].
+    pub fn is_no_jobserver(&self) -> bool {
+        matches!(self.inner, FromEnvErrorInner::NoJobserver)
+    }
+
+    /// Returns `true` if the jobserver described by the environment variable
+    /// couldn't be parsed, see [`FromEnvErrorKind::CannotParse`] and
+    /// [`FromEnvErrorKind::NotUtf8`].
+    pub fn is_parse_error(&self) -> bool {
+        matches!(
+            self.inner,
+            FromEnvErrorInner::CannotParse(_) | FromEnvErrorInner::NotUtf8
+        )
+    }
+
+    /// Returns `true` if the jobserver described by the environment variable
+    /// was parsed but couldn't be opened, see
+    /// [`FromEnvErrorKind::CannotOpenPath`], [`FromEnvErrorKind::CannotOpenFd`]
+    /// and [`FromEnvErrorKind::NotAPipe`].
+    pub fn is_io_error(&self) -> bool {
+        matches!(
+            self.inner,
+            FromEnvErrorInner::CannotOpenPath(..)
+                | FromEnvErrorInner::CannotOpenFd(..)
+                | FromEnvErrorInner::NotAPipe(..)
+        )
+    }
 }
 
 impl std::fmt::Display for FromEnvError {
@@ -60,11 +152,13 @@ impl std::fmt::Display for FromEnvError {
             FromEnvErrorInner::NoEnvVar => write!(f, "there is no environment variable that describes jobserver to inherit"),
             FromEnvErrorInner::NoJobserver => write!(f, "there is no `--jobserver-fds=` or `--jobserver-auth=` in the environment variable"),
             FromEnvErrorInner::CannotParse(s) => write!(f, "cannot parse jobserver environment variable value: {s}"),
+            FromEnvErrorInner::NotUtf8 => write!(f, "jobserver environment variable value is not valid UTF-8"),
             FromEnvErrorInner::CannotOpenPath(s, err) => write!(f, "cannot open path or name {s} from the jobserver environment variable value: {err}"),
             FromEnvErrorInner::CannotOpenFd(fd, err) => write!(f, "cannot open file descriptor {fd} from the jobserver environment variable value: {err}"),
             FromEnvErrorInner::NegativeFd(fd) => write!(f, "file descriptor {fd} from the jobserver environment variable value is negative"),
-            FromEnvErrorInner::NotAPipe(fd, None) => write!(f, "file descriptor {fd} from the jobserver environment variable value is not a pipe"),
-            FromEnvErrorInner::NotAPipe(fd, Some(err)) => write!(f, "file descriptor {fd} from the jobserver environment variable value is not a pipe: {err}"),
+            FromEnvErrorInner::NotAPipe(fd, FdKind::Unknown, None) => write!(f, "file descriptor {fd} from the jobserver environment variable value is not a pipe"),
+            FromEnvErrorInner::NotAPipe(fd, actual, None) => write!(f, "file descriptor {fd} from the jobserver environment variable value is not a pipe but a {actual}"),
+            FromEnvErrorInner::NotAPipe(fd, _, Some(err)) => write!(f, "file descriptor {fd} from the jobserver environment variable value is not a pipe: {err}"),
             FromEnvErrorInner::Unsupported => write!(f, "jobserver inheritance is not supported on this platform"),
         }
     }
@@ -73,23 +167,240 @@ impl std::error::Error for FromEnvError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match &self.inner {
             FromEnvErrorInner::CannotOpenPath(_, err) => Some(err),
-            FromEnvErrorInner::NotAPipe(_, Some(err)) | FromEnvErrorInner::CannotOpenFd(_, err) => {
-                Some(err)
-            }
+            FromEnvErrorInner::NotAPipe(_, _, Some(err))
+            | FromEnvErrorInner::CannotOpenFd(_, err) => Some(err),
             _ => None,
         }
     }
 }
 
+impl From<FromEnvError> for std::io::Error {
+    fn from(err: FromEnvError) -> std::io::Error {
+        use std::io::ErrorKind;
+
+        let kind = match &err.inner {
+            FromEnvErrorInner::NoEnvVar | FromEnvErrorInner::NoJobserver => ErrorKind::NotFound,
+            FromEnvErrorInner::CannotParse(_)
+            | FromEnvErrorInner::NotUtf8
+            | FromEnvErrorInner::NegativeFd(_) => ErrorKind::InvalidData,
+            FromEnvErrorInner::CannotOpenPath(_, err) | FromEnvErrorInner::CannotOpenFd(_, err) => {
+                err.kind()
+            }
+            FromEnvErrorInner::NotAPipe(..) => ErrorKind::InvalidInput,
+            FromEnvErrorInner::Unsupported => ErrorKind::Unsupported,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug)]
 pub(crate) enum FromEnvErrorInner {
     NoEnvVar,
     NoJobserver,
     CannotParse(String),
+    NotUtf8,
     CannotOpenPath(String, std::io::Error),
     CannotOpenFd(RawFd, std::io::Error),
     NegativeFd(RawFd),
-    NotAPipe(RawFd, Option<std::io::Error>),
+    NotAPipe(RawFd, FdKind, Option<std::io::Error>),
     Unsupported,
 }
+
+/// Error type for [`Client::new_checked`] function.
+///
+/// [`Client::new_checked`]: crate::Client::new_checked
+#[derive(Debug)]
+pub struct LimitError {
+    pub(crate) inner: LimitErrorInner,
+}
+
+/// Kind of an error returned from [`Client::new_checked`] function.
+///
+/// [`Client::new_checked`]: crate::Client::new_checked
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LimitErrorKind {
+    /// The requested limit is larger than the number of tokens the
+    /// platform's jobserver primitive can hold.
+    TooLarge,
+    /// An I/O error happened while creating the jobserver.
+    Io,
+}
+
+impl LimitError {
+    pub(crate) fn too_large(limit: usize, max: Option<usize>) -> LimitError {
+        LimitError {
+            inner: LimitErrorInner::TooLarge { limit, max },
+        }
+    }
+
+    /// Get the error kind.
+    pub fn kind(&self) -> LimitErrorKind {
+        match self.inner {
+            LimitErrorInner::TooLarge { .. } => LimitErrorKind::TooLarge,
+            LimitErrorInner::Io(_) => LimitErrorKind::Io,
+        }
+    }
+
+    /// Get the largest limit supported by this platform, if the error is
+    /// [`LimitErrorKind::TooLarge`] and the maximum is known.
+    ///
+    /// On Unix the maximum depends on the capacity of the pipe backing the
+    /// jobserver, which is only discovered when it fills up. On Linux this
+    /// returns the capacity of the pipe, and on other Unix platforms it
+    /// returns `None`.
+    pub fn max_limit(&self) -> Option<usize> {
+        match self.inner {
+            LimitErrorInner::TooLarge { max, .. } => max,
+            LimitErrorInner::Io(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.inner {
+            LimitErrorInner::TooLarge { limit, max: Some(max) } => write!(f, "jobserver limit {limit} is larger than the maximum of {max} supported on this platform"),
+            LimitErrorInner::TooLarge { limit, max: None } => write!(f, "jobserver limit {limit} is larger than supported on this platform"),
+            LimitErrorInner::Io(err) => write!(f, "cannot create jobserver: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.inner {
+            LimitErrorInner::Io(err) => Some(err),
+            LimitErrorInner::TooLarge { .. } => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for LimitError {
+    fn from(err: std::io::Error) -> LimitError {
+        LimitError {
+            inner: LimitErrorInner::Io(err),
+        }
+    }
+}
+
+impl From<LimitError> for std::io::Error {
+    fn from(err: LimitError) -> std::io::Error {
+        match err.inner {
+            LimitErrorInner::Io(err) => err,
+            LimitErrorInner::TooLarge { .. } => {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum LimitErrorInner {
+    TooLarge { limit: usize, max: Option<usize> },
+    Io(std::io::Error),
+}
+
+/// Any error returned by this crate.
+///
+/// Connecting to a jobserver fails with a [`FromEnvError`], creating one with
+/// a [`LimitError`] and using one with an [`std::io::Error`]. Libraries which
+/// wrap a jobserver and would rather expose a single error type can convert
+/// all of them into this enum, for example with the `?` operator.
+///
+/// # Examples
+///
+/// ```
+/// use jobserver::{Client, Error};
+///
+/// fn run() -> Result<(), Error> {
+///     let client = match unsafe { Client::from_env_ext(false) }.client {
+///         Ok(client) => client,
+///         Err(e) if e.is_no_env_var() => Client::new_checked(4)?,
+///         Err(e) => return Err(e.into()),
+///     };
+///     let _token = client.acquire()?;
+///     Ok(())
+/// }
+/// # run().unwrap();
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Connecting to a jobserver described by the environment failed.
+    FromEnv(FromEnvError),
+    /// Creating a new jobserver failed.
+    Limit(LimitError),
+    /// An I/O error happened while using a jobserver.
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// Returns `true` if the operation isn't supported on this platform or
+    /// by this kind of jobserver.
+    ///
+    /// This covers both [`FromEnvErrorKind::Unsupported`] and I/O errors of
+    /// kind [`std::io::ErrorKind::Unsupported`], such as returned by
+    /// [`Client::try_acquire`] where tokens can't be acquired without
+    /// blocking.
+    ///
+    /// [`Client::try_acquire`]: crate::Client::try_acquire
+    pub fn is_unsupported(&self) -> bool {
+        match self {
+            Error::FromEnv(err) => matches!(err.inner, FromEnvErrorInner::Unsupported),
+            Error::Limit(_) => false,
+            Error::Io(err) => err.kind() == std::io::ErrorKind::Unsupported,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::FromEnv(err) => err.fmt(f),
+            Error::Limit(err) => err.fmt(f),
+            Error::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // The wrapped error is displayed as this one, so skip straight to its
+        // source to avoid reporting the same message twice.
+        match self {
+            Error::FromEnv(err) => err.source(),
+            Error::Limit(err) => err.source(),
+            Error::Io(err) => err.source(),
+        }
+    }
+}
+
+impl From<FromEnvError> for Error {
+    fn from(err: FromEnvError) -> Error {
+        Error::FromEnv(err)
+    }
+}
+
+impl From<LimitError> for Error {
+    fn from(err: LimitError) -> Error {
+        Error::Limit(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> std::io::Error {
+        match err {
+            Error::FromEnv(err) => err.into(),
+            Error::Limit(err) => err.into(),
+            Error::Io(err) => err,
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::io;
+
+use crate::{Acquired, Client};
+
+/// The acquiring side of a jobserver, returned by [`Client::read_half`].
+///
+/// This only allows taking tokens out of the jobserver. Together with a
+/// [`WriteHalf`] of another jobserver it's the building block for bridges
+/// which forward tokens from one jobserver to another. The jobserver's OS
+/// handles stay open as long as either half or any client of it is alive.
+#[derive(Debug, Clone)]
+pub struct ReadHalf {
+    client: Client,
+}
+
+/// The releasing side of a jobserver, returned by [`Client::write_half`].
+///
+/// This only allows putting tokens into the jobserver, see [`ReadHalf`].
+#[derive(Debug, Clone)]
+pub struct WriteHalf {
+    client: Client,
+}
+
+pub(crate) fn read_half(client: &Client) -> ReadHalf {
+    ReadHalf {
+        client: client.clone(),
+    }
+}
+
+pub(crate) fn write_half(client: &Client) -> WriteHalf {
+    WriteHalf {
+        client: client.clone(),
+    }
+}
+
+impl ReadHalf {
+    /// Acquires a token from the jobserver, see [`Client::acquire`].
+    ///
+    /// The token is released back to this jobserver when dropped. A token
+    /// forwarded to another jobserver must be given up with
+    /// [`Acquired::drop_without_releasing`] instead.
+    pub fn acquire(&self) -> io::Result<Acquired> {
+        self.client.acquire()
+    }
+
+    /// Acquires a token from the jobserver without blocking, see
+    /// [`Client::try_acquire`].
+    pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
+        self.client.try_acquire()
+    }
+}
+
+impl WriteHalf {
+    /// Puts a token into the jobserver, see [`Client::release_raw`].
+    pub fn release(&self) -> io::Result<()> {
+        self.client.release_raw()
+    }
+}
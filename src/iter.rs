@@ -0,0 +1,67 @@
+use std::io;
+use std::thread;
+
+use crate::Client;
+
+/// Extension methods running the items of an iterator in parallel under a
+/// jobserver's limit.
+///
+/// This is implemented for every [`Iterator`], so importing the trait is all
+/// that's needed:
+///
+/// ```
+/// use jobserver::{Client, JobserverIteratorExt};
+///
+/// let client = Client::new(4).unwrap();
+/// let files = ["a.c", "b.c", "c.c"];
+/// files
+///     .iter()
+///     .jobserver_for_each(&client, |file| println!("compiling {}", file))
+///     .unwrap();
+/// ```
+pub trait JobserverIteratorExt: Iterator {
+    /// Calls `f` on each item of this iterator on a thread of its own, with
+    /// no more calls running at once than `client` has tokens to spare.
+    ///
+    /// A token is acquired before the thread for an item is spawned and
+    /// released once `f` returns, so this blocks while the jobserver is
+    /// exhausted, and also plays along with any other process sharing the
+    /// jobserver. Items are taken from the iterator in order, but may finish
+    /// in any order. This returns once every call to `f` has returned.
+    ///
+    /// A thread is spawned per item, so this is meant for items with a
+    /// substantial amount of work each, like compiling a file.
+    ///
+    /// # Errors
+    ///
+    /// If acquiring a token fails no more items are taken from the iterator,
+    /// and the error is returned once the calls to `f` already running have
+    /// returned.
+    ///
+    /// # Panics
+    ///
+    /// If any call to `f` panics, the panic is propagated once all other
+    /// calls have returned.
+    fn jobserver_for_each<F>(self, client: &Client, f: F) -> io::Result<()>
+    where
+        Self: Sized,
+        Self::Item: Send,
+        F: Fn(Self::Item) + Sync,
+    {
+        let f = &f;
+        thread::scope(|scope| {
+            for item in self {
+                let mut token = client.acquire()?;
+                token.detach_holder();
+                scope.spawn(move || {
+                    token.attach_holder();
+                    f(item);
+                    drop(token);
+                });
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<I: Iterator> JobserverIteratorExt for I {}
@@ -0,0 +1,126 @@
+// `std::backtrace` needs Rust 1.65, which the `leak-check` feature requires.
+#![allow(clippy::incompatible_msrv)]
+
+use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Backtraces of where every token currently held through a client was
+/// acquired, see [`crate::Client::check_leaks`].
+#[derive(Debug, Default)]
+pub(crate) struct LiveTokens {
+    next_id: AtomicUsize,
+    live: Mutex<BTreeMap<usize, LiveToken>>,
+}
+
+#[derive(Debug, Clone)]
+struct LiveToken {
+    /// Set by [`crate::Client::acquire_labeled`].
+    label: Option<String>,
+    backtrace: Arc<Backtrace>,
+}
+
+impl LiveTokens {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<usize, LiveToken>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn report(&self) -> Option<LeakReport> {
+        let live = self.lock();
+        if live.is_empty() {
+            return None;
+        }
+        Some(LeakReport {
+            tokens: live.values().cloned().collect(),
+        })
+    }
+}
+
+/// Keeps track of a [`crate::Acquired`] token from its acquisition until
+/// it's dropped.
+#[derive(Debug)]
+pub(crate) struct LeakGuard {
+    id: usize,
+    state: Arc<crate::ClientState>,
+}
+
+impl LeakGuard {
+    pub(crate) fn new(client: &crate::Client) -> LeakGuard {
+        let tokens = &client.state.live_tokens;
+        let id = tokens.next_id.fetch_add(1, Ordering::Relaxed);
+        tokens.lock().insert(
+            id,
+            LiveToken {
+                label: None,
+                backtrace: Arc::new(Backtrace::force_capture()),
+            },
+        );
+        LeakGuard {
+            id,
+            state: client.state.clone(),
+        }
+    }
+
+    /// Records the label the token was acquired with.
+    pub(crate) fn label(&self, label: &str) {
+        if let Some(token) = self.state.live_tokens.lock().get_mut(&self.id) {
+            token.label = Some(label.to_owned());
+        }
+    }
+}
+
+impl Drop for LeakGuard {
+    fn drop(&mut self) {
+        self.state.live_tokens.lock().remove(&self.id);
+    }
+}
+
+/// Tokens still held through a client, returned by
+/// [`Client::check_leaks`](crate::Client::check_leaks).
+///
+/// The [`Display`](fmt::Display) implementation lists where each of the
+/// tokens was acquired, oldest first, along with the label of tokens acquired
+/// with [`Client::acquire_labeled`](crate::Client::acquire_labeled).
+#[derive(Debug, Clone)]
+pub struct LeakReport {
+    tokens: Vec<LiveToken>,
+}
+
+impl LeakReport {
+    /// Returns the number of tokens still held.
+    #[allow(clippy::len_without_is_empty)] // a report is never empty
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns the backtraces of where each of the tokens still held was
+    /// acquired, oldest first.
+    pub fn backtraces(&self) -> impl Iterator<Item = &Backtrace> {
+        self.tokens.iter().map(|t| &*t.backtrace)
+    }
+
+    /// Returns the labels each of the tokens still held was acquired with,
+    /// in the same order as [`LeakReport::backtraces`].
+    ///
+    /// The label is `None` for tokens not acquired with
+    /// [`Client::acquire_labeled`](crate::Client::acquire_labeled).
+    pub fn labels(&self) -> impl Iterator<Item = Option<&str>> {
+        self.tokens.iter().map(|t| t.label.as_deref())
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} jobserver token(s) still held", self.len())?;
+        for (i, token) in self.tokens.iter().enumerate() {
+            let backtrace = &token.backtrace;
+            match &token.label {
+                Some(label) => write!(f, "\n\ntoken {i} ({label}) acquired at:\n{backtrace}")?,
+                None => write!(f, "\n\ntoken {i} acquired at:\n{backtrace}")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -65,6 +65,36 @@
 //! Note that this is typically ok as ctrl-c means that an entire build process
 //! is being torn down, but it's worth being aware of at least!
 //!
+//! ## Cargo features
+//!
+//! * `std` (enabled by default): everything but [`Semaphore`] requires the
+//!   standard library. With `default-features = false` this crate is
+//!   `#![no_std]` and only provides [`Semaphore`], an in-process limiter
+//!   which doesn't even need `alloc`. Every other feature enables `std`.
+//! * `tracing`: emits [`tracing`](https://docs.rs/tracing) spans and events
+//!   for every token acquired and released, recording how long acquisition
+//!   blocked for and how many tokens are held through the client. Without
+//!   this feature no instrumentation is compiled in at all.
+//! * `leak-check`: records a backtrace whenever a token is acquired, so that
+//!   [`Client::check_leaks`] can report where tokens which are still held
+//!   were acquired. This is meant for debugging builds which use fewer
+//!   cores than expected and is costly, so don't enable it in release
+//!   builds. It requires Rust 1.65 or later.
+//! * `serde`: implements `Serialize` and `Deserialize` for
+//!   [`ClientDescriptor`], so the information needed to connect to a
+//!   jobserver can be sent to other processes.
+//! * `testing`: adds [`Client::set_fail_next_acquire`] to make acquiring a
+//!   token from a [`Client::counting`] jobserver fail on demand, for testing
+//!   how code copes with jobserver errors, and
+//!   [`Client::set_release_order`] to hand released tokens back in a
+//!   deterministic order.
+//! * `rayon`: adds [`Client::bind_rayon`] to run tasks on a
+//!   [`rayon`](https://docs.rs/rayon) thread pool under the jobserver's
+//!   limit.
+//! * `async-io`: adds `Client::acquire_async_io` on Unix to wait for tokens
+//!   on the [`async-io`](https://docs.rs/async-io) reactor used by smol and
+//!   async-std, without blocking an executor thread.
+//!
 //! ## Windows caveats
 //!
 //! There appear to be two implementations of `make` on Windows. On MSYS2 one
@@ -78,25 +108,99 @@
 //!
 //! [docs]: http://make.mad-scientist.net/papers/jobserver-implementation/
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(missing_docs, missing_debug_implementations)]
 #![doc(html_root_url = "https://docs.rs/jobserver/0.1")]
 
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::env;
-use std::ffi::OsString;
+#[cfg(feature = "std")]
+use std::ffi::{OsStr, OsString};
+#[cfg(feature = "std")]
+use std::fmt;
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::process::Command;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(feature = "std")]
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
 
+#[cfg(all(unix, feature = "async-io"))]
+mod async_acquire;
+#[cfg(feature = "std")]
+mod audit;
+#[cfg(feature = "std")]
+mod boost;
+#[cfg(feature = "std")]
+mod bridge;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "std")]
+mod cancel;
+#[cfg(feature = "std")]
+mod capped;
+#[cfg(feature = "std")]
+mod child;
+#[cfg(feature = "std")]
+mod command;
+#[cfg(feature = "std")]
+mod counting;
+#[cfg(feature = "std")]
+mod deadlock;
+#[cfg(feature = "std")]
+mod descriptor;
+#[cfg(feature = "std")]
 mod error;
-#[cfg(unix)]
+#[cfg(feature = "std")]
+mod half;
+#[cfg(all(feature = "std", unix))]
 #[path = "unix.rs"]
 mod imp;
-#[cfg(windows)]
+#[cfg(all(feature = "std", windows))]
 #[path = "windows.rs"]
 mod imp;
-#[cfg(not(any(unix, windows)))]
+#[cfg(all(feature = "std", not(any(unix, windows))))]
 #[path = "wasm.rs"]
 mod imp;
+#[cfg(feature = "std")]
+mod iter;
+#[cfg(feature = "leak-check")]
+mod leak;
+#[cfg(feature = "std")]
+mod makeflags;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
+mod raw;
+#[cfg(feature = "rayon")]
+mod rayon_pool;
+#[cfg(all(unix, feature = "std"))]
+mod readiness;
+#[cfg(feature = "testing")]
+mod release_order;
+#[cfg(feature = "std")]
+mod sanity;
+mod semaphore;
+#[cfg(feature = "std")]
+mod split;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod swap;
+#[cfg(feature = "tracing")]
+mod trace;
+#[cfg(feature = "std")]
+mod watch;
+#[cfg(feature = "std")]
+mod weighted;
 
 /// A client of a jobserver
 ///
@@ -111,23 +215,255 @@ mod imp;
 ///
 /// Note that a [`Client`] implements the [`Clone`] trait, and all instances of
 /// a [`Client`] refer to the same jobserver instance.
+#[cfg(feature = "std")]
 #[derive(Clone, Debug)]
 pub struct Client {
-    inner: Arc<imp::Client>,
+    inner: Arc<swap::BackendCell>,
+    state: Arc<ClientState>,
+}
+
+/// In-process state shared by all clones of a [`Client`].
+#[cfg(feature = "std")]
+#[derive(Default, Debug)]
+struct ClientState {
+    /// What this process's implicit token is currently used for.
+    implicit: Mutex<ImplicitToken>,
+    /// Total number of tokens in the jobserver, if known.
+    limit: Mutex<Option<usize>>,
+    /// Whether the jobserver was created by this process, see
+    /// [`Client::is_owner`].
+    owned: bool,
+    /// Number of tokens currently held through this client.
+    #[cfg(feature = "tracing")]
+    held: std::sync::atomic::AtomicUsize,
+    /// Where every token currently held through this client was acquired.
+    #[cfg(feature = "leak-check")]
+    live_tokens: leak::LiveTokens,
+    /// Number of tokens held by each thread, if this client checks for
+    /// deadlocks, see [`Client::new_deadlock_checked`].
+    holders: Option<deadlock::Holders>,
+    /// Whether [`Client::shutdown`] has been called on a clone of this
+    /// client.
+    shut_down: AtomicBool,
+    /// Whether to yield after releasing a token, see
+    /// [`Client::set_cooperative`].
+    cooperative: AtomicBool,
+    /// See [`Client::set_track_depth`].
+    track_depth: AtomicBool,
+    /// Whether acquisitions are held back, see [`Client::pause`].
+    paused: Mutex<bool>,
+    /// Bumped by [`Client::reset_to`], see [`Client::epoch`].
+    epoch: AtomicU64,
+    /// See [`Client::set_default_acquire_timeout`].
+    acquire_timeout: Mutex<Option<Duration>>,
+    /// The backends of the clients sharing this state, see
+    /// [`Client::replace_inner`].
+    cells: swap::Cells,
+    /// Held while gathering the tokens of [`Client::acquire_weighted`].
+    weighted: Mutex<()>,
+    /// Notified when the client is resumed or shut down.
+    resumed: Condvar,
+    /// The number of available tokens as last queried, see
+    /// [`Client::available_cached`].
+    available: cache::AvailableCache,
+    /// See [`Client::stats`].
+    stats: stats::Counters,
+    /// See [`Client::set_release_order`].
+    #[cfg(feature = "testing")]
+    release_queue: release_order::ReleaseQueue,
+}
+
+/// State of the implicit token every process spawned by `make` holds.
+#[cfg(feature = "std")]
+#[derive(Default, Debug)]
+struct ImplicitToken {
+    /// Whether it has been handed back to the jobserver with
+    /// [`Client::release_implicit`].
+    released: bool,
+    /// Whether it's handed out by [`Client::acquire`], see
+    /// [`Client::with_implicit_token`].
+    lendable: bool,
+    /// Whether it's currently handed out as an [`Acquired`] token.
+    lent: bool,
+}
+
+/// The actual implementation behind a [`Client`].
+#[cfg(feature = "std")]
+#[derive(Debug)]
+enum Backend {
+    /// A jobserver shared with other processes through the OS.
+    Os(imp::Client),
+    /// A purely in-process jobserver, see [`Client::counting`].
+    Counting(counting::Client),
+    /// Another backend with an in-process limit on top, see
+    /// [`Client::capped`].
+    Capped {
+        inner: Arc<Backend>,
+        cap: counting::Client,
+    },
+    /// Another backend whose blocking acquisitions can be cancelled, see
+    /// [`Client::with_cancellation`].
+    Cancellable {
+        inner: Arc<Backend>,
+        signal: Arc<cancel::Signal>,
+    },
+    /// What's left after [`Client::shutdown`], which holds on to no OS
+    /// handles.
+    Closed,
 }
 
 /// An acquired token from a jobserver.
 ///
 /// This token will be released back to the jobserver when it is dropped and
 /// otherwise represents the ability to spawn off another thread of work.
+///
+/// On Unix each token is a byte read from the jobserver's pipe, and releasing
+/// the token writes that same byte back rather than a placeholder, as GNU
+/// `make` may use different bytes to tell kinds of tokens apart.
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub struct Acquired {
-    client: Arc<imp::Client>,
-    data: imp::Acquired,
-    disabled: bool,
+    hold: Arc<TokenHold>,
+}
+
+/// A weak handle to an [`Acquired`] token, returned by
+/// [`Acquired::downgrade`].
+///
+/// This doesn't keep the token held: it's released as usual once the
+/// [`Acquired`] token it was created from is dropped, after which
+/// [`WeakAcquired::upgrade`] returns `None`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct WeakAcquired {
+    hold: std::sync::Weak<TokenHold>,
+}
+
+/// The hold on a token shared by [`Acquired`] handles to it, which releases
+/// the token when dropped.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct TokenHold {
+    client: Arc<Backend>,
+    /// `None` for tokens acquired from an in-process backend.
+    data: Option<imp::Acquired>,
+    disabled: AtomicBool,
+    #[cfg(feature = "tracing")]
+    trace: trace::TokenSpan,
+    #[cfg(feature = "leak-check")]
+    _leak: leak::LeakGuard,
+    holder: Option<deadlock::HolderGuard>,
+    state: Arc<ClientState>,
+    /// Whether this is the implicit token, see [`Client::with_implicit_token`].
+    implicit: bool,
+    /// Set by [`Client::acquire_labeled`].
+    label: Option<String>,
+    acquired_at: Instant,
+    /// The [`Client::epoch`] the token was acquired in.
+    epoch: u64,
 }
 
+#[cfg(feature = "std")]
 impl Acquired {
+    fn new(client: &Client, data: Option<imp::Acquired>) -> Acquired {
+        client.state.available.invalidate();
+        client.state.stats.acquired();
+        let hold = TokenHold {
+            client: client.backend(),
+            data,
+            disabled: AtomicBool::new(false),
+            #[cfg(feature = "tracing")]
+            trace: trace::TokenSpan::new(client),
+            #[cfg(feature = "leak-check")]
+            _leak: leak::LeakGuard::new(client),
+            holder: deadlock::HolderGuard::new(&client.state),
+            state: client.state.clone(),
+            implicit: false,
+            label: None,
+            acquired_at: Instant::now(),
+            epoch: client.epoch(),
+        };
+        Acquired {
+            hold: Arc::new(hold),
+        }
+    }
+
+    /// Returns the hold on this token for setting it up, which is only done
+    /// before it's handed out and so before it could be shared.
+    fn hold_mut(&mut self) -> &mut TokenHold {
+        Arc::get_mut(&mut self.hold).expect("token is not shared yet")
+    }
+
+    /// Stops counting this token towards the current thread for deadlock
+    /// checks, see [`Client::new_deadlock_checked`], before handing it to
+    /// another thread which calls [`Acquired::attach_holder`].
+    fn detach_holder(&mut self) {
+        self.hold_mut().holder = None;
+    }
+
+    /// Counts this token towards the current thread for deadlock checks.
+    fn attach_holder(&mut self) {
+        let hold = self.hold_mut();
+        hold.holder = deadlock::HolderGuard::new(&hold.state);
+    }
+
+    fn set_label(&mut self, label: &str) {
+        let hold = self.hold_mut();
+        #[cfg(feature = "tracing")]
+        hold.trace.record_label(label);
+        #[cfg(feature = "leak-check")]
+        hold._leak.label(label);
+        hold.label = Some(label.to_owned());
+    }
+
+    /// Returns how long this token has been held for since it was acquired.
+    ///
+    /// Once dropped the time a token was held for is added to the
+    /// [`JobserverStats::held_time`] of its client, which together with this
+    /// can be used to find jobs holding on to tokens for long.
+    pub fn held_for(&self) -> Duration {
+        self.hold.acquired_at.elapsed()
+    }
+
+    /// Returns whether the jobserver was reset with [`Client::reset_to`]
+    /// since this token was acquired.
+    ///
+    /// Stale tokens aren't released when dropped, as the reset already put
+    /// back the tokens the jobserver should have, so releasing them on top
+    /// would leave it with too many. With the `tracing` feature a warning is
+    /// emitted when that happens.
+    pub fn is_stale(&self) -> bool {
+        self.hold.is_stale()
+    }
+
+    /// Returns the label this token was acquired with by
+    /// [`Client::acquire_labeled`], if any.
+    pub fn label(&self) -> Option<&str> {
+        self.hold.label.as_deref()
+    }
+
+    /// Hands out the implicit token of `client` if it's lendable and not in
+    /// use, see [`Client::with_implicit_token`].
+    fn implicit(client: &Client) -> Option<Acquired> {
+        let mut implicit = client.state.lock_implicit();
+        if !implicit.lendable || implicit.released || implicit.lent {
+            return None;
+        }
+        implicit.lent = true;
+        drop(implicit);
+        let mut acquired = Acquired::new(client, None);
+        acquired.hold_mut().implicit = true;
+        Some(acquired)
+    }
+
+    /// Moves this token, acquired from the backend underneath a capped
+    /// client, over to the capped `client` itself.
+    fn recap(mut self, client: &Client) -> Acquired {
+        // Both clients share their state, so only the backend to release the
+        // token to changes.
+        self.hold_mut().client = client.backend();
+        self
+    }
+
     /// This drops the [`Acquired`] token without releasing the associated token.
     ///
     /// This is not generally useful, but can be helpful if you do not have the
@@ -135,17 +471,144 @@ impl Acquired {
     ///
     /// You'll typically want to follow this up with a call to
     /// [`Client::release_raw`] or similar to actually release the token later on.
-    pub fn drop_without_releasing(mut self) {
-        self.disabled = true;
+    /// That doesn't work for the implicit token handed out by a client
+    /// created with [`Client::with_implicit_token`], which stays in use for
+    /// good, so use [`Acquired::leak`] and [`Client::reclaim_raw`] for it
+    /// instead.
+    pub fn drop_without_releasing(self) {
+        self.hold.disabled.store(true, Ordering::SeqCst);
+    }
+
+    /// Converts this [`Acquired`] token into a raw value without releasing it.
+    ///
+    /// This is intended for handing ownership of a token across an FFI
+    /// boundary. The returned [`AcquiredRaw`] should eventually be passed back
+    /// to [`Client::reclaim_raw`] on the client it was acquired from to turn it
+    /// back into an [`Acquired`] token, which releases it as usual.
+    pub fn leak(self) -> AcquiredRaw {
+        self.hold.disabled.store(true, Ordering::SeqCst);
+        if self.hold.implicit {
+            return AcquiredRaw::IMPLICIT;
+        }
+        AcquiredRaw(self.hold.data.as_ref().map_or(0, imp::Acquired::to_raw))
+    }
+
+    /// Creates a weak handle to this token, which doesn't keep it held.
+    ///
+    /// This is for observing which tokens are still held without extending
+    /// their lifetime, for example from a cache mapping tasks to the tokens
+    /// they hold: the token is still released when this [`Acquired`] is
+    /// dropped, no matter how many weak handles to it are around.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// let token = client.acquire().unwrap();
+    /// let weak = token.downgrade();
+    /// assert!(weak.upgrade().is_some());
+    /// drop(token);
+    /// assert!(weak.upgrade().is_none());
+    /// ```
+    pub fn downgrade(&self) -> WeakAcquired {
+        WeakAcquired {
+            hold: Arc::downgrade(&self.hold),
+        }
+    }
+
+    /// Splits this token into `n` shares, for handing out to sub-tasks.
+    ///
+    /// This lets a subsystem run its own finer-grained jobs under a single
+    /// jobserver slot without touching the jobserver again. The token is held
+    /// until every [`SubToken`] has been dropped, at which point it's released
+    /// back to the jobserver, or until the last one is turned back into this
+    /// token with [`SubToken::merge`].
+    ///
+    /// Splitting into zero shares releases the token right away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// let shares = client.acquire().unwrap().split(4);
+    /// let mut token = None;
+    /// for share in shares {
+    ///     // ... hand out to a sub-task, which eventually gives it back ...
+    ///     token = share.merge();
+    /// }
+    /// let token = token.unwrap();
+    /// ```
+    pub fn split(self, n: usize) -> Vec<SubToken> {
+        split::split(self, n)
+    }
+}
+
+/// A raw jobserver token returned by [`Acquired::leak`].
+///
+/// This is a plain integer which can be passed through FFI, for example with
+/// [`AcquiredRaw::into_usize`], and later be turned back into an [`Acquired`]
+/// token with [`Client::reclaim_raw`].
+#[cfg(feature = "std")]
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AcquiredRaw(usize);
+
+#[cfg(feature = "std")]
+impl AcquiredRaw {
+    /// The implicit token, see [`Client::with_implicit_token`], which isn't
+    /// from the jobserver and so can't clash with the tokens which are.
+    const IMPLICIT: AcquiredRaw = AcquiredRaw(usize::MAX);
+
+    /// Gets the integer representation of this token.
+    ///
+    /// The implicit token handed out by a client created with
+    /// [`Client::with_implicit_token`] is represented by `usize::MAX`.
+    pub fn into_usize(self) -> usize {
+        self.0
+    }
+
+    /// Creates a token from its integer representation as returned by
+    /// [`AcquiredRaw::into_usize`].
+    pub fn from_usize(raw: usize) -> AcquiredRaw {
+        AcquiredRaw(raw)
+    }
+}
+
+#[cfg(feature = "std")]
+impl WeakAcquired {
+    /// Returns a handle to the token if it's still held, or `None` if it has
+    /// been released already.
+    ///
+    /// The returned [`Acquired`] shares the token with the one this weak
+    /// handle was created from, keeping it held until both are dropped, so it
+    /// should only be kept around briefly to not hold the token for longer
+    /// than its owner intended. Calling [`Acquired::drop_without_releasing`]
+    /// or [`Acquired::leak`] on either keeps the token from being released
+    /// when the other is dropped as well.
+    pub fn upgrade(&self) -> Option<Acquired> {
+        self.hold.upgrade().map(|hold| Acquired { hold })
+    }
+}
+
+#[cfg(feature = "std")]
+impl TokenHold {
+    fn is_stale(&self) -> bool {
+        !self.implicit && self.epoch != self.state.epoch.load(Ordering::SeqCst)
     }
 }
 
+#[cfg(feature = "std")]
 #[derive(Default, Debug)]
 struct HelperState {
     lock: Mutex<HelperInner>,
     cvar: Condvar,
 }
 
+#[cfg(feature = "std")]
 #[derive(Default, Debug)]
 struct HelperInner {
     requests: usize,
@@ -153,10 +616,59 @@ struct HelperInner {
     consumer_done: bool,
 }
 
+#[cfg(feature = "std")]
+pub use audit::AuditReport;
+#[cfg(feature = "std")]
+pub use boost::BoostGuard;
+#[cfg(feature = "std")]
+pub use bridge::JobserverBridge;
+#[cfg(feature = "std")]
+pub use cancel::CancelHandle;
+#[cfg(feature = "std")]
+pub use child::LimitedChild;
+#[cfg(feature = "std")]
+pub use command::ConfigurableCommand;
+#[cfg(feature = "std")]
+pub use descriptor::{ClientDescriptor, TransportKind};
+#[cfg(feature = "std")]
 use error::FromEnvErrorInner;
-pub use error::{FromEnvError, FromEnvErrorKind};
+#[cfg(feature = "std")]
+pub use error::{Error, FdKind, FromEnvError, FromEnvErrorKind, LimitError, LimitErrorKind};
+#[cfg(feature = "std")]
+pub use half::{ReadHalf, WriteHalf};
+#[cfg(feature = "std")]
+pub use iter::JobserverIteratorExt;
+#[cfg(feature = "leak-check")]
+pub use leak::LeakReport;
+#[cfg(feature = "std")]
+pub use makeflags::{parse_makeflags, MakeFlags};
+#[cfg(feature = "std")]
+pub use pool::{PooledToken, TokenPool};
+#[cfg(feature = "std")]
+pub use raw::RawClient;
+#[cfg(feature = "rayon")]
+pub use rayon_pool::RayonPool;
+#[cfg(all(unix, feature = "std"))]
+pub use readiness::ReadinessFd;
+#[cfg(feature = "testing")]
+pub use release_order::ReleaseOrder;
+#[cfg(feature = "std")]
+pub use sanity::Warning;
+pub use semaphore::{Semaphore, SemaphoreToken};
+#[cfg(feature = "std")]
+pub use split::SubToken;
+#[cfg(feature = "std")]
+pub use stats::JobserverStats;
+#[cfg(feature = "std")]
+pub use watch::AvailableWatch;
+#[cfg(feature = "std")]
+pub use weighted::WeightedAcquired;
+
+#[cfg(all(feature = "std", windows))]
+pub use imp::JobObject;
 
 /// Return type for [`Client::from_env_ext`] function.
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub struct FromEnv {
     /// Result of trying to get jobserver client from env.
@@ -164,24 +676,229 @@ pub struct FromEnv {
     /// Name and value of the environment variable.
     /// `None` if no relevant environment variable is found.
     pub var: Option<(&'static str, OsString)>,
+    /// Flavor of the jobserver protocol found in the environment variable.
+    /// `None` if no jobserver is described by it.
+    ///
+    /// This is set even if connecting to the jobserver failed.
+    pub protocol: Option<JobserverProtocol>,
+    /// Number of processes between this one and the outermost process which
+    /// configured a child with [`Client::configure`] while tracking the
+    /// depth, see [`Client::set_track_depth`], read from the
+    /// `JOBSERVER_DEPTH` environment variable.
+    ///
+    /// This is 0 if the variable isn't set or isn't a number, and is read even
+    /// if no jobserver is found. A process creating a jobserver of its own at
+    /// a depth greater than 0 probably runs under a build which already has
+    /// one, and could inherit that instead of fragmenting the token pool.
+    pub depth: usize,
+}
+
+/// Flavor of the jobserver protocol passed down by `make`, see
+/// [`FromEnv::protocol`].
+///
+/// Each generation of GNU `make` describes its jobserver differently, so this
+/// can be used to tell roughly which version of `make` a process is running
+/// under.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum JobserverProtocol {
+    /// `--jobserver-fds=R,W`, passed by `make` before 4.2.
+    Fds,
+    /// `--jobserver-auth=R,W` on Unix, passed by `make` 4.2 and 4.3 (and by
+    /// later versions with `--jobserver-style=pipe`), or
+    /// `--jobserver-auth=NAME` of a semaphore on Windows.
+    Auth,
+    /// `--jobserver-auth=fifo:PATH`, passed by `make` 4.4 and later on Unix.
+    Fifo,
 }
 
+/// Kind of jobserver named by a `--jobserver-style=` option next to the
+/// jobserver auth, which `make` 4.4 and later use to tell them apart.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobserverStyle {
+    /// An anonymous pipe named by `R,W`.
+    Pipe,
+    /// A named pipe named by `fifo:PATH`.
+    Fifo,
+    /// A semaphore on Windows, named by its name.
+    Sem,
+}
+
+#[cfg(feature = "std")]
 impl FromEnv {
-    fn new_ok(client: Client, var_name: &'static str, var_value: OsString) -> FromEnv {
+    /// Returns the result of connecting to the jobserver, discarding the
+    /// environment variable it was found in.
+    ///
+    /// Combined with the conversion from [`FromEnvError`] to [`io::Error`]
+    /// this can be used with `?` in functions returning [`io::Result`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// fn connect() -> std::io::Result<Client> {
+    ///     Ok(unsafe { Client::from_env_ext(false) }.into_result()?)
+    /// }
+    /// ```
+    pub fn into_result(self) -> Result<Client, FromEnvError> {
+        self.client
+    }
+
+    /// Returns the client connected to, or calls `f` with the error if
+    /// connecting to the jobserver failed.
+    ///
+    /// # Examples
+    ///
+    /// Falling back to a jobserver of our own if none was passed down:
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = unsafe { Client::from_env_ext(false) }
+    ///     .client_or_else(|_| Client::new(4))
+    ///     .unwrap();
+    /// ```
+    pub fn client_or_else<E, F>(self, f: F) -> Result<Client, E>
+    where
+        F: FnOnce(FromEnvError) -> Result<Client, E>,
+    {
+        self.client.or_else(f)
+    }
+
+    fn new_ok(
+        client: Client,
+        protocol: Option<JobserverProtocol>,
+        var_name: &'static str,
+        var_value: OsString,
+    ) -> FromEnv {
         FromEnv {
             client: Ok(client),
             var: Some((var_name, var_value)),
+            protocol,
+            depth: 0,
         }
     }
-    fn new_err(kind: FromEnvErrorInner, var_name: &'static str, var_value: OsString) -> FromEnv {
+    fn new_err(
+        kind: FromEnvErrorInner,
+        protocol: Option<JobserverProtocol>,
+        var_name: &'static str,
+        var_value: OsString,
+    ) -> FromEnv {
         FromEnv {
             client: Err(FromEnvError { inner: kind }),
             var: Some((var_name, var_value)),
+            protocol,
+            depth: 0,
+        }
+    }
+}
+
+/// The jobserver most recently connected to by [`Client::from_env_ext`]
+/// along with the environment it was connected from.
+#[cfg(feature = "std")]
+static FROM_ENV_CACHE: Mutex<Option<FromEnvCache>> = Mutex::new(None);
+
+/// The environment variables a jobserver is looked for in, in order.
+#[cfg(feature = "std")]
+const MAKEFLAGS_VARS: [&str; 4] = ["CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS", "GNUMAKEFLAGS"];
+
+/// The environment variable [`FromEnv::depth`] is read from, and which
+/// [`Client::configure`] sets one higher for the child if asked to, see
+/// [`Client::set_track_depth`].
+#[cfg(feature = "std")]
+const DEPTH_VAR: &str = "JOBSERVER_DEPTH";
+
+/// Parses the value of [`DEPTH_VAR`], treating anything but a number as 0.
+#[cfg(feature = "std")]
+fn parse_depth(var: Option<OsString>) -> usize {
+    var.and_then(|var| var.to_str()?.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns the bytes of the value `var` of one of [`MAKEFLAGS_VARS`].
+///
+/// On Unix the jobserver auth may legitimately contain non-UTF-8 bytes, for
+/// example in the path of a `fifo:` jobserver, so this is the raw bytes
+/// there. Elsewhere the value has to be valid UTF-8.
+#[cfg(feature = "std")]
+fn var_bytes(var: &OsStr) -> Option<&[u8]> {
+    #[cfg(unix)]
+    return Some(std::os::unix::ffi::OsStrExt::as_bytes(var));
+    #[cfg(not(unix))]
+    return var.to_str().map(str::as_bytes);
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug)]
+struct FromEnvCache {
+    var_name: &'static str,
+    var_value: OsString,
+    check_pipe: bool,
+    /// Shared by the clients returned for this environment, which each have
+    /// their own state.
+    backend: Arc<Backend>,
+    limit: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl FromEnvCache {
+    /// Forgets the cached jobserver if it's `backend`, so that the next
+    /// [`Client::from_env_ext`] connects to it again.
+    fn forget(backend: &Backend) {
+        let mut cache = FROM_ENV_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = &*cache {
+            if std::ptr::eq(&*cached.backend, backend) {
+                *cache = None;
+            }
         }
     }
 }
 
+#[cfg(feature = "std")]
 impl Client {
+    /// `owned` is whether the jobserver was created by this process, see
+    /// [`Client::is_owner`].
+    fn from_backend(backend: Backend, limit: Option<usize>, owned: bool) -> Client {
+        Client::from_shared_backend(Arc::new(backend), limit, owned)
+    }
+
+    /// Like `from_backend`, for a backend which may already be used by
+    /// other clients, which don't share anything else with this one.
+    fn from_shared_backend(backend: Arc<Backend>, limit: Option<usize>, owned: bool) -> Client {
+        let state = ClientState {
+            limit: Mutex::new(limit),
+            owned,
+            ..Default::default()
+        };
+        Client::from_parts(backend, Arc::new(state))
+    }
+
+    /// Creates a client for `backend` which isn't shared with any other
+    /// client yet, but is switched over along with every other client
+    /// sharing `state`, see [`Client::replace_inner`].
+    fn from_parts(backend: Arc<Backend>, state: Arc<ClientState>) -> Client {
+        let client = Client::pinned(backend, state);
+        client.state.cells.register(&client.inner);
+        client
+    }
+
+    /// Like `from_parts`, but keeps using `backend` for good, for clients
+    /// used internally by helper threads.
+    fn pinned(backend: Arc<Backend>, state: Arc<ClientState>) -> Client {
+        Client {
+            inner: Arc::new(swap::BackendCell::new(backend)),
+            state,
+        }
+    }
+
+    /// Returns the backend this client currently uses.
+    fn backend(&self) -> Arc<Backend> {
+        self.inner.load()
+    }
+
     /// Creates a new jobserver initialized with the given parallelism limit.
     ///
     /// A client to the jobserver created will be returned. This client will
@@ -193,6 +910,10 @@ impl Client {
     /// [`Client::configure`] function is required for a child process to have
     /// access to a job server.
     ///
+    /// A `limit` of zero is allowed and creates a jobserver without any
+    /// tokens, so [`Client::acquire`] blocks until a token is released into it
+    /// with [`Client::release_raw`].
+    ///
     /// # Examples
     ///
     /// ```
@@ -203,164 +924,2302 @@ impl Client {
     ///
     /// # Errors
     ///
-    /// Returns an error if any I/O error happens when attempting to create the
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] if `limit` is more tokens than the
+    /// platform's jobserver can hold, see [`Client::new_checked`]. Otherwise
+    /// returns an error if any I/O error happens when attempting to create the
     /// jobserver client.
     pub fn new(limit: usize) -> io::Result<Client> {
-        Ok(Client {
-            inner: Arc::new(imp::Client::new(limit)?),
-        })
+        Ok(Client::new_checked(limit)?)
     }
 
-    /// Attempts to connect to the jobserver specified in this process's
-    /// environment.
+    /// Creates a new jobserver initialized with the given parallelism limit,
+    /// reporting limits the platform can't represent separately from I/O
+    /// errors.
     ///
-    /// When the a `make` executable calls a child process it will configure the
-    /// environment of the child to ensure that it has handles to the jobserver
-    /// it's passing down. This function will attempt to look for these details
-    /// and connect to the jobserver.
+    /// On Windows the limit can't exceed the maximum count of a semaphore,
+    /// which is `i32::MAX`. On Unix all tokens are written into a pipe up
+    /// front, so the limit can't exceed the capacity of that pipe (typically
+    /// 64KiB), which is only discovered when creating the jobserver. On Linux
+    /// the pipe is grown to fit the limit if needed, up to
+    /// `/proc/sys/fs/pipe-max-size` (1MiB by default) for unprivileged
+    /// processes.
     ///
-    /// Note that the created [`Client`] is not automatically inherited into
-    /// spawned child processes from this program. Manual usage of the
-    /// [`Client::configure`] function is required for a child process to have
-    /// access to a job server.
+    /// # Examples
     ///
-    /// # Return value
+    /// ```
+    /// use jobserver::{Client, LimitErrorKind};
     ///
-    /// [`FromEnv`] contains result and relevant environment variable.
-    /// If a jobserver was found in the environment and it looks correct then
-    /// result with the connected client will be returned. In other cases
-    /// result will contain `Err(FromEnvErr)`.
+    /// match Client::new_checked(usize::MAX) {
+    ///     Ok(_) => {}
+    ///     Err(e) => assert!(matches!(e.kind(), LimitErrorKind::TooLarge)),
+    /// }
+    /// ```
     ///
-    /// Additionally on Unix this function will configure the file descriptors
-    /// with `CLOEXEC` so they're not automatically inherited by spawned
-    /// children.
+    /// # Errors
     ///
-    /// On unix if `check_pipe` enabled this function will check if provided
-    /// files are actually pipes.
+    /// Returns an error of kind [`LimitErrorKind::TooLarge`] if `limit` is
+    /// larger than the platform supports, and of kind [`LimitErrorKind::Io`]
+    /// if any other I/O error happens when attempting to create the jobserver
+    /// client.
+    pub fn new_checked(limit: usize) -> Result<Client, LimitError> {
+        if !Client::is_valid_limit(limit) {
+            return Err(LimitError::too_large(limit, Some(imp::MAX_LIMIT)));
+        }
+        Ok(Client::from_backend(
+            Backend::Os(imp::Client::new(limit)?),
+            Some(limit),
+            true,
+        ))
+    }
+
+    /// Creates a new jobserver like [`Client::new`], filling it with tokens
+    /// which are each the given `byte`.
     ///
-    /// # Safety
+    /// The bytes read from and written to the pipe of a jobserver don't mean
+    /// anything to this crate, and by default it writes `|`. GNU make writes
+    /// `+` for its tokens though, so this is useful to exactly mimic a
+    /// jobserver created by make, for example when testing tools which
+    /// inspect the tokens. Tokens released with [`Client::release_raw`] are
+    /// written as `byte` too.
     ///
-    /// This function is `unsafe` to call on Unix specifically as it
-    /// transitively requires usage of the `from_raw_fd` function, which is
-    /// itself unsafe in some circumstances.
+    /// # Examples
     ///
-    /// It's recommended to call this function very early in the lifetime of a
-    /// program before any other file descriptors are opened. That way you can
-    /// make sure to take ownership properly of the file descriptors passed
-    /// down, if any.
+    /// ```
+    /// use jobserver::Client;
     ///
-    /// It is ok to call this function any number of times.
-    pub unsafe fn from_env_ext(check_pipe: bool) -> FromEnv {
-        let (env, var_os) = match ["CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS"]
-            .iter()
-            .map(|&env| env::var_os(env).map(|var| (env, var)))
-            .find_map(|p| p)
-        {
-            Some((env, var_os)) => (env, var_os),
-            None => return FromEnv::new_err(FromEnvErrorInner::NoEnvVar, "", Default::default()),
-        };
-
-        let var = match var_os.to_str() {
-            Some(var) => var,
-            None => {
-                let err = FromEnvErrorInner::CannotParse("not valid UTF-8".to_string());
-                return FromEnv::new_err(err, env, var_os);
-            }
-        };
-
-        let s = match find_jobserver_auth(var) {
-            Some(s) => s,
-            None => return FromEnv::new_err(FromEnvErrorInner::NoJobserver, env, var_os),
-        };
-        match imp::Client::open(s, check_pipe) {
-            Ok(c) => FromEnv::new_ok(Client { inner: Arc::new(c) }, env, var_os),
-            Err(err) => FromEnv::new_err(err, env, var_os),
-        }
+    /// let client = Client::new_with_token_byte(4, b'+').unwrap();
+    /// let token = client.acquire().unwrap();
+    /// assert_eq!(token.leak().into_usize(), usize::from(b'+'));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`Client::new`].
+    #[cfg(unix)]
+    pub fn new_with_token_byte(limit: usize, byte: u8) -> io::Result<Client> {
+        Ok(Client::from_backend(
+            Backend::Os(imp::Client::new_with_token_byte(limit, byte)?),
+            Some(limit),
+            true,
+        ))
     }
 
-    /// Attempts to connect to the jobserver specified in this process's
-    /// environment.
+    /// Creates a new jobserver like [`Client::new`], which additionally
+    /// detects this process deadlocking itself on it.
     ///
-    /// Wraps [`Client::from_env_ext`] and discards error details.
+    /// The returned client, and all of its clones, keep track of how many
+    /// tokens each thread holds and which threads are blocked in
+    /// [`Client::acquire`]. If this process already holds every token of the
+    /// jobserver, and each thread holding one is either the thread calling
+    /// [`Client::acquire`] or itself blocked waiting for another token, the
+    /// call would block forever, so an error of kind
+    /// [`io::ErrorKind::Other`] is returned instead. This includes a thread
+    /// which already holds every token, as well as a thread which holds none
+    /// waiting on threads which hold them all and wait for more.
     ///
-    /// # Safety
+    /// Only these certain deadlocks are detected: a thread blocking on a
+    /// token held by another thread which in turn waits on it some other
+    /// way, such as by joining it, can't be told apart from that thread
+    /// simply being busy. Acquisitions with a timeout, such as
+    /// [`Client::acquire_timeout`], don't block forever and so are neither
+    /// checked nor counted as blocked. Tokens count towards the thread which
+    /// acquired them, even if they're moved to another thread, except for
+    /// those acquired by [`Client::spawn_scoped`] and
+    /// [`JobserverIteratorExt::jobserver_for_each`], which count towards the
+    /// thread spawned with them. Tokens handled with [`Client::acquire_raw`]
+    /// and [`Client::release_raw`] aren't counted at all.
     ///
-    /// This function is `unsafe` to call on Unix specifically as it
-    /// transitively requires usage of the `from_raw_fd` function, which is
-    /// itself unsafe in some circumstances.
+    /// Keeping track of token holders has a small cost on every acquisition
+    /// and release, which is why it's opt-in.
     ///
-    /// It's recommended to call this function very early in the lifetime of a
-    /// program before any other file descriptors are opened. That way you can
-    /// make sure to take ownership properly of the file descriptors passed
-    /// down, if any.
+    /// # Examples
     ///
-    /// It is ok to call this function any number of times.
-    pub unsafe fn from_env() -> Option<Client> {
-        Self::from_env_ext(false).client.ok()
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new_deadlock_checked(1).unwrap();
+    /// let token = client.acquire().unwrap();
+    /// assert!(client.acquire().is_err());
+    /// drop(token);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`Client::new`].
+    pub fn new_deadlock_checked(limit: usize) -> io::Result<Client> {
+        let mut client = Client::new(limit)?;
+        let state = Arc::get_mut(&mut client.state).expect("state is not shared yet");
+        state.holders = Some(deadlock::Holders::default());
+        Ok(client)
     }
 
-    /// Acquires a token from this jobserver client.
+    /// Returns whether `limit` is within the maximum number of tokens this
+    /// platform's jobserver can hold, as far as can be told without creating
+    /// one.
     ///
-    /// This function will block the calling thread until a new token can be
-    /// acquired from the jobserver.
+    /// This is a `const fn` so it can be checked at compile time. Note that on
+    /// Unix the capacity of the pipe backing the jobserver is only known at
+    /// runtime, so [`Client::new_checked`] may still fail there.
     ///
-    /// # Return value
+    /// # Examples
     ///
-    /// On successful acquisition of a token an instance of [`Acquired`] is
-    /// returned. This structure, when dropped, will release the token back to
-    /// the jobserver. It's recommended to avoid leaking this value.
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// const JOBS: usize = 16;
+    /// const _: () = assert!(Client::is_valid_limit(JOBS));
+    /// ```
+    #[allow(clippy::absurd_extreme_comparisons)] // `MAX_LIMIT` is `usize::MAX` on Unix
+    pub const fn is_valid_limit(limit: usize) -> bool {
+        limit <= imp::MAX_LIMIT
+    }
+
+    /// Creates a new jobserver initialized with the given parallelism limit,
+    /// naming its semaphore after `name`.
+    ///
+    /// This is the same as [`Client::new`], except that the name of the
+    /// Windows semaphore backing the jobserver starts with `name` instead of
+    /// an opaque generated prefix, so it can be told apart in tools like
+    /// Process Explorer. A random suffix is still appended to keep the name
+    /// unique.
     ///
     /// # Errors
     ///
-    /// If an I/O error happens while acquiring a token then this function will
-    /// return immediately with the error. If an error is returned then a token
-    /// was not acquired.
-    pub fn acquire(&self) -> io::Result<Acquired> {
-        let data = self.inner.acquire()?;
-        Ok(Acquired {
-            client: self.inner.clone(),
-            data,
-            disabled: false,
-        })
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] if `name` contains backslashes, spaces
+    /// or nul bytes, and otherwise the same errors as [`Client::new`].
+    #[cfg(windows)]
+    pub fn new_named(limit: usize, name: &str) -> io::Result<Client> {
+        Ok(Client::from_backend(
+            Backend::Os(imp::Client::new_named(limit, name)?),
+            Some(limit),
+            true,
+        ))
     }
 
-    /// Acquires a token from this jobserver client in a non-blocking way.
+    /// Creates a client for a jobserver whose pipe was created by other means
+    /// than [`Client::new`], such as by a larger system managing its own file
+    /// descriptors.
     ///
-    /// # Return value
+    /// `read` and `write` are the two ends of the pipe, which should hold one
+    /// byte for each token of the jobserver. If `owned` the client takes
+    /// ownership of the file descriptors and closes them once it and its
+    /// clones are dropped, otherwise they're duplicated and the originals are
+    /// left open for the caller to close. Either way the client's file
+    /// descriptors are made close-on-exec, so they're only inherited by child
+    /// processes configured with [`Client::configure`] or equivalent.
     ///
-    /// On successful acquisition of a token an instance of [`Acquired`] is
-    /// returned. This structure, when dropped, will release the token back to
-    /// the jobserver. It's recommended to avoid leaking this value.
+    /// The client counts as the owner of the jobserver, see
+    /// [`Client::is_owner`], but the number of tokens in it isn't known, see
+    /// [`Client::total_tokens`]. If `owned` the file descriptors are
+    /// returned by [`Client::raw_fds`].
     ///
     /// # Errors
     ///
-    /// If an I/O error happens while acquiring a token then this function will
-    /// return immediately with the error. If an error is returned then a token
-    /// was not acquired.
+    /// Returns an error if the file descriptors can't be duplicated or made
+    /// close-on-exec, for example because they aren't open. The file
+    /// descriptors aren't checked to be a pipe.
     ///
-    /// If non-blocking acquire is not supported, the return error will have its `kind()`
+    /// # Safety
+    ///
+    /// `read` and `write` must be open file descriptors, and if `owned`
+    /// nothing else may use or close them afterwards, as with
+    /// [`std::os::unix::io::FromRawFd::from_raw_fd`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let outer = Client::new(2).unwrap();
+    /// let (read, write) = outer.raw_fds().unwrap();
+    /// let client = unsafe { Client::from_pipe_fds(read, write, false) }.unwrap();
+    /// drop(client.acquire().unwrap());
+    /// assert_eq!(outer.available().unwrap(), 2);
+    /// ```
+    #[cfg(unix)]
+    pub unsafe fn from_pipe_fds(
+        read: std::os::unix::io::RawFd,
+        write: std::os::unix::io::RawFd,
+        owned: bool,
+    ) -> io::Result<Client> {
+        Ok(Client::from_backend(
+            Backend::Os(imp::Client::from_pipe_fds(read, write, owned)?),
+            None,
+            true,
+        ))
+    }
+
+    /// Creates a client for a jobserver whose semaphore was created by other
+    /// means than [`Client::new`], such as by a larger system managing its own
+    /// handles.
+    ///
+    /// The client takes ownership of `handle`, which must have been opened
+    /// with at least `SYNCHRONIZE` and `SEMAPHORE_MODIFY_STATE` access, and
+    /// closes it once it and its clones are dropped. `name` is the name of
+    /// the semaphore, which is passed to child processes configured with
+    /// [`Client::configure`] so they can open it.
+    ///
+    /// The client counts as the owner of the jobserver, see
+    /// [`Client::is_owner`], but the number of tokens in it isn't known, see
+    /// [`Client::total_tokens`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] if `name` contains backslashes, spaces
+    /// or nul bytes.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open handle of a semaphore named `name`, which
+    /// nothing else may use or close afterwards.
+    #[cfg(windows)]
+    pub unsafe fn from_semaphore_handle(
+        handle: std::os::windows::io::RawHandle,
+        name: &str,
+    ) -> io::Result<Client> {
+        Ok(Client::from_backend(
+            Backend::Os(imp::Client::from_semaphore_handle(handle, name)?),
+            None,
+            true,
+        ))
+    }
+
+    /// Creates a new in-process jobserver initialized with the given
+    /// parallelism limit.
+    ///
+    /// The returned [`Client`] behaves just like one created with
+    /// [`Client::new`] for [`Client::acquire`], [`Client::try_acquire`],
+    /// [`Client::release_raw`] and [`Client::available`], but it's implemented
+    /// with a plain mutex and condition variable and never touches the OS. This
+    /// is useful for limiting parallelism within a single process without the
+    /// overhead of a pipe or semaphore.
+    ///
+    /// Since nothing is shared with the OS, this jobserver can't be inherited
+    /// by child processes and [`Client::configure`] and
+    /// [`Client::configure_make`] will panic if called on it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::counting(4);
+    /// let token = client.acquire().unwrap();
+    /// assert_eq!(client.available().unwrap(), 3);
+    /// drop(token);
+    /// ```
+    pub fn counting(limit: usize) -> Client {
+        Client::from_backend(
+            Backend::Counting(counting::Client::new(limit)),
+            Some(limit),
+            true,
+        )
+    }
+
+    /// Limits this client to at most `max` tokens held at once, on top of
+    /// the limit of the jobserver itself.
+    ///
+    /// This is useful to leave room in a shared jobserver for other tools: a
+    /// capped client blocks in [`Client::acquire`] once `max` tokens acquired
+    /// through it (or its clones) are held, even if the jobserver has tokens
+    /// to spare. The cap is waited for before a token is taken from the
+    /// jobserver, so no jobserver token is held by a thread blocked on the
+    /// cap, and a `max` larger than the jobserver just has no effect.
+    ///
+    /// The cap is in-process only: child processes configured with
+    /// [`Client::configure`] share the jobserver without being subject to it.
+    /// The implicit token managed by [`Client::release_implicit`] isn't
+    /// counted against the cap either.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(16).unwrap().capped(4);
+    /// let tokens = (0..4).map(|_| client.acquire().unwrap()).collect::<Vec<_>>();
+    /// assert!(client.try_acquire().unwrap().is_none());
+    /// ```
+    pub fn capped(self, max: usize) -> Client {
+        let backend = Backend::Capped {
+            inner: self.backend(),
+            cap: counting::Client::new(max),
+        };
+        Client::from_parts(Arc::new(backend), self.state)
+    }
+
+    /// Makes the blocking acquisitions of this client cancellable, returning
+    /// the client along with a handle to cancel them.
+    ///
+    /// Once [`CancelHandle::cancel`] is called, from any thread, every thread
+    /// blocked in [`Client::acquire`] or a similar method of the returned
+    /// client or its clones returns an error of kind
+    /// [`io::ErrorKind::Interrupted`], and so do later calls. Unlike the
+    /// signals used to wake up helper threads, see
+    /// [`Client::into_helper_thread`], this works for any caller of
+    /// [`Client::acquire`] and doesn't interfere with other signal handling.
+    ///
+    /// On Unix a thread waiting for a token from the jobserver's pipe is
+    /// woken up through a pipe of its own. Other backends, such as
+    /// [`Client::counting`], are checked for cancellation every few
+    /// milliseconds while waiting, and so is waiting for room under the cap
+    /// of a client later made with [`Client::capped`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io;
+    /// use std::thread;
+    /// use jobserver::Client;
+    ///
+    /// let (client, cancel) = Client::new(1).unwrap().with_cancellation().unwrap();
+    /// let _token = client.acquire().unwrap();
+    /// let waiter = thread::spawn(move || client.acquire().map(drop));
+    /// cancel.cancel();
+    /// let err = waiter.join().unwrap().unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pipe to wake up waiting threads can't be created.
+    pub fn with_cancellation(self) -> io::Result<(Client, CancelHandle)> {
+        let (signal, handle) = cancel::new()?;
+        let backend = Backend::Cancellable {
+            inner: self.backend(),
+            signal,
+        };
+        Ok((Client::from_parts(Arc::new(backend), self.state), handle))
+    }
+
+    /// Creates a new client for the same jobserver with its own OS handles.
+    ///
+    /// Unlike [`Clone::clone`], which shares everything between the clones,
+    /// this duplicates the file descriptors (on Unix) or semaphore handle (on
+    /// Windows) of the jobserver. The new client can be shut down with
+    /// [`Client::shutdown`] and dropped without affecting this one, and its
+    /// handles are closed as soon as it and its tokens are gone. Tokens
+    /// acquired and released through either client still come from and go
+    /// to the same jobserver.
+    ///
+    /// Only the total number of tokens, see [`Client::total_tokens`], carries
+    /// over to the new client. It doesn't share the state of the implicit
+    /// token, see [`Client::release_implicit`], and doesn't check for
+    /// deadlocks even if this client does. Clients created with
+    /// [`Client::counting`] have no OS handles, so the new client shares the
+    /// in-process jobserver, just like an in-process cap set with
+    /// [`Client::capped`] is shared.
+    ///
+    /// On Unix a jobserver backed by a named pipe is opened again rather than
+    /// duplicated, so it's not affected by [`Client::set_nonblocking`] on this
+    /// client.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS handles can't be duplicated.
+    pub fn clone_independent(&self) -> io::Result<Client> {
+        self.check_open()?;
+        Ok(Client::from_backend(
+            self.backend().clone_independent()?,
+            self.known_limit(),
+            self.is_owner(),
+        ))
+    }
+
+    /// Returns a handle which can only acquire tokens from this client's
+    /// jobserver, see [`ReadHalf`].
+    ///
+    /// # Examples
+    ///
+    /// Forwarding a token from one jobserver to another:
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let from = Client::new(2).unwrap();
+    /// let to = Client::new(0).unwrap();
+    /// let (read, write) = (from.read_half(), to.write_half());
+    /// drop((from, to));
+    ///
+    /// let token = read.acquire().unwrap();
+    /// write.release().unwrap();
+    /// token.drop_without_releasing();
+    /// ```
+    pub fn read_half(&self) -> ReadHalf {
+        half::read_half(self)
+    }
+
+    /// Returns a handle which can only release tokens into this client's
+    /// jobserver, see [`WriteHalf`].
+    pub fn write_half(&self) -> WriteHalf {
+        half::write_half(self)
+    }
+
+    /// Creates a clone of this client with its own OS handles, for handing to
+    /// a thread which acquires tokens concurrently with others.
+    ///
+    /// Like with [`Clone::clone`] everything but the OS handles is shared
+    /// with this client: the implicit token, the limit, pausing, shutting
+    /// down, statistics and deadlock checks work across all clones as before.
+    /// The OS handles are duplicated like by [`Client::clone_independent`],
+    /// so threads blocked acquiring tokens don't wait on the same file
+    /// descriptor. Read-ahead, see [`Client::set_read_ahead`], is shared with
+    /// the clone as well, so tokens kept back by one of them go to threads
+    /// blocked on the other.
+    ///
+    /// How much this helps depends on the platform. On Linux a jobserver
+    /// backed by a fifo, which includes pipes inherited from `make`, is
+    /// opened again, giving the clone its own open file description. An
+    /// anonymous pipe created by this process can only be duplicated, so the
+    /// clone's file descriptor still refers to the same open file
+    /// description and the kernel serializes reads on it just the same. The
+    /// `threads` benchmarks compare both approaches.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS handles can't be duplicated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let handles = (0..4)
+    ///     .map(|_| {
+    ///         let client = client.try_clone_for_thread().unwrap();
+    ///         thread::spawn(move || drop(client.acquire().unwrap()))
+    ///     })
+    ///     .collect::<Vec<_>>();
+    /// for handle in handles {
+    ///     handle.join().unwrap();
+    /// }
+    /// assert_eq!(client.available().unwrap(), 2);
+    /// ```
+    pub fn try_clone_for_thread(&self) -> io::Result<Client> {
+        self.check_open()?;
+        let inner = self.backend().clone_for_thread()?;
+        Ok(Client::from_parts(Arc::new(inner), self.state.clone()))
+    }
+
+    /// Makes [`Client::acquire`] hand out this process's implicit token
+    /// before reading tokens from the jobserver.
+    ///
+    /// Every process spawned by `make` holds one implicit token, its right to
+    /// run at all, which isn't in the jobserver. A process which acquires a
+    /// token before doing any work, including its first job, effectively
+    /// wastes that implicit token, and under `make -j1`, where the jobserver
+    /// is empty, never gets to do any work at all.
+    ///
+    /// With this, [`Client::acquire`] and [`Client::try_acquire`] first hand
+    /// out the implicit token, without touching the jobserver, whenever it
+    /// isn't in use by another [`Acquired`] token and hasn't been released
+    /// with [`Client::release_implicit`]. Dropping that token makes the
+    /// implicit token available again rather than writing it to the
+    /// jobserver. This applies to all clones of this client.
+    ///
+    /// Only use this for a client connected with [`Client::from_env`] or
+    /// similar, where this process actually holds an implicit token. Tokens
+    /// acquired by helper threads, see [`Client::into_helper_thread`], always
+    /// come from the jobserver.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jobserver::Client;
+    ///
+    /// let client = unsafe { Client::from_env() }.unwrap().with_implicit_token();
+    /// // Doesn't block even under `make -j1`.
+    /// let token = client.acquire().unwrap();
+    /// ```
+    pub fn with_implicit_token(self) -> Client {
+        self.state.lock_implicit().lendable = true;
+        self
+    }
+
+    /// Attempts to connect to the jobserver specified in this process's
+    /// environment.
+    ///
+    /// When the a `make` executable calls a child process it will configure the
+    /// environment of the child to ensure that it has handles to the jobserver
+    /// it's passing down. This function will attempt to look for these details
+    /// and connect to the jobserver.
+    ///
+    /// The first of the `CARGO_MAKEFLAGS`, `MAKEFLAGS`, `MFLAGS` and
+    /// `GNUMAKEFLAGS` environment variables which is set is used.
+    ///
+    /// The kind of jobserver is told from the format of its
+    /// `--jobserver-auth=`, unless the variable also has a
+    /// `--jobserver-style=pipe`, `fifo` or `sem` option like `make` 4.4 may
+    /// pass, in which case the jobserver has to be of that kind.
+    ///
+    /// Note that the created [`Client`] is not automatically inherited into
+    /// spawned child processes from this program. Manual usage of the
+    /// [`Client::configure`] function is required for a child process to have
+    /// access to a job server.
+    ///
+    /// # Return value
+    ///
+    /// [`FromEnv`] contains result and relevant environment variable.
+    /// If a jobserver was found in the environment and it looks correct then
+    /// result with the connected client will be returned. In other cases
+    /// result will contain `Err(FromEnvErr)`.
+    ///
+    /// Additionally on Unix this function will configure the file descriptors
+    /// with `CLOEXEC` so they're not automatically inherited by spawned
+    /// children.
+    ///
+    /// On unix if `check_pipe` enabled this function will check if provided
+    /// files are actually pipes.
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` to call on Unix specifically as it
+    /// transitively requires usage of the `from_raw_fd` function, which is
+    /// itself unsafe in some circumstances.
+    ///
+    /// It's recommended to call this function very early in the lifetime of a
+    /// program before any other file descriptors are opened. That way you can
+    /// make sure to take ownership properly of the file descriptors passed
+    /// down, if any.
+    ///
+    /// It is ok to call this function any number of times. Successfully
+    /// connected jobservers are cached, so as long as the environment
+    /// variable keeps the same value later calls share the OS handles of the
+    /// same jobserver instead of opening and validating it again. Each call
+    /// still returns a [`Client`] of its own, which doesn't share anything
+    /// else, such as [`Client::pause`] or [`Client::stats`], with the others.
+    /// [`Client::shutdown`] forgets the cached jobserver.
+    pub unsafe fn from_env_ext(check_pipe: bool) -> FromEnv {
+        Self::from_env_lookup(|env| env::var_os(env), check_pipe)
+    }
+
+    /// Attempts to connect to the jobserver specified in the environment
+    /// `env`, instead of this process's environment.
+    ///
+    /// This works just like [`Client::from_env_ext`], reading the variables
+    /// from `env`, which makes it possible to connect to a jobserver from a
+    /// captured environment, such as the one a child process was spawned
+    /// with, and to test the handling of the environment without changing
+    /// the environment of the process, which is racy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use jobserver::{Client, FromEnvErrorKind};
+    ///
+    /// let env = HashMap::new();
+    /// let from_env = unsafe { Client::from_env_map(&env, false) };
+    /// let err = from_env.client.unwrap_err();
+    /// assert!(matches!(err.kind(), FromEnvErrorKind::NoEnvVar));
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` for the same reasons as
+    /// [`Client::from_env_ext`]: the file descriptors named in `env` are
+    /// taken ownership of like those named in the environment of the process.
+    pub unsafe fn from_env_map(env: &HashMap<OsString, OsString>, check_pipe: bool) -> FromEnv {
+        Self::from_env_lookup(|name| env.get(OsStr::new(name)).cloned(), check_pipe)
+    }
+
+    /// Connects to the jobserver in the first of [`MAKEFLAGS_VARS`] which
+    /// `lookup` finds.
+    unsafe fn from_env_lookup(
+        lookup: impl Fn(&str) -> Option<OsString>,
+        check_pipe: bool,
+    ) -> FromEnv {
+        let mut from_env = match MAKEFLAGS_VARS
+            .iter()
+            .find_map(|&env| lookup(env).map(|var| (env, var)))
+        {
+            Some((env, var_os)) => Self::from_env_var(env, var_os, check_pipe),
+            None => FromEnv::new_err(FromEnvErrorInner::NoEnvVar, None, "", Default::default()),
+        };
+        from_env.depth = parse_depth(lookup(DEPTH_VAR));
+        from_env
+    }
+
+    /// Attempts to connect to every distinct jobserver specified in this
+    /// process's environment.
+    ///
+    /// Where [`Client::from_env_ext`] only looks at the first of the
+    /// `CARGO_MAKEFLAGS`, `MAKEFLAGS`, `MFLAGS` and `GNUMAKEFLAGS` variables
+    /// which is set, this looks at all of them, for a process which was
+    /// handed several jobservers, for example one from `cargo` and another one
+    /// from an outer `make`. There's an entry for each variable which names a
+    /// jobserver in the order above, skipping those naming the same jobserver
+    /// as an earlier one, so usually there's at most one. An entry is returned
+    /// even if connecting to its jobserver fails, with the error in
+    /// [`FromEnv::client`].
+    ///
+    /// The clients are cached like with [`Client::from_env_ext`], see there
+    /// for the meaning of `check_pipe`.
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` for the same reasons as
+    /// [`Client::from_env_ext`].
+    pub unsafe fn from_env_all(check_pipe: bool) -> Vec<FromEnv> {
+        let depth = parse_depth(env::var_os(DEPTH_VAR));
+        let mut seen = Vec::new();
+        let mut all = Vec::new();
+        for &env in MAKEFLAGS_VARS.iter() {
+            let var_os = match env::var_os(env) {
+                Some(var_os) => var_os,
+                None => continue,
+            };
+            let auth = match var_bytes(&var_os).and_then(find_jobserver_auth) {
+                Some(auth) => auth.into_owned(),
+                None => continue,
+            };
+            if seen.contains(&auth) {
+                continue;
+            }
+            seen.push(auth);
+            let mut from_env = Self::from_env_var(env, var_os, check_pipe);
+            from_env.depth = depth;
+            all.push(from_env);
+        }
+        all
+    }
+
+    /// Connects to the jobserver in the value `var_os` of the variable `env`.
+    unsafe fn from_env_var(env: &'static str, var_os: OsString, check_pipe: bool) -> FromEnv {
+        let var = match var_bytes(&var_os) {
+            Some(var) => var,
+            None => return FromEnv::new_err(FromEnvErrorInner::NotUtf8, None, env, var_os),
+        };
+        let protocol = find_protocol(var);
+
+        let mut cache = FROM_ENV_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(cached) = &*cache {
+            // A client validated to be a pipe is good enough for callers
+            // which don't care about that, but not the other way around.
+            if cached.var_name == env
+                && cached.var_value == var_os
+                && (cached.check_pipe || !check_pipe)
+            {
+                let client =
+                    Client::from_shared_backend(cached.backend.clone(), cached.limit, false);
+                return FromEnv::new_ok(client, protocol, env, var_os);
+            }
+        }
+
+        let s = match find_jobserver_auth(var) {
+            Some(s) => s,
+            None => return FromEnv::new_err(FromEnvErrorInner::NoJobserver, None, env, var_os),
+        };
+        match imp::Client::open(&s, check_pipe, find_style(var)) {
+            Ok(c) => {
+                // `-jN` means that there are `N - 1` tokens in the jobserver
+                // in addition to the implicit token of every process.
+                let limit = find_jobs(var).map(|jobs| jobs.saturating_sub(1));
+                let backend = Arc::new(Backend::Os(c));
+                *cache = Some(FromEnvCache {
+                    var_name: env,
+                    var_value: var_os.clone(),
+                    check_pipe,
+                    backend: backend.clone(),
+                    limit,
+                });
+                let client = Client::from_shared_backend(backend, limit, false);
+                FromEnv::new_ok(client, protocol, env, var_os)
+            }
+            Err(err) => FromEnv::new_err(err, protocol, env, var_os),
+        }
+    }
+
+    /// Attempts to connect to the jobserver specified in this process's
+    /// environment.
+    ///
+    /// Wraps [`Client::from_env_ext`] and discards error details.
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` to call on Unix specifically as it
+    /// transitively requires usage of the `from_raw_fd` function, which is
+    /// itself unsafe in some circumstances.
+    ///
+    /// It's recommended to call this function very early in the lifetime of a
+    /// program before any other file descriptors are opened. That way you can
+    /// make sure to take ownership properly of the file descriptors passed
+    /// down, if any.
+    ///
+    /// It is ok to call this function any number of times.
+    pub unsafe fn from_env() -> Option<Client> {
+        Self::from_env_ext(false).client.ok()
+    }
+
+    /// Connects to the jobserver specified in this process's environment, or
+    /// creates a new one with `default_limit` tokens if there's none.
+    ///
+    /// This is the usual way for a tool to limit its parallelism: share the
+    /// jobserver of the `make` or `cargo` it runs under, and otherwise fall
+    /// back to a jobserver of its own, typically sized by the number of CPUs.
+    /// The fallback is taken whenever [`Client::from_env`] returns `None`,
+    /// which includes a jobserver named in the environment which can't be
+    /// connected to, for example because the rule running this process
+    /// wasn't marked with `+` and so didn't pass the file descriptors down.
+    /// Use [`Client::from_env_ext`] instead to tell those cases apart.
+    ///
+    /// The client works the same way in either case, and child processes
+    /// configured with [`Client::configure`] share it either way. Only
+    /// [`Client::is_owner`] tells which case it was.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::thread;
+    /// use jobserver::Client;
+    ///
+    /// let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    /// let client = unsafe { Client::from_env_or_new(cpus) }.unwrap();
+    /// drop(client.acquire().unwrap());
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` for the same reasons as
+    /// [`Client::from_env`]: on Unix it takes ownership of the file
+    /// descriptors named in the environment, which must not be owned by
+    /// anything else in this process, so it should be called early, before
+    /// other file descriptors are opened. Nothing is taken ownership of if a
+    /// new jobserver is created instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Client::new`] if a new jobserver has to be
+    /// created and that fails.
+    pub unsafe fn from_env_or_new(default_limit: usize) -> io::Result<Client> {
+        match Self::from_env() {
+            Some(client) => Ok(client),
+            None => Client::new(default_limit),
+        }
+    }
+
+    /// Returns the information needed to connect to this client's jobserver
+    /// again, see [`ClientDescriptor`].
+    ///
+    /// Returns `None` if this client can't be inherited, see
+    /// [`Client::is_inheritable`], or if its auth string isn't valid UTF-8,
+    /// which may be the case for the path of a fifo on Unix.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let descriptor = client.descriptor().unwrap();
+    /// assert_eq!(descriptor.limit(), Some(4));
+    /// ```
+    pub fn descriptor(&self) -> Option<ClientDescriptor> {
+        if !self.is_inheritable() {
+            return None;
+        }
+        let auth = self.backend().string_arg().into_string().ok()?;
+        Some(ClientDescriptor::new(auth, self.known_limit()))
+    }
+
+    /// Connects to the jobserver described by `descriptor`, as returned by
+    /// [`Client::descriptor`].
+    ///
+    /// The jobserver is opened and validated the same way as by
+    /// [`Client::from_env_ext`] with `check_pipe` enabled. Additionally an
+    /// error of kind [`FromEnvErrorKind::CannotParse`] is returned if the
+    /// descriptor's [`TransportKind`] doesn't match its auth string on this
+    /// platform, for example for a semaphore described on Windows being
+    /// connected to on Unix.
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` for the same reasons as
+    /// [`Client::from_env_ext`]: a descriptor of an anonymous pipe refers to
+    /// file descriptors by number, which are used without knowing what they
+    /// are.
+    pub unsafe fn from_descriptor(descriptor: &ClientDescriptor) -> Result<Client, FromEnvError> {
+        if descriptor.kind() != TransportKind::of(descriptor.auth()) {
+            return Err(FromEnvError {
+                inner: FromEnvErrorInner::CannotParse(format!(
+                    "jobserver `{}` is not a {:?} on this platform",
+                    descriptor.auth(),
+                    descriptor.kind()
+                )),
+            });
+        }
+        match imp::Client::open(descriptor.auth().as_bytes(), true, None) {
+            Ok(c) => Ok(Client::from_backend(
+                Backend::Os(c),
+                descriptor.limit(),
+                false,
+            )),
+            Err(inner) => Err(FromEnvError { inner }),
+        }
+    }
+
+    /// Connects to the jobserver passed on the command line `args` with
+    /// `--flag=<auth>`, as added by [`Client::configure_argv`].
+    ///
+    /// This is for processes which are run without any environment, so the
+    /// jobserver can't be passed in `MAKEFLAGS`, and is usually called with
+    /// [`std::env::args_os`]. If the flag is given more than once the last
+    /// one wins, like with the flags of `make`. The jobserver is opened and
+    /// validated the same way as by [`Client::from_env_ext`] with
+    /// `check_pipe` enabled.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of kind [`FromEnvErrorKind::NoJobserver`] if there's
+    /// no `--flag=` in `args`, and otherwise the same errors as
+    /// [`Client::from_env_ext`].
+    ///
+    /// # Safety
+    ///
+    /// This function is `unsafe` for the same reasons as
+    /// [`Client::from_env_ext`]: the file descriptors named on the command
+    /// line are taken ownership of like those named in the environment.
+    pub unsafe fn from_argv<I>(flag: &str, args: I) -> Result<Client, FromEnvError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let prefix = format!("--{}=", flag);
+        let mut auth = None;
+        for arg in args {
+            let arg = arg.as_ref();
+            match var_bytes(arg) {
+                Some(arg) if arg.starts_with(prefix.as_bytes()) => {
+                    auth = Some(arg[prefix.len()..].to_vec());
+                }
+                Some(_) => {}
+                None if arg.to_string_lossy().starts_with(&prefix) => {
+                    return Err(FromEnvError {
+                        inner: FromEnvErrorInner::NotUtf8,
+                    });
+                }
+                None => {}
+            }
+        }
+        let auth = auth.ok_or(FromEnvError {
+            inner: FromEnvErrorInner::NoJobserver,
+        })?;
+        match imp::Client::open(&auth, true, None) {
+            Ok(c) => Ok(Client::from_backend(Backend::Os(c), None, false)),
+            Err(inner) => Err(FromEnvError { inner }),
+        }
+    }
+
+    /// Acquires a token from this jobserver client.
+    ///
+    /// This function will block the calling thread until a new token can be
+    /// acquired from the jobserver.
+    ///
+    /// # Return value
+    ///
+    /// On successful acquisition of a token an instance of [`Acquired`] is
+    /// returned. This structure, when dropped, will release the token back to
+    /// the jobserver. It's recommended to avoid leaking this value.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring a token then this function will
+    /// return immediately with the error. If an error is returned then a token
+    /// was not acquired.
+    ///
+    /// For clients created with [`Client::new_deadlock_checked`] an error is
+    /// returned instead of blocking forever if the calling thread already
+    /// holds every token.
+    ///
+    /// Being interrupted by a signal isn't an error: on Unix the read or poll
+    /// interrupted with `EINTR` is simply retried, whatever the signal.
+    ///
+    /// If a default timeout is set with
+    /// [`Client::set_default_acquire_timeout`] this behaves like
+    /// [`Client::acquire_timeout`] with that timeout.
+    pub fn acquire(&self) -> io::Result<Acquired> {
+        self.acquire_with_timeout(self.default_acquire_timeout())
+    }
+
+    /// Acquires a token from this jobserver client like [`Client::acquire`],
+    /// but gives up after waiting for `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to [`io::ErrorKind::TimedOut`]
+    /// if no token could be acquired within `timeout`, and otherwise the same
+    /// errors as [`Client::acquire`]. Time spent waiting for a paused client
+    /// to be resumed, see [`Client::pause`], counts towards `timeout`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io;
+    /// use std::time::Duration;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// let token = client.acquire().unwrap();
+    /// let err = client.acquire_timeout(Duration::from_millis(10)).unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    /// ```
+    pub fn acquire_timeout(&self, timeout: Duration) -> io::Result<Acquired> {
+        self.acquire_with_timeout(Some(timeout))
+    }
+
+    /// Acquires a token from this jobserver client like [`Client::acquire`],
+    /// but gives up once `deadline` has passed, returning `Ok(None)`.
+    ///
+    /// This is [`Client::acquire_timeout`] for schedulers which pass down
+    /// absolute deadlines, waiting for the time left until `deadline` the
+    /// same way. If `deadline` has already passed, a token is only taken if
+    /// one is available right away, without blocking, and `Ok(None)` is
+    /// returned at once if the client is paused, see [`Client::pause`].
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::acquire`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// let deadline = Instant::now() + Duration::from_millis(10);
+    /// let token = client.acquire_deadline(deadline).unwrap();
+    /// assert!(token.is_some());
+    /// assert!(client.acquire_deadline(deadline).unwrap().is_none());
+    /// ```
+    pub fn acquire_deadline(&self, deadline: Instant) -> io::Result<Option<Acquired>> {
+        self.acquire_within(Some(deadline.saturating_duration_since(Instant::now())))
+    }
+
+    /// Makes every blocking acquisition through this client and its clones
+    /// give up after waiting for `timeout`, or wait as long as it takes again
+    /// with `None`.
+    ///
+    /// With a default timeout set [`Client::acquire`], [`Client::acquire_raw`]
+    /// and everything built on them behave like [`Client::acquire_timeout`],
+    /// failing with [`io::ErrorKind::TimedOut`] instead of blocking forever.
+    /// This is meant for tools which must never hang, even if the jobserver
+    /// they inherited is broken, without having to pass a timeout to every
+    /// place tokens are acquired.
+    ///
+    /// The timeout also covers waiting for a paused client to be resumed, see
+    /// [`Client::pause`]. Helper threads, see [`Client::into_helper_thread`],
+    /// aren't affected.
+    pub fn set_default_acquire_timeout(&self, timeout: Option<Duration>) {
+        *self.state.lock_acquire_timeout() = timeout;
+    }
+
+    /// Returns the timeout set with
+    /// [`Client::set_default_acquire_timeout`], if any.
+    pub fn default_acquire_timeout(&self) -> Option<Duration> {
+        *self.state.lock_acquire_timeout()
+    }
+
+    fn acquire_with_timeout(&self, timeout: Option<Duration>) -> io::Result<Acquired> {
+        self.acquire_within(timeout)?.ok_or_else(timed_out)
+    }
+
+    /// Acquires a token, returning `None` if none turned up within
+    /// `timeout`.
+    fn acquire_within(&self, timeout: Option<Duration>) -> io::Result<Option<Acquired>> {
+        let start = Instant::now();
+        if !self.state.wait_unpaused(timeout) {
+            return Ok(None);
+        }
+        self.check_open()?;
+        let timeout = timeout.map(|timeout| timeout.saturating_sub(start.elapsed()));
+        if let Some(acquired) = Acquired::implicit(self) {
+            return Ok(Some(acquired));
+        }
+        // Only waiting without a timeout could go on forever.
+        let limit = *self.state.lock_limit();
+        let _waiting = match (&self.state.holders, limit, timeout) {
+            (Some(holders), Some(limit), None) => Some(holders.wait(limit)?),
+            _ => None,
+        };
+        let start = Instant::now();
+        let data = match self.acquire_backend(timeout)? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+        let blocked = start.elapsed();
+        self.state.stats.blocked(blocked);
+        let acquired = Acquired::new(self, data);
+        #[cfg(feature = "tracing")]
+        acquired.hold.trace.record_blocked(blocked);
+        Ok(Some(acquired))
+    }
+
+    /// Acquires a token from this jobserver client like [`Client::acquire`],
+    /// tagging it with `label` for diagnostics.
+    ///
+    /// The label names the logical task the token is acquired for. It's
+    /// returned by [`Acquired::label`] and shown in the token's `Debug`
+    /// output, recorded on its span with the `tracing` feature, and listed in
+    /// the report of `Client::check_leaks` with the `leak-check` feature, so
+    /// it can be told which task holds each slot.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let token = client.acquire_labeled("link libfoo").unwrap();
+    /// assert_eq!(token.label(), Some("link libfoo"));
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::acquire`].
+    pub fn acquire_labeled(&self, label: &str) -> io::Result<Acquired> {
+        let mut acquired = self.acquire()?;
+        acquired.set_label(label);
+        Ok(acquired)
+    }
+
+    /// Acquires a token from this jobserver client, spinning for a little
+    /// while before blocking.
+    ///
+    /// This first tries [`Client::try_acquire`] up to `spin_iters` times,
+    /// hinting to the CPU that it's spinning for the first half of the
+    /// attempts and yielding the rest of its time slice to other threads for
+    /// the second half. If no token turned up it falls back to a blocking
+    /// [`Client::acquire`]. The same happens right away if non-blocking
+    /// acquisition isn't supported.
+    ///
+    /// This only pays off when tokens are released within microseconds of
+    /// each other and the overhead of blocking dominates, such as when lots
+    /// of tiny jobs are run. Otherwise it just burns CPU time which other
+    /// jobs could use, so prefer [`Client::acquire`] unless benchmarks show
+    /// otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::acquire`], and any error other
+    /// than [`io::ErrorKind::Unsupported`] from [`Client::try_acquire`].
+    pub fn acquire_spin(&self, spin_iters: usize) -> io::Result<Acquired> {
+        for i in 0..spin_iters {
+            match self.try_acquire() {
+                Ok(Some(token)) => return Ok(token),
+                Ok(None) if i < spin_iters / 2 => std::hint::spin_loop(),
+                Ok(None) => std::thread::yield_now(),
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.acquire()
+    }
+
+    /// Acquires a token from this jobserver client in a non-blocking way.
+    ///
+    /// # Return value
+    ///
+    /// On successful acquisition of a token an instance of [`Acquired`] is
+    /// returned. This structure, when dropped, will release the token back to
+    /// the jobserver. It's recommended to avoid leaking this value.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring a token then this function will
+    /// return immediately with the error. If an error is returned then a token
+    /// was not acquired.
+    ///
+    /// If non-blocking acquire is not supported, the return error will have its `kind()`
     /// set to [`io::ErrorKind::Unsupported`].
     pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
-        let ret = self.inner.try_acquire()?;
+        self.check_open()?;
+        if self.is_paused() {
+            return Ok(None);
+        }
+        if let Some(acquired) = Acquired::implicit(self) {
+            return Ok(Some(acquired));
+        }
+        #[cfg(feature = "testing")]
+        self.state.release_queue.flush();
+        let ret = self.backend().try_acquire()?;
+
+        Ok(ret.map(|data| Acquired::new(self, data)))
+    }
+
+    /// Returns whether [`Client::try_acquire`] is supported by this client.
+    ///
+    /// Non-blocking acquisition isn't available for every kind of jobserver,
+    /// notably anonymous pipes on Unix platforms other than Linux. This allows
+    /// picking a strategy up front instead of waiting for
+    /// [`Client::try_acquire`] to fail with [`io::ErrorKind::Unsupported`].
+    ///
+    /// On Linux, support for anonymous pipes depends on the kernel and is only
+    /// known after the first attempt, so this may return `true` until a call
+    /// to [`Client::try_acquire`] has discovered otherwise.
+    pub fn supports_try_acquire(&self) -> bool {
+        self.backend().supports_try_acquire()
+    }
+
+    /// Returns the file descriptor tokens are read from, for registering with
+    /// an event loop like `epoll`.
+    ///
+    /// The file descriptor becoming readable means that a token may be
+    /// available, which can then be acquired with [`Client::try_acquire`].
+    /// Note that readiness may be spurious, as other processes sharing the
+    /// jobserver can take the token first, so callers must be prepared for
+    /// [`Client::try_acquire`] to return `Ok(None)` and keep polling. Check
+    /// [`Client::supports_try_acquire`] before relying on this.
+    ///
+    /// The file descriptor remains owned by this client and must not be read
+    /// from or closed directly.
+    ///
+    /// Tokens kept back by read-ahead wouldn't make the file descriptor
+    /// readable, so this turns read-ahead off for good, see
+    /// [`Client::set_read_ahead`].
+    ///
+    /// Returns `None` for clients created with [`Client::counting`].
+    #[cfg(unix)]
+    pub fn as_raw_read_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match self.backend().base() {
+            Backend::Os(c) => {
+                c.mark_polled();
+                Some(c.read_fd())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the file descriptor of the named pipe backing this jobserver,
+    /// for watching it for readability in an event loop like `epoll`.
+    ///
+    /// This is a narrower [`Client::as_raw_read_fd`] for `fifo:` jobservers,
+    /// which are the ones whose readiness can be relied on: the fifo is
+    /// opened for reading and writing, so it never reports end of file or a
+    /// hangup while other processes come and go, and it's put into
+    /// nonblocking mode for good by [`Client::try_acquire`], so acquiring a
+    /// token once it's readable never blocks. On Linux jobservers inherited
+    /// as anonymous pipes are usually reopened as fifos through `/dev/fd`
+    /// too, so this covers those as well.
+    ///
+    /// Like [`Client::as_raw_read_fd`] this turns read-ahead off for good.
+    /// The returned [`ReadinessFd`] keeps the fifo open, even if this client
+    /// is switched over to another jobserver with [`Client::replace_inner`].
+    ///
+    /// Returns `None` for anonymous pipes and for clients created with
+    /// [`Client::counting`].
+    #[cfg(unix)]
+    pub fn readiness_fd(&self) -> Option<ReadinessFd> {
+        ReadinessFd::new(self.backend())
+    }
+
+    /// Acquires a token from this jobserver client without blocking the
+    /// thread, for async code running on the `async-io` reactor, like that of
+    /// smol and async-std.
+    ///
+    /// This waits for the read end of the jobserver to become readable, see
+    /// [`Client::as_raw_read_fd`], and then takes a token with
+    /// [`Client::try_acquire`], starting over if another process took it
+    /// first. The token is released when the returned [`Acquired`] is dropped
+    /// as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let token = async_io::block_on(client.acquire_async_io()).unwrap();
+    /// drop(token);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::try_acquire`], notably with its
+    /// `kind()` set to [`io::ErrorKind::Unsupported`] where non-blocking
+    /// acquisition isn't supported, see [`Client::supports_try_acquire`]. The
+    /// same error is returned for clients created with [`Client::counting`],
+    /// which have nothing to wait on.
+    #[cfg(all(unix, feature = "async-io"))]
+    pub async fn acquire_async_io(&self) -> io::Result<Acquired> {
+        async_acquire::acquire(self).await
+    }
+
+    /// Returns the read and write file descriptors of the pipe backing a
+    /// jobserver created by [`Client::new`].
+    ///
+    /// This is useful for passing the jobserver on to code which wants raw file
+    /// descriptors rather than a [`Command`] to configure. The file
+    /// descriptors remain owned by this client and must not be closed
+    /// directly. They are close-on-exec, so they're only inherited by child
+    /// processes configured with [`Client::configure`] or equivalent.
+    ///
+    /// Returns `None` for jobservers inherited from the environment, whose
+    /// file descriptors may be shared with other processes, for jobservers
+    /// backed by a named pipe, and for clients created with
+    /// [`Client::counting`].
+    #[cfg(unix)]
+    pub fn raw_fds(&self) -> Option<(std::os::unix::io::RawFd, std::os::unix::io::RawFd)> {
+        match self.backend().base() {
+            Backend::Os(c) => c.raw_fds(),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the close-on-exec flag is set on the read and write
+    /// file descriptors of this client's jobserver, in that order.
+    ///
+    /// Jobserver file descriptors inherited from the environment are set to
+    /// close-on-exec by [`Client::from_env_ext`], so they're only passed on to
+    /// child processes configured with [`Client::configure`]. This allows
+    /// checking that, for example in tests or as a runtime audit. For a
+    /// jobserver backed by a named pipe both values describe the same file
+    /// descriptor.
+    ///
+    /// Returns `None` for clients created with [`Client::counting`], or if
+    /// the flags can't be read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// assert_eq!(client.cloexec_state(), Some((true, true)));
+    /// ```
+    #[cfg(unix)]
+    pub fn cloexec_state(&self) -> Option<(bool, bool)> {
+        match self.backend().base() {
+            Backend::Os(c) => c.cloexec_state(),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the buffer of this client's jobserver pipe, which
+    /// is the most tokens it can hold.
+    ///
+    /// Each token takes up a byte in the pipe, so this is the upper bound for
+    /// the limit of a jobserver created with [`Client::new`], which grows the
+    /// pipe as far as it's allowed to for large limits. Comparing it with
+    /// [`Client::total_tokens`] shows how much room is left, for example
+    /// before releasing extra tokens with [`Client::boost`], as releasing a
+    /// token into a full pipe blocks.
+    ///
+    /// The size is read with `fcntl(F_GETPIPE_SZ)` on Linux, for both
+    /// anonymous and named pipes. `None` is returned on other platforms,
+    /// which have no way to ask for it, and for jobservers which aren't
+    /// backed by a pipe, such as those created with [`Client::counting`] and
+    /// all jobservers on Windows.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// if let Some(capacity) = client.pipe_capacity().unwrap() {
+    ///     assert!(capacity >= 4);
+    /// }
+    /// assert_eq!(Client::counting(4).pipe_capacity().unwrap(), None);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns any I/O error reported by `fcntl`.
+    pub fn pipe_capacity(&self) -> io::Result<Option<usize>> {
+        match self.backend().base() {
+            #[cfg(unix)]
+            Backend::Os(c) => c.pipe_capacity(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Puts the read end of this client's jobserver into nonblocking mode, or
+    /// back into blocking mode.
+    ///
+    /// This is meant for driving acquisition from a reactor: with the read
+    /// file descriptor, see [`Client::as_raw_read_fd`], registered for
+    /// readiness, [`Client::try_acquire`] takes tokens without ever blocking,
+    /// and is supported for anonymous pipes on every Unix platform then.
+    /// [`Client::acquire`] keeps working in nonblocking mode by waiting for
+    /// the pipe to become readable with `poll` before reading, so occasional
+    /// blocking acquisitions can be mixed in.
+    ///
+    /// Note that the nonblocking flag belongs to the pipe as opened, which is
+    /// shared with `make` and every other process using a jobserver inherited
+    /// from the environment, and older versions of `make` don't expect a
+    /// nonblocking jobserver. Only change it on a jobserver created with
+    /// [`Client::new`], or when every user of the jobserver is known to cope.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] for clients created with
+    /// [`Client::counting`], and when trying to make a jobserver backed by a
+    /// named pipe blocking again, as [`Client::try_acquire`] puts those into
+    /// nonblocking mode for good. Errors from `fcntl` are returned as-is.
+    #[cfg(unix)]
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.check_open()?;
+        match self.backend().base() {
+            Backend::Os(c) => c.set_nonblocking(nonblocking),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-process jobservers have no file descriptors",
+            )),
+        }
+    }
+
+    /// Sends this client's jobserver to another process over a Unix socket.
+    ///
+    /// This passes the file descriptors of the jobserver with `SCM_RIGHTS`,
+    /// so a process which wasn't spawned by this one, like a worker connecting
+    /// to a daemon, can share the jobserver. The other end receives it with
+    /// [`Client::recv_from_socket`].
+    ///
+    /// Nothing else should be read from or written to `sock` while the client
+    /// is being sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] for clients created with
+    /// [`Client::counting`], and any I/O error that happens while writing to
+    /// `sock`.
+    #[cfg(unix)]
+    pub fn send_over_socket(&self, sock: &std::os::unix::net::UnixStream) -> io::Result<()> {
+        self.check_open()?;
+        match self.backend().base() {
+            Backend::Os(c) => c.send_over_socket(sock, *self.state.lock_limit()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-process jobservers cannot be sent to other processes",
+            )),
+        }
+    }
+
+    /// Receives a jobserver sent by [`Client::send_over_socket`] from another
+    /// process over a Unix socket.
+    ///
+    /// The returned client owns duplicates of the file descriptors of the
+    /// sending client's jobserver, which are close-on-exec like those of any
+    /// other client. A cap set with [`Client::capped`] isn't transferred.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to [`io::ErrorKind::InvalidData`]
+    /// if what's received isn't a jobserver sent with
+    /// [`Client::send_over_socket`], and any I/O error that happens while
+    /// reading from `sock`.
+    #[cfg(unix)]
+    pub fn recv_from_socket(sock: &std::os::unix::net::UnixStream) -> io::Result<Client> {
+        let (client, limit) = imp::Client::recv_from_socket(sock)?;
+        Ok(Client::from_backend(Backend::Os(client), limit, false))
+    }
+
+    /// Returns whether this process created the jobserver, rather than
+    /// connecting to an existing one.
+    ///
+    /// This is `true` for clients created with [`Client::new`] and its
+    /// variants, including [`Client::counting`], and `false` for clients
+    /// connected to a jobserver with [`Client::from_env`] and its variants,
+    /// [`Client::from_descriptor`] or [`Client::recv_from_socket`]. Clones,
+    /// including those made with [`Client::clone_independent`], and capped
+    /// clients keep the answer of the client they were made from.
+    ///
+    /// Some operations, such as [`Client::reset_to`] and
+    /// [`Client::shutdown`], are only meant for jobservers this process is in
+    /// charge of, and this allows checking that up front.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// assert!(client.is_owner());
+    /// ```
+    pub fn is_owner(&self) -> bool {
+        self.state.owned
+    }
+
+    /// Makes this client and its clones yield the rest of the current
+    /// thread's time slice after releasing a token, or stop doing so.
+    ///
+    /// Under heavy contention a process which releases a token and right away
+    /// acquires one again tends to get the token back before the operating
+    /// system schedules any of the other processes waiting for it, starving
+    /// them. Yielding after the release gives those waiters a fair shot at
+    /// the token, at the cost of a system call per release. This applies to
+    /// dropping an [`Acquired`] token and to [`Client::release_raw`].
+    ///
+    /// This is off by default.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// client.set_cooperative(true);
+    /// assert!(client.clone().is_cooperative());
+    /// ```
+    pub fn set_cooperative(&self, cooperative: bool) {
+        self.state.cooperative.store(cooperative, Ordering::Relaxed);
+    }
+
+    /// Returns whether this client yields after releasing a token, see
+    /// [`Client::set_cooperative`].
+    pub fn is_cooperative(&self) -> bool {
+        self.state.cooperative.load(Ordering::Relaxed)
+    }
+
+    /// Makes this client and its clones pass the nesting depth of jobservers
+    /// down to the child processes they configure, or stop doing so.
+    ///
+    /// When on, [`Client::configure`] and its variants set the
+    /// `JOBSERVER_DEPTH` environment variable of the child to one more than
+    /// its value in this process, which the child reads back as
+    /// [`FromEnv::depth`]. A tool which finds itself at a depth greater than
+    /// 0 without a jobserver to inherit can then warn that it's fragmenting
+    /// the token pool of the build it runs under.
+    ///
+    /// This is off by default, so children only see the environment
+    /// variables of the jobserver protocol itself.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::process::Command;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// client.set_track_depth(true);
+    /// let mut cmd = Command::new("make");
+    /// client.configure(&mut cmd);
+    /// assert!(cmd.get_envs().any(|(name, _)| name == "JOBSERVER_DEPTH"));
+    /// ```
+    pub fn set_track_depth(&self, track: bool) {
+        self.state.track_depth.store(track, Ordering::Relaxed);
+    }
+
+    /// Returns whether this client passes the nesting depth of jobservers
+    /// down to child processes, see [`Client::set_track_depth`].
+    pub fn tracks_depth(&self) -> bool {
+        self.state.track_depth.load(Ordering::Relaxed)
+    }
+
+    /// Returns the value of `JOBSERVER_DEPTH` for children configured by this
+    /// client, if it tracks the depth.
+    fn child_depth(&self) -> Option<OsString> {
+        if !self.tracks_depth() {
+            return None;
+        }
+        let depth = parse_depth(env::var_os(DEPTH_VAR));
+        Some(OsString::from(depth.saturating_add(1).to_string()))
+    }
+
+    /// Stops this client and its clones from acquiring any more tokens until
+    /// [`Client::resume`] is called.
+    ///
+    /// While paused, [`Client::acquire`], [`Client::acquire_raw`] and
+    /// everything built on them block, and [`Client::try_acquire`] returns
+    /// `Ok(None)`, no matter how many tokens the jobserver has available.
+    /// Acquisitions with a timeout or deadline, such as
+    /// [`Client::acquire_timeout`], give up once it has passed.
+    /// This only holds back this process: the jobserver itself is untouched,
+    /// tokens already held stay valid and are released as usual, and other
+    /// processes sharing the jobserver keep acquiring tokens.
+    ///
+    /// Helper threads, see [`Client::into_helper_thread`], aren't paused.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// client.pause();
+    /// assert!(client.try_acquire().unwrap().is_none());
+    /// client.resume();
+    /// assert!(client.try_acquire().unwrap().is_some());
+    /// ```
+    pub fn pause(&self) {
+        *self.state.lock_paused() = true;
+    }
+
+    /// Lets this client and its clones acquire tokens again after
+    /// [`Client::pause`], waking up every thread waiting to acquire one.
+    pub fn resume(&self) {
+        *self.state.lock_paused() = false;
+        self.state.resumed.notify_all();
+    }
+
+    /// Returns whether this client is paused, see [`Client::pause`].
+    pub fn is_paused(&self) -> bool {
+        *self.state.lock_paused()
+    }
+
+    /// Returns the total number of tokens in this jobserver, whether they're
+    /// currently available or not, if it can be told.
+    ///
+    /// For jobservers created by this process this is the limit they were
+    /// created with. For jobservers inherited from the environment this is
+    /// derived from the `-jN` flag `make` passes along with the jobserver, as
+    /// `N - 1` since every process also holds an implicit token. `None` is
+    /// returned if there's no such flag, in which case
+    /// [`Client::count_tokens_by_draining`] can count them instead.
+    ///
+    /// Together with [`Client::available`] this can be used to compute how
+    /// much of the jobserver is currently in use.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let _token = client.acquire().unwrap();
+    /// let total = client.total_tokens().unwrap().unwrap();
+    /// let used = total - client.available().unwrap();
+    /// assert_eq!(used * 100 / total, 25);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to [`io::ErrorKind::BrokenPipe`]
+    /// if the limit isn't known and this client has been shut down, see
+    /// [`Client::shutdown`].
+    pub fn total_tokens(&self) -> io::Result<Option<usize>> {
+        if let Some(limit) = self.known_limit() {
+            return Ok(Some(limit));
+        }
+        self.check_open()?;
+        Ok(None)
+    }
 
-        Ok(ret.map(|data| Acquired {
-            client: self.inner.clone(),
-            data,
-            disabled: false,
-        }))
+    /// Counts the tokens in this jobserver, whether they're currently
+    /// available or not, by taking them all out of it.
+    ///
+    /// This is disruptive, and only meant for when [`Client::total_tokens`]
+    /// doesn't know the limit: every token available is acquired without
+    /// blocking and released right away, so other processes sharing the
+    /// jobserver may find no token available while they're counted, and
+    /// could even give up on it if they don't expect that. The [`Acquired`]
+    /// tokens currently held through this client and its clones are added,
+    /// see [`JobserverStats::held`]. This can't see tokens held by other
+    /// processes, or acquired with [`Client::acquire_raw`], so it's only
+    /// exact while those hold none. The count isn't remembered, so each call
+    /// counts again. `None` is returned if tokens can't be acquired without
+    /// blocking, see [`Client::supports_try_acquire`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if acquiring or releasing a token while counting
+    /// them fails, with its `kind()` set to [`io::ErrorKind::BrokenPipe`] if
+    /// this client has been shut down, see [`Client::shutdown`].
+    pub fn count_tokens_by_draining(&self) -> io::Result<Option<usize>> {
+        self.check_open()?;
+        let backend = self.backend();
+        // Count the tokens of the jobserver itself, not of any caps on top.
+        let base = backend.base();
+        if !base.supports_try_acquire() {
+            return Ok(None);
+        }
+        let mut drained = Vec::new();
+        let ret = loop {
+            match base.try_acquire() {
+                Ok(Some(data)) => drained.push(data),
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        // Hand every token back before anything else, even on errors.
+        let released = drained
+            .iter()
+            .map(|data| base.release(data.as_ref()))
+            .fold(Ok(()), io::Result::and);
+        ret.and(released)?;
+        self.state.available.invalidate();
+        Ok(Some(drained.len() + self.held_tokens()))
+    }
+
+    /// Returns the number of tokens taken from the jobserver and held through
+    /// this client, leaving out the implicit token which isn't in it.
+    fn held_tokens(&self) -> usize {
+        let implicit = usize::from(self.state.lock_implicit().lent);
+        self.state.stats.snapshot().held().saturating_sub(implicit)
+    }
+
+    /// Returns the total number of tokens in this jobserver if it's known
+    /// without counting them, see [`Client::total_tokens`].
+    fn known_limit(&self) -> Option<usize> {
+        *self.state.lock_limit()
+    }
+
+    /// Checks the size of this jobserver against the number of CPUs available
+    /// to this process, returning a [`Warning`] for each likely
+    /// misconfiguration found.
+    ///
+    /// The number of jobs allowed at once is [`Client::total_tokens`], plus
+    /// the implicit token for jobservers inherited from another process. It's
+    /// compared with [`std::thread::available_parallelism`], and a warning is
+    /// returned if either is several times the other, like a `make -j1` on a
+    /// 64-core machine or a `make -j512` on a laptop. Nothing is returned if
+    /// either number isn't known.
+    ///
+    /// This is meant for tools to log, the jobserver works the same either
+    /// way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// for warning in client.sanity_check() {
+    ///     eprintln!("warning: {}", warning);
+    /// }
+    /// ```
+    pub fn sanity_check(&self) -> Vec<Warning> {
+        let tokens = match self.known_limit() {
+            Some(tokens) => tokens,
+            None => return Vec::new(),
+        };
+        let cpus = match std::thread::available_parallelism() {
+            Ok(cpus) => cpus.get(),
+            Err(_) => return Vec::new(),
+        };
+        let jobs = tokens.saturating_add(usize::from(!self.is_owner()));
+        sanity::check(jobs, cpus)
+    }
+
+    /// Reports every token acquired through this client, or any of its
+    /// clones, which hasn't been dropped yet.
+    ///
+    /// Call this at a point where no tokens are expected to be held, like
+    /// between two phases of a build, to find tokens which were accidentally
+    /// kept alive. Returns `None` if no tokens are held, and otherwise a
+    /// [`LeakReport`] listing where each of them was acquired.
+    ///
+    /// Tokens leaked on purpose with [`Acquired::leak`] or
+    /// [`Acquired::drop_without_releasing`] aren't reported.
+    ///
+    /// This is only available with the `leak-check` Cargo feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let token = client.acquire().unwrap();
+    /// let report = client.check_leaks().unwrap();
+    /// assert_eq!(report.len(), 1);
+    /// eprintln!("{}", report);
+    ///
+    /// drop(token);
+    /// assert!(client.check_leaks().is_none());
+    /// ```
+    #[cfg(feature = "leak-check")]
+    pub fn check_leaks(&self) -> Option<LeakReport> {
+        self.state.live_tokens.report()
+    }
+
+    /// Makes the next [`Client::acquire`] or [`Client::try_acquire`] of a
+    /// token from this jobserver fail with an error of the given `kind`.
+    ///
+    /// Failures are queued: calling this several times makes as many of the
+    /// following acquisitions fail, in order, after which acquiring works
+    /// normally again. The failure is shared by all clones of this client.
+    /// Only acquisitions which actually take a token from the jobserver
+    /// consume a failure, so handing out the implicit token (see
+    /// [`Client::with_implicit_token`]) or waiting on the limit of a
+    /// [`Client::capped`] client doesn't.
+    ///
+    /// This is only available with the `testing` Cargo feature.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this client wasn't created with [`Client::counting`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    /// use std::io;
+    ///
+    /// let client = Client::counting(2);
+    /// client.set_fail_next_acquire(io::ErrorKind::Interrupted);
+    /// let err = client.acquire().unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    /// assert!(client.acquire().is_ok());
+    /// ```
+    #[cfg(feature = "testing")]
+    pub fn set_fail_next_acquire(&self, kind: io::ErrorKind) {
+        self.backend()
+            .base()
+            .counting()
+            .expect("failures can only be injected into `Client::counting` jobservers")
+            .fail_next_acquire(kind);
+    }
+
+    /// Makes tokens released through this client, and its clones, be handed
+    /// back to the jobserver in a deterministic `order`, for reproducing
+    /// scheduling-dependent behavior in tests.
+    ///
+    /// With any order other than [`ReleaseOrder::Immediate`], the default,
+    /// released tokens are queued in-process instead of being handed back
+    /// right away. The queue is handed back in the chosen order by
+    /// [`Client::flush`], before this client acquires a token, and
+    /// as soon as a token is released while a thread is blocked acquiring
+    /// through this client, so that it can't wait forever. Waiting for a
+    /// token in other ways, such as in another process or on a helper
+    /// thread, doesn't flush the queue, so call [`Client::flush`]
+    /// before doing so. Switching back to [`ReleaseOrder::Immediate`] also
+    /// flushes the queue, and so does dropping the last clone of the client.
+    ///
+    /// Setting [`ReleaseOrder::Seeded`] restarts its sequence, so the same
+    /// releases are shuffled the same way after setting the same seed.
+    ///
+    /// This is only available with the `testing` Cargo feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::{Client, ReleaseOrder};
+    ///
+    /// let client = Client::counting(2);
+    /// client.set_release_order(ReleaseOrder::Lifo);
+    /// let a = client.acquire().unwrap();
+    /// drop(a);
+    /// // The token is queued until it's flushed.
+    /// assert_eq!(client.available().unwrap(), 1);
+    /// client.flush().unwrap();
+    /// assert_eq!(client.available().unwrap(), 2);
+    /// ```
+    #[cfg(feature = "testing")]
+    pub fn set_release_order(&self, order: ReleaseOrder) {
+        self.state.release_queue.set_order(order);
+    }
+
+    /// Returns a snapshot of cumulative statistics of the tokens acquired
+    /// through this client and its clones.
+    ///
+    /// The statistics are always collected, which only costs a few atomic
+    /// operations per token, and can be used to tune how much parallelism is
+    /// asked for, for example by printing a summary once a build is done.
+    /// Tokens acquired by helper threads count as well, but the time they
+    /// spend waiting isn't included in the time blocked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let a = client.acquire().unwrap();
+    /// let b = client.acquire().unwrap();
+    /// drop((a, b));
+    ///
+    /// let stats = client.stats();
+    /// assert_eq!(stats.acquired(), 2);
+    /// assert_eq!(stats.peak_held(), 2);
+    /// eprintln!("{}", stats);
+    /// ```
+    pub fn stats(&self) -> JobserverStats {
+        self.state.stats.snapshot()
+    }
+
+    /// Acquires every token currently available from this jobserver without
+    /// blocking.
+    ///
+    /// This repeatedly calls [`Client::try_acquire`] until no more tokens are
+    /// immediately available, which is useful to temporarily quiesce work
+    /// sharing this jobserver. Dropping the returned tokens releases them back
+    /// to the jobserver.
+    ///
+    /// Note that this never waits for tokens, so tokens released by other
+    /// processes after this returns are not included.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring a token then the tokens
+    /// acquired so far are released and the error is returned. Like
+    /// [`Client::try_acquire`], this returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if non-blocking acquire is not supported.
+    pub fn drain(&self) -> io::Result<Vec<Acquired>> {
+        let mut tokens = Vec::new();
+        while let Some(token) = self.try_acquire()? {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+
+    /// Acquires `n` tokens without blocking, or none at all.
+    ///
+    /// This repeatedly calls [`Client::try_acquire`] until `n` tokens are
+    /// acquired, which are then returned. If fewer than `n` tokens are
+    /// immediately available, every token acquired so far is released again
+    /// and `Ok(None)` is returned, so no tokens are left held.
+    ///
+    /// Note that this isn't atomic from the point of view of other processes
+    /// sharing the jobserver: they may briefly see fewer tokens available
+    /// while the tokens are being acquired and rolled back.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring a token then the tokens
+    /// acquired so far are released and the error is returned. Like
+    /// [`Client::try_acquire`], this returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if non-blocking acquire is not supported.
+    pub fn try_acquire_many(&self, n: usize) -> io::Result<Option<Vec<Acquired>>> {
+        // `n` may be far more than could ever be acquired, so don't allocate
+        // for it up front.
+        let mut tokens = Vec::new();
+        while tokens.len() < n {
+            match self.try_acquire()? {
+                Some(token) => tokens.push(token),
+                // Dropping `tokens` releases everything acquired so far.
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(tokens))
+    }
+
+    /// Acquires `weight` tokens as a single unit, for a task which should
+    /// count as that many jobs.
+    ///
+    /// This blocks until all `weight` tokens are acquired, like calling
+    /// [`Client::acquire`] `weight` times, and bundles them into a
+    /// [`WeightedAcquired`] which releases exactly those tokens when dropped.
+    /// Weighted acquisitions through this client and its clones gather their
+    /// tokens one at a time, so two of them can't deadlock each holding part
+    /// of the tokens the other one needs. A weight of zero returns an empty
+    /// bundle right away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let link = client.acquire_weighted(3).unwrap();
+    /// assert_eq!(link.weight(), 3);
+    /// assert_eq!(client.available().unwrap(), 1);
+    /// drop(link);
+    /// assert_eq!(client.available().unwrap(), 4);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] if `weight` is more than the number of
+    /// tokens in the jobserver, as far as it's known, see
+    /// [`Client::total_tokens`], as that many could never be acquired at
+    /// once.
+    ///
+    /// If acquiring any of the tokens fails the tokens acquired so far are
+    /// released and the error is returned, so no tokens are left held. The
+    /// errors are the same as those of [`Client::acquire`].
+    pub fn acquire_weighted(&self, weight: usize) -> io::Result<WeightedAcquired> {
+        if let Some(limit) = self.known_limit() {
+            // The implicit token may be handed out on top, see
+            // `with_implicit_token`.
+            let slots = limit.saturating_add(usize::from(self.state.lock_implicit().lendable));
+            if weight > slots {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "cannot acquire {} tokens from a jobserver with {}",
+                        weight, slots
+                    ),
+                ));
+            }
+        }
+        let _gathering = self
+            .state
+            .weighted
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        // Without a known limit `weight` is unchecked, so don't allocate for
+        // it up front.
+        let mut tokens = Vec::new();
+        while tokens.len() < weight {
+            // Dropping `tokens` on error releases everything acquired so far.
+            tokens.push(self.acquire()?);
+        }
+        Ok(WeightedAcquired::new(tokens))
+    }
+
+    /// Adds `extra` tokens to the jobserver until the returned guard is
+    /// dropped.
+    ///
+    /// This is for bursts of work known in advance, such as a parallel link
+    /// step, which should run with more jobs than the jobserver normally
+    /// allows. The tokens are released into the jobserver right away, so
+    /// they're available to child processes as well, and
+    /// [`Client::total_tokens`] grows by `extra` if it's known. Dropping the
+    /// [`BoostGuard`] takes them out again, waiting for tokens to be released
+    /// if they're all held at the time, see there for details.
+    ///
+    /// The tokens are added to the jobserver itself, so a cap set with
+    /// [`Client::capped`] still applies to this client.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::counting(2);
+    /// {
+    ///     let _boost = client.boost(2).unwrap();
+    ///     assert_eq!(client.total_tokens().unwrap(), Some(4));
+    ///     // ... run the burst of jobs ...
+    /// }
+    /// assert_eq!(client.total_tokens().unwrap(), Some(2));
+    /// assert_eq!(client.available().unwrap(), 2);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if this process didn't create the
+    /// jobserver, see [`Client::is_owner`], as the tokens of a jobserver
+    /// shared with a parent process aren't ours to add to.
+    ///
+    /// Any I/O error while releasing the tokens is returned as well, after
+    /// taking back those added so far. On Windows this is the case when
+    /// `extra` would take the semaphore over the number of tokens it was
+    /// created with, and on Unix releasing tokens blocks if the pipe can't
+    /// hold any more.
+    pub fn boost(&self, extra: usize) -> io::Result<BoostGuard> {
+        self.check_open()?;
+        if !self.is_owner() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only jobservers created by this process can be boosted",
+            ));
+        }
+        self.add_tokens(extra)?;
+        Ok(BoostGuard::new(self.clone(), extra))
+    }
+
+    /// Puts `n` new tokens into the jobserver, growing its limit to match.
+    fn add_tokens(&self, n: usize) -> io::Result<()> {
+        for added in 0..n {
+            if let Err(e) = self.backend().base().release(None) {
+                drop(self.remove_tokens(added));
+                return Err(e);
+            }
+            if let Some(limit) = &mut *self.state.lock_limit() {
+                *limit = limit.saturating_add(1);
+            }
+            self.state.available.invalidate();
+        }
+        Ok(())
+    }
+
+    /// Takes `n` tokens out of the jobserver for good, shrinking its limit to
+    /// match. This blocks until that many tokens are free.
+    fn remove_tokens(&self, n: usize) -> io::Result<()> {
+        for _ in 0..n {
+            self.backend().base().acquire()?;
+            if let Some(limit) = &mut *self.state.lock_limit() {
+                *limit = limit.saturating_sub(1);
+            }
+            self.state.available.invalidate();
+        }
+        Ok(())
+    }
+
+    /// Restores a jobserver backed by a named pipe to exactly `limit` available
+    /// tokens.
+    ///
+    /// A `fifo:` jobserver outlives the processes using it, so if a previous
+    /// build crashed while holding tokens, or released more tokens than it
+    /// acquired, the fifo is left with the wrong number of tokens. This
+    /// discards every token currently in the fifo without blocking and then
+    /// writes `limit` fresh ones back, after which [`Client::total_tokens`]
+    /// returns `Some(limit)`.
+    ///
+    /// This must only be done while no other process is using the jobserver,
+    /// since tokens they hold are released on top of the `limit` written here.
+    /// Tokens held by this process are safe: resetting starts a new
+    /// [`Client::epoch`], and [`Acquired`] tokens from before that are
+    /// dropped without being released, see [`Acquired::is_stale`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if this jobserver isn't backed by a
+    /// named pipe, and with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] if the pipe can't hold `limit` tokens.
+    /// Any other I/O error while reading or writing the pipe is returned
+    /// as-is.
+    pub fn reset_to(&self, limit: usize) -> io::Result<()> {
+        self.check_open()?;
+        self.backend().reset_to(limit)?;
+        self.state.epoch.fetch_add(1, Ordering::SeqCst);
+        self.state.available.invalidate();
+        *self.state.lock_limit() = Some(limit);
+        Ok(())
+    }
+
+    /// Returns the number of times this jobserver was reset with
+    /// [`Client::reset_to`] through this client or a clone of it.
+    ///
+    /// Every [`Acquired`] token is stamped with the epoch it was acquired in,
+    /// so that tokens from before a reset aren't released into the fresh
+    /// pool, see [`Acquired::is_stale`].
+    pub fn epoch(&self) -> u64 {
+        self.state.epoch.load(Ordering::SeqCst)
+    }
+
+    /// Compares the number of tokens in this jobserver with the
+    /// `expected_limit` it should have in total, without changing anything.
+    ///
+    /// The tokens currently available, see [`Client::available`], and the
+    /// [`Acquired`] tokens held through this client and its clones are
+    /// counted. Tokens held with [`Client::acquire_raw`] or by other
+    /// processes can't be told apart from leaked ones, so this is meant to be
+    /// used while nobody else is using the jobserver, such as when a tool
+    /// picks up a jobserver it persists across runs on startup.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of [`Client::available`].
+    pub fn audit(&self, expected_limit: usize) -> io::Result<AuditReport> {
+        let found = self.available()?;
+        Ok(AuditReport::new(expected_limit, found, self.held_tokens()))
+    }
+
+    /// Like [`Client::audit`], but also corrects the number of tokens in the
+    /// jobserver with [`Client::reset_to`] if it's off.
+    ///
+    /// This recovers tokens leaked by a previous run which crashed while
+    /// holding them, and removes tokens released more than once, while
+    /// reporting the discrepancy so it can be logged:
+    ///
+    /// ```no_run
+    /// use jobserver::Client;
+    ///
+    /// # let client = Client::new(4).unwrap();
+    /// let report = client.audit_and_repair(4).unwrap();
+    /// if !report.is_consistent() {
+    ///     eprintln!("{}", report); // "recovered 3 leaked jobserver token(s)"
+    /// }
+    /// ```
+    ///
+    /// The report describes the jobserver before the repair. Tokens held
+    /// through this client are counted as in use, but become stale if the
+    /// jobserver is reset, see [`Acquired::is_stale`], and the jobserver ends
+    /// up with `expected_limit` available tokens. Until they're dropped
+    /// later audits still count them as held.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of [`Client::audit`], or of [`Client::reset_to`] if
+    /// the jobserver needs repairing, notably with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if it isn't backed by a named pipe.
+    pub fn audit_and_repair(&self, expected_limit: usize) -> io::Result<AuditReport> {
+        let mut report = self.audit(expected_limit)?;
+        if !report.is_consistent() {
+            self.reset_to(expected_limit)?;
+            report.set_repaired();
+        }
+        Ok(report)
     }
 
-    /// Returns amount of tokens in the read-side pipe.
+    /// Returns the number of tokens currently available from the jobserver.
     ///
     /// # Return value
     ///
-    /// Number of bytes available to be read from the jobserver pipe
+    /// On Unix this is the number of bytes available to be read from the
+    /// jobserver pipe.
+    ///
+    /// Windows has no way to read the count of a semaphore, so it's probed
+    /// instead: a token is taken from the semaphore without waiting and put
+    /// right back, which reveals the count. The result is best-effort, as
+    /// other processes may acquire or release tokens at any time, and another
+    /// process trying to acquire a token may briefly find none while this
+    /// probe holds the last one.
     ///
     /// # Errors
     ///
-    /// Underlying errors from the ioctl will be passed up.
+    /// Underlying errors from the ioctl, or from releasing the probed token
+    /// on Windows, will be passed up.
     pub fn available(&self) -> io::Result<usize> {
-        self.inner.available()
+        self.check_open()?;
+        self.backend().available()
+    }
+
+    /// Returns the number of tokens available from the jobserver like
+    /// [`Client::available`], reusing the last number if it was queried at
+    /// most `max_age` ago.
+    ///
+    /// This avoids a system call per call in hot scheduling loops. The
+    /// cached number is shared by all clones of this client, and forgotten
+    /// whenever a token is acquired or released through any of them, so it
+    /// only ever goes stale because of other processes sharing the jobserver.
+    /// Use [`Client::refresh_available`] to query the jobserver again right
+    /// away.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    /// use std::time::Duration;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let max_age = Duration::from_millis(10);
+    /// assert_eq!(client.available_cached(max_age).unwrap(), 4);
+    /// let token = client.acquire().unwrap();
+    /// assert_eq!(client.available_cached(max_age).unwrap(), 3);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::available`] if the jobserver is
+    /// queried.
+    pub fn available_cached(&self, max_age: Duration) -> io::Result<usize> {
+        self.check_open()?;
+        match self.state.available.get(max_age) {
+            Some(available) => Ok(available),
+            None => self.refresh_available(),
+        }
+    }
+
+    /// Queries the number of tokens available from the jobserver like
+    /// [`Client::available`], replacing the number cached by
+    /// [`Client::available_cached`].
+    pub fn refresh_available(&self) -> io::Result<usize> {
+        self.check_open()?;
+        self.state.available.refresh(|| self.backend().available())
+    }
+
+    /// Runs tasks on the rayon thread `pool` under the limit of this
+    /// jobserver.
+    ///
+    /// Each task spawned through the returned [`RayonPool`] acquires a token
+    /// from this jobserver before it runs and releases it once it's done, so
+    /// that a rayon pool plays along with `make` and other tools sharing the
+    /// jobserver. See [`RayonPool`] for which tasks are covered.
+    ///
+    /// This is only available with the `rayon` Cargo feature.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+    /// let pool = client.bind_rayon(&pool);
+    /// let (a, b) = pool.join(|| 1 + 1, || 2 + 2).unwrap();
+    /// assert_eq!((a, b), (2, 4));
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn bind_rayon<'a>(&self, pool: &'a rayon::ThreadPool) -> RayonPool<'a> {
+        RayonPool::new(self.clone(), pool)
+    }
+
+    /// Blocks until at least `n` tokens are available from the jobserver, or
+    /// `timeout` expires, without acquiring any of them.
+    ///
+    /// This is meant as a heuristic gate, for example for a phase which only
+    /// starts scheduling work once there's room for enough of it. It's
+    /// inherently racy: other processes may take the tokens again right
+    /// after this returns, so the tokens still have to be acquired, and
+    /// possibly waited for, as usual. Like [`Client::watch_available`] the
+    /// number of available tokens is sampled periodically, so a short burst
+    /// of available tokens may be missed.
+    ///
+    /// `None` waits without a timeout. Note that this never returns if the
+    /// jobserver doesn't have `n` tokens in total.
+    ///
+    /// # Return value
+    ///
+    /// Returns `true` once at least `n` tokens were seen available, and
+    /// `false` if the timeout expired first.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    /// use std::time::Duration;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// assert!(client.wait_available(4, None).unwrap());
+    /// let token = client.acquire().unwrap();
+    /// let timeout = Some(Duration::from_millis(10));
+    /// assert!(!client.wait_available(4, timeout).unwrap());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns the same errors as [`Client::available`].
+    pub fn wait_available(&self, n: usize, timeout: Option<Duration>) -> io::Result<bool> {
+        watch::wait_available(self, n, timeout)
+    }
+
+    /// Returns an iterator yielding the number of tokens available from this
+    /// jobserver whenever it changes, for example to display how much of the
+    /// jobserver is in use.
+    ///
+    /// Each call to [`Iterator::next`] blocks until the number returned by
+    /// [`Client::available`] changes. This is best-effort: the number is
+    /// sampled periodically, so short-lived changes may be missed. See
+    /// [`AvailableWatch`] for details.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// for available in client.watch_available() {
+    ///     println!("{} of 4 tokens in use", 4 - available.unwrap());
+    /// }
+    /// ```
+    pub fn watch_available(&self) -> AvailableWatch {
+        AvailableWatch::new(self.clone())
+    }
+
+    /// Returns whether this client's jobserver can be passed on to child
+    /// processes with [`Client::configure`].
+    ///
+    /// This is `false` for clients created with [`Client::counting`], and for
+    /// every client on platforms without cross-process jobserver support
+    /// (anything other than Unix and Windows), where [`Client::configure`]
+    /// would panic. The other ways of passing the jobserver on, such as
+    /// [`Client::configure_make`] and [`Client::configure_generic`], panic
+    /// under the same conditions, so checking this first lets a tool fall
+    /// back to not passing the jobserver on instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::process::Command;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::counting(4);
+    /// let mut cmd = Command::new("make");
+    /// if client.is_inheritable() {
+    ///     client.configure(&mut cmd);
+    /// }
+    /// ```
+    #[doc(alias = "can_configure")]
+    pub fn is_inheritable(&self) -> bool {
+        match self.backend().base() {
+            Backend::Os(_) => imp::INHERITABLE,
+            _ => false,
+        }
     }
 
     /// Configures a child process to have access to this client's jobserver as
@@ -373,16 +3232,56 @@ impl Client {
     /// in the child process (or the equivalent of [`Client::from_env`] that
     /// `make` uses).
     ///
-    /// ## Platform-specific behavior
+    /// ## Platform-specific behavior
+    ///
+    /// On Unix and Windows this will clobber the `CARGO_MAKEFLAGS` environment
+    /// variables for the child process, and on Unix this will also allow the
+    /// two file descriptors for this client to be inherited to the child.
+    ///
+    /// If the number of tokens in the jobserver is known, see
+    /// [`Client::total_tokens`], the variable includes a `-jN` flag with `N`
+    /// one more than that, following `make`'s convention of counting the
+    /// implicit token every process holds: `Client::new(3)` is passed as
+    /// `-j4`, just like `make -j4` creates a jobserver with 3 tokens, and
+    /// children see the same number of jobs and tokens either way. Otherwise
+    /// it includes a bare `-j`.
+    ///
+    /// With [`Client::set_track_depth`] turned on `JOBSERVER_DEPTH` is set
+    /// for the child process as well, to one more than its value in this
+    /// process, see [`FromEnv::depth`].
+    ///
+    /// Configuring the same command more than once, with this client or a
+    /// clone of it and with any of the `configure` methods, is harmless: the
+    /// environment variables are set to the same values again, and the file
+    /// descriptors are inherited just the same.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this client can't be inherited by child processes, which is
+    /// the case for clients created with [`Client::counting`] and for all
+    /// clients on platforms other than Unix and Windows. Check
+    /// [`Client::is_inheritable`] first to degrade gracefully instead.
+    pub fn configure(&self, cmd: &mut Command) {
+        self.configure_generic(cmd);
+    }
+
+    /// Configures a child process spawned by any [`ConfigurableCommand`] to
+    /// have access to this client's jobserver as well.
+    ///
+    /// This is the same as [`Client::configure`], except that it works with
+    /// process builders other than [`std::process::Command`], such as
+    /// `tokio::process::Command` or a custom spawner, by implementing
+    /// [`ConfigurableCommand`] for them.
     ///
-    /// On Unix and Windows this will clobber the `CARGO_MAKEFLAGS` environment
-    /// variables for the child process, and on Unix this will also allow the
-    /// two file descriptors for this client to be inherited to the child.
+    /// # Panics
     ///
-    /// On platforms other than Unix and Windows this panics.
-    pub fn configure(&self, cmd: &mut Command) {
-        cmd.env("CARGO_MAKEFLAGS", &self.mflags_env());
-        self.inner.configure(cmd);
+    /// Panics under the same conditions as [`Client::configure`].
+    pub fn configure_generic<C: ConfigurableCommand>(&self, cmd: &mut C) {
+        cmd.env("CARGO_MAKEFLAGS".as_ref(), &self.mflags_env());
+        if let Some(depth) = self.child_depth() {
+            cmd.env(DEPTH_VAR.as_ref(), &depth);
+        }
+        self.backend().configure(cmd);
     }
 
     /// Configures a child process to have access to this client's jobserver as
@@ -398,25 +3297,306 @@ impl Client {
     /// ## Platform-specific behavior
     ///
     /// On Unix and Windows this will clobber the `CARGO_MAKEFLAGS`,
-    /// `MAKEFLAGS` and `MFLAGS` environment variables for the child process,
-    /// and on Unix this will also allow the two file descriptors for
-    /// this client to be inherited to the child.
+    /// `MAKEFLAGS`, `MFLAGS` and `GNUMAKEFLAGS` environment variables for the
+    /// child process, and on Unix this will also allow the two file
+    /// descriptors for this client to be inherited to the child.
     ///
-    /// On platforms other than Unix and Windows this panics.
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`Client::configure`].
     pub fn configure_make(&self, cmd: &mut Command) {
         let value = self.mflags_env();
         cmd.env("CARGO_MAKEFLAGS", &value);
         cmd.env("MAKEFLAGS", &value);
         cmd.env("MFLAGS", &value);
-        self.inner.configure(cmd);
+        cmd.env("GNUMAKEFLAGS", &value);
+        if let Some(depth) = self.child_depth() {
+            cmd.env(DEPTH_VAR, depth);
+        }
+        self.backend().configure(cmd);
+    }
+
+    /// Configures a child process to have access to this client's jobserver
+    /// through its command line instead of its environment.
+    ///
+    /// This appends `--flag=<auth>` to the arguments of `cmd`, where `<auth>`
+    /// is what `make` passes in `--jobserver-auth=`, for the child to connect
+    /// with [`Client::from_argv`]. It's meant for sandboxes which wipe the
+    /// environment of the processes they run but keep their arguments and
+    /// file descriptors. Like with [`Client::configure`] the file descriptors
+    /// of the jobserver are inherited by the child on Unix, but the
+    /// environment of `cmd` is left alone.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`Client::configure`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::process::Command;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let mut cmd = Command::new("sandboxed-tool");
+    /// client.configure_argv(&mut cmd, "jobserver");
+    /// let arg = cmd.get_args().last().unwrap().to_str().unwrap();
+    /// assert!(arg.starts_with("--jobserver="));
+    /// ```
+    pub fn configure_argv(&self, cmd: &mut Command, flag: &str) {
+        let mut arg = OsString::from(format!("--{}=", flag));
+        arg.push(self.backend().string_arg());
+        cmd.arg(arg);
+        self.backend().configure(cmd);
+    }
+
+    /// Hides this client's jobserver from a child process, undoing
+    /// [`Client::configure`] and its variants.
+    ///
+    /// This removes the `CARGO_MAKEFLAGS`, `MAKEFLAGS`, `MFLAGS` and
+    /// `GNUMAKEFLAGS` environment variables from `cmd`, so the child doesn't
+    /// find a jobserver with [`Client::from_env`] even if this process was
+    /// given one. Note that `MAKEFLAGS` carries other flags of `make` along
+    /// with the jobserver, which are removed too. On Unix the file
+    /// descriptors of the jobserver's pipe are also made close-on-exec in the
+    /// child again, even if `cmd` was configured with this client before, so
+    /// the child can't get at the jobserver at all.
+    ///
+    /// This is the way to sandbox a child from the jobserver, since setting
+    /// the environment of `cmd` alone doesn't undo the inheritance of the
+    /// file descriptors by an earlier call to [`Client::configure`].
+    ///
+    /// Unlike [`Client::configure`] this works for every client: those which
+    /// can't be inherited have nothing to hide but the environment.
+    pub fn deconfigure(&self, cmd: &mut Command) {
+        for var in MAKEFLAGS_VARS {
+            cmd.env_remove(var);
+        }
+        self.backend().deconfigure(cmd);
+    }
+
+    /// Acquires a token and spawns `cmd` holding it, for running one job per
+    /// token.
+    ///
+    /// The token is released when the returned [`LimitedChild`] is waited
+    /// for, or right away if spawning `cmd` fails. If this client can be
+    /// inherited, see [`Client::is_inheritable`], `cmd` is configured with
+    /// [`Client::configure`] first so the child process can share the
+    /// jobserver as well.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if acquiring the token or spawning `cmd` fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::process::Command;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let mut child = client.spawn_limited(&mut Command::new("cc")).unwrap();
+    /// // The token is released once the child has exited.
+    /// assert!(child.wait().unwrap().success());
+    /// ```
+    pub fn spawn_limited(&self, cmd: &mut Command) -> io::Result<LimitedChild> {
+        let token = self.acquire()?;
+        if self.is_inheritable() {
+            self.configure(cmd);
+        }
+        let child = cmd.spawn()?;
+        Ok(LimitedChild::new(child, token))
+    }
+
+    /// Acquires a token and spawns a scoped thread running `f` holding it.
+    ///
+    /// This is [`thread::Scope::spawn`](std::thread::Scope::spawn) under the
+    /// jobserver's limit, so `f` can borrow from outside of the scope, like
+    /// with any scoped thread. The token is released once `f` returns or
+    /// panics.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if acquiring the token fails, in which case no
+    /// thread is spawned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    /// use std::thread;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let mut results = vec![0; 4];
+    /// thread::scope(|scope| {
+    ///     for (i, result) in results.iter_mut().enumerate() {
+    ///         client.spawn_scoped(scope, move || *result = i * i).unwrap();
+    ///     }
+    /// });
+    /// assert_eq!(results, [0, 1, 4, 9]);
+    /// ```
+    pub fn spawn_scoped<'scope, 'env, F, T>(
+        &self,
+        scope: &'scope std::thread::Scope<'scope, 'env>,
+        f: F,
+    ) -> io::Result<std::thread::ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let mut token = self.acquire()?;
+        // The token counts towards the thread running `f` for deadlock
+        // checks, not this one, which may go on to acquire more.
+        token.detach_holder();
+        Ok(scope.spawn(move || {
+            let mut token = token;
+            token.attach_holder();
+            let ret = f();
+            drop(token);
+            ret
+        }))
+    }
+
+    /// Spawns a scoped thread calling `f` for each of `items`, each holding
+    /// a token like with [`Client::spawn_scoped`].
+    ///
+    /// Unlike [`JobserverIteratorExt::jobserver_for_each`], which runs a
+    /// scope of its own, the threads are spawned into the caller's `scope`:
+    /// this returns once a thread has been spawned for every item, and the
+    /// threads are joined when the scope ends, so other work can be spawned
+    /// into the same scope alongside them. This blocks while the jobserver
+    /// is exhausted.
+    ///
+    /// # Errors
+    ///
+    /// If acquiring a token fails no more items are taken from `items`, and
+    /// the error is returned right away. Threads already spawned keep
+    /// running until the scope ends.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::thread;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let total = AtomicUsize::new(0);
+    /// thread::scope(|scope| {
+    ///     client
+    ///         .for_each_scoped(scope, 1..=4, |i| {
+    ///             total.fetch_add(i, Ordering::SeqCst);
+    ///         })
+    ///         .unwrap();
+    /// });
+    /// assert_eq!(total.into_inner(), 10);
+    /// ```
+    pub fn for_each_scoped<'scope, 'env, I, F>(
+        &self,
+        scope: &'scope std::thread::Scope<'scope, 'env>,
+        items: I,
+        f: F,
+    ) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Send + 'scope,
+        F: Fn(I::Item) + Send + Sync + 'scope,
+    {
+        let f = Arc::new(f);
+        for item in items {
+            let f = f.clone();
+            self.spawn_scoped(scope, move || f(item))?;
+        }
+        Ok(())
+    }
+
+    /// Configures `cmd` to have access to this client's jobserver like
+    /// [`Client::configure`] does, and spawns it as part of the Windows job
+    /// object `job`.
+    ///
+    /// A [`Command`] can't be made to start in a job object, so this spawns
+    /// `cmd` itself and then assigns the child process to `job`, returning
+    /// the child. Once `job` is dropped or this process exits, even
+    /// abnormally, the child and every process it spawned in turn are
+    /// terminated by the OS, so they can't be left holding tokens of a
+    /// jobserver which has been torn down.
+    ///
+    /// Note that the child is assigned to the job object right after it's
+    /// spawned, so processes it spawns before that happens aren't part of the
+    /// job object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if spawning the process fails, or if assigning it to
+    /// `job` fails, in which case the child process is killed.
+    #[cfg(windows)]
+    pub fn configure_with_job_object(
+        &self,
+        cmd: &mut Command,
+        job: &JobObject,
+    ) -> io::Result<std::process::Child> {
+        self.configure(cmd);
+        let mut child = cmd.spawn()?;
+        if let Err(e) = job.assign(&child) {
+            drop(child.kill());
+            drop(child.wait());
+            return Err(e);
+        }
+        Ok(child)
+    }
+
+    /// Returns the file descriptor mappings a child process spawned with
+    /// `posix_spawn` needs to inherit this client's jobserver.
+    ///
+    /// [`Client::configure`] relies on [`std::process::Command`] to clear the
+    /// close-on-exec flag of the jobserver's file descriptors in the child.
+    /// Spawners which call `posix_spawn` directly can't do that, so instead
+    /// each `(source_fd, target_fd)` pair returned here should be passed to
+    /// `posix_spawn_file_actions_adddup2`. The target is the same as the
+    /// source, as the child finds the file descriptors by their numbers in
+    /// `MAKEFLAGS`, and duplicating a file descriptor onto itself clears its
+    /// close-on-exec flag in the child. That's specified by POSIX.1-2024 and
+    /// implemented by glibc 2.29 and later, but older C libraries may need a
+    /// different approach.
+    ///
+    /// The child's environment still has to be set as [`Client::configure`]
+    /// does, for example by implementing [`ConfigurableCommand`] for the
+    /// spawner and calling [`Client::configure_generic`]. No mappings are
+    /// needed for a jobserver backed by a named pipe, which the child opens
+    /// by its path.
+    ///
+    /// # Panics
+    ///
+    /// Panics under the same conditions as [`Client::configure`].
+    #[cfg(unix)]
+    pub fn fd_actions(&self) -> Vec<(std::os::unix::io::RawFd, std::os::unix::io::RawFd)> {
+        match self.backend().base() {
+            Backend::Os(c) => c.fd_actions(),
+            _ => not_inheritable(),
+        }
     }
 
-    fn mflags_env(&self) -> String {
-        let arg = self.inner.string_arg();
+    fn mflags_env(&self) -> OsString {
+        let arg = quote_for_env(self.backend().string_arg());
+        // Like `make` itself, pass along the number of jobs if we know it.
+        // That's one more than the number of tokens in the jobserver, as
+        // every process also holds an implicit token: `make -j4` puts 3
+        // tokens in its pipe. Passing `-j{limit}` instead would tell `make`
+        // children to run one job fewer than the jobserver allows, and
+        // `from_env`, which takes `-jN` to mean `N - 1` tokens for the same
+        // reason, would report one token fewer than there are.
+        let mut value = match *self.state.lock_limit() {
+            Some(limit) => OsString::from(format!("-j{}", limit.saturating_add(1))),
+            None => OsString::from("-j"),
+        };
         // Older implementations of make use `--jobserver-fds` and newer
         // implementations use `--jobserver-auth`, pass both to try to catch
         // both implementations.
-        format!("-j --jobserver-fds={0} --jobserver-auth={0}", arg)
+        value.push(" --jobserver-fds=");
+        value.push(&arg);
+        value.push(" --jobserver-auth=");
+        value.push(&arg);
+        value
     }
 
     /// Converts this [`Client`] into a helper thread to deal with a blocking
@@ -484,8 +3664,11 @@ impl Client {
     ///
     /// # Platform-specific behavior
     ///
-    /// On Windows this function behaves pretty normally as expected, but on
-    /// Unix the implementation is... a little heinous. As mentioned above
+    /// On Windows this function behaves pretty normally as expected: the
+    /// helper thread waits on both the jobserver's semaphore and a
+    /// manual-reset event which is signalled when the [`HelperThread`] is
+    /// dropped, so tearing it down never waits for a token and doesn't involve
+    /// signals. On Unix the implementation is... a little heinous. As mentioned above
     /// we're forced into blocking I/O for token acquisition, namely a blocking
     /// call to `read`. We must be able to unblock this, however, to tear down
     /// the helper thread gracefully!
@@ -496,54 +3679,977 @@ impl Client {
     /// nothing along with sending signals to that thread. This may cause
     /// odd behavior in some applications, so it's recommended to review and
     /// test thoroughly before using this.
+    ///
+    /// An `EINTR` caused by any other signal delivered to the helper thread
+    /// is not treated as an error: the helper thread simply goes back to
+    /// waiting for a token, so a stray signal never fails a pending request.
+    ///
+    /// Applications which can't have a `SIGUSR1` handler installed behind
+    /// their back should use [`Client::into_helper_thread_no_signals`]
+    /// instead.
     pub fn into_helper_thread<F>(self, f: F) -> io::Result<HelperThread>
     where
         F: FnMut(io::Result<Acquired>) + Send + 'static,
     {
+        self.spawn_helper_thread(f, true)
+    }
+
+    /// Same as [`Client::into_helper_thread`], except that no signals are
+    /// used to shut down the helper thread.
+    ///
+    /// On Unix the helper thread waits for a token with `poll` on both the
+    /// jobserver and a private self-pipe, which is written to when the
+    /// [`HelperThread`] is dropped. No process-global `SIGUSR1` handler is
+    /// installed, making this suitable for applications, such as language
+    /// runtimes, which manage signal handlers themselves.
+    ///
+    /// When the jobserver is an anonymous pipe inherited from the environment
+    /// on platforms other than Linux, tokens can't be read from it without
+    /// blocking. If another process takes a token just before the helper
+    /// thread reads it, the helper thread blocks in `read`, and tearing down
+    /// the [`HelperThread`] gives up on joining it, as described for
+    /// [`Client::into_helper_thread`].
+    ///
+    /// On other platforms this is the same as [`Client::into_helper_thread`],
+    /// which doesn't use signals there.
+    pub fn into_helper_thread_no_signals<F>(self, f: F) -> io::Result<HelperThread>
+    where
+        F: FnMut(io::Result<Acquired>) + Send + 'static,
+    {
+        self.spawn_helper_thread(f, false)
+    }
+
+    fn spawn_helper_thread<F>(self, f: F, signals: bool) -> io::Result<HelperThread>
+    where
+        F: FnMut(io::Result<Acquired>) + Send + 'static,
+    {
+        self.check_open()?;
+        // Pin the helper thread to the current backend, so that the tokens
+        // it acquires are always handed out for the backend they're from.
+        let backend = self.backend();
+        let this = Client::pinned(backend.clone(), self.state.clone());
+        drop(self);
         let state = Arc::new(HelperState::default());
+        let inner = match &*backend {
+            #[cfg(unix)]
+            Backend::Os(_) if !signals => Helper::Os(imp::spawn_helper_no_signals(
+                this,
+                state.clone(),
+                Box::new(f),
+            )?),
+            Backend::Os(_) => Helper::Os(imp::spawn_helper(this, state.clone(), Box::new(f))?),
+            Backend::Counting(_) => {
+                Helper::Counting(counting::spawn_helper(this, state.clone(), Box::new(f))?)
+            }
+            Backend::Capped { .. } => Helper::Capped(capped::spawn_helper(
+                this,
+                state.clone(),
+                Box::new(f),
+                signals,
+            )?),
+            // Helper threads are shut down their own way.
+            Backend::Cancellable { inner, .. } => {
+                let uncancellable = Client::pinned(inner.clone(), this.state);
+                return uncancellable.spawn_helper_thread(f, signals);
+            }
+            Backend::Closed => return Err(shut_down()),
+        };
         Ok(HelperThread {
-            inner: Some(imp::spawn_helper(self, state.clone(), Box::new(f))?),
+            inner: Some(inner),
             state,
         })
     }
 
-    /// Blocks the current thread until a token is acquired.
-    ///
-    /// This is the same as [`Client::acquire`], except that it doesn't return
-    /// an RAII helper. If successful the process will need to guarantee that
-    /// [`Client::release_raw`] is called in the future.
-    pub fn acquire_raw(&self) -> io::Result<()> {
-        self.inner.acquire()?;
-        Ok(())
+    /// Blocks the current thread until a token is acquired.
+    ///
+    /// This is the same as [`Client::acquire`], except that it doesn't return
+    /// an RAII helper. If successful the process will need to guarantee that
+    /// [`Client::release_raw`] is called in the future.
+    pub fn acquire_raw(&self) -> io::Result<()> {
+        let timeout = self.default_acquire_timeout();
+        let start = Instant::now();
+        if !self.state.wait_unpaused(timeout) {
+            return Err(timed_out());
+        }
+        self.check_open()?;
+        let timeout = timeout.map(|timeout| timeout.saturating_sub(start.elapsed()));
+        self.acquire_backend(timeout)?.ok_or_else(timed_out)?;
+        self.state.stats.blocked(start.elapsed());
+        self.state.stats.acquired_raw();
+        self.state.available.invalidate();
+        Ok(())
+    }
+
+    /// Makes sure every token released through this client so far is visible
+    /// to other processes sharing the jobserver.
+    ///
+    /// Releases are only buffered with read-ahead, see
+    /// [`Client::set_read_ahead`], in which case the tokens kept in this
+    /// process are written back to the pipe, and with the `testing` Cargo
+    /// feature when tokens are queued because of `Client::set_release_order`,
+    /// in which case the queue is handed back in the configured order first.
+    /// Otherwise dropping an [`Acquired`] token or calling
+    /// [`Client::release_raw`] writes the token back to the pipe, or releases
+    /// the semaphore on Windows, with a system call of its own before
+    /// returning, so there's nothing to do.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let tokens = [client.acquire().unwrap(), client.acquire().unwrap()];
+    /// drop(tokens);
+    /// client.flush().unwrap();
+    /// assert_eq!(client.available().unwrap(), 2);
+    /// ```
+    pub fn flush(&self) -> io::Result<()> {
+        #[cfg(feature = "testing")]
+        self.state.release_queue.flush();
+        self.backend().flush()?;
+        self.state.available.invalidate();
+        Ok(())
+    }
+
+    /// Keeps up to `tokens` tokens of the jobserver in this process instead
+    /// of going through the pipe for every token, or turns this off again
+    /// with zero.
+    ///
+    /// By default every acquisition reads a token from the jobserver's pipe
+    /// and every release writes it back, two system calls for every job. With
+    /// read-ahead a single read takes as many tokens as the pipe has, up to
+    /// `tokens` more than asked for, and released tokens are kept too as long
+    /// as fewer than `tokens` are kept already. Later acquisitions through
+    /// this client or its clones are then served without system calls, which
+    /// matters for processes running many short jobs.
+    ///
+    /// Tokens kept in this process can't be acquired by other processes
+    /// sharing the jobserver, so a process which hoards them while it's idle
+    /// holds back the rest of the build. Only enable this with a small
+    /// `tokens` for processes which are likely the only ones acquiring
+    /// tokens at the time, and call [`Client::flush`] before going idle.
+    /// Kept tokens are never lost: they're written back to the pipe by
+    /// [`Client::flush`], when `tokens` is lowered, and once the last clone
+    /// of the client is dropped. Released tokens are written to the pipe
+    /// instead of being kept while a thread of this process is blocked in
+    /// [`Client::acquire`] or similar methods, or waits in
+    /// `Client::acquire_async_io` or [`Client::wait_available`]. Code polling
+    /// the file descriptor itself can't be told apart from idle code though,
+    /// so read-ahead is turned off for good once the file descriptor has
+    /// been handed out by [`Client::as_raw_read_fd`] or
+    /// [`Client::readiness_fd`].
+    ///
+    /// At most 64 tokens are kept, larger values are clamped.
+    ///
+    /// # Errors
+    ///
+    /// Read-ahead is only supported for jobservers backed by a pipe or fifo
+    /// on Unix, for other clients an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] is returned. Writing excess tokens back
+    /// to the pipe when lowering `tokens` can fail as well.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// # #[cfg(unix)]
+    /// # {
+    /// client.set_read_ahead(4).unwrap();
+    /// for _ in 0..100 {
+    ///     // After the first acquisition these don't touch the pipe.
+    ///     let _token = client.acquire().unwrap();
+    /// }
+    /// assert_eq!(client.available().unwrap(), 4);
+    /// client.flush().unwrap();
+    /// # }
+    /// ```
+    pub fn set_read_ahead(&self, tokens: usize) -> io::Result<()> {
+        self.check_open()?;
+        self.backend().set_read_ahead(tokens)?;
+        self.state.available.invalidate();
+        Ok(())
+    }
+
+    /// Returns the number of tokens this client keeps in the process, see
+    /// [`Client::set_read_ahead`].
+    pub fn read_ahead(&self) -> usize {
+        self.backend().read_ahead()
+    }
+
+    /// Releases a jobserver token back to the original jobserver.
+    ///
+    /// This is intended to be paired with [`Client::acquire_raw`] if it was
+    /// called, but in some situations it could also be called to relinquish a
+    /// process's implicit token temporarily which is then re-acquired later.
+    pub fn release_raw(&self) -> io::Result<()> {
+        self.check_open()?;
+        let backend = self.backend();
+        #[cfg(feature = "testing")]
+        if self.state.release_queue.release(&backend, None).is_some() {
+            backend.release(None)?;
+        }
+        #[cfg(not(feature = "testing"))]
+        backend.release(None)?;
+        self.state.stats.released();
+        self.state.available.invalidate();
+        self.state.yield_if_cooperative();
+        Ok(())
+    }
+
+    /// Turns a raw token obtained from [`Acquired::leak`] back into an
+    /// [`Acquired`] token which releases it when dropped.
+    ///
+    /// The raw token must have been leaked from a token acquired from this
+    /// client, or a clone of it, and must only be reclaimed once. Otherwise
+    /// the jobserver ends up with more tokens than it should have. A leaked
+    /// implicit token, see [`Client::with_implicit_token`], is turned back
+    /// into the implicit token, which is never released to the jobserver.
+    pub fn reclaim_raw(&self, raw: AcquiredRaw) -> Acquired {
+        if raw == AcquiredRaw::IMPLICIT {
+            // Leaking it left the implicit token in use, which the reclaimed
+            // token takes over.
+            self.state.lock_implicit().lent = true;
+            let mut acquired = Acquired::new(self, None);
+            acquired.hold_mut().implicit = true;
+            return acquired;
+        }
+        let data = match self.backend().base() {
+            Backend::Os(_) => Some(imp::Acquired::from_raw(raw.0)),
+            _ => None,
+        };
+        Acquired::new(self, data)
+    }
+
+    /// Returns low-level access to this client's jobserver, where tokens are
+    /// plain [`AcquiredRaw`] values instead of [`Acquired`] guards.
+    ///
+    /// This is for performance-sensitive wrappers which manage the lifetime
+    /// of tokens themselves, see [`RawClient`] for what's bypassed and what
+    /// the caller is responsible for.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(2).unwrap();
+    /// let raw = client.raw_client();
+    /// let token = raw.acquire_raw().unwrap();
+    /// // ... do some work ...
+    /// raw.release_raw(token).unwrap();
+    /// ```
+    pub fn raw_client(&self) -> RawClient {
+        RawClient::new(self.backend())
+    }
+
+    /// Releases the implicit token of this process back to the jobserver.
+    ///
+    /// Every process spawned by `make` implicitly holds one token, its right
+    /// to run at all, which isn't read from the jobserver. A process which is
+    /// about to block waiting on its own children, like a recursive `make`,
+    /// should hand that token back while waiting so the children can use it
+    /// and then take it back with [`Client::reacquire_implicit`] before doing
+    /// its own work again. Otherwise a jobserver with few tokens can deadlock.
+    ///
+    /// This keeps track of whether the implicit token is currently released,
+    /// shared by all clones of this [`Client`], so that the jobserver never
+    /// gains or loses tokens in net: calling this while the implicit token is
+    /// already released does nothing.
+    ///
+    /// This also does nothing while the implicit token is handed out as an
+    /// [`Acquired`] token, see [`Client::with_implicit_token`], as it's in
+    /// use then.
+    pub fn release_implicit(&self) -> io::Result<()> {
+        self.check_open()?;
+        let mut implicit = self.state.lock_implicit();
+        if !implicit.released && !implicit.lent {
+            self.backend().base().release(None)?;
+            self.state.available.invalidate();
+            implicit.released = true;
+        }
+        Ok(())
+    }
+
+    /// Takes back the implicit token of this process from the jobserver.
+    ///
+    /// This blocks the calling thread until a token can be acquired from the
+    /// jobserver to stand in for the implicit token given away by
+    /// [`Client::release_implicit`]. If the implicit token isn't currently
+    /// released this does nothing.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring the token then the implicit
+    /// token remains released.
+    pub fn reacquire_implicit(&self) -> io::Result<()> {
+        self.check_open()?;
+        let mut implicit = self.state.lock_implicit();
+        if implicit.released {
+            self.backend().base().acquire()?;
+            self.state.available.invalidate();
+            implicit.released = false;
+        }
+        Ok(())
+    }
+
+    /// Shuts down this client, and every clone of it, for good.
+    ///
+    /// Every later operation on a clone of this client which can fail returns
+    /// an error with its `kind()` set to [`io::ErrorKind::BrokenPipe`]
+    /// instead of touching the jobserver. This way long-running processes
+    /// creating many short-lived jobservers with [`Client::new`] can make
+    /// sure they're no longer used, even if clones of the client are still
+    /// stashed away somewhere.
+    ///
+    /// The OS handles of the jobserver, such as the pipe on Unix or the
+    /// semaphore on Windows, are let go of by this client and its clones
+    /// right away, so they're closed as soon as no tokens acquired from it
+    /// are still held, no thread is still blocked acquiring one and no
+    /// helper thread is still running, whether or not clones are still
+    /// around. Tokens still held are released as usual when dropped, and
+    /// threads already blocked in [`Client::acquire`] keep waiting for a
+    /// token. Threads waiting for a paused client to be resumed, see
+    /// [`Client::pause`], fail right away instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::io;
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(4).unwrap();
+    /// let clone = client.clone();
+    /// client.shutdown().unwrap();
+    /// let err = clone.acquire().unwrap_err();
+    /// assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This currently never fails, but may report errors closing the OS
+    /// handles in the future.
+    pub fn shutdown(self) -> io::Result<()> {
+        self.state.shut_down.store(true, Ordering::SeqCst);
+        // Take the lock so that no waiter misses the notification between
+        // checking the flag and starting to wait.
+        drop(self.state.lock_paused());
+        self.state.resumed.notify_all();
+        // Let go of the OS handles in every client sharing this one's state,
+        // which closes them once the last token and acquisition under way
+        // are done with them.
+        let closed = Arc::new(Backend::Closed);
+        let old = self
+            .state
+            .cells
+            .live()
+            .iter()
+            .map(|cell| cell.update(|_| closed.clone()))
+            .collect::<Vec<_>>();
+        for backend in &old {
+            FromEnvCache::forget(backend.base());
+        }
+        old.iter().try_for_each(|backend| backend.flush())
+    }
+
+    /// Connects this client to the jobserver currently described by the
+    /// environment again, replacing the one it was connected to.
+    ///
+    /// This lets long-running processes recover once the jobserver they
+    /// inherited went away, for example when a daemon's parent `make` is
+    /// restarted and the environment is updated to point to the new
+    /// jobserver. The environment is read again like [`Client::from_env`]
+    /// does, ignoring the client it may have cached. A cap set with
+    /// [`Client::capped`] is kept, and so are [`Client::stats`] and the state
+    /// of the implicit token.
+    ///
+    /// This client and all its clones are switched over to the new jobserver
+    /// at once, along with the clients derived from it, like with
+    /// [`Client::replace_inner`].
+    ///
+    /// # Safety
+    ///
+    /// This is `unsafe` for the same reasons as [`Client::from_env`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::Unsupported`] if this process created the jobserver,
+    /// see [`Client::is_owner`], as there's nothing to reconnect to then.
+    ///
+    /// Returns an error with its `kind()` set to [`io::ErrorKind::Other`] if
+    /// any [`Acquired`] tokens are held through this client or its clones, as
+    /// they would be released to the wrong jobserver. Tokens acquired with
+    /// [`Client::acquire_raw`] can't be told apart from the implicit token
+    /// and aren't checked for, so they should be released first.
+    ///
+    /// Otherwise the errors of [`Client::from_env_ext`] are returned,
+    /// converted to [`io::Error`], and the client is left connected to the
+    /// old jobserver.
+    pub unsafe fn reconnect(&self) -> io::Result<()> {
+        self.check_open()?;
+        if self.is_owner() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only clients connected to an existing jobserver can reconnect",
+            ));
+        }
+        let held = self.state.stats.snapshot().held();
+        if held > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "cannot reconnect while {} jobserver token(s) are held",
+                    held
+                ),
+            ));
+        }
+
+        *FROM_ENV_CACHE.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        let client = Client::from_env_ext(false).into_result()?;
+        self.replace_inner(client)
+    }
+
+    /// Switches this client and all its clones over to the jobserver of
+    /// `new`, for example after reconnecting to a restarted `make`.
+    ///
+    /// The switch is atomic: each call made through this client or a clone
+    /// of it afterwards uses the new jobserver, while calls already under
+    /// way, such as threads blocked in [`Client::acquire`], finish with the
+    /// old one. [`Acquired`] tokens are always released to the jobserver
+    /// they were acquired from, so tokens still held are given back to the
+    /// old jobserver when they're dropped. Tokens kept in the process by
+    /// [`Client::set_read_ahead`] are handed back to the old jobserver right
+    /// away.
+    ///
+    /// A cap set with [`Client::capped`] and cancellation set up with
+    /// [`Client::with_cancellation`] are kept, as are [`Client::stats`] and
+    /// [`Client::is_owner`], while [`Client::total_tokens`] is taken from
+    /// `new`. Clients derived from this one which share its state, such as
+    /// those made with [`Client::capped`], [`Client::with_cancellation`] and
+    /// [`Client::try_clone_for_thread`], follow the switch as well, keeping
+    /// their caps and cancellation. Clients made with
+    /// [`Client::clone_independent`] have state of their own and keep using
+    /// the old jobserver, and so do helper threads, see
+    /// [`Client::into_helper_thread`], which keep the jobserver they were
+    /// started with. [`Client::reconnect`] switches over the same way.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use jobserver::Client;
+    ///
+    /// let client = Client::new(1).unwrap();
+    /// let clone = client.clone();
+    /// client.replace_inner(Client::new(4).unwrap()).unwrap();
+    /// assert_eq!(clone.total_tokens().unwrap(), Some(4));
+    /// assert_eq!(clone.available().unwrap(), 4);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to [`io::ErrorKind::BrokenPipe`]
+    /// if either client has been shut down, see [`Client::shutdown`], and any
+    /// I/O error while handing tokens kept by read-ahead back to the old
+    /// jobserver, after switching over.
+    pub fn replace_inner(&self, new: Client) -> io::Result<()> {
+        self.check_open()?;
+        new.check_open()?;
+        let base = new.backend();
+        *self.state.lock_limit() = new.known_limit();
+        let old = self
+            .state
+            .cells
+            .live()
+            .iter()
+            .map(|cell| cell.update(|backend| backend.with_base(base.clone())))
+            .collect::<Vec<_>>();
+        self.state.available.invalidate();
+        old.iter().try_for_each(|backend| backend.flush())
+    }
+
+    /// Blocks until a token is acquired from the backend, for up to
+    /// `timeout` if given, returning `None` if it timed out.
+    fn acquire_backend(
+        &self,
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<Option<imp::Acquired>>> {
+        #[cfg(feature = "testing")]
+        let _waiting = self.state.release_queue.wait();
+        match timeout {
+            None => self.backend().acquire().map(Some),
+            Some(timeout) => self.backend().acquire_timeout(timeout),
+        }
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        if self.state.shut_down.load(Ordering::SeqCst) {
+            return Err(shut_down());
+        }
+        Ok(())
+    }
+}
+
+/// Gives a one-line summary of the jobserver for logging, such as
+/// `jobserver(fifo:/tmp/x, limit=4)` or `jobserver(fds 3,4)`.
+///
+/// Only what's fixed about the jobserver is shown, so formatting never
+/// touches it: the limit only if it's known, see [`Client::total_tokens`],
+/// and no count of available tokens, see [`Client::available`] for that.
+/// A client which has been shut down shows as `jobserver(closed)`.
+#[cfg(feature = "std")]
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("jobserver(")?;
+        if let Backend::Closed = self.backend().base() {
+            f.write_str("closed")?;
+        } else if self.is_inheritable() {
+            let auth = self.backend().string_arg();
+            let auth = auth.to_string_lossy();
+            match TransportKind::of(&auth) {
+                TransportKind::Pipe => write!(f, "fds {}", auth)?,
+                TransportKind::Fifo => f.write_str(&auth)?,
+                TransportKind::Semaphore => write!(f, "semaphore {}", auth)?,
+            }
+        } else {
+            f.write_str("in-process")?;
+        }
+        if let Some(limit) = self.known_limit() {
+            write!(f, ", limit={}", limit)?;
+        }
+        f.write_str(")")
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for TokenHold {
+    fn drop(&mut self) {
+        let disabled = *self.disabled.get_mut();
+        // Stale tokens are dropped without releasing them, like disabled ones,
+        // but the in-process caps they hold aren't reset along with the
+        // jobserver.
+        let stale = !disabled && self.is_stale();
+        if stale {
+            self.client.release_caps();
+        }
+        let release = !disabled && !stale;
+        if release {
+            if self.implicit {
+                self.state.lock_implicit().lent = false;
+            } else {
+                #[cfg(feature = "testing")]
+                if let Some(data) = self
+                    .state
+                    .release_queue
+                    .release(&self.client, self.data.take())
+                {
+                    drop(self.client.release(data.as_ref()));
+                }
+                #[cfg(not(feature = "testing"))]
+                drop(self.client.release(self.data.as_ref()));
+                self.state.available.invalidate();
+            }
+        }
+        self.state
+            .stats
+            .dropped(!release, self.acquired_at.elapsed());
+        #[cfg(feature = "tracing")]
+        {
+            if stale {
+                self.trace.stale();
+            }
+            self.trace.dropped(!release);
+        }
+        if release && !self.implicit {
+            self.state.yield_if_cooperative();
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl ClientState {
+    fn lock_implicit(&self) -> MutexGuard<'_, ImplicitToken> {
+        self.implicit.lock().unwrap_or_else(|e| e.into_inner())
     }
 
-    /// Releases a jobserver token back to the original jobserver.
-    ///
-    /// This is intended to be paired with [`Client::acquire_raw`] if it was
-    /// called, but in some situations it could also be called to relinquish a
-    /// process's implicit token temporarily which is then re-acquired later.
-    pub fn release_raw(&self) -> io::Result<()> {
-        self.inner.release(None)?;
-        Ok(())
+    fn lock_limit(&self) -> MutexGuard<'_, Option<usize>> {
+        self.limit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_acquire_timeout(&self) -> MutexGuard<'_, Option<Duration>> {
+        self.acquire_timeout
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn lock_paused(&self) -> MutexGuard<'_, bool> {
+        self.paused.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Blocks while the client is paused, unless it's shut down, for at most
+    /// `timeout`. Returns `false` if it's still paused once that's passed.
+    fn wait_unpaused(&self, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut paused = self.lock_paused();
+        while *paused && !self.shut_down.load(Ordering::SeqCst) {
+            paused = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left == Duration::ZERO {
+                        return false;
+                    }
+                    self.resumed
+                        .wait_timeout(paused, left)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+                None => self.resumed.wait(paused).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+        true
+    }
+
+    fn yield_if_cooperative(&self) {
+        if self.cooperative.load(Ordering::Relaxed) {
+            std::thread::yield_now();
+        }
     }
 }
 
-impl Drop for Acquired {
-    fn drop(&mut self) {
-        if !self.disabled {
-            drop(self.client.release(Some(&self.data)));
+#[cfg(feature = "std")]
+impl Backend {
+    /// Gets the in-process backend, if that's what this is.
+    fn counting(&self) -> Option<&counting::Client> {
+        match self {
+            Backend::Counting(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Gets the signal cancelling the acquisitions of this backend, if any.
+    fn signal(&self) -> Option<&cancel::Signal> {
+        match self {
+            Backend::Cancellable { signal, .. } => Some(signal),
+            Backend::Capped { inner, .. } => inner.signal(),
+            _ => None,
+        }
+    }
+
+    /// Gets the OS-backed client, panicking if this is an in-process one.
+    fn os(&self) -> &imp::Client {
+        match self {
+            Backend::Os(c) => c,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the backend underneath any caps.
+    fn base(&self) -> &Backend {
+        match self {
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => inner.base(),
+            _ => self,
+        }
+    }
+
+    /// Puts the caps of this backend on top of `base` instead.
+    fn with_base(&self, base: Arc<Backend>) -> Arc<Backend> {
+        match self {
+            Backend::Capped { inner, cap } => Arc::new(Backend::Capped {
+                inner: inner.with_base(base),
+                cap: cap.clone(),
+            }),
+            Backend::Cancellable { inner, signal } => Arc::new(Backend::Cancellable {
+                inner: inner.with_base(base),
+                signal: signal.clone(),
+            }),
+            _ => base,
+        }
+    }
+
+    /// Duplicates the OS handles of this backend for another thread of this
+    /// process, see [`Client::try_clone_for_thread`].
+    fn clone_for_thread(&self) -> io::Result<Backend> {
+        Ok(match self {
+            Backend::Os(c) => Backend::Os(c.clone_for_thread()?),
+            Backend::Counting(c) => Backend::Counting(c.clone()),
+            Backend::Capped { inner, cap } => Backend::Capped {
+                inner: Arc::new(inner.clone_for_thread()?),
+                cap: cap.clone(),
+            },
+            Backend::Cancellable { inner, signal } => Backend::Cancellable {
+                inner: Arc::new(inner.clone_for_thread()?),
+                signal: signal.clone(),
+            },
+            Backend::Closed => return Err(shut_down()),
+        })
+    }
+
+    /// Duplicates the OS handles of this backend, see
+    /// [`Client::clone_independent`].
+    fn clone_independent(&self) -> io::Result<Backend> {
+        Ok(match self {
+            Backend::Os(c) => Backend::Os(c.clone_independent()?),
+            Backend::Counting(c) => Backend::Counting(c.clone()),
+            Backend::Capped { inner, cap } => Backend::Capped {
+                inner: Arc::new(inner.clone_independent()?),
+                cap: cap.clone(),
+            },
+            Backend::Cancellable { inner, signal } => Backend::Cancellable {
+                inner: Arc::new(inner.clone_independent()?),
+                signal: signal.clone(),
+            },
+            Backend::Closed => return Err(shut_down()),
+        })
+    }
+
+    fn acquire(&self) -> io::Result<Option<imp::Acquired>> {
+        match self {
+            Backend::Os(c) => c.acquire().map(Some),
+            Backend::Counting(c) => c.acquire().map(|()| None),
+            // Wait for room under the cap before taking a token from the
+            // jobserver, so that no token is held while blocked on the cap.
+            Backend::Capped { inner, cap } => {
+                match inner.signal() {
+                    Some(signal) => signal.acquire_cap(cap, None).map(drop)?,
+                    None => cap.acquire()?,
+                }
+                inner.acquire().map_err(|e| {
+                    drop(cap.release());
+                    e
+                })
+            }
+            Backend::Cancellable { inner, signal } => signal.acquire(inner),
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    /// Like `acquire`, returning `None` if no token turned up within
+    /// `timeout`.
+    fn acquire_timeout(&self, timeout: Duration) -> io::Result<Option<Option<imp::Acquired>>> {
+        match self {
+            Backend::Os(c) => Ok(c.acquire_timeout(timeout)?.map(Some)),
+            Backend::Counting(c) => Ok(c.acquire_timeout(timeout)?.map(|()| None)),
+            Backend::Capped { inner, cap } => {
+                let start = Instant::now();
+                let room = match inner.signal() {
+                    Some(signal) => signal.acquire_cap(cap, Some(timeout))?,
+                    None => cap.acquire_timeout(timeout)?,
+                };
+                if room.is_none() {
+                    return Ok(None);
+                }
+                let ret = inner.acquire_timeout(timeout.saturating_sub(start.elapsed()));
+                if !matches!(ret, Ok(Some(_))) {
+                    drop(cap.release());
+                }
+                ret
+            }
+            Backend::Cancellable { inner, signal } => signal.acquire_timeout(inner, timeout),
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    fn try_acquire(&self) -> io::Result<Option<Option<imp::Acquired>>> {
+        match self {
+            Backend::Os(c) => Ok(c.try_acquire()?.map(Some)),
+            Backend::Counting(c) => Ok(c.try_acquire()?.map(|()| None)),
+            Backend::Capped { inner, cap } => {
+                if cap.try_acquire()?.is_none() {
+                    return Ok(None);
+                }
+                let ret = inner.try_acquire();
+                if !matches!(ret, Ok(Some(_))) {
+                    drop(cap.release());
+                }
+                ret
+            }
+            Backend::Cancellable { inner, .. } => inner.try_acquire(),
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    fn supports_try_acquire(&self) -> bool {
+        match self {
+            Backend::Os(c) => c.supports_try_acquire(),
+            Backend::Counting(_) => true,
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.supports_try_acquire()
+            }
+            Backend::Closed => false,
+        }
+    }
+
+    fn set_read_ahead(&self, tokens: usize) -> io::Result<()> {
+        match self {
+            Backend::Os(c) => c.set_read_ahead(tokens),
+            Backend::Counting(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "in-process jobservers don't need read-ahead",
+            )),
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.set_read_ahead(tokens)
+            }
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    fn read_ahead(&self) -> usize {
+        match self {
+            Backend::Os(c) => c.read_ahead_tokens(),
+            Backend::Counting(_) | Backend::Closed => 0,
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.read_ahead()
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            Backend::Os(c) => c.flush(),
+            Backend::Counting(_) | Backend::Closed => Ok(()),
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => inner.flush(),
+        }
+    }
+
+    fn reset_to(&self, limit: usize) -> io::Result<()> {
+        match self {
+            Backend::Os(c) => c.reset_to(limit),
+            Backend::Counting(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only jobservers backed by a fifo can be reset",
+            )),
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.reset_to(limit)
+            }
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    /// Gives back the slots of in-process caps a token holds, without
+    /// releasing the token itself, see [`Acquired::is_stale`].
+    fn release_caps(&self) {
+        match self {
+            Backend::Os(_) | Backend::Counting(_) | Backend::Closed => {}
+            Backend::Capped { inner, cap } => {
+                inner.release_caps();
+                drop(cap.release());
+            }
+            Backend::Cancellable { inner, .. } => inner.release_caps(),
+        }
+    }
+
+    fn release(&self, data: Option<&imp::Acquired>) -> io::Result<()> {
+        match self {
+            Backend::Os(c) => c.release(data),
+            Backend::Counting(c) => c.release(),
+            Backend::Capped { inner, cap } => {
+                let ret = inner.release(data);
+                drop(cap.release());
+                ret
+            }
+            Backend::Cancellable { inner, .. } => inner.release(data),
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    fn available(&self) -> io::Result<usize> {
+        match self {
+            Backend::Os(c) => c.available(),
+            Backend::Counting(c) => c.available(),
+            Backend::Capped { inner, cap } => Ok(inner.available()?.min(cap.available()?)),
+            Backend::Cancellable { inner, .. } => inner.available(),
+            Backend::Closed => Err(shut_down()),
+        }
+    }
+
+    fn string_arg(&self) -> OsString {
+        match self {
+            Backend::Os(c) => c.string_arg(),
+            Backend::Counting(_) => not_inheritable(),
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.string_arg()
+            }
+            Backend::Closed => panic!("jobserver client has been shut down"),
+        }
+    }
+
+    fn configure(&self, cmd: &mut impl ConfigurableCommand) {
+        match self {
+            Backend::Os(c) => c.configure(cmd),
+            Backend::Counting(_) => unreachable!(),
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.configure(cmd)
+            }
+            Backend::Closed => panic!("jobserver client has been shut down"),
         }
     }
+
+    fn deconfigure(&self, cmd: &mut Command) {
+        match self {
+            Backend::Os(c) => c.deconfigure(cmd),
+            Backend::Counting(_) | Backend::Closed => {}
+            Backend::Capped { inner, .. } | Backend::Cancellable { inner, .. } => {
+                inner.deconfigure(cmd)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn shut_down() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "jobserver client has been shut down",
+    )
+}
+
+#[cfg(feature = "std")]
+fn timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "timed out waiting for a jobserver token",
+    )
+}
+
+#[cfg(feature = "std")]
+fn not_inheritable() -> ! {
+    panic!(
+        "jobservers created with `Client::counting` are in-process only and \
+         cannot be inherited by child processes"
+    )
 }
 
 /// Structure returned from [`Client::into_helper_thread`] to manage the lifetime
 /// of the helper thread returned, see those associated docs for more info.
+#[cfg(feature = "std")]
 #[derive(Debug)]
 pub struct HelperThread {
-    inner: Option<imp::Helper>,
+    inner: Option<Helper>,
     state: Arc<HelperState>,
 }
 
+#[cfg(feature = "std")]
+#[derive(Debug)]
+enum Helper {
+    Os(imp::Helper),
+    Counting(counting::Helper),
+    Capped(capped::Helper),
+}
+
+#[cfg(feature = "std")]
+impl Helper {
+    fn join(self) {
+        match self {
+            Helper::Os(h) => h.join(),
+            Helper::Counting(h) => h.join(),
+            Helper::Capped(h) => h.join(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl HelperThread {
     /// Request that the helper thread acquires a token, eventually calling the
     /// original closure with a token when it's available.
@@ -551,12 +4657,20 @@ impl HelperThread {
     /// For more information, see the docs on [`Client::into_helper_thread`].
     pub fn request_token(&self) {
         // Indicate that there's one more request for a token and then wake up
-        // the helper thread if it's sleeping.
-        self.state.lock().requests += 1;
-        self.state.cvar.notify_one();
+        // the helper thread if it's sleeping. It only ever sleeps while there
+        // are no requests, so if there already were some it's awake and will
+        // get to this one without being notified again.
+        let mut lock = self.state.lock();
+        lock.requests += 1;
+        let was_idle = lock.requests == 1;
+        drop(lock);
+        if was_idle {
+            self.state.cvar.notify_one();
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl Drop for HelperThread {
     fn drop(&mut self) {
         // Flag that the producer half is done so the helper thread should exit
@@ -569,6 +4683,7 @@ impl Drop for HelperThread {
     }
 }
 
+#[cfg(feature = "std")]
 impl HelperState {
     fn lock(&self) -> MutexGuard<'_, HelperInner> {
         self.lock.lock().unwrap_or_else(|e| e.into_inner())
@@ -622,14 +4737,195 @@ impl HelperState {
 /// [^2]: Refer to [the release announcement](https://git.savannah.gnu.org/cgit/make.git/tree/NEWS?h=4.2#n31)
 /// of GNU Make 4.2, which states that `--jobserver-fds` was initially an
 /// internal-only flag and was later renamed to `--jobserver-auth`.
-fn find_jobserver_auth(var: &str) -> Option<&str> {
-    ["--jobserver-auth=", "--jobserver-fds="]
+///
+/// The value ends at the first unescaped blank. `make` escapes blanks and
+/// backslashes within a value with a backslash, so a `fifo:` path containing
+/// spaces is unescaped here. Other backslashes are kept as they are.
+///
+/// On Windows the value is the name of a semaphore, which may contain spaces
+/// that aren't escaped by every tool, so there it ends at the first unescaped
+/// blank followed by another option (or the `--` preceding variable
+/// definitions), or at trailing blanks.
+#[cfg(feature = "std")]
+fn find_jobserver_auth(var: &[u8]) -> Option<Cow<'_, [u8]>> {
+    find_jobserver_auth_impl(var, cfg!(windows))
+}
+
+/// [`find_jobserver_auth`], with blanks only ending the value before another
+/// option if `spaced_names` is set.
+#[cfg(feature = "std")]
+fn find_jobserver_auth_impl(var: &[u8], spaced_names: bool) -> Option<Cow<'_, [u8]>> {
+    let s = [&b"--jobserver-auth="[..], &b"--jobserver-fds="[..]]
+        .iter()
+        .find_map(|&arg| rsplit_once(var, arg))?;
+    let is_escaped = |i: usize| is_escaped(s, i);
+    let is_end = |i: usize| {
+        if !is_blank(s[i]) {
+            return false;
+        }
+        if !spaced_names {
+            return true;
+        }
+        match s[i..].iter().position(|&b| !is_blank(b)) {
+            Some(next) => s[i + next] == b'-',
+            None => true,
+        }
+    };
+
+    // Only allocate if there's anything to unescape.
+    let end = (0..s.len())
+        .find(|&i| is_end(i) || is_escaped(i))
+        .unwrap_or(s.len());
+    if end == s.len() || is_end(end) {
+        return Some(Cow::Borrowed(&s[..end]));
+    }
+    let mut value = s[..end].to_vec();
+    let mut i = end;
+    while i < s.len() && !is_end(i) {
+        if is_escaped(i) {
+            i += 1;
+        }
+        value.push(s[i]);
+        i += 1;
+    }
+    Some(Cow::Owned(value))
+}
+
+/// Returns whether `b` separates the options in `MAKEFLAGS`.
+#[cfg(feature = "std")]
+fn is_blank(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+/// Returns whether `s[i]` is a backslash escaping the blank or backslash
+/// after it, see [`quote_for_env`].
+#[cfg(feature = "std")]
+fn is_escaped(s: &[u8], i: usize) -> bool {
+    s[i] == b'\\' && s.get(i + 1).map_or(false, |&b| is_blank(b) || b == b'\\')
+}
+
+/// Splits `var` into options at unescaped blanks, unescaping each one like
+/// [`find_jobserver_auth`].
+#[cfg(feature = "std")]
+fn split_flags(var: &[u8]) -> Vec<Cow<'_, [u8]>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    while i < var.len() {
+        if is_blank(var[i]) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        // Only allocate if there's anything to unescape.
+        let mut owned: Option<Vec<u8>> = None;
+        while i < var.len() && !is_blank(var[i]) {
+            if is_escaped(var, i) {
+                owned.get_or_insert_with(|| var[start..i].to_vec());
+                i += 1;
+            }
+            if let Some(owned) = &mut owned {
+                owned.push(var[i]);
+            }
+            i += 1;
+        }
+        args.push(owned.map_or(Cow::Borrowed(&var[start..i]), Cow::Owned));
+    }
+    args
+}
+
+/// Escapes blanks and backslashes in `arg` with a backslash, like `make` does
+/// for the values of options in `MAKEFLAGS`, see [`find_jobserver_auth`].
+#[cfg(feature = "std")]
+fn quote_for_env(arg: OsString) -> OsString {
+    let needs_escape = |b: u8| b == b' ' || b == b'\t' || b == b'\\';
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        if !arg.as_bytes().iter().any(|&b| needs_escape(b)) {
+            return arg;
+        }
+        let mut quoted = Vec::new();
+        for &b in arg.as_bytes() {
+            if needs_escape(b) {
+                quoted.push(b'\\');
+            }
+            quoted.push(b);
+        }
+        OsString::from_vec(quoted)
+    }
+    #[cfg(not(unix))]
+    {
+        // Semaphore names are always valid UTF-8, we made them ourselves.
+        let arg = arg.to_string_lossy();
+        let mut quoted = String::new();
+        for c in arg.chars() {
+            if c.is_ascii() && needs_escape(c as u8) {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        }
+        quoted.into()
+    }
+}
+
+/// Works out which flavor of the jobserver protocol the given environment
+/// variable describes, if any.
+#[cfg(feature = "std")]
+fn find_protocol(var: &[u8]) -> Option<JobserverProtocol> {
+    let auth = find_jobserver_auth(var)?;
+    Some(if auth.starts_with(b"fifo:") {
+        JobserverProtocol::Fifo
+    } else if rsplit_once(var, b"--jobserver-auth=").is_some() {
+        JobserverProtocol::Auth
+    } else {
+        JobserverProtocol::Fds
+    })
+}
+
+/// Finds the style of the jobserver in the given environment variable from
+/// the last `--jobserver-style=` option, if any.
+///
+/// Styles this crate doesn't know of are ignored, leaving it to tell the
+/// jobserver's kind from its auth as it does without the option.
+#[cfg(feature = "std")]
+fn find_style(var: &[u8]) -> Option<JobserverStyle> {
+    let value = rsplit_once(var, b"--jobserver-style=")?;
+    let end = value
+        .iter()
+        .position(|&b| b == b' ' || b == b'\t')
+        .unwrap_or(value.len());
+    match &value[..end] {
+        b"pipe" => Some(JobserverStyle::Pipe),
+        b"fifo" => Some(JobserverStyle::Fifo),
+        b"sem" => Some(JobserverStyle::Sem),
+        _ => None,
+    }
+}
+
+/// Finds and returns the `N` of the last `-jN` flag in the given environment
+/// variable, ignoring the variable definitions after `--`.
+#[cfg(feature = "std")]
+fn find_jobs(var: &[u8]) -> Option<usize> {
+    split_flags(var)
         .iter()
-        .find_map(|&arg| var.rsplit_once(arg).map(|(_, s)| s))
-        .and_then(|s| s.split(' ').next())
+        .take_while(|arg| arg[..] != b"--"[..])
+        .filter_map(|arg| arg.strip_prefix(b"-j"))
+        .filter_map(|n| std::str::from_utf8(n).ok()?.parse().ok())
+        .last()
+}
+
+/// Returns what follows the last occurrence of `needle` in `haystack`.
+#[cfg(feature = "std")]
+fn rsplit_once<'a>(haystack: &'a [u8], needle: &[u8]) -> Option<&'a [u8]> {
+    (0..=haystack.len().checked_sub(needle.len())?)
+        .rev()
+        .find(|&i| haystack[i..].starts_with(needle))
+        .map(|i| &haystack[i + needle.len()..])
 }
 
-#[cfg(test)]
+#[cfg(all(feature = "std", test))]
 mod test {
     use super::*;
 
@@ -644,6 +4940,97 @@ mod test {
         client.try_acquire().unwrap().unwrap();
     }
 
+    #[test]
+    fn test_from_env_error_predicates() {
+        let err = |inner| FromEnvError { inner };
+        assert!(err(FromEnvErrorInner::NoEnvVar).is_no_env_var());
+        assert!(err(FromEnvErrorInner::NoJobserver).is_no_jobserver());
+        assert!(err(FromEnvErrorInner::CannotParse(String::new())).is_parse_error());
+        assert!(err(FromEnvErrorInner::NotUtf8).is_parse_error());
+        let io_err = || io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert!(err(FromEnvErrorInner::CannotOpenPath(String::new(), io_err())).is_io_error());
+        assert!(!err(FromEnvErrorInner::Unsupported).is_io_error());
+        assert!(!err(FromEnvErrorInner::NoEnvVar).is_no_jobserver());
+    }
+
+    #[test]
+    fn test_from_env_error_into_io_error() {
+        let err = |inner| io::Error::from(FromEnvError { inner });
+        assert_eq!(
+            err(FromEnvErrorInner::NoEnvVar).kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            err(FromEnvErrorInner::CannotParse(String::new())).kind(),
+            io::ErrorKind::InvalidData
+        );
+        let io_err = io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+        let converted = err(FromEnvErrorInner::CannotOpenPath(String::new(), io_err));
+        assert_eq!(converted.kind(), io::ErrorKind::PermissionDenied);
+        assert!(converted.to_string().starts_with("cannot open path"));
+
+        let from_env = FromEnv {
+            client: Err(FromEnvError {
+                inner: FromEnvErrorInner::NoEnvVar,
+            }),
+            var: None,
+            protocol: None,
+            depth: 0,
+        };
+        assert!(from_env.client_or_else(|_| Client::new(1)).is_ok());
+    }
+
+    #[test]
+    fn test_unified_error() {
+        let err = crate::Error::from(FromEnvError {
+            inner: FromEnvErrorInner::Unsupported,
+        });
+        assert!(err.is_unsupported());
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::Unsupported);
+
+        let err = crate::Error::from(LimitError::too_large(2, Some(1)));
+        assert!(!err.is_unsupported());
+        assert!(err.to_string().contains("maximum of 1"));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput);
+
+        let err = crate::Error::from(io::Error::from(io::ErrorKind::Unsupported));
+        assert!(err.is_unsupported());
+        assert!(matches!(err, crate::Error::Io(_)));
+    }
+
+    #[test]
+    fn test_mflags_jobs() {
+        let c = Client::new(3).unwrap();
+        let flags = c.mflags_env().into_string().unwrap();
+        assert!(flags.starts_with("-j4 --jobserver-fds="), "{flags}");
+        let var = flags.as_bytes();
+        assert_eq!(find_jobs(var).map(|jobs| jobs - 1), c.known_limit());
+
+        let c = Client::from_backend(Backend::Os(imp::Client::new(1).unwrap()), None, true);
+        let flags = c.mflags_env().into_string().unwrap();
+        assert!(flags.starts_with("-j --jobserver-fds="), "{flags}");
+    }
+
+    #[test]
+    fn test_find_protocol() {
+        let cases = [
+            ("-j2", None),
+            ("-j2 --jobserver-fds=3,4", Some(JobserverProtocol::Fds)),
+            ("-j2 --jobserver-auth=3,4", Some(JobserverProtocol::Auth)),
+            (
+                "-j --jobserver-fds=3,4 --jobserver-auth=3,4",
+                Some(JobserverProtocol::Auth),
+            ),
+            (
+                "-j2 --jobserver-auth=fifo:/myfifo",
+                Some(JobserverProtocol::Fifo),
+            ),
+        ];
+        for (var, expected) in cases {
+            assert_eq!(find_protocol(var.as_bytes()), expected, "input `{var:?}`");
+        }
+    }
+
     #[cfg(not(unix))]
     #[test]
     fn test_try_acquire() {
@@ -652,6 +5039,36 @@ mod test {
         run_named_fifo_try_acquire_tests(&client);
     }
 
+    #[test]
+    fn test_try_acquire_counting() {
+        let client = Client::counting(0);
+
+        run_named_fifo_try_acquire_tests(&client);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_fail_next_acquire() {
+        use std::io::ErrorKind;
+
+        let client = Client::counting(2).capped(2);
+        client.set_fail_next_acquire(ErrorKind::Interrupted);
+        client.set_fail_next_acquire(ErrorKind::BrokenPipe);
+
+        let err = client.try_acquire().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        let err = client.clone().acquire().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+
+        // The failures didn't take any tokens, from the jobserver or the cap.
+        let tokens = (0..2)
+            .map(|_| client.acquire().unwrap())
+            .collect::<Vec<_>>();
+        assert!(client.try_acquire().unwrap().is_none());
+        drop(tokens);
+        assert_eq!(client.available().unwrap(), 2);
+    }
+
     #[test]
     fn no_helper_deadlock() {
         let x = crate::Client::new(32).unwrap();
@@ -659,6 +5076,63 @@ mod test {
         std::mem::drop(x.into_helper_thread(|_| {}).unwrap());
     }
 
+    #[test]
+    fn test_find_style() {
+        let cases = [
+            ("-j2 --jobserver-auth=3,4", None),
+            (
+                "-j2 --jobserver-style=pipe --jobserver-auth=3,4",
+                Some(JobserverStyle::Pipe),
+            ),
+            (
+                "--jobserver-auth=fifo:/myfifo --jobserver-style=fifo",
+                Some(JobserverStyle::Fifo),
+            ),
+            (
+                "--jobserver-style=fifo --jobserver-style=sem",
+                Some(JobserverStyle::Sem),
+            ),
+            ("--jobserver-style=socket --jobserver-auth=3,4", None),
+        ];
+        for (var, expected) in cases {
+            assert_eq!(find_style(var.as_bytes()), expected, "input `{var:?}`");
+        }
+    }
+
+    #[test]
+    fn test_find_jobs() {
+        let cases = [
+            ("", None),
+            ("-j", None),
+            (" -j4", Some(4)),
+            ("k -j4 --jobserver-auth=3,4", Some(4)),
+            ("-j2 -j8", Some(8)),
+            ("-jfoo --jobserver-auth=3,4", None),
+            ("-j8 --jobserver-auth=fifo:/tmp/x\\ -j3", Some(8)),
+            ("--jobserver-auth=fifo:/tmp/x\\ -j3", None),
+            ("-j4 -- FOO=-j8", Some(4)),
+            ("-- -j8", None),
+        ];
+        for (var, expected) in cases {
+            assert_eq!(find_jobs(var.as_bytes()), expected, "input `{var:?}`");
+        }
+    }
+
+    #[test]
+    fn test_quote_for_env() {
+        for arg in [
+            "3,4",
+            "fifo:/path with space",
+            "fifo:/a\\b",
+            "fifo:/a\tb\\ c",
+        ] {
+            let quoted = quote_for_env(arg.into()).into_string().unwrap();
+            let var = format!("-j2 --jobserver-auth={quoted} -j3");
+            let auth = find_jobserver_auth(var.as_bytes()).unwrap();
+            assert_eq!(&*auth, arg.as_bytes(), "{var:?}");
+        }
+    }
+
     #[test]
     fn test_find_jobserver_auth() {
         let cases = [
@@ -692,12 +5166,124 @@ mod test {
                 "--jobserver-fds=fds-a --jobserver-auth=auth-a --jobserver-fds=fds-b",
                 Some("auth-a"),
             ),
+            (
+                "--jobserver-auth=fifo:/path\\ with\\ space -j2",
+                Some("fifo:/path with space"),
+            ),
+            ("--jobserver-auth=fifo:/a\\\\b -j2", Some("fifo:/a\\b")),
+            ("--jobserver-auth=fifo:/a\\\tb", Some("fifo:/a\tb")),
+            ("--jobserver-auth=Global\\name", Some("Global\\name")),
+            ("--jobserver-auth=trailing\\", Some("trailing\\")),
         ];
         for (var, expected) in cases {
-            let actual = find_jobserver_auth(var);
+            // These are parsed the same whether or not semaphore names may
+            // contain spaces.
+            for spaced_names in [false, true] {
+                let actual = find_jobserver_auth_impl(var.as_bytes(), spaced_names);
+                let actual = actual.as_deref();
+                let expected = expected.map(str::as_bytes);
+                assert_eq!(
+                    actual, expected,
+                    "expect {expected:?}, got {actual:?}, input `{var:?}`"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_semaphore() {
+        use crate::Semaphore;
+
+        let semaphore = Semaphore::new(2);
+        let held = std::sync::atomic::AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        let _token = semaphore.acquire();
+                        let now = held.fetch_add(1, Ordering::SeqCst) + 1;
+                        assert!(now <= 2);
+                        held.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert_eq!(semaphore.available(), 2);
+    }
+
+    #[test]
+    fn test_sanity_check() {
+        use crate::sanity::check;
+        use crate::Warning;
+
+        assert_eq!(check(4, 8), []);
+        assert_eq!(check(8, 64), [Warning::TooFewTokens { jobs: 8, cpus: 64 }]);
+        assert_eq!(check(1, 4), []);
+        assert_eq!(check(32, 4), [Warning::TooManyTokens { jobs: 32, cpus: 4 }]);
+        assert_eq!(check(usize::MAX, 1).len(), 1);
+        assert_eq!(
+            Warning::TooFewTokens { jobs: 1, cpus: 64 }.to_string(),
+            "the jobserver only allows 1 job(s) at once but 64 CPUs are available"
+        );
+    }
+
+    #[test]
+    fn test_parse_makeflags() {
+        let flags = parse_makeflags(" -j4 --jobserver-auth=fifo:/a\\ b -- FOO=bar");
+        assert_eq!(flags.auth(), Some("fifo:/a b"));
+        assert_eq!(flags.jobs(), Some(4));
+        assert_eq!(flags.protocol(), Some(JobserverProtocol::Fifo));
+        #[cfg(unix)]
+        assert_eq!(flags.transport(), Some(TransportKind::Fifo));
+        assert!(!flags.dry_run() && !flags.keep_going());
+
+        for (var, dry_run, keep_going) in [
+            ("n", true, false),
+            ("ks -j4", false, true),
+            ("nk --jobserver-auth=3,4", true, true),
+            ("s -- n=k", false, false),
+            ("FOO=nk", false, false),
+            ("", false, false),
+        ] {
+            let flags = parse_makeflags(var);
+            assert_eq!(flags.dry_run(), dry_run, "input `{var:?}`");
+            assert_eq!(flags.keep_going(), keep_going, "input `{var:?}`");
+        }
+
+        let flags = parse_makeflags("-k -jfoo");
+        assert!(!flags.keep_going());
+        assert_eq!(flags.auth(), None);
+        assert_eq!(flags.jobs(), None);
+        assert_eq!(flags.protocol(), None);
+        assert_eq!(flags.transport(), None);
+
+        // Multi-byte characters are kept intact.
+        let flags = parse_makeflags("--jobserver-auth=fifo:/tmp/\u{e9}\\\u{e9}");
+        assert_eq!(flags.auth(), Some("fifo:/tmp/\u{e9}\\\u{e9}"));
+    }
+
+    #[test]
+    fn test_find_jobserver_auth_spaced_names() {
+        let cases = [
+            ("--jobserver-auth=my sem", "my sem", "my"),
+            ("-j2 --jobserver-auth=my sem -j2", "my sem", "my"),
+            ("--jobserver-auth=a b  --no-print-directory", "a b", "a"),
+            ("--jobserver-auth=a b -- FOO=bar", "a b", "a"),
+            ("--jobserver-auth=a b  ", "a b", "a"),
+            ("--jobserver-auth=a\\ b c", "a b c", "a b"),
+        ];
+        for (var, spaced, unspaced) in cases {
+            let actual = find_jobserver_auth_impl(var.as_bytes(), true);
+            assert_eq!(
+                actual.as_deref(),
+                Some(spaced.as_bytes()),
+                "input `{var:?}`"
+            );
+            let actual = find_jobserver_auth_impl(var.as_bytes(), false);
             assert_eq!(
-                actual, expected,
-                "expect {expected:?}, got {actual:?}, input `{var:?}`"
+                actual.as_deref(),
+                Some(unspaced.as_bytes()),
+                "input `{var:?}`"
             );
         }
     }
@@ -0,0 +1,98 @@
+use crate::{JobserverProtocol, TransportKind};
+
+/// What the value of a `MAKEFLAGS` environment variable says about the
+/// jobserver and the mode `make` runs in, returned by [`parse_makeflags`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MakeFlags {
+    auth: Option<String>,
+    jobs: Option<usize>,
+    protocol: Option<JobserverProtocol>,
+    dry_run: bool,
+    keep_going: bool,
+}
+
+impl MakeFlags {
+    /// Returns the value of the last `--jobserver-auth=` option, or of the
+    /// last `--jobserver-fds=` option if there's none, with backslash escapes
+    /// removed.
+    pub fn auth(&self) -> Option<&str> {
+        self.auth.as_deref()
+    }
+
+    /// Returns the `N` of the last `-jN` option.
+    pub fn jobs(&self) -> Option<usize> {
+        self.jobs
+    }
+
+    /// Returns the flavor of the jobserver protocol, see
+    /// [`FromEnv::protocol`](crate::FromEnv::protocol).
+    pub fn protocol(&self) -> Option<JobserverProtocol> {
+        self.protocol
+    }
+
+    /// Returns the kind of OS object the jobserver is shared through on this
+    /// platform, if there's a jobserver at all.
+    pub fn transport(&self) -> Option<TransportKind> {
+        self.auth.as_deref().map(TransportKind::of)
+    }
+
+    /// Returns whether `make` was asked to only print the commands it would
+    /// run, with `-n`.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns whether `make` was asked to keep going after errors, with
+    /// `-k`.
+    pub fn keep_going(&self) -> bool {
+        self.keep_going
+    }
+}
+
+/// Parses the value of a `MAKEFLAGS` environment variable, or of any of the
+/// other variables [`Client::from_env`](crate::Client::from_env) looks at,
+/// the same way this crate does when connecting to a jobserver.
+///
+/// This doesn't connect to the jobserver or check that it exists, it only
+/// extracts what `make` passed down. Parsing never fails: anything which
+/// isn't understood is ignored.
+///
+/// `make` puts all single-letter flags without arguments into the first word
+/// of `MAKEFLAGS`, without a leading `-`, such as `ks` for `-k -s`. That word
+/// is what [`MakeFlags::dry_run`] and [`MakeFlags::keep_going`] are taken
+/// from. If there are no such flags the value starts with a space instead.
+///
+/// # Examples
+///
+/// ```
+/// use jobserver::{parse_makeflags, JobserverProtocol};
+///
+/// let flags = parse_makeflags("-j8 --jobserver-auth=fifo:/tmp/GMfifo1234");
+/// assert_eq!(flags.auth(), Some("fifo:/tmp/GMfifo1234"));
+/// assert_eq!(flags.jobs(), Some(8));
+/// assert_eq!(flags.protocol(), Some(JobserverProtocol::Fifo));
+/// assert!(!flags.dry_run());
+///
+/// let flags = parse_makeflags("kn -j8 --jobserver-auth=fifo:/tmp/GMfifo1234");
+/// assert!(flags.dry_run());
+/// assert!(flags.keep_going());
+/// ```
+pub fn parse_makeflags(var: &str) -> MakeFlags {
+    // The first word holds the single-letter flags, unless it's an option or
+    // a variable definition.
+    let short = var
+        .split([' ', '\t'])
+        .next()
+        .filter(|word| !word.starts_with('-') && !word.contains('='))
+        .unwrap_or("");
+    let var = var.as_bytes();
+    MakeFlags {
+        // Unescaping only removes ASCII backslashes, so the value stays valid
+        // UTF-8.
+        auth: crate::find_jobserver_auth(var).map(|s| String::from_utf8_lossy(&s).into_owned()),
+        jobs: crate::find_jobs(var),
+        protocol: crate::find_protocol(var),
+        dry_run: short.contains('n'),
+        keep_going: short.contains('k'),
+    }
+}
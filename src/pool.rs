@@ -0,0 +1,208 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::{Acquired, Client};
+
+/// A cache of tokens acquired from a [`Client`], amortizing the cost of
+/// acquiring and releasing tokens when lots of tiny jobs are run.
+///
+/// Tokens handed out by [`TokenPool::get`] aren't released back to the
+/// jobserver when the returned [`PooledToken`] is dropped but instead kept in
+/// the pool, up to a maximum number of idle tokens, so the next call to
+/// [`TokenPool::get`] can reuse them without a syscall.
+///
+/// Idle tokens are still held from the point of view of the jobserver, so to
+/// avoid starving other processes sharing it, a background thread releases
+/// every token which has been idle for longer than the configured timeout.
+/// Call [`TokenPool::flush`] to release all idle tokens right away. All idle
+/// tokens are released when the pool is dropped.
+///
+/// # Examples
+///
+/// ```
+/// use std::time::Duration;
+/// use jobserver::{Client, TokenPool};
+///
+/// let client = Client::new(4).unwrap();
+/// let pool = TokenPool::new(client, 2, Duration::from_millis(10)).unwrap();
+/// for _ in 0..100 {
+///     let token = pool.get().unwrap();
+///     // ... do a tiny bit of work ...
+///     drop(token); // kept in the pool for the next iteration
+/// }
+/// ```
+#[derive(Debug)]
+pub struct TokenPool {
+    inner: Arc<PoolInner>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// A token handed out by a [`TokenPool`].
+///
+/// When dropped the token is returned to the pool it came from, or released
+/// back to the jobserver if the pool is already full or has been dropped.
+#[derive(Debug)]
+pub struct PooledToken {
+    token: Option<Acquired>,
+    pool: Arc<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    client: Client,
+    max_idle: usize,
+    idle_timeout: Duration,
+    state: Mutex<PoolState>,
+    cvar: Condvar,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    /// Idle tokens along with when they were returned to the pool, the most
+    /// recently returned one last.
+    idle: VecDeque<(Acquired, Instant)>,
+    done: bool,
+}
+
+impl TokenPool {
+    /// Creates a new pool handing out tokens acquired from `client`.
+    ///
+    /// At most `max_idle` tokens are kept in the pool while they're not in
+    /// use, and each of them is released back to the jobserver once it's been
+    /// idle for `idle_timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the background thread releasing idle tokens could
+    /// not be spawned.
+    pub fn new(client: Client, max_idle: usize, idle_timeout: Duration) -> io::Result<TokenPool> {
+        let inner = Arc::new(PoolInner {
+            client,
+            max_idle,
+            idle_timeout,
+            state: Mutex::new(PoolState {
+                idle: VecDeque::new(),
+                done: false,
+            }),
+            cvar: Condvar::new(),
+        });
+        let inner2 = inner.clone();
+        let thread = Builder::new().spawn(move || inner2.release_expired())?;
+        Ok(TokenPool {
+            inner,
+            thread: Some(thread),
+        })
+    }
+
+    /// Gets a token, reusing an idle one from the pool if there is one and
+    /// otherwise blocking until one is acquired from the jobserver.
+    ///
+    /// # Errors
+    ///
+    /// If an I/O error happens while acquiring a token from the jobserver then
+    /// it is returned, see [`Client::acquire`].
+    pub fn get(&self) -> io::Result<PooledToken> {
+        let idle = self.inner.lock().idle.pop_back();
+        let token = match idle {
+            Some((token, _)) => token,
+            None => self.inner.client.acquire()?,
+        };
+        Ok(PooledToken {
+            token: Some(token),
+            pool: self.inner.clone(),
+        })
+    }
+
+    /// Releases all idle tokens in the pool back to the jobserver.
+    pub fn flush(&self) {
+        let idle = std::mem::take(&mut self.inner.lock().idle);
+        drop(idle);
+    }
+
+    /// Returns the number of idle tokens currently held by the pool.
+    pub fn idle(&self) -> usize {
+        self.inner.lock().idle.len()
+    }
+}
+
+impl Drop for TokenPool {
+    fn drop(&mut self) {
+        let idle = {
+            let mut state = self.inner.lock();
+            state.done = true;
+            std::mem::take(&mut state.idle)
+        };
+        self.inner.cvar.notify_one();
+        drop(idle);
+        drop(self.thread.take().unwrap().join());
+    }
+}
+
+impl PooledToken {
+    /// Takes the token out of the pool for good, so that dropping it releases
+    /// it back to the jobserver.
+    pub fn into_inner(mut self) -> Acquired {
+        self.token.take().unwrap()
+    }
+}
+
+impl Drop for PooledToken {
+    fn drop(&mut self) {
+        let token = match self.token.take() {
+            Some(token) => token,
+            None => return,
+        };
+        let mut state = self.pool.lock();
+        if state.done || state.idle.len() >= self.pool.max_idle {
+            drop(state);
+            drop(token);
+            return;
+        }
+        state.idle.push_back((token, Instant::now()));
+        let was_empty = state.idle.len() == 1;
+        drop(state);
+        // The background thread only needs waking up if it's waiting for a
+        // token to be returned, otherwise it's already waiting for the oldest
+        // idle token to expire.
+        if was_empty {
+            self.pool.cvar.notify_one();
+        }
+    }
+}
+
+impl PoolInner {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Body of the background thread, releasing tokens which have been idle
+    /// for too long until the pool is dropped.
+    fn release_expired(&self) {
+        let mut state = self.lock();
+        while !state.done {
+            let oldest = match state.idle.front() {
+                Some((_, returned)) => *returned,
+                None => {
+                    state = self.cvar.wait(state).unwrap_or_else(|e| e.into_inner());
+                    continue;
+                }
+            };
+            let elapsed = oldest.elapsed();
+            if elapsed >= self.idle_timeout {
+                let expired = state.idle.pop_front();
+                drop(state);
+                drop(expired);
+                state = self.lock();
+            } else {
+                state = self
+                    .cvar
+                    .wait_timeout(state, self.idle_timeout - elapsed)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::{imp, AcquiredRaw, Backend};
+
+/// Low-level access to a jobserver without RAII tokens, returned by
+/// [`Client::raw_client`](crate::Client::raw_client).
+///
+/// This is an advanced API for building custom abstractions on top of a
+/// jobserver where the bookkeeping of [`Acquired`](crate::Acquired) tokens is
+/// measurable: tokens are plain [`AcquiredRaw`] values, so acquiring one
+/// neither allocates nor bumps a reference count. In exchange, the caller is
+/// responsible for handing every token back with [`RawClient::release_raw`],
+/// exactly once. A token which is never released is lost to the jobserver
+/// and every process sharing it, and one which is released twice adds a
+/// token which was never there.
+///
+/// Tokens go straight to and from the jobserver the client was connected to
+/// when this was created, bypassing everything [`Client`](crate::Client)
+/// layers on top: the implicit token, [`Client::pause`](crate::Client::pause)
+/// and [`Client::shutdown`](crate::Client::shutdown), the default acquire
+/// timeout, statistics and diagnostics. It also keeps using the same
+/// jobserver after [`Client::replace_inner`](crate::Client::replace_inner).
+///
+/// Raw tokens are interchangeable with those of [`Acquired::leak`] and
+/// [`Client::reclaim_raw`] on the same jobserver.
+///
+/// [`Acquired::leak`]: crate::Acquired::leak
+/// [`Client::reclaim_raw`]: crate::Client::reclaim_raw
+#[derive(Debug, Clone)]
+pub struct RawClient {
+    backend: Arc<Backend>,
+}
+
+impl RawClient {
+    pub(crate) fn new(backend: Arc<Backend>) -> RawClient {
+        RawClient { backend }
+    }
+
+    /// Acquires a token, blocking until one is available.
+    ///
+    /// # Errors
+    ///
+    /// Returns any I/O error which happens while waiting for the token.
+    pub fn acquire_raw(&self) -> io::Result<AcquiredRaw> {
+        let data = self.backend.acquire()?;
+        Ok(to_raw(data))
+    }
+
+    /// Acquires a token if one is available right away, returning `None`
+    /// otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Like [`Client::try_acquire`](crate::Client::try_acquire), this fails
+    /// with [`io::ErrorKind::Unsupported`] for jobservers which can't be
+    /// polled without blocking.
+    pub fn try_acquire_raw(&self) -> io::Result<Option<AcquiredRaw>> {
+        let data = self.backend.try_acquire()?;
+        Ok(data.map(to_raw))
+    }
+
+    /// Hands a token acquired from this jobserver back to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error with its `kind()` set to
+    /// [`io::ErrorKind::InvalidInput`] for a leaked implicit token, see
+    /// [`Client::with_implicit_token`](crate::Client::with_implicit_token),
+    /// which isn't from the jobserver and has to be reclaimed with
+    /// [`Client::reclaim_raw`](crate::Client::reclaim_raw) instead.
+    /// Otherwise returns any I/O error which happens while releasing the
+    /// token, in which case the token may be lost.
+    pub fn release_raw(&self, token: AcquiredRaw) -> io::Result<()> {
+        if token == AcquiredRaw::IMPLICIT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the implicit token can't be released to the jobserver",
+            ));
+        }
+        let data = match self.backend.base() {
+            Backend::Os(_) => Some(imp::Acquired::from_raw(token.into_usize())),
+            _ => None,
+        };
+        self.backend.release(data.as_ref())
+    }
+}
+
+fn to_raw(data: Option<imp::Acquired>) -> AcquiredRaw {
+    AcquiredRaw::from_usize(data.as_ref().map_or(0, imp::Acquired::to_raw))
+}
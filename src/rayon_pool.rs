@@ -0,0 +1,120 @@
+use std::cell::Cell;
+use std::io;
+use std::sync::Arc;
+
+use rayon::ThreadPool;
+
+use crate::{Acquired, Client};
+
+/// A rayon thread pool whose tasks run under a jobserver's limit, returned by
+/// [`Client::bind_rayon`].
+///
+/// Every task run through this type acquires a token from the jobserver on
+/// the pool's thread before it starts and releases it once it's done, so no
+/// more tasks run at once than the jobserver allows, across all processes
+/// sharing it.
+///
+/// Rayon has no hook into the execution of each of its tasks, so only the
+/// tasks run through this type are governed by the jobserver. Work split off
+/// by them, such as with parallel iterators, runs on the pool as usual,
+/// under the token of the task it was split off from. The same goes for
+/// [`RayonPool::install`] and [`RayonPool::join`] called from within a task
+/// run by either of them, which would otherwise wait for more tokens while
+/// holding one and so could deadlock the pool. A thread of the pool
+/// blocks while waiting for a token, so the pool shouldn't have more threads
+/// than the jobserver has tokens to make good use of it.
+#[derive(Debug)]
+pub struct RayonPool<'a> {
+    client: Client,
+    pool: &'a ThreadPool,
+}
+
+impl<'a> RayonPool<'a> {
+    pub(crate) fn new(client: Client, pool: &'a ThreadPool) -> RayonPool<'a> {
+        RayonPool { client, pool }
+    }
+
+    /// Returns the thread pool tasks are run on.
+    pub fn pool(&self) -> &'a ThreadPool {
+        self.pool
+    }
+
+    /// Spawns `f` onto the pool, once a token has been acquired for it.
+    ///
+    /// Like the callback of [`Client::into_helper_thread`], `f` is passed the
+    /// result of acquiring the token and holds it until it's dropped, which
+    /// at the latest happens once `f` returns.
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce(io::Result<Acquired>) + Send + 'static,
+    {
+        let client = self.client.clone();
+        self.pool.spawn(move || f(client.acquire()));
+    }
+
+    /// Runs `op` on the pool while holding a token, and returns its result.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of acquiring the token, in which case `op` isn't
+    /// run.
+    pub fn install<OP, R>(&self, op: OP) -> io::Result<R>
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        self.pool.install(|| with_token(&self.client, op))
+    }
+
+    /// Runs `a` and `b` on the pool, potentially in parallel, each while
+    /// holding a token of its own, and returns both of their results.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of acquiring either token, in which case the
+    /// closure it was acquired for isn't run.
+    pub fn join<A, B, RA, RB>(&self, a: A, b: B) -> io::Result<(RA, RB)>
+    where
+        A: FnOnce() -> RA + Send,
+        B: FnOnce() -> RB + Send,
+        RA: Send,
+        RB: Send,
+    {
+        let (ra, rb) = self.pool.join(
+            || with_token(&self.client, a),
+            || with_token(&self.client, b),
+        );
+        Ok((ra?, rb?))
+    }
+}
+
+thread_local! {
+    /// The address of the state of the client whose token is held by the
+    /// task running on this thread, or 0.
+    static HOLDING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Runs `f` holding a token of `client`, which is only acquired if the task
+/// running on this thread doesn't hold one already.
+fn with_token<F, R>(client: &Client, f: F) -> io::Result<R>
+where
+    F: FnOnce() -> R,
+{
+    let id = Arc::as_ptr(&client.state) as usize;
+    if HOLDING.with(Cell::get) == id {
+        return Ok(f());
+    }
+    let _token = client.acquire()?;
+    let _holding = Holding(HOLDING.with(|holding| holding.replace(id)));
+    Ok(f())
+}
+
+/// Restores what the task running on this thread held before once dropped,
+/// even if the task panics.
+struct Holding(usize);
+
+impl Drop for Holding {
+    fn drop(&mut self) {
+        HOLDING.with(|holding| holding.set(self.0));
+    }
+}
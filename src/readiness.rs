@@ -0,0 +1,46 @@
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
+use std::sync::Arc;
+
+use crate::Backend;
+
+/// The file descriptor of the named pipe backing a jobserver, returned by
+/// [`Client::readiness_fd`](crate::Client::readiness_fd).
+///
+/// This keeps the jobserver it was taken from open, even if the client is
+/// switched over to another one with
+/// [`Client::replace_inner`](crate::Client::replace_inner), so the file
+/// descriptor stays valid for as long as this is around. Dropping it doesn't
+/// close the file descriptor while the jobserver is still in use.
+#[derive(Debug, Clone)]
+pub struct ReadinessFd {
+    backend: Arc<Backend>,
+}
+
+impl ReadinessFd {
+    /// Returns the file descriptor of the fifo backing `backend`, if any.
+    pub(crate) fn new(backend: Arc<Backend>) -> Option<ReadinessFd> {
+        match backend.base() {
+            Backend::Os(c) => {
+                c.fifo_fd()?;
+                c.mark_polled();
+            }
+            _ => return None,
+        }
+        Some(ReadinessFd { backend })
+    }
+}
+
+impl AsFd for ReadinessFd {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self.backend.base() {
+            Backend::Os(c) => c.fifo_fd().expect("checked to be a fifo on creation"),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl AsRawFd for ReadinessFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.as_fd().as_raw_fd()
+    }
+}
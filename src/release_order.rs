@@ -0,0 +1,138 @@
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::{imp, Backend};
+
+/// The order in which released tokens are handed back to the jobserver, see
+/// [`Client::set_release_order`](crate::Client::set_release_order).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReleaseOrder {
+    /// Tokens are handed back as soon as they're released, the default.
+    #[default]
+    Immediate,
+    /// Released tokens are queued and handed back in the order they were
+    /// released in.
+    Fifo,
+    /// Released tokens are queued and handed back most recently released
+    /// first.
+    Lifo,
+    /// Released tokens are queued and handed back in a pseudo-random order
+    /// determined by the seed, so that the same seed shuffles the same
+    /// sequence of releases the same way every time.
+    Seeded(u64),
+}
+
+/// Tokens released while a [`ReleaseOrder`] other than
+/// [`ReleaseOrder::Immediate`] is set, waiting to be handed back.
+#[derive(Debug, Default)]
+pub(crate) struct ReleaseQueue {
+    inner: Mutex<Queue>,
+    /// Number of threads blocked acquiring a token through the client, which
+    /// releases mustn't be held back from.
+    waiters: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    order: ReleaseOrder,
+    /// State of the generator shuffling releases for
+    /// [`ReleaseOrder::Seeded`].
+    rng: u64,
+    pending: Vec<(Arc<Backend>, Option<imp::Acquired>)>,
+}
+
+impl ReleaseQueue {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_order(&self, order: ReleaseOrder) {
+        let mut queue = self.lock();
+        queue.order = order;
+        if let ReleaseOrder::Seeded(seed) = order {
+            queue.rng = seed;
+        }
+        if order == ReleaseOrder::Immediate {
+            queue.flush();
+        }
+    }
+
+    /// Hands the token in `data` back to `backend`, or queues it until the
+    /// next flush. The token is returned if it should be released right away.
+    pub(crate) fn release(
+        &self,
+        backend: &Arc<Backend>,
+        data: Option<imp::Acquired>,
+    ) -> Option<Option<imp::Acquired>> {
+        let mut queue = self.lock();
+        if queue.order == ReleaseOrder::Immediate {
+            return Some(data);
+        }
+        queue.pending.push((backend.clone(), data));
+        // A thread already waiting for a token would otherwise wait forever.
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            queue.flush();
+        }
+        None
+    }
+
+    /// Hands back every queued token in the configured order.
+    pub(crate) fn flush(&self) {
+        self.lock().flush();
+    }
+
+    /// Marks the current thread as about to block acquiring a token until the
+    /// returned guard is dropped, handing back the queued tokens first.
+    pub(crate) fn wait(&self) -> WaitGuard<'_> {
+        // Registering before flushing makes sure a release racing with this
+        // either is flushed here or sees the waiter and flushes itself.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        self.flush();
+        WaitGuard { queue: self }
+    }
+}
+
+impl Drop for ReleaseQueue {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl Queue {
+    fn flush(&mut self) {
+        let mut pending = mem::take(&mut self.pending);
+        match self.order {
+            ReleaseOrder::Immediate | ReleaseOrder::Fifo => {}
+            ReleaseOrder::Lifo => pending.reverse(),
+            ReleaseOrder::Seeded(_) => {
+                for i in (1..pending.len()).rev() {
+                    let j = (self.next_random() % (i as u64 + 1)) as usize;
+                    pending.swap(i, j);
+                }
+            }
+        }
+        for (backend, data) in pending {
+            drop(backend.release(data.as_ref()));
+        }
+    }
+
+    /// splitmix64, which is plenty for shuffling and needs no dependency.
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+pub(crate) struct WaitGuard<'a> {
+    queue: &'a ReleaseQueue,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.queue.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,61 @@
+use std::fmt;
+
+/// How far the size of a jobserver has to be off from the number of CPUs
+/// for [`check`] to warn about it.
+const MISMATCH_FACTOR: usize = 8;
+
+/// A likely misconfiguration of a jobserver, returned by
+/// [`Client::sanity_check`](crate::Client::sanity_check).
+///
+/// The [`Display`](fmt::Display) implementation gives a message suitable for
+/// logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Warning {
+    /// The jobserver allows far fewer jobs at once than there are CPUs, so
+    /// most of the machine sits idle.
+    TooFewTokens {
+        /// The number of jobs the jobserver allows at once.
+        jobs: usize,
+        /// The number of CPUs available to this process.
+        cpus: usize,
+    },
+    /// The jobserver allows far more jobs at once than there are CPUs, so
+    /// jobs mostly compete for CPU time and memory.
+    TooManyTokens {
+        /// The number of jobs the jobserver allows at once.
+        jobs: usize,
+        /// The number of CPUs available to this process.
+        cpus: usize,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::TooFewTokens { jobs, cpus } => write!(
+                f,
+                "the jobserver only allows {} job(s) at once but {} CPUs are available",
+                jobs, cpus
+            ),
+            Warning::TooManyTokens { jobs, cpus } => write!(
+                f,
+                "the jobserver allows {} jobs at once but only {} CPU(s) are available",
+                jobs, cpus
+            ),
+        }
+    }
+}
+
+/// Compares the number of jobs a jobserver allows at once with the number of
+/// CPUs.
+pub(crate) fn check(jobs: usize, cpus: usize) -> Vec<Warning> {
+    let mut warnings = Vec::new();
+    if jobs.saturating_mul(MISMATCH_FACTOR) <= cpus {
+        warnings.push(Warning::TooFewTokens { jobs, cpus });
+    }
+    if cpus.saturating_mul(MISMATCH_FACTOR) <= jobs {
+        warnings.push(Warning::TooManyTokens { jobs, cpus });
+    }
+    warnings
+}
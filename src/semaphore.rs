@@ -0,0 +1,91 @@
+use core::hint;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore limiting parallelism within a single process.
+///
+/// This is the counting logic behind [`Client::counting`] on its own: a
+/// number of tokens which can be taken and given back, without any
+/// connection to the OS. It only relies on `core`, so it's available without
+/// the `std` feature, when it's all this crate provides.
+///
+/// Without the OS there's no way to sleep until a token turns up, so
+/// [`Semaphore::acquire`] spins. Prefer [`Client::counting`] where `std` is
+/// available.
+///
+/// # Examples
+///
+/// ```
+/// use jobserver::Semaphore;
+///
+/// static LIMIT: Semaphore = Semaphore::new(2);
+///
+/// let a = LIMIT.acquire();
+/// let b = LIMIT.try_acquire().unwrap();
+/// assert!(LIMIT.try_acquire().is_none());
+/// drop((a, b));
+/// assert_eq!(LIMIT.available(), 2);
+/// ```
+///
+/// [`Client::counting`]: crate::Client::counting
+#[derive(Debug)]
+pub struct Semaphore {
+    available: AtomicUsize,
+}
+
+/// A token taken from a [`Semaphore`], which is given back when dropped.
+#[derive(Debug)]
+pub struct SemaphoreToken<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `limit` tokens.
+    pub const fn new(limit: usize) -> Semaphore {
+        Semaphore {
+            available: AtomicUsize::new(limit),
+        }
+    }
+
+    /// Returns the number of tokens currently available.
+    pub fn available(&self) -> usize {
+        self.available.load(Ordering::Acquire)
+    }
+
+    /// Takes a token if one is available.
+    pub fn try_acquire(&self) -> Option<SemaphoreToken<'_>> {
+        if self.try_take() {
+            Some(SemaphoreToken { semaphore: self })
+        } else {
+            None
+        }
+    }
+
+    /// Takes a token, spinning until one is available.
+    pub fn acquire(&self) -> SemaphoreToken<'_> {
+        loop {
+            if let Some(token) = self.try_acquire() {
+                return token;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// Takes a token without handing out a [`SemaphoreToken`] for it, which
+    /// has to be given back with [`Semaphore::put`].
+    pub(crate) fn try_take(&self) -> bool {
+        self.available
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Gives back a token.
+    pub(crate) fn put(&self) {
+        self.available.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl Drop for SemaphoreToken<'_> {
+    fn drop(&mut self) {
+        self.semaphore.put();
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::Acquired;
+
+/// A share of a single jobserver token, returned by [`Acquired::split`].
+///
+/// The token itself is released back to the jobserver once every share of it
+/// has been dropped, unless it's taken back with [`SubToken::merge`].
+#[derive(Debug)]
+pub struct SubToken {
+    group: Option<Arc<Group>>,
+}
+
+#[derive(Debug)]
+struct Group {
+    state: Mutex<GroupState>,
+}
+
+#[derive(Debug)]
+struct GroupState {
+    token: Option<Acquired>,
+    /// Number of shares of the token which haven't been dropped or merged.
+    live: usize,
+}
+
+impl Group {
+    fn lock(&self) -> MutexGuard<'_, GroupState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub(crate) fn split(token: Acquired, n: usize) -> Vec<SubToken> {
+    let group = Arc::new(Group {
+        state: Mutex::new(GroupState {
+            token: Some(token),
+            live: n,
+        }),
+    });
+    (0..n)
+        .map(|_| SubToken {
+            group: Some(group.clone()),
+        })
+        .collect()
+}
+
+impl SubToken {
+    /// Gives up this share of the token, returning the token itself if this
+    /// was the last share.
+    ///
+    /// Once all other shares have been dropped or merged this returns the
+    /// original [`Acquired`] token, which can then be used or split again.
+    /// Otherwise it returns `None`.
+    pub fn merge(mut self) -> Option<Acquired> {
+        self.leave()
+    }
+
+    fn leave(&mut self) -> Option<Acquired> {
+        let group = self.group.take()?;
+        let mut state = group.lock();
+        state.live -= 1;
+        if state.live == 0 {
+            state.token.take()
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for SubToken {
+    fn drop(&mut self) {
+        // Releases the token if this was the last share of it.
+        drop(self.leave());
+    }
+}
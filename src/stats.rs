@@ -0,0 +1,134 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Cumulative counters of the tokens acquired through a client, see
+/// [`crate::Client::stats`].
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    acquired: AtomicU64,
+    released: AtomicU64,
+    blocked_nanos: AtomicU64,
+    held_nanos: AtomicU64,
+    held: AtomicUsize,
+    peak_held: AtomicUsize,
+}
+
+impl Counters {
+    /// Counts a token acquired as an [`crate::Acquired`], which is held until
+    /// [`Counters::dropped`] is called for it.
+    pub(crate) fn acquired(&self) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        let held = self.held.fetch_add(1, Ordering::Relaxed) + 1;
+        self.peak_held.fetch_max(held, Ordering::Relaxed);
+    }
+
+    /// Counts a token acquired with [`crate::Client::acquire_raw`].
+    pub(crate) fn acquired_raw(&self) {
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an [`crate::Acquired`] token being dropped after it was held
+    /// for `held_for`, releasing it unless `disabled`.
+    pub(crate) fn dropped(&self, disabled: bool, held_for: Duration) {
+        self.held.fetch_sub(1, Ordering::Relaxed);
+        add_nanos(&self.held_nanos, held_for);
+        if !disabled {
+            self.released();
+        }
+    }
+
+    pub(crate) fn released(&self) {
+        self.released.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn blocked(&self, blocked: Duration) {
+        add_nanos(&self.blocked_nanos, blocked);
+    }
+
+    pub(crate) fn snapshot(&self) -> JobserverStats {
+        JobserverStats {
+            acquired: self.acquired.load(Ordering::Relaxed),
+            released: self.released.load(Ordering::Relaxed),
+            blocked: Duration::from_nanos(self.blocked_nanos.load(Ordering::Relaxed)),
+            held_time: Duration::from_nanos(self.held_nanos.load(Ordering::Relaxed)),
+            held: self.held.load(Ordering::Relaxed),
+            peak_held: self.peak_held.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn add_nanos(counter: &AtomicU64, duration: Duration) {
+    let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+    counter.fetch_add(nanos, Ordering::Relaxed);
+}
+
+/// A snapshot of the cumulative statistics of the tokens acquired through a
+/// client and its clones, returned by
+/// [`Client::stats`](crate::Client::stats).
+///
+/// The [`Display`](fmt::Display) implementation gives a one-line summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobserverStats {
+    acquired: u64,
+    released: u64,
+    blocked: Duration,
+    held_time: Duration,
+    held: usize,
+    peak_held: usize,
+}
+
+impl JobserverStats {
+    /// Returns the number of tokens acquired, including with
+    /// [`Client::acquire_raw`](crate::Client::acquire_raw) and the implicit
+    /// token when it's handed out as an [`Acquired`](crate::Acquired) token.
+    pub fn acquired(&self) -> u64 {
+        self.acquired
+    }
+
+    /// Returns the number of tokens released, including with
+    /// [`Client::release_raw`](crate::Client::release_raw). Tokens dropped
+    /// without being released aren't counted.
+    pub fn released(&self) -> u64 {
+        self.released
+    }
+
+    /// Returns the total time spent blocked in
+    /// [`Client::acquire`](crate::Client::acquire) and
+    /// [`Client::acquire_raw`](crate::Client::acquire_raw).
+    pub fn blocked(&self) -> Duration {
+        self.blocked
+    }
+
+    /// Returns the total time [`Acquired`](crate::Acquired) tokens were held
+    /// for, from their acquisition until they were dropped, see
+    /// [`Acquired::held_for`](crate::Acquired::held_for).
+    ///
+    /// Tokens still held aren't included.
+    pub fn held_time(&self) -> Duration {
+        self.held_time
+    }
+
+    /// Returns the number of [`Acquired`](crate::Acquired) tokens currently
+    /// held.
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Returns the largest number of [`Acquired`](crate::Acquired) tokens
+    /// held at once.
+    pub fn peak_held(&self) -> usize {
+        self.peak_held
+    }
+}
+
+impl fmt::Display for JobserverStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} jobserver token(s) acquired, {} released, blocked for {:.2?}, \
+             at most {} held at once",
+            self.acquired, self.released, self.blocked, self.peak_held
+        )
+    }
+}
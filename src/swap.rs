@@ -0,0 +1,64 @@
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+
+use crate::Backend;
+
+/// The backend of a [`Client`](crate::Client), shared by all its clones so
+/// that [`Client::replace_inner`](crate::Client::replace_inner) can swap it
+/// out for all of them at once.
+///
+/// Callers take a reference to the current backend for each operation, so an
+/// operation already under way, such as a blocked acquisition, finishes with
+/// the backend it started with, and each [`Acquired`](crate::Acquired) token
+/// keeps the backend it was acquired from.
+#[derive(Debug)]
+pub(crate) struct BackendCell {
+    current: RwLock<Arc<Backend>>,
+}
+
+impl BackendCell {
+    pub(crate) fn new(backend: Arc<Backend>) -> BackendCell {
+        BackendCell {
+            current: RwLock::new(backend),
+        }
+    }
+
+    /// Returns the current backend.
+    pub(crate) fn load(&self) -> Arc<Backend> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    /// Replaces the current backend with what `f` makes of it, returning the
+    /// previous one.
+    pub(crate) fn update(&self, f: impl FnOnce(&Backend) -> Arc<Backend>) -> Arc<Backend> {
+        let mut current = self.current.write().unwrap_or_else(|e| e.into_inner());
+        let new = f(&current);
+        mem::replace(&mut *current, new)
+    }
+}
+
+/// The cells of every client sharing the same state, such as a client and
+/// the clients derived from it with
+/// [`Client::capped`](crate::Client::capped), which are switched over
+/// together.
+#[derive(Debug, Default)]
+pub(crate) struct Cells {
+    cells: Mutex<Vec<Weak<BackendCell>>>,
+}
+
+impl Cells {
+    pub(crate) fn register(&self, cell: &Arc<BackendCell>) {
+        let mut cells = self.cells.lock().unwrap_or_else(|e| e.into_inner());
+        cells.retain(|cell| cell.strong_count() > 0);
+        cells.push(Arc::downgrade(cell));
+    }
+
+    /// Returns the cells still in use.
+    pub(crate) fn live(&self) -> Vec<Arc<BackendCell>> {
+        let cells = self.cells.lock().unwrap_or_else(|e| e.into_inner());
+        cells.iter().filter_map(Weak::upgrade).collect()
+    }
+}
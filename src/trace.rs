@@ -0,0 +1,60 @@
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::field;
+
+/// Tracing span following a [`crate::Acquired`] token from its acquisition
+/// until it's dropped.
+#[derive(Debug)]
+pub(crate) struct TokenSpan {
+    span: tracing::Span,
+    state: Arc<crate::ClientState>,
+}
+
+impl TokenSpan {
+    pub(crate) fn new(client: &crate::Client) -> TokenSpan {
+        let held = client.state.held.fetch_add(1, Ordering::Relaxed) + 1;
+        let span = tracing::debug_span!(
+            "jobserver_token",
+            client = Arc::as_ptr(&client.inner) as *const () as usize,
+            blocked = field::Empty,
+            label = field::Empty,
+        );
+        tracing::debug!(parent: &span, held, "acquired jobserver token");
+        TokenSpan {
+            span,
+            state: client.state.clone(),
+        }
+    }
+
+    /// Records how long acquiring the token blocked for.
+    pub(crate) fn record_blocked(&self, blocked: Duration) {
+        self.span.record("blocked", field::debug(blocked));
+    }
+
+    /// Records the label the token was acquired with.
+    pub(crate) fn record_label(&self, label: &str) {
+        self.span.record("label", label);
+    }
+
+    /// Warns that the token is dropped without releasing it as the jobserver
+    /// was reset since it was acquired.
+    pub(crate) fn stale(&self) {
+        tracing::warn!(
+            parent: &self.span,
+            "not releasing jobserver token acquired before the jobserver was reset"
+        );
+    }
+
+    /// Records that the token was dropped, either releasing it back to the
+    /// jobserver or not if `disabled`.
+    pub(crate) fn dropped(&self, disabled: bool) {
+        let held = self.state.held.fetch_sub(1, Ordering::Relaxed) - 1;
+        if disabled {
+            tracing::debug!(parent: &self.span, held, "dropped jobserver token without releasing");
+        } else {
+            tracing::debug!(parent: &self.span, held, "released jobserver token");
+        }
+    }
+}
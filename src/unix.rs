@@ -1,25 +1,37 @@
 use libc::c_int;
 
-use crate::FromEnvErrorInner;
+use crate::{ConfigurableCommand, FdKind, FromEnvErrorInner, JobserverStyle, LimitError};
+use std::ffi::{OsStr, OsString};
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Write};
 use std::mem;
 use std::mem::MaybeUninit;
+use std::os::unix::net::UnixStream;
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::ptr;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc, Once,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, MutexGuard, Once,
 };
 use std::thread::{self, Builder, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub enum Client {
     /// `--jobserver-auth=R,W`
-    Pipe { read: File, write: File },
+    Pipe {
+        read: File,
+        write: File,
+        /// Whether we created this pipe ourselves, rather than inheriting it
+        /// from the environment.
+        created: bool,
+        /// The byte written for tokens released without the one acquired,
+        /// see `Client::new_with_token_byte`.
+        token: u8,
+        read_ahead: Arc<ReadAhead>,
+    },
     /// `--jobserver-auth=fifo:PATH`
     Fifo {
         file: File,
@@ -27,69 +39,132 @@ pub enum Client {
         /// it can only go from false -> true but not the other way around, since that
         ///  could cause a race condition.
         is_non_blocking: AtomicBool,
+        read_ahead: Arc<ReadAhead>,
     },
 }
 
+/// The most tokens `set_read_ahead` keeps in the process.
+const MAX_READ_AHEAD: usize = 64;
+
+/// Tokens kept in this process instead of in the pipe, see
+/// `Client::set_read_ahead`.
+#[derive(Debug, Default)]
+pub(crate) struct ReadAhead {
+    max: AtomicUsize,
+    /// Set once the read end has been handed out for polling, see
+    /// `Client::mark_polled`, which turns read-ahead off for good.
+    polled: AtomicBool,
+    stash: Mutex<Stash>,
+}
+
+#[derive(Debug, Default)]
+struct Stash {
+    tokens: Vec<u8>,
+    /// The number of threads blocked waiting on the pipe, which tokens must
+    /// go to rather than into the stash, where they wouldn't see them.
+    waiters: usize,
+}
+
+impl ReadAhead {
+    fn max(&self) -> usize {
+        if self.polled.load(Ordering::SeqCst) {
+            return 0;
+        }
+        self.max.load(Ordering::Relaxed)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Stash> {
+        self.stash.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Tokens kept back by read-ahead belong to the jobserver.
+        drop(self.flush());
+    }
+}
+
+/// Registers a thread as blocked on the pipe, see `Stash::waiters`.
+pub(crate) struct Waiter<'a>(&'a ReadAhead);
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.0.lock().waiters -= 1;
+    }
+}
+
 #[derive(Debug)]
 pub struct Acquired {
     byte: u8,
 }
 
-impl Client {
-    pub fn new(mut limit: usize) -> io::Result<Client> {
-        let client = unsafe { Client::mk()? };
+impl Acquired {
+    pub fn to_raw(&self) -> usize {
+        self.byte.into()
+    }
 
-        // I don't think the character written here matters, but I could be
-        // wrong!
-        const BUFFER: [u8; 128] = [b'|'; 128];
+    pub fn from_raw(raw: usize) -> Acquired {
+        Acquired { byte: raw as u8 }
+    }
+}
 
-        let mut write = client.write();
+/// The capacity of the pipe backing a jobserver is only known once it fills
+/// up, so nothing is rejected up front.
+pub(crate) const MAX_LIMIT: usize = usize::MAX;
 
-        set_nonblocking(write.as_raw_fd(), true)?;
+pub(crate) const INHERITABLE: bool = true;
 
-        while limit > 0 {
-            let n = limit.min(BUFFER.len());
+impl Client {
+    pub fn new(limit: usize) -> Result<Client, LimitError> {
+        Client::new_with_token_byte(limit, DEFAULT_TOKEN)
+    }
 
-            write.write_all(&BUFFER[..n])?;
-            limit -= n;
-        }
+    pub fn new_with_token_byte(limit: usize, byte: u8) -> Result<Client, LimitError> {
+        let client = unsafe { Client::mk(byte)? };
 
+        let write = client.write();
+        set_nonblocking(write.as_raw_fd(), true)?;
+        write_tokens(write, limit, byte)?;
         set_nonblocking(write.as_raw_fd(), false)?;
 
         Ok(client)
     }
 
-    unsafe fn mk() -> io::Result<Client> {
-        let mut pipes = [0; 2];
+    unsafe fn mk(token: u8) -> io::Result<Client> {
+        let (read, write) = cloexec_pipe()?;
+        Ok(Client::from_fds(read, write, token))
+    }
 
-        // Attempt atomically-create-with-cloexec if we can on Linux,
-        // detected by using the `syscall` function in `libc` to try to work
-        // with as many kernels/glibc implementations as possible.
-        #[cfg(target_os = "linux")]
-        {
-            static PIPE2_AVAILABLE: AtomicBool = AtomicBool::new(true);
-            if PIPE2_AVAILABLE.load(Ordering::SeqCst) {
-                match libc::syscall(libc::SYS_pipe2, pipes.as_mut_ptr(), libc::O_CLOEXEC) {
-                    -1 => {
-                        let err = io::Error::last_os_error();
-                        if err.raw_os_error() == Some(libc::ENOSYS) {
-                            PIPE2_AVAILABLE.store(false, Ordering::SeqCst);
-                        } else {
-                            return Err(err);
-                        }
-                    }
-                    _ => return Ok(Client::from_fds(pipes[0], pipes[1])),
-                }
+    /// Connects to the jobserver named by `s`, which is of the given `style`
+    /// if `make` told, and is otherwise told from the format of `s`.
+    pub(crate) unsafe fn open(
+        s: &[u8],
+        check_pipe: bool,
+        style: Option<JobserverStyle>,
+    ) -> Result<Client, FromEnvErrorInner> {
+        let mismatch = |expected: &str, style: &str| {
+            FromEnvErrorInner::CannotParse(format!(
+                "expected `{}` for `--jobserver-style={}`, found `{}`",
+                expected,
+                style,
+                String::from_utf8_lossy(s)
+            ))
+        };
+        match style {
+            Some(JobserverStyle::Fifo) => {
+                return Self::from_fifo(s)?.ok_or_else(|| mismatch("fifo:PATH", "fifo"))
             }
+            Some(JobserverStyle::Pipe) => {
+                return Self::from_pipe(s, check_pipe)?.ok_or_else(|| mismatch("R,W", "pipe"))
+            }
+            Some(JobserverStyle::Sem) => {
+                return Err(FromEnvErrorInner::CannotParse(
+                    "`--jobserver-style=sem` is only supported on Windows".to_string(),
+                ))
+            }
+            None => {}
         }
-
-        cvt(libc::pipe(pipes.as_mut_ptr()))?;
-        drop(set_cloexec(pipes[0], true));
-        drop(set_cloexec(pipes[1], true));
-        Ok(Client::from_fds(pipes[0], pipes[1]))
-    }
-
-    pub(crate) unsafe fn open(s: &str, check_pipe: bool) -> Result<Client, FromEnvErrorInner> {
         if let Some(client) = Self::from_fifo(s)? {
             return Ok(client);
         }
@@ -97,35 +172,46 @@ impl Client {
             return Ok(client);
         }
         Err(FromEnvErrorInner::CannotParse(format!(
-            "expected `fifo:PATH` or `R,W`, found `{s}`"
+            "expected `fifo:PATH` or `R,W`, found `{}`",
+            String::from_utf8_lossy(s)
         )))
     }
 
     /// `--jobserver-auth=fifo:PATH`
-    fn from_fifo(s: &str) -> Result<Option<Client>, FromEnvErrorInner> {
-        let mut parts = s.splitn(2, ':');
-        if parts.next().unwrap() != "fifo" {
+    fn from_fifo(s: &[u8]) -> Result<Option<Client>, FromEnvErrorInner> {
+        let mut parts = s.splitn(2, |&b| b == b':');
+        if parts.next().unwrap() != b"fifo" {
             return Ok(None);
         }
-        let path_str = parts.next().ok_or_else(|| {
+        let path_bytes = parts.next().ok_or_else(|| {
             FromEnvErrorInner::CannotParse("expected a path after `fifo:`".to_string())
         })?;
-        let path = Path::new(path_str);
+        let path = Path::new(OsStr::from_bytes(path_bytes));
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)
-            .map_err(|err| FromEnvErrorInner::CannotOpenPath(path_str.to_string(), err))?;
+            .map_err(|err| {
+                FromEnvErrorInner::CannotOpenPath(
+                    String::from_utf8_lossy(path_bytes).into_owned(),
+                    err,
+                )
+            })?;
 
         Ok(Some(Client::Fifo {
             file,
             path: path.into(),
             is_non_blocking: AtomicBool::new(false),
+            read_ahead: Arc::default(),
         }))
     }
 
     /// `--jobserver-auth=R,W`
-    unsafe fn from_pipe(s: &str, check_pipe: bool) -> Result<Option<Client>, FromEnvErrorInner> {
+    unsafe fn from_pipe(s: &[u8], check_pipe: bool) -> Result<Option<Client>, FromEnvErrorInner> {
+        let s = match std::str::from_utf8(s) {
+            Ok(s) => s,
+            Err(_) => return Ok(None),
+        };
         let mut parts = s.splitn(2, ',');
         let read = parts.next().unwrap();
         let write = match parts.next() {
@@ -175,7 +261,7 @@ impl Client {
                 // I tested this on macOS 14 and Linux 6.5.13
                 #[cfg(target_os = "linux")]
                 if let Ok(Some(jobserver)) =
-                    Self::from_fifo(&format!("fifo:/dev/fd/{}", read.as_raw_fd()))
+                    Self::from_fifo(format!("fifo:/dev/fd/{}", read.as_raw_fd()).as_bytes())
                 {
                     return Ok(Some(jobserver));
                 }
@@ -185,13 +271,42 @@ impl Client {
         Ok(Some(Client::Pipe {
             read: clone_fd_and_set_cloexec(read)?,
             write: clone_fd_and_set_cloexec(write)?,
+            created: false,
+            token: MAKE_TOKEN,
+            read_ahead: Arc::default(),
         }))
     }
 
-    unsafe fn from_fds(read: c_int, write: c_int) -> Client {
+    /// Wraps the pipe `read` and `write` of a jobserver created elsewhere,
+    /// duplicating the file descriptors unless they're `owned`.
+    pub unsafe fn from_pipe_fds(read: RawFd, write: RawFd, owned: bool) -> io::Result<Client> {
+        let (read, write) = if owned {
+            let (read, write) = (File::from_raw_fd(read), File::from_raw_fd(write));
+            set_cloexec(read.as_raw_fd(), true)?;
+            set_cloexec(write.as_raw_fd(), true)?;
+            (read, write)
+        } else {
+            (
+                File::from(BorrowedFd::borrow_raw(read).try_clone_to_owned()?),
+                File::from(BorrowedFd::borrow_raw(write).try_clone_to_owned()?),
+            )
+        };
+        Ok(Client::Pipe {
+            read,
+            write,
+            created: owned,
+            token: MAKE_TOKEN,
+            read_ahead: Arc::default(),
+        })
+    }
+
+    unsafe fn from_fds(read: c_int, write: c_int, token: u8) -> Client {
         Client::Pipe {
             read: File::from_raw_fd(read),
             write: File::from_raw_fd(write),
+            created: true,
+            token,
+            read_ahead: Arc::default(),
         }
     }
 
@@ -203,6 +318,20 @@ impl Client {
         }
     }
 
+    /// The byte to write for a token released without the one acquired.
+    fn token(&self) -> u8 {
+        match self {
+            Client::Pipe { token, .. } => *token,
+            Client::Fifo { .. } => MAKE_TOKEN,
+        }
+    }
+
+    fn read_ahead(&self) -> &Arc<ReadAhead> {
+        match self {
+            Client::Pipe { read_ahead, .. } | Client::Fifo { read_ahead, .. } => read_ahead,
+        }
+    }
+
     /// Gets the write end of our jobserver client.
     fn write(&self) -> &File {
         match self {
@@ -211,6 +340,163 @@ impl Client {
         }
     }
 
+    /// Returns how many bytes, and so tokens, the jobserver's pipe can hold,
+    /// if that can be found out on this platform.
+    pub fn pipe_capacity(&self) -> io::Result<Option<usize>> {
+        #[cfg(target_os = "linux")]
+        return pipe_capacity(self.write()).map(Some);
+        #[cfg(not(target_os = "linux"))]
+        return Ok(None);
+    }
+
+    pub fn read_fd(&self) -> RawFd {
+        self.read().as_raw_fd()
+    }
+
+    /// The fifo is opened for reading and writing, so it never reports EOF
+    /// or hangup to pollers, whether other processes have it open or not.
+    pub fn fifo_fd(&self) -> Option<BorrowedFd<'_>> {
+        match self {
+            Client::Fifo { file, .. } => Some(file.as_fd()),
+            Client::Pipe { .. } => None,
+        }
+    }
+
+    pub fn raw_fds(&self) -> Option<(RawFd, RawFd)> {
+        match self {
+            Client::Pipe {
+                read,
+                write,
+                created: true,
+                ..
+            } => Some((read.as_raw_fd(), write.as_raw_fd())),
+            _ => None,
+        }
+    }
+
+    pub fn clone_independent(&self) -> io::Result<Client> {
+        self.duplicate(Arc::default())
+    }
+
+    /// Like `clone_independent`, but sharing the tokens kept by read-ahead
+    /// with this client, so that a thread blocked on either one's pipe gets
+    /// the tokens released through the other.
+    pub fn clone_for_thread(&self) -> io::Result<Client> {
+        self.duplicate(self.read_ahead().clone())
+    }
+
+    fn duplicate(&self, read_ahead: Arc<ReadAhead>) -> io::Result<Client> {
+        Ok(match self {
+            Client::Pipe {
+                read,
+                write,
+                created,
+                token,
+                ..
+            } => Client::Pipe {
+                read: read.try_clone()?,
+                write: write.try_clone()?,
+                created: *created,
+                token: *token,
+                read_ahead,
+            },
+            // Duplicated file descriptors share the nonblocking flag, which
+            // `try_acquire` relies on not being cleared behind its back, so
+            // open the fifo again instead.
+            Client::Fifo { path, .. } => Client::Fifo {
+                file: OpenOptions::new().read(true).write(true).open(path)?,
+                path: path.clone(),
+                is_non_blocking: AtomicBool::new(false),
+                read_ahead,
+            },
+        })
+    }
+
+    pub fn cloexec_state(&self) -> Option<(bool, bool)> {
+        let cloexec = |file: &File| unsafe {
+            let flags = cvt(libc::fcntl(file.as_raw_fd(), libc::F_GETFD)).ok()?;
+            Some(flags & libc::FD_CLOEXEC != 0)
+        };
+        Some((cloexec(self.read())?, cloexec(self.write())?))
+    }
+
+    pub fn send_over_socket(&self, sock: &UnixStream, limit: Option<usize>) -> io::Result<()> {
+        let (kind, fds, path) = match self {
+            Client::Pipe { read, write, .. } => {
+                (SOCKET_PIPE, [read.as_raw_fd(), write.as_raw_fd()], &[][..])
+            }
+            Client::Fifo { file, path, .. } => (
+                SOCKET_FIFO,
+                [file.as_raw_fd(), -1],
+                path.as_os_str().as_bytes(),
+            ),
+        };
+        let nfds = if kind == SOCKET_PIPE { 2 } else { 1 };
+        let path_len = u32::try_from(path.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "fifo path is too long"))?;
+
+        let mut header = [0; SOCKET_HEADER_LEN];
+        header[0] = kind;
+        header[1..9].copy_from_slice(&limit.map_or(u64::MAX, |l| l as u64).to_ne_bytes());
+        header[9..].copy_from_slice(&path_len.to_ne_bytes());
+        send_fds(sock, &header, &fds[..nfds])?;
+        (&*sock).write_all(path)
+    }
+
+    pub fn recv_from_socket(sock: &UnixStream) -> io::Result<(Client, Option<usize>)> {
+        let mut header = [0; SOCKET_HEADER_LEN];
+        let mut fds = recv_fds(sock, &mut header)?.into_iter();
+        let limit = match u64::from_ne_bytes(header[1..9].try_into().unwrap()) {
+            u64::MAX => None,
+            l => Some(l as usize),
+        };
+        let path_len = u32::from_ne_bytes(header[9..].try_into().unwrap()) as usize;
+        // The length comes from the peer, so check it before allocating.
+        if path_len > libc::PATH_MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "jobserver path received on socket is too long",
+            ));
+        }
+        let mut path = vec![0; path_len];
+        (&*sock).read_exact(&mut path)?;
+
+        let client = match (header[0], fds.next(), fds.next(), fds.next()) {
+            (SOCKET_PIPE, Some(read), Some(write), None) => Client::Pipe {
+                read,
+                write,
+                created: false,
+                token: MAKE_TOKEN,
+                read_ahead: Arc::default(),
+            },
+            (SOCKET_FIFO, Some(file), None, None) => {
+                let mut path = PathBuf::from(OsString::from_vec(path));
+                // A pipe inherited from `make` may have been turned into a
+                // fifo through `/dev/fd`, see `from_pipe`, whose number only
+                // makes sense in the sending process.
+                if path.starts_with("/dev/fd") {
+                    path = PathBuf::from(format!("/dev/fd/{}", file.as_raw_fd()));
+                }
+                let flags = cvt(unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) })?;
+                Client::Fifo {
+                    file,
+                    path,
+                    // The file description, and with it `O_NONBLOCK`, is
+                    // shared with the sending process.
+                    is_non_blocking: AtomicBool::new(flags & libc::O_NONBLOCK != 0),
+                    read_ahead: Arc::default(),
+                }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "malformed jobserver message on socket",
+                ))
+            }
+        };
+        Ok((client, limit))
+    }
+
     pub fn acquire(&self) -> io::Result<Acquired> {
         // Ignore interrupts and keep trying if that happens
         loop {
@@ -241,23 +527,37 @@ impl Client {
         // the `read` returns that it would block then we start over and try
         // again.
         //
-        // Also note that we explicitly don't handle EINTR here. That's used
-        // to shut us down, so we otherwise punt all errors upwards.
+        // Also note that we explicitly don't retry on EINTR here. That's used
+        // to shut us down, so it's reported as `Ok(None)` and left to the
+        // caller to decide whether it was a request to shut down or just a
+        // stray signal.
+        //
+        // With read-ahead enabled the same `read` picks up more than one
+        // token if the pipe has them, keeping the rest for later.
+        let waiter = match self.take_or_wait() {
+            Ok(token) => return Ok(Some(token)),
+            Err(waiter) => waiter,
+        };
         unsafe {
             let mut fd: libc::pollfd = mem::zeroed();
             let mut read = self.read();
             fd.fd = read.as_raw_fd();
             fd.events = libc::POLLIN;
+            let mut buf = [0; 1 + MAX_READ_AHEAD];
+            let buf = &mut buf[..1 + self.read_ahead().max()];
             loop {
-                let mut buf = [0];
-                match read.read(&mut buf) {
-                    Ok(1) => return Ok(Some(Acquired { byte: buf[0] })),
-                    Ok(_) => {
+                match read.read(buf) {
+                    Ok(0) => {
                         return Err(io::Error::new(
                             io::ErrorKind::UnexpectedEof,
                             "early EOF on jobserver pipe",
                         ));
                     }
+                    Ok(n) => {
+                        drop(waiter);
+                        self.keep(&buf[1..n]);
+                        return Ok(Some(Acquired { byte: buf[0] }));
+                    }
                     Err(e) => match e.kind() {
                         io::ErrorKind::WouldBlock => { /* fall through to polling */ }
                         io::ErrorKind::Interrupted => return Ok(None),
@@ -282,8 +582,139 @@ impl Client {
         }
     }
 
-    pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
+    /// Block waiting for a token, returning `None` once `wake` becomes
+    /// readable.
+    ///
+    /// This is used by helper threads which mustn't rely on signals to be
+    /// shut down. Unlike `acquire_allow_interrupts` we never block in `read`
+    /// here, as nothing could wake us up from it, so we wait for either file
+    /// descriptor to become readable and then only try to read a token.
+    pub(crate) fn acquire_allow_wakeup(&self, wake: &File) -> io::Result<Option<Acquired>> {
+        let _waiter = match self.take_or_wait() {
+            Ok(token) => return Ok(Some(token)),
+            Err(waiter) => waiter,
+        };
+        let mut fds: [libc::pollfd; 2] = unsafe { mem::zeroed() };
+        fds[0].fd = self.read().as_raw_fd();
+        fds[0].events = libc::POLLIN;
+        fds[1].fd = wake.as_raw_fd();
+        fds[1].events = libc::POLLIN;
+        loop {
+            fds[0].revents = 0;
+            fds[1].revents = 0;
+            if unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) } == -1 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            if fds[1].revents != 0 {
+                return Ok(None);
+            }
+            if fds[0].revents == 0 {
+                continue;
+            }
+
+            match self.try_acquire() {
+                Ok(Some(token)) => return Ok(Some(token)),
+                // Someone else got to the token first, go back to waiting.
+                Ok(None) => {}
+                // There's no way to read from an inherited anonymous pipe
+                // without blocking here, so fall back to a blocking read. This
+                // only blocks if another process takes the token between the
+                // `poll` and the `read`, in which case shutting down the
+                // helper thread may not be prompt.
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    if let Some(token) = self.read_after_poll()? {
+                        return Ok(Some(token));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads a token once `poll` reported the read end readable, for pipes
+    /// which `try_acquire` doesn't support. Returns `None` if there was no
+    /// token to read after all.
+    fn read_after_poll(&self) -> io::Result<Option<Acquired>> {
         let mut buf = [0];
+        match self.read().read(&mut buf) {
+            Ok(1) => Ok(Some(Acquired { byte: buf[0] })),
+            Ok(_) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "early EOF on jobserver pipe",
+            )),
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    /// Block waiting for a token for up to `timeout`, returning `None` if
+    /// none turned up in time.
+    ///
+    /// Like `acquire_allow_wakeup` this waits with `poll` and only then reads,
+    /// so it can't overshoot the timeout by blocking in `read`, except on
+    /// anonymous pipes without support for `try_acquire` if another process
+    /// takes the token between the `poll` and the `read`.
+    pub fn acquire_timeout(&self, timeout: Duration) -> io::Result<Option<Acquired>> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            None => return self.acquire().map(Some),
+        };
+        let _waiter = match self.take_or_wait() {
+            Ok(token) => return Ok(Some(token)),
+            Err(waiter) => waiter,
+        };
+        let mut fd: libc::pollfd = unsafe { mem::zeroed() };
+        fd.fd = self.read().as_raw_fd();
+        fd.events = libc::POLLIN;
+        let mut blocking_read = false;
+        loop {
+            if !blocking_read {
+                match self.try_acquire() {
+                    Ok(Some(token)) => return Ok(Some(token)),
+                    Ok(None) => {}
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => blocking_read = true,
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // Round up so that we don't spin for the last fraction of a
+            // millisecond.
+            let millis = (remaining.as_nanos() + 999_999) / 1_000_000;
+            let millis = millis.min(c_int::MAX as u128) as c_int;
+            fd.revents = 0;
+            if unsafe { libc::poll(&mut fd, 1, millis) } == -1 {
+                let e = io::Error::last_os_error();
+                match e.kind() {
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(e),
+                }
+            }
+            // Without `try_acquire` the pipe is only read once `poll` says
+            // so, which with no time left makes this a non-blocking try.
+            if fd.revents != 0 && blocking_read {
+                if let Some(token) = self.read_after_poll()? {
+                    return Ok(Some(token));
+                }
+            }
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+        }
+    }
+
+    pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
+        if let Some(byte) = self.read_ahead().lock().tokens.pop() {
+            return Ok(Some(Acquired { byte }));
+        }
+        let mut buf = [0; 1 + MAX_READ_AHEAD];
+        let buf = &mut buf[..1 + self.read_ahead().max()];
 
         // On Linux, we can use preadv2 to do non-blocking read,
         // even if `O_NONBLOCK` is not set.
@@ -291,14 +722,17 @@ impl Client {
         {
             let read = self.read().as_raw_fd();
             loop {
-                match non_blocking_read(read, &buf) {
-                    Ok(1) => return Ok(Some(Acquired { byte: buf[0] })),
-                    Ok(_) => {
+                match non_blocking_read(read, buf) {
+                    Ok(0) => {
                         return Err(io::Error::new(
                             io::ErrorKind::UnexpectedEof,
                             "early EOF on jobserver pipe",
                         ))
                     }
+                    Ok(n) => {
+                        self.keep(&buf[1..n]);
+                        return Ok(Some(Acquired { byte: buf[0] }));
+                    }
 
                     Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
                     Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
@@ -309,29 +743,36 @@ impl Client {
             }
         }
 
-        let (mut fifo, is_non_blocking) = match self {
+        let mut file = match self {
             Self::Fifo {
                 file,
                 is_non_blocking,
                 ..
-            } => (file, is_non_blocking),
+            } => {
+                if !is_non_blocking.load(Ordering::Relaxed) {
+                    set_nonblocking(file.as_raw_fd(), true)?;
+                    is_non_blocking.store(true, Ordering::Relaxed);
+                }
+                file
+            }
+            // An anonymous pipe put into nonblocking mode with
+            // `set_nonblocking` can simply be read from.
+            Self::Pipe { read, .. } if is_nonblocking(read.as_raw_fd())? => read,
             _ => return Err(io::ErrorKind::Unsupported.into()),
         };
 
-        if !is_non_blocking.load(Ordering::Relaxed) {
-            set_nonblocking(fifo.as_raw_fd(), true)?;
-            is_non_blocking.store(true, Ordering::Relaxed);
-        }
-
         loop {
-            match fifo.read(&mut buf) {
-                Ok(1) => break Ok(Some(Acquired { byte: buf[0] })),
-                Ok(_) => {
+            match file.read(buf) {
+                Ok(0) => {
                     break Err(io::Error::new(
                         io::ErrorKind::UnexpectedEof,
                         "early EOF on jobserver pipe",
                     ))
                 }
+                Ok(n) => {
+                    self.keep(&buf[1..n]);
+                    break Ok(Some(Acquired { byte: buf[0] }));
+                }
 
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(None),
                 Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
@@ -341,12 +782,101 @@ impl Client {
         }
     }
 
+    pub fn reset_to(&self, limit: usize) -> io::Result<()> {
+        let (mut fifo, is_non_blocking) = match self {
+            Client::Fifo {
+                file,
+                is_non_blocking,
+                ..
+            } => (file, is_non_blocking),
+            Client::Pipe { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only jobservers backed by a fifo can be reset",
+                ))
+            }
+        };
+
+        // Tokens kept back by read-ahead are replaced along with the others.
+        self.read_ahead().lock().tokens.clear();
+
+        // The fifo stays nonblocking from here on, see `try_acquire`, so
+        // reading it dry returns `WouldBlock` instead of waiting for tokens.
+        if !is_non_blocking.load(Ordering::Relaxed) {
+            set_nonblocking(fifo.as_raw_fd(), true)?;
+            is_non_blocking.store(true, Ordering::Relaxed);
+        }
+
+        let mut buf = [0; 128];
+        loop {
+            match fifo.read(&mut buf) {
+                // We hold the fifo open for writing ourselves, so it shouldn't
+                // ever report EOF, but don't spin if it does.
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        write_tokens(fifo, limit, DEFAULT_TOKEN)?;
+        Ok(())
+    }
+
+    pub fn supports_try_acquire(&self) -> bool {
+        match self {
+            Client::Fifo { .. } => true,
+            // Anonymous pipes can only be read without blocking through
+            // `preadv2`, which we only learn isn't supported by the kernel
+            // once we've tried it.
+            #[cfg(target_os = "linux")]
+            Client::Pipe { .. } => !IS_NONBLOCKING_READ_UNSUPPORTED.load(Ordering::Relaxed),
+            #[cfg(not(target_os = "linux"))]
+            Client::Pipe { read, .. } => is_nonblocking(read.as_raw_fd()).unwrap_or(false),
+        }
+    }
+
+    pub fn set_nonblocking(&self, set: bool) -> io::Result<()> {
+        match self {
+            Client::Pipe { read, .. } => set_nonblocking(read.as_raw_fd(), set),
+            Client::Fifo {
+                file,
+                is_non_blocking,
+                ..
+            } => {
+                if set {
+                    set_nonblocking(file.as_raw_fd(), true)?;
+                    is_non_blocking.store(true, Ordering::Relaxed);
+                    Ok(())
+                } else if is_non_blocking.load(Ordering::Relaxed) {
+                    // `try_acquire` relies on the fifo staying nonblocking
+                    // once it has been made so.
+                    Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "a fifo jobserver cannot be made blocking again",
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
     pub fn release(&self, data: Option<&Acquired>) -> io::Result<()> {
         // Note that the fd may be nonblocking but we're going to go ahead
         // and assume that the writes here are always nonblocking (we can
         // always quickly release a token). If that turns out to not be the
         // case we'll get an error anyway!
-        let byte = data.map(|d| d.byte).unwrap_or(b'+');
+        let byte = data.map_or_else(|| self.token(), |d| d.byte);
+        let read_ahead = self.read_ahead();
+        if read_ahead.max() > 0 {
+            let mut stash = read_ahead.lock();
+            if stash.waiters == 0 && stash.tokens.len() < read_ahead.max() {
+                stash.tokens.push(byte);
+                return Ok(());
+            }
+        }
         match self.write().write(&[byte])? {
             1 => Ok(()),
             _ => Err(io::Error::new(
@@ -356,20 +886,129 @@ impl Client {
         }
     }
 
-    pub fn string_arg(&self) -> String {
+    pub fn string_arg(&self) -> OsString {
         match self {
-            Client::Pipe { read, write } => format!("{},{}", read.as_raw_fd(), write.as_raw_fd()),
-            Client::Fifo { path, .. } => format!("fifo:{}", path.to_str().unwrap()),
+            Client::Pipe { read, write, .. } => {
+                format!("{},{}", read.as_raw_fd(), write.as_raw_fd()).into()
+            }
+            Client::Fifo { path, .. } => {
+                let mut arg = OsString::from("fifo:");
+                arg.push(path);
+                arg
+            }
         }
     }
 
     pub fn available(&self) -> io::Result<usize> {
         let mut len = MaybeUninit::<c_int>::uninit();
         cvt(unsafe { libc::ioctl(self.read().as_raw_fd(), libc::FIONREAD, len.as_mut_ptr()) })?;
-        Ok(unsafe { len.assume_init() } as usize)
+        let stashed = self.read_ahead().lock().tokens.len();
+        Ok(unsafe { len.assume_init() } as usize + stashed)
+    }
+
+    /// Keeps up to `tokens` tokens in the process when they're released or
+    /// read along with another one, instead of going through the pipe.
+    pub fn set_read_ahead(&self, tokens: usize) -> io::Result<()> {
+        let read_ahead = self.read_ahead();
+        let mut stash = read_ahead.lock();
+        let max = tokens.min(MAX_READ_AHEAD);
+        read_ahead.max.store(max, Ordering::Relaxed);
+        let keep = stash.tokens.len().min(max);
+        let excess = stash.tokens.split_off(keep);
+        drop(stash);
+        self.write_tokens_back(&excess)
+    }
+
+    pub fn read_ahead_tokens(&self) -> usize {
+        self.read_ahead().max()
     }
 
-    pub fn configure(&self, cmd: &mut Command) {
+    /// Writes the tokens kept back by read-ahead to the pipe.
+    pub fn flush(&self) -> io::Result<()> {
+        let tokens = mem::take(&mut self.read_ahead().lock().tokens);
+        self.write_tokens_back(&tokens)
+    }
+
+    /// Registers the caller as waiting for the pipe to become readable, like
+    /// a thread blocked reading from it, until the returned guard is dropped.
+    /// Tokens kept back by read-ahead are written to the pipe first, so that
+    /// polling sees them.
+    pub(crate) fn poller(&self) -> Waiter<'_> {
+        let read_ahead = self.read_ahead();
+        let mut stash = read_ahead.lock();
+        stash.waiters += 1;
+        let tokens = mem::take(&mut stash.tokens);
+        drop(stash);
+        // Rather keep the tokens than lose them, `try_acquire` takes them.
+        if self.write_tokens_back(&tokens).is_err() {
+            read_ahead.lock().tokens.extend_from_slice(&tokens);
+        }
+        Waiter(read_ahead)
+    }
+
+    /// Turns read-ahead off for good, as the read end is handed out to be
+    /// polled by code which can't be registered as waiting on it.
+    pub(crate) fn mark_polled(&self) {
+        self.read_ahead().polled.store(true, Ordering::SeqCst);
+        drop(self.flush());
+    }
+
+    /// Takes a token kept back by read-ahead, or otherwise registers the
+    /// calling thread as about to block on the pipe.
+    fn take_or_wait(&self) -> Result<Acquired, Waiter<'_>> {
+        let read_ahead = self.read_ahead();
+        let mut stash = read_ahead.lock();
+        match stash.tokens.pop() {
+            Some(byte) => Ok(Acquired { byte }),
+            None => {
+                stash.waiters += 1;
+                Err(Waiter(read_ahead))
+            }
+        }
+    }
+
+    /// Keeps tokens read along with the one being acquired, as far as
+    /// read-ahead allows and no other thread is waiting for them.
+    fn keep(&self, extra: &[u8]) {
+        if extra.is_empty() {
+            return;
+        }
+        let read_ahead = self.read_ahead();
+        let mut stash = read_ahead.lock();
+        let room = if stash.waiters == 0 {
+            read_ahead.max().saturating_sub(stash.tokens.len())
+        } else {
+            0
+        };
+        let (kept, rest) = extra.split_at(room.min(extra.len()));
+        stash.tokens.extend_from_slice(kept);
+        drop(stash);
+        // Rather keep too many tokens than lose them.
+        if self.write_tokens_back(rest).is_err() {
+            read_ahead.lock().tokens.extend_from_slice(rest);
+        }
+    }
+
+    fn write_tokens_back(&self, tokens: &[u8]) -> io::Result<()> {
+        if tokens.is_empty() {
+            return Ok(());
+        }
+        self.write().write_all(tokens)
+    }
+
+    /// The `dup2` mappings a child spawned with `posix_spawn` needs to
+    /// inherit the file descriptors `configure` would.
+    pub fn fd_actions(&self) -> Vec<(RawFd, RawFd)> {
+        match self {
+            Client::Fifo { .. } => Vec::new(),
+            Client::Pipe { read, write, .. } => {
+                let (read, write) = (read.as_raw_fd(), write.as_raw_fd());
+                vec![(read, read), (write, write)]
+            }
+        }
+    }
+
+    pub fn configure(&self, cmd: &mut impl ConfigurableCommand) {
         match self {
             // We `File::open`ed it when inheriting from environment,
             // so no need to set cloexec for fifo.
@@ -380,14 +1019,19 @@ impl Client {
         // we'll configure the read/write file descriptors to *not* be
         // cloexec, so they're inherited across the exec and specified as
         // integers through `string_arg` above.
-        let read = self.read().as_raw_fd();
-        let write = self.write().as_raw_fd();
-        unsafe {
-            cmd.pre_exec(move || {
-                set_cloexec(read, false)?;
-                set_cloexec(write, false)?;
-                Ok(())
-            });
+        cmd.inherit_fd(self.read().as_raw_fd());
+        cmd.inherit_fd(self.write().as_raw_fd());
+    }
+
+    pub fn deconfigure(&self, cmd: &mut Command) {
+        // Hooks run in the order they were added, so this overrides the
+        // hooks added by an earlier `configure`. A fifo is never inherited.
+        if let Client::Pipe { read, write, .. } = self {
+            for fd in [read.as_raw_fd(), write.as_raw_fd()] {
+                unsafe {
+                    cmd.pre_exec(move || set_cloexec(fd, true));
+                }
+            }
         }
     }
 }
@@ -396,12 +1040,15 @@ impl Client {
 pub struct Helper {
     thread: JoinHandle<()>,
     state: Arc<super::HelperState>,
+    /// The write end of the self-pipe used to wake up the helper thread, or
+    /// `None` if it's woken up with `SIGUSR1` instead.
+    wake: Option<File>,
 }
 
 pub(crate) fn spawn_helper(
     client: crate::Client,
     state: Arc<super::HelperState>,
-    mut f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
+    f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
 ) -> io::Result<Helper> {
     static USR1_INIT: Once = Once::new();
     let mut err = None;
@@ -413,7 +1060,7 @@ pub(crate) fn spawn_helper(
         }
         #[cfg(not(target_os = "aix"))]
         {
-            new.sa_sigaction = sigusr1_handler as usize;
+            new.sa_sigaction = sigusr1_handler as *const () as usize;
         }
         new.sa_flags = libc::SA_SIGINFO as _;
         if libc::sigaction(libc::SIGUSR1, &new, ptr::null_mut()) != 0 {
@@ -425,25 +1072,65 @@ pub(crate) fn spawn_helper(
         return Err(e);
     }
 
+    spawn(client, state, f, None)
+}
+
+/// Like `spawn_helper`, but wakes up the helper thread through a self-pipe
+/// rather than with a signal, so no signal handler is installed.
+pub(crate) fn spawn_helper_no_signals(
+    client: crate::Client,
+    state: Arc<super::HelperState>,
+    f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
+) -> io::Result<Helper> {
+    spawn(client, state, f, Some(self_pipe()?))
+}
+
+/// Creates a pipe for waking up threads blocked in `acquire_allow_wakeup`,
+/// returning its read and write ends.
+pub(crate) fn self_pipe() -> io::Result<(File, File)> {
+    unsafe {
+        let (read, write) = cloexec_pipe()?;
+        Ok((File::from_raw_fd(read), File::from_raw_fd(write)))
+    }
+}
+
+fn spawn(
+    client: crate::Client,
+    state: Arc<super::HelperState>,
+    mut f: Box<dyn FnMut(io::Result<crate::Acquired>) + Send>,
+    wake: Option<(File, File)>,
+) -> io::Result<Helper> {
+    let (wake_read, wake) = match wake {
+        Some((read, write)) => (Some(read), Some(write)),
+        None => (None, None),
+    };
     let state2 = state.clone();
     let thread = Builder::new().spawn(move || {
         state2.for_each_request(|helper| loop {
-            match client.inner.acquire_allow_interrupts() {
+            let acquired = match &wake_read {
+                Some(wake) => client.backend().os().acquire_allow_wakeup(wake),
+                None => client.backend().os().acquire_allow_interrupts(),
+            };
+            match acquired {
                 Ok(Some(data)) => {
-                    break f(Ok(crate::Acquired {
-                        client: client.inner.clone(),
-                        data,
-                        disabled: false,
-                    }));
+                    break f(Ok(crate::Acquired::new(&client, Some(data))));
                 }
                 Err(e) => break f(Err(e)),
+                // EINTR or the self-pipe is how `Helper::join` wakes us up to
+                // shut down, but any other signal may interrupt the read as
+                // well. Only bail out if we were actually asked to, otherwise
+                // keep waiting for a token for the pending request.
                 Ok(None) if helper.lock().producer_done => break,
                 Ok(None) => {}
             }
         });
     })?;
 
-    Ok(Helper { thread, state })
+    Ok(Helper {
+        thread,
+        state,
+        wake,
+    })
 }
 
 impl Helper {
@@ -459,21 +1146,30 @@ impl Helper {
         // of interrupting that, so resort to `pthread_kill` as a fallback.
         // This signal should interrupt any blocking `read` call with
         // `io::ErrorKind::Interrupt` and cause the thread to cleanly exit.
+        // Helper threads spawned without signals are woken up by writing to
+        // their self-pipe instead.
         //
         // Note that we don't do this forever though since there's a chance
         // of bugs, so only do this opportunistically to make a best effort
         // at clearing ourselves up.
+        if let Some(mut wake) = self.wake.as_ref() {
+            // The self-pipe is never drained, so a single byte is enough to
+            // keep the helper thread from blocking again.
+            drop(wake.write(&[0]));
+        }
         for _ in 0..100 {
             if state.consumer_done {
                 break;
             }
-            unsafe {
+            if self.wake.is_none() {
                 // Ignore the return value here of `pthread_kill`,
                 // apparently on OSX if you kill a dead thread it will
                 // return an error, but on other platforms it may not. In
                 // that sense we don't actually know if this will succeed or
                 // not!
-                libc::pthread_kill(self.thread.as_pthread_t() as _, libc::SIGUSR1);
+                unsafe {
+                    libc::pthread_kill(self.thread.as_pthread_t() as _, libc::SIGUSR1);
+                }
             }
             state = self
                 .state
@@ -509,25 +1205,42 @@ unsafe fn fd_check(fd: c_int, check_pipe: bool) -> Result<(), FromEnvErrorInner>
         if libc::fstat(fd, &mut stat) == -1 {
             let last_os_error = io::Error::last_os_error();
             fcntl_check(fd)?;
-            Err(FromEnvErrorInner::NotAPipe(fd, Some(last_os_error)))
+            Err(FromEnvErrorInner::NotAPipe(
+                fd,
+                FdKind::Unknown,
+                Some(last_os_error),
+            ))
         } else {
-            // On android arm and i686 mode_t is u16 and st_mode is u32,
-            // this generates a type mismatch when S_IFIFO (declared as mode_t)
-            // is used in operations with st_mode, so we use this workaround
-            // to get the value of S_IFIFO with the same type of st_mode.
-            #[allow(unused_assignments)]
-            let mut s_ififo = stat.st_mode;
-            s_ififo = libc::S_IFIFO as _;
-            if stat.st_mode & s_ififo == s_ififo {
-                return Ok(());
+            match fd_kind(stat.st_mode as u32) {
+                FdKind::Fifo => Ok(()),
+                kind => Err(FromEnvErrorInner::NotAPipe(fd, kind, None)),
             }
-            Err(FromEnvErrorInner::NotAPipe(fd, None))
         }
     } else {
         fcntl_check(fd)
     }
 }
 
+/// Classifies the `st_mode` of a file as returned by `fstat`.
+#[allow(clippy::unnecessary_cast)]
+fn fd_kind(mode: u32) -> FdKind {
+    // On android arm and i686 mode_t is u16 and st_mode is u32, so both are
+    // widened to compare them.
+    let kinds = [
+        (libc::S_IFIFO, FdKind::Fifo),
+        (libc::S_IFSOCK, FdKind::Socket),
+        (libc::S_IFREG, FdKind::Regular),
+        (libc::S_IFDIR, FdKind::Directory),
+        (libc::S_IFCHR, FdKind::CharDevice),
+        (libc::S_IFBLK, FdKind::BlockDevice),
+        (libc::S_IFLNK, FdKind::Symlink),
+    ];
+    kinds
+        .iter()
+        .find(|&&(fmt, _)| mode & libc::S_IFMT as u32 == fmt as u32)
+        .map_or(FdKind::Unknown, |&(_, kind)| kind)
+}
+
 fn clone_fd_and_set_cloexec(fd: c_int) -> Result<File, FromEnvErrorInner> {
     // Safety: fd is a valid fd dand it remains open until returns
     unsafe { BorrowedFd::borrow_raw(fd) }
@@ -536,7 +1249,153 @@ fn clone_fd_and_set_cloexec(fd: c_int) -> Result<File, FromEnvErrorInner> {
         .map_err(|err| FromEnvErrorInner::CannotOpenFd(fd, err))
 }
 
-fn set_cloexec(fd: c_int, set: bool) -> io::Result<()> {
+/// Kinds of jobservers sent over a socket by `Client::send_over_socket`.
+const SOCKET_PIPE: u8 = b'P';
+const SOCKET_FIFO: u8 = b'F';
+
+/// The kind of jobserver, the limit (`u64::MAX` if unknown) and the length of
+/// the fifo path following the header.
+const SOCKET_HEADER_LEN: usize = 1 + 8 + 4;
+
+/// Sends `data` over `sock` along with the file descriptors `fds`, at most two
+/// of them.
+fn send_fds(sock: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    // `u64` to get the alignment of a `cmsghdr` right, and large enough for
+    // the header and two file descriptors on all platforms.
+    let mut cmsg_buf = [0u64; 8];
+    let fds_len = mem::size_of_val(fds) as libc::c_uint;
+
+    unsafe {
+        let space = libc::CMSG_SPACE(fds_len) as usize;
+        assert!(space <= mem::size_of_val(&cmsg_buf));
+
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = space as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+
+        let sent = loop {
+            match cvt_ssize(libc::sendmsg(sock.as_raw_fd(), &msg, 0)) {
+                Ok(n) => break n as usize,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        // The file descriptors went along with the first byte, the rest of
+        // the data can be sent as usual.
+        (&*sock).write_all(&data[sent..])
+    }
+}
+
+/// Receives `data.len()` bytes from `sock` along with the file descriptors
+/// sent with them by `send_fds`.
+fn recv_fds(sock: &UnixStream, data: &mut [u8]) -> io::Result<Vec<File>> {
+    let mut cmsg_buf = [0u64; 8];
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let flags = 0;
+
+    let mut fds = Vec::new();
+    let received = unsafe {
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr().cast();
+        msg.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+        let received = loop {
+            match cvt_ssize(libc::recvmsg(sock.as_raw_fd(), &mut msg, flags)) {
+                Ok(n) => break n as usize,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+
+        // Take ownership of everything we received before looking any
+        // further, so nothing is leaked on error.
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(File::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many file descriptors received on socket",
+            ));
+        }
+        received
+    };
+
+    if received == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    (&*sock).read_exact(&mut data[received..])?;
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    for fd in &fds {
+        set_cloexec(fd.as_raw_fd(), true)?;
+    }
+
+    Ok(fds)
+}
+
+/// Creates an anonymous pipe with both ends set to close-on-exec.
+unsafe fn cloexec_pipe() -> io::Result<(c_int, c_int)> {
+    let mut pipes = [0; 2];
+
+    // Attempt atomically-create-with-cloexec if we can on Linux,
+    // detected by using the `syscall` function in `libc` to try to work
+    // with as many kernels/glibc implementations as possible.
+    #[cfg(target_os = "linux")]
+    {
+        static PIPE2_AVAILABLE: AtomicBool = AtomicBool::new(true);
+        if PIPE2_AVAILABLE.load(Ordering::SeqCst) {
+            match libc::syscall(libc::SYS_pipe2, pipes.as_mut_ptr(), libc::O_CLOEXEC) {
+                -1 => {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() == Some(libc::ENOSYS) {
+                        PIPE2_AVAILABLE.store(false, Ordering::SeqCst);
+                    } else {
+                        return Err(err);
+                    }
+                }
+                _ => return Ok((pipes[0], pipes[1])),
+            }
+        }
+    }
+
+    cvt(libc::pipe(pipes.as_mut_ptr()))?;
+    drop(set_cloexec(pipes[0], true));
+    drop(set_cloexec(pipes[1], true));
+    Ok((pipes[0], pipes[1]))
+}
+
+pub(crate) fn set_cloexec(fd: c_int, set: bool) -> io::Result<()> {
     unsafe {
         let previous = cvt(libc::fcntl(fd, libc::F_GETFD))?;
         let new = if set {
@@ -551,16 +1410,85 @@ fn set_cloexec(fd: c_int, set: bool) -> io::Result<()> {
     }
 }
 
-fn set_nonblocking(fd: c_int, set: bool) -> io::Result<()> {
-    let status_flag = if set { libc::O_NONBLOCK } else { 0 };
+/// The byte written for each token of a jobserver created by this crate.
+///
+/// I don't think the character written here matters, but I could be wrong!
+/// `Client::new_with_token_byte` can be used to pick another one.
+const DEFAULT_TOKEN: u8 = b'|';
+
+/// The byte GNU make writes for each token, which is also written for tokens
+/// released without the one acquired to jobservers created elsewhere.
+const MAKE_TOKEN: u8 = b'+';
+
+/// Writes `limit` tokens of `byte` into the nonblocking write end of a
+/// jobserver.
+fn write_tokens(mut write: &File, mut limit: usize, byte: u8) -> Result<(), LimitError> {
+    let buffer = [byte; 128];
+
+    // Pipes start out with room for 64KiB on Linux, but unprivileged
+    // processes can grow them up to `/proc/sys/fs/pipe-max-size`, 1MiB by
+    // default. If that's not allowed the tokens may still fit.
+    #[cfg(target_os = "linux")]
+    if let Ok(size) = libc::c_int::try_from(limit) {
+        if pipe_capacity(write).map_or(false, |capacity| capacity < limit) {
+            unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETPIPE_SZ, size) };
+        }
+    }
 
+    let requested = limit;
+    while limit > 0 {
+        let n = limit.min(buffer.len());
+
+        // The write end is nonblocking, so running out of room in the pipe
+        // means it can't hold as many tokens as were asked for.
+        match write.write_all(&buffer[..n]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                #[cfg(target_os = "linux")]
+                let max = pipe_capacity(write).ok();
+                #[cfg(not(target_os = "linux"))]
+                let max = None;
+                return Err(LimitError::too_large(requested, max));
+            }
+            Err(e) => return Err(e.into()),
+        }
+        limit -= n;
+    }
+
+    Ok(())
+}
+
+/// Returns how many bytes the pipe `file` can hold.
+#[cfg(target_os = "linux")]
+fn pipe_capacity(file: &File) -> io::Result<usize> {
+    match unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETPIPE_SZ) } {
+        -1 => Err(io::Error::last_os_error()),
+        size => Ok(size as usize),
+    }
+}
+
+fn set_nonblocking(fd: c_int, set: bool) -> io::Result<()> {
     unsafe {
-        cvt(libc::fcntl(fd, libc::F_SETFL, status_flag))?;
+        // Leave the other status flags as they are.
+        let previous = cvt(libc::fcntl(fd, libc::F_GETFL))?;
+        let new = if set {
+            previous | libc::O_NONBLOCK
+        } else {
+            previous & !libc::O_NONBLOCK
+        };
+        if new != previous {
+            cvt(libc::fcntl(fd, libc::F_SETFL, new))?;
+        }
     }
 
     Ok(())
 }
 
+fn is_nonblocking(fd: c_int) -> io::Result<bool> {
+    let flags = unsafe { cvt(libc::fcntl(fd, libc::F_GETFL))? };
+    Ok(flags & libc::O_NONBLOCK != 0)
+}
+
 fn cvt(t: c_int) -> io::Result<c_int> {
     if t == -1 {
         Err(io::Error::last_os_error())
@@ -577,7 +1505,6 @@ extern "C" fn sigusr1_handler(
     // nothing to do
 }
 
-#[cfg(target_os = "linux")]
 fn cvt_ssize(t: libc::ssize_t) -> io::Result<libc::ssize_t> {
     if t == -1 {
         Err(io::Error::last_os_error())
@@ -587,9 +1514,10 @@ fn cvt_ssize(t: libc::ssize_t) -> io::Result<libc::ssize_t> {
 }
 
 #[cfg(target_os = "linux")]
-fn non_blocking_read(fd: c_int, buf: &[u8]) -> io::Result<usize> {
-    static IS_NONBLOCKING_READ_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+static IS_NONBLOCKING_READ_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
 
+#[cfg(target_os = "linux")]
+fn non_blocking_read(fd: c_int, buf: &[u8]) -> io::Result<usize> {
     if IS_NONBLOCKING_READ_UNSUPPORTED.load(Ordering::Relaxed) {
         return Err(io::ErrorKind::Unsupported.into());
     }
@@ -623,30 +1551,349 @@ fn non_blocking_read(fd: c_int, buf: &[u8]) -> io::Result<usize> {
 mod test {
     use super::Client as ClientImp;
 
-    use crate::{test::run_named_fifo_try_acquire_tests, Client};
-
-    use std::sync::Arc;
+    use super::DEFAULT_TOKEN;
+    use crate::{test::run_named_fifo_try_acquire_tests, Backend, Client};
 
     fn from_imp_client(imp: ClientImp) -> Client {
-        Client {
-            inner: Arc::new(imp),
+        Client::from_backend(Backend::Os(imp), None, false)
+    }
+
+    /// Connects to a new, empty fifo, which is removed along with the
+    /// returned directory.
+    fn fifo_client() -> (tempfile::TempDir, Client) {
+        let dir = tempfile::tempdir().unwrap();
+        let fifo_path = dir.path().join("fifo");
+        nix::unistd::mkfifo(&fifo_path, nix::sys::stat::Mode::S_IRWXU).unwrap();
+        let auth = format!("fifo:{}", fifo_path.display());
+        let client = from_imp_client(ClientImp::from_fifo(auth.as_bytes()).unwrap().unwrap());
+        (dir, client)
+    }
+
+    #[test]
+    fn test_token_byte() {
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+
+        let client = Client::new_with_token_byte(2, b'+').unwrap();
+        let token = client.acquire().unwrap();
+        assert_eq!(token.leak().into_usize(), usize::from(b'+'));
+
+        let (read, _) = client.raw_fds().unwrap();
+        let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+        let mut byte = [0];
+        read.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, *b"+");
+        std::mem::forget(read);
+
+        // Tokens released with `release_raw` are written as the same byte.
+        for (client, expected) in [
+            (Client::new_with_token_byte(0, b'x').unwrap(), b'x'),
+            (Client::new(0).unwrap(), DEFAULT_TOKEN),
+        ] {
+            client.release_raw().unwrap();
+            let (read, _) = client.raw_fds().unwrap();
+            let mut read = unsafe { std::fs::File::from_raw_fd(read) };
+            read.read_exact(&mut byte).unwrap();
+            assert_eq!(byte, [expected]);
+            std::mem::forget(read);
         }
     }
 
     #[test]
     fn test_try_acquire_named_fifo() {
-        let file = tempfile::NamedTempFile::new().unwrap();
-        let fifo_path = file.path().to_owned();
-        file.close().unwrap(); // Remove the NamedTempFile to create fifo
+        let (_dir, client) = fifo_client();
+        run_named_fifo_try_acquire_tests(&client);
+    }
+
+    #[test]
+    fn test_reset_fifo() {
+        let (_dir, client) = fifo_client();
+
+        // An empty fifo must not block.
+        client.reset_to(0).unwrap();
+        assert_eq!(client.available().unwrap(), 0);
+
+        for _ in 0..5 {
+            client.release_raw().unwrap();
+        }
+        client.reset_to(2).unwrap();
+        assert_eq!(client.available().unwrap(), 2);
+        assert_eq!(client.total_tokens().unwrap(), Some(2));
+
+        let pipe = Client::new(1).unwrap();
+        let err = pipe.reset_to(1).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn test_reset_fifo_stale_tokens() {
+        let (_dir, client) = fifo_client();
+        client.reset_to(2).unwrap();
+        assert_eq!(client.epoch(), 1);
+
+        let old = client.acquire().unwrap();
+        assert!(!old.is_stale());
+        client.reset_to(2).unwrap();
+        assert_eq!(client.epoch(), 2);
+        assert!(old.is_stale());
+
+        // The token from before the reset isn't released into the new pool.
+        let new = client.acquire().unwrap();
+        drop(old);
+        assert_eq!(client.available().unwrap(), 1);
+        drop(new);
+        assert_eq!(client.available().unwrap(), 2);
+
+        // A stale token still gives back its slot of an in-process cap.
+        let capped = client.clone().capped(1);
+        let old = capped.acquire().unwrap();
+        client.reset_to(2).unwrap();
+        drop(old);
+        assert!(capped.try_acquire().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_audit_and_repair() {
+        // A previous run which crashed while holding three tokens.
+        let (_dir, crashed) = fifo_client();
+        crashed.reset_to(4).unwrap();
+        for _ in 0..3 {
+            crashed.acquire().unwrap().drop_without_releasing();
+        }
+
+        let client = crashed.clone_independent().unwrap();
+        let held = client.acquire().unwrap();
+        let report = client.audit(4).unwrap();
+        assert_eq!((report.found(), report.held()), (0, 1));
+        assert_eq!(report.leaked(), 3);
+        assert_eq!(report.to_string(), "found 3 leaked jobserver token(s)");
+
+        let report = client.audit_and_repair(4).unwrap();
+        assert!(report.repaired());
+        assert_eq!(report.to_string(), "recovered 3 leaked jobserver token(s)");
+        assert_eq!(client.available().unwrap(), 4);
+        assert!(held.is_stale());
+        drop(held);
+
+        client.release_raw().unwrap();
+        let report = client.audit_and_repair(4).unwrap();
+        assert_eq!(report.excess(), 1);
+        assert_eq!(client.available().unwrap(), 4);
+
+        let report = client.audit_and_repair(4).unwrap();
+        assert!(report.is_consistent() && !report.repaired());
+        assert_eq!(report.to_string(), "all 4 jobserver token(s) accounted for");
+    }
 
+    #[test]
+    fn test_read_ahead() {
+        let client = Client::new(4).unwrap();
+        let pipe = |c: &Client| match &*c.backend() {
+            Backend::Os(imp) => imp.clone_independent().map(from_imp_client).unwrap(),
+            _ => unreachable!(),
+        };
+        let other = pipe(&client);
+        client.set_read_ahead(2).unwrap();
+        assert_eq!(client.read_ahead(), 2);
+
+        // One read takes three tokens, keeping two of them.
+        let a = client.acquire().unwrap();
+        assert_eq!(other.available().unwrap(), 1);
+        assert_eq!(client.available().unwrap(), 3);
+        let b = client.acquire().unwrap();
+        assert_eq!(other.available().unwrap(), 1);
+
+        // Released tokens are kept until there are two.
+        drop((a, b));
+        assert_eq!(other.available().unwrap(), 2);
+        assert_eq!(client.available().unwrap(), 4);
+        client.flush().unwrap();
+        assert_eq!(other.available().unwrap(), 4);
+
+        // A thread waiting on the pipe gets released tokens through it.
+        let held: Vec<_> = (0..4).map(|_| client.acquire().unwrap()).collect();
+        assert_eq!(other.available().unwrap(), 0);
+        let client2 = client.clone();
+        let t = std::thread::spawn(move || drop(client2.acquire().unwrap()));
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(held);
+        t.join().unwrap();
+
+        // Lowering the limit and dropping the client give tokens back.
+        client.set_read_ahead(1).unwrap();
+        assert_eq!(other.available().unwrap(), 3);
+        drop(client);
+        assert_eq!(other.available().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_readiness_fd() {
+        use std::os::unix::io::AsRawFd;
+
+        let (_dir, client) = fifo_client();
+        client.reset_to(0).unwrap();
+
+        let readable = || {
+            let fd = client.readiness_fd().unwrap();
+            let mut pollfd = libc::pollfd {
+                fd: fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            assert_ne!(unsafe { libc::poll(&mut pollfd, 1, 0) }, -1);
+            pollfd.revents
+        };
+        assert_eq!(readable(), 0);
+        client.release_raw().unwrap();
+        assert_eq!(readable(), libc::POLLIN);
+        let token = client.try_acquire().unwrap().unwrap();
+        assert_eq!(readable(), 0);
+        drop(token);
+
+        assert!(Client::new(1).unwrap().readiness_fd().is_none());
+        assert!(Client::counting(1).readiness_fd().is_none());
+    }
+
+    #[test]
+    fn test_fifo_descriptor() {
+        let (dir, client) = fifo_client();
+        let descriptor = client.descriptor().unwrap();
+        assert_eq!(descriptor.kind(), crate::TransportKind::Fifo);
+        let auth = format!("fifo:{}", dir.path().join("fifo").display());
+        assert_eq!(descriptor.auth(), auth);
+        // The fifo is opened by its path, no file descriptors are inherited.
+        assert!(client.fd_actions().is_empty());
+
+        let client2 = unsafe { Client::from_descriptor(&descriptor) }.unwrap();
+        client2.release_raw().unwrap();
+        drop(client.acquire().unwrap());
+    }
+
+    #[test]
+    fn test_send_fifo_over_socket() {
+        use std::os::unix::net::UnixStream;
+
+        let (_dir, client) = fifo_client();
+
+        let (a, b) = UnixStream::pair().unwrap();
+        client.send_over_socket(&a).unwrap();
+        let received = Client::recv_from_socket(&b).unwrap();
+        assert_eq!(
+            received.backend().string_arg(),
+            client.backend().string_arg()
+        );
+
+        received.release_raw().unwrap();
+        assert_eq!(client.available().unwrap(), 1);
+        client.acquire_raw().unwrap();
+        assert_eq!(received.available().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_recv_overlong_path_from_socket() {
+        use std::io::Write;
+        use std::os::unix::net::UnixStream;
+
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let mut header = [0; super::SOCKET_HEADER_LEN];
+        header[0] = super::SOCKET_FIFO;
+        header[9..].copy_from_slice(&u32::MAX.to_ne_bytes());
+        a.write_all(&header).unwrap();
+        let err = Client::recv_from_socket(&b).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_non_utf8_fifo_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let fifo_path = dir.path().join(OsStr::from_bytes(b"jobserver-\xff"));
         nix::unistd::mkfifo(&fifo_path, nix::sys::stat::Mode::S_IRWXU).unwrap();
 
-        let client = ClientImp::from_fifo(&format!("fifo:{}", fifo_path.to_str().unwrap()))
-            .unwrap()
-            .map(from_imp_client)
+        let mut auth = b"fifo:".to_vec();
+        auth.extend_from_slice(fifo_path.as_os_str().as_bytes());
+        let client = ClientImp::from_fifo(&auth).unwrap().unwrap();
+        assert_eq!(client.string_arg().as_bytes(), &auth[..]);
+        from_imp_client(client).release_raw().unwrap();
+    }
+
+    #[test]
+    fn test_helper_ignores_stray_signals() {
+        use std::os::unix::thread::JoinHandleExt;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let client = Client::new(1).unwrap();
+        let token = client.acquire().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let helper = client
+            .clone()
+            .into_helper_thread(move |acq| tx.send(acq.map(drop)).unwrap())
             .unwrap();
+        helper.request_token();
 
-        run_named_fifo_try_acquire_tests(&client);
+        let thread = match helper.inner.as_ref().unwrap() {
+            crate::Helper::Os(helper) => helper.thread.as_pthread_t(),
+            _ => unreachable!(),
+        };
+        for _ in 0..10 {
+            std::thread::sleep(Duration::from_millis(10));
+            unsafe {
+                libc::pthread_kill(thread, libc::SIGUSR1);
+            }
+        }
+        assert!(rx.try_recv().is_err());
+
+        drop(token);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_acquire_retries_on_eintr() {
+        use std::os::unix::thread::JoinHandleExt;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        extern "C" fn handler(_: libc::c_int) {}
+
+        // Without `SA_RESTART` a blocking `read` or `poll` fails with `EINTR`
+        // when the signal is delivered to the thread.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as *const () as usize;
+            assert_eq!(
+                libc::sigaction(libc::SIGUSR2, &action, std::ptr::null_mut()),
+                0
+            );
+        }
+
+        let client = Client::new(1).unwrap();
+        let mut token = client.acquire().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let client2 = client.clone();
+        let thread = std::thread::spawn(move || {
+            tx.send(client2.acquire()).unwrap();
+            tx.send(client2.acquire_timeout(Duration::from_secs(5)))
+                .unwrap();
+        });
+
+        for _ in 0..2 {
+            for _ in 0..10 {
+                std::thread::sleep(Duration::from_millis(10));
+                unsafe {
+                    libc::pthread_kill(thread.as_pthread_t(), libc::SIGUSR2);
+                }
+            }
+            assert!(rx.try_recv().is_err());
+            // Hand the token over to the thread.
+            drop(token);
+            token = rx.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+        }
+        thread.join().unwrap();
+        drop(token);
+        assert_eq!(client.available().unwrap(), 1);
     }
 
     #[cfg(not(target_os = "linux"))]
@@ -665,7 +1912,10 @@ mod test {
         write.write_all(b"1").unwrap();
 
         let client = unsafe {
-            ClientImp::from_pipe(&format!("{},{}", read.as_raw_fd(), write.as_raw_fd()), true)
+            ClientImp::from_pipe(
+                format!("{},{}", read.as_raw_fd(), write.as_raw_fd()).as_bytes(),
+                true,
+            )
         }
         .unwrap()
         .map(from_imp_client)
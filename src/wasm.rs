@@ -1,83 +1,122 @@
-use crate::FromEnvErrorInner;
+use crate::{ConfigurableCommand, FromEnvErrorInner, LimitError};
+use std::ffi::OsString;
 use std::io;
 use std::process::Command;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::Arc;
 use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Client {
-    inner: Arc<Inner>,
+    inner: crate::counting::Client,
 }
 
 #[derive(Debug)]
-struct Inner {
-    count: Mutex<usize>,
-    cvar: Condvar,
+pub struct Acquired(());
+
+impl Acquired {
+    pub fn to_raw(&self) -> usize {
+        0
+    }
+
+    pub fn from_raw(_raw: usize) -> Acquired {
+        Acquired(())
+    }
 }
 
-#[derive(Debug)]
-pub struct Acquired(());
+pub(crate) const MAX_LIMIT: usize = usize::MAX;
+
+/// There's no cross-process jobserver here, only an in-process semaphore.
+pub(crate) const INHERITABLE: bool = false;
 
 impl Client {
-    pub fn new(limit: usize) -> io::Result<Client> {
+    pub fn new(limit: usize) -> Result<Client, LimitError> {
         Ok(Client {
-            inner: Arc::new(Inner {
-                count: Mutex::new(limit),
-                cvar: Condvar::new(),
-            }),
+            inner: crate::counting::Client::new(limit),
         })
     }
 
-    pub(crate) unsafe fn open(_s: &str, _check_pipe: bool) -> Result<Client, FromEnvErrorInner> {
+    pub(crate) unsafe fn open(
+        _s: &[u8],
+        _check_pipe: bool,
+        _style: Option<crate::JobserverStyle>,
+    ) -> Result<Client, FromEnvErrorInner> {
         Err(FromEnvErrorInner::Unsupported)
     }
 
+    pub fn clone_for_thread(&self) -> io::Result<Client> {
+        self.clone_independent()
+    }
+
+    pub fn clone_independent(&self) -> io::Result<Client> {
+        // There are no OS handles to duplicate, share the semaphore.
+        Ok(Client {
+            inner: self.inner.clone(),
+        })
+    }
+
     pub fn acquire(&self) -> io::Result<Acquired> {
-        let mut lock = self.inner.count.lock().unwrap_or_else(|e| e.into_inner());
-        while *lock == 0 {
-            lock = self
-                .inner
-                .cvar
-                .wait(lock)
-                .unwrap_or_else(|e| e.into_inner());
-        }
-        *lock -= 1;
+        self.inner.acquire()?;
         Ok(Acquired(()))
     }
 
     pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
-        let mut lock = self.inner.count.lock().unwrap_or_else(|e| e.into_inner());
-        if *lock == 0 {
-            None
-        } else {
-            *lock -= 1;
-            Ok(Acquired(()))
-        }
+        Ok(self.inner.try_acquire()?.map(Acquired))
+    }
+
+    pub fn acquire_timeout(&self, timeout: Duration) -> io::Result<Option<Acquired>> {
+        Ok(self.inner.acquire_timeout(timeout)?.map(Acquired))
+    }
+
+    pub fn reset_to(&self, _limit: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only jobservers backed by a fifo can be reset",
+        ))
+    }
+
+    pub fn supports_try_acquire(&self) -> bool {
+        true
     }
 
     pub fn release(&self, _data: Option<&Acquired>) -> io::Result<()> {
-        let mut lock = self.inner.count.lock().unwrap_or_else(|e| e.into_inner());
-        *lock += 1;
-        drop(lock);
-        self.inner.cvar.notify_one();
-        Ok(())
+        self.inner.release()
     }
 
-    pub fn string_arg(&self) -> String {
+    pub fn string_arg(&self) -> OsString {
         panic!(
-            "On this platform there is no cross process jobserver support,
-             so Client::configure is not supported."
+            "on this platform there is no cross process jobserver support, \
+             so Client::configure is not supported"
         );
     }
 
     pub fn available(&self) -> io::Result<usize> {
-        let lock = self.inner.count.lock().unwrap_or_else(|e| e.into_inner());
-        Ok(*lock)
+        self.inner.available()
+    }
+
+    pub fn set_read_ahead(&self, _tokens: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "read-ahead is only supported for jobservers backed by a pipe",
+        ))
     }
 
-    pub fn configure(&self, _cmd: &mut Command) {
+    pub fn read_ahead_tokens(&self) -> usize {
+        0
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn configure(&self, _cmd: &mut impl ConfigurableCommand) {
         unreachable!();
     }
+
+    pub fn deconfigure(&self, _cmd: &mut Command) {
+        // Nothing is ever passed on to child processes here, so there's
+        // nothing to hide but the environment.
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,103 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Client;
+
+/// How often the number of available tokens is sampled.
+const INTERVAL: Duration = Duration::from_millis(20);
+
+/// An iterator over changes in the number of tokens available from a
+/// jobserver, returned by [`Client::watch_available`].
+///
+/// Each call to [`Iterator::next`] blocks until the number of available
+/// tokens differs from the one last yielded, and then yields the new number.
+/// The first call yields the current number right away. The iterator never
+/// ends.
+///
+/// This is best-effort: the number of available tokens is sampled
+/// periodically, so changes which are undone before the next sample are
+/// missed, and changes are reported with some delay.
+#[derive(Debug)]
+pub struct AvailableWatch {
+    client: Client,
+    last: Option<usize>,
+}
+
+impl AvailableWatch {
+    pub(crate) fn new(client: Client) -> AvailableWatch {
+        AvailableWatch { client, last: None }
+    }
+}
+
+/// Waits up to `timeout` before sampling the number of tokens available from
+/// `client` again, given whether none were available at the last sample.
+fn wait(client: &Client, empty: bool, timeout: Duration) {
+    // While no tokens are available the read end of a pipe isn't readable, so
+    // wait for that to change instead of sleeping to notice tokens being
+    // released right away.
+    #[cfg(unix)]
+    if empty {
+        if let crate::Backend::Os(c) = client.backend().base() {
+            // Released tokens have to go through the pipe to wake this up.
+            let _polling = c.poller();
+            let mut pollfd = libc::pollfd {
+                fd: c.read_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let ret = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+            // Once every writer is gone the pipe is always readable, so fall
+            // back to sleeping to not spin.
+            if ret != -1 && pollfd.revents & libc::POLLHUP == 0 {
+                return;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (client, empty);
+    thread::sleep(timeout);
+}
+
+/// Blocks until at least `n` tokens are available from `client`, see
+/// [`Client::wait_available`].
+pub(crate) fn wait_available(
+    client: &Client,
+    n: usize,
+    timeout: Option<Duration>,
+) -> io::Result<bool> {
+    // A timeout too large to represent is as good as none.
+    let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+    loop {
+        let available = client.available()?;
+        if available >= n {
+            return Ok(true);
+        }
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::ZERO => left.min(INTERVAL),
+                _ => return Ok(false),
+            },
+            None => INTERVAL,
+        };
+        wait(client, available == 0, timeout);
+    }
+}
+
+impl Iterator for AvailableWatch {
+    type Item = io::Result<usize>;
+
+    fn next(&mut self) -> Option<io::Result<usize>> {
+        loop {
+            let available = match self.client.available() {
+                Ok(available) => available,
+                Err(e) => return Some(Err(e)),
+            };
+            if self.last != Some(available) {
+                self.last = Some(available);
+                return Some(Ok(available));
+            }
+            wait(&self.client, available == 0, INTERVAL);
+        }
+    }
+}
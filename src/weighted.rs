@@ -0,0 +1,29 @@
+use crate::Acquired;
+
+/// A bundle of tokens acquired as one unit by [`Client::acquire_weighted`].
+///
+/// This stands for a single task which counts as several jobs. All of its
+/// tokens are released back to the jobserver together when it's dropped.
+///
+/// [`Client::acquire_weighted`]: crate::Client::acquire_weighted
+#[derive(Debug)]
+pub struct WeightedAcquired {
+    tokens: Vec<Acquired>,
+}
+
+impl WeightedAcquired {
+    pub(crate) fn new(tokens: Vec<Acquired>) -> WeightedAcquired {
+        WeightedAcquired { tokens }
+    }
+
+    /// Returns the number of tokens held, the weight this was acquired with.
+    pub fn weight(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Splits this bundle up into its tokens, which are then released one by
+    /// one as they're dropped.
+    pub fn into_tokens(self) -> Vec<Acquired> {
+        self.tokens
+    }
+}
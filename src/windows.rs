@@ -1,10 +1,13 @@
-use crate::FromEnvErrorInner;
-use std::ffi::CString;
+use crate::{ConfigurableCommand, FromEnvErrorInner, JobserverStyle, LimitError};
+use std::ffi::{CString, OsString};
 use std::io;
-use std::process::Command;
+use std::mem;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::process::{Child, Command};
 use std::ptr;
 use std::sync::Arc;
 use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
 
 #[derive(Debug)]
 pub struct Client {
@@ -15,6 +18,16 @@ pub struct Client {
 #[derive(Debug)]
 pub struct Acquired;
 
+impl Acquired {
+    pub fn to_raw(&self) -> usize {
+        0
+    }
+
+    pub fn from_raw(_raw: usize) -> Acquired {
+        Acquired
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 type BOOL = i32;
 #[allow(clippy::upper_case_acronyms)]
@@ -24,9 +37,12 @@ type HANDLE = *mut u8;
 #[allow(clippy::upper_case_acronyms)]
 type LONG = i32;
 
+const DUPLICATE_SAME_ACCESS: DWORD = 0x2;
 const ERROR_ALREADY_EXISTS: DWORD = 183;
 const FALSE: BOOL = 0;
 const INFINITE: DWORD = 0xffffffff;
+const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS: DWORD = 9;
+const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: DWORD = 0x2000;
 const SEMAPHORE_MODIFY_STATE: DWORD = 0x2;
 const SYNCHRONIZE: DWORD = 0x00100000;
 const TRUE: BOOL = 1;
@@ -38,6 +54,16 @@ const WAIT_TIMEOUT: DWORD = 258u32;
 
 extern "system" {
     fn CloseHandle(handle: HANDLE) -> BOOL;
+    fn DuplicateHandle(
+        hSourceProcessHandle: HANDLE,
+        hSourceHandle: HANDLE,
+        hTargetProcessHandle: HANDLE,
+        lpTargetHandle: *mut HANDLE,
+        dwDesiredAccess: DWORD,
+        bInheritHandle: BOOL,
+        dwOptions: DWORD,
+    ) -> BOOL;
+    fn GetCurrentProcess() -> HANDLE;
     fn SetEvent(hEvent: HANDLE) -> BOOL;
     fn WaitForMultipleObjects(
         ncount: DWORD,
@@ -64,6 +90,14 @@ extern "system" {
     ) -> HANDLE;
     fn OpenSemaphoreA(dwDesiredAccess: DWORD, bInheritHandle: BOOL, lpName: *const i8) -> HANDLE;
     fn WaitForSingleObject(hHandle: HANDLE, dwMilliseconds: DWORD) -> DWORD;
+    fn CreateJobObjectA(lpJobAttributes: *mut u8, lpName: *const i8) -> HANDLE;
+    fn SetInformationJobObject(
+        hJob: HANDLE,
+        JobObjectInformationClass: DWORD,
+        lpJobObjectInformation: *mut u8,
+        cbJobObjectInformationLength: DWORD,
+    ) -> BOOL;
+    fn AssignProcessToJobObject(hJob: HANDLE, hProcess: HANDLE) -> BOOL;
     #[link_name = "SystemFunction036"]
     fn RtlGenRandom(RandomBuffer: *mut u8, RandomBufferLength: u32) -> u8;
 }
@@ -87,8 +121,36 @@ fn getrandom(dest: &mut [u8]) -> io::Result<()> {
     Ok(())
 }
 
+/// Checks that `name` can be used for a semaphore passed to child processes.
+fn check_name(name: &str) -> io::Result<()> {
+    // Backslashes separate the namespace of a kernel object from its name
+    // and the name ends up in `MAKEFLAGS`, where it's split on spaces.
+    if name.contains(|c| c == '\\' || c == ' ' || c == '\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "semaphore names cannot contain backslashes, spaces or nul bytes",
+        ));
+    }
+    Ok(())
+}
+
+/// Semaphore counts are a `LONG`.
+pub(crate) const MAX_LIMIT: usize = i32::MAX as usize;
+
+pub(crate) const INHERITABLE: bool = true;
+
 impl Client {
-    pub fn new(limit: usize) -> io::Result<Client> {
+    pub fn new(limit: usize) -> Result<Client, LimitError> {
+        Client::new_named(limit, "__rust_jobserver_semaphore")
+    }
+
+    pub fn new_named(limit: usize, prefix: &str) -> Result<Client, LimitError> {
+        if limit > MAX_LIMIT {
+            return Err(LimitError::too_large(limit, Some(MAX_LIMIT)));
+        }
+
+        check_name(prefix)?;
+
         // Try a bunch of random semaphore names until we get a unique one,
         // but don't try for too long.
         //
@@ -100,7 +162,7 @@ impl Client {
         for _ in 0..100 {
             let mut bytes = [0; 4];
             getrandom(&mut bytes)?;
-            let mut name = format!("__rust_jobserver_semaphore_{}\0", u32::from_ne_bytes(bytes));
+            let mut name = format!("{}_{}\0", prefix, u32::from_ne_bytes(bytes));
             unsafe {
                 let create_limit = if limit == 0 { 1 } else { limit };
                 let r = CreateSemaphoreA(
@@ -110,7 +172,7 @@ impl Client {
                     name.as_ptr() as *const _,
                 );
                 if r.is_null() {
-                    return Err(io::Error::last_os_error());
+                    return Err(io::Error::last_os_error().into());
                 }
                 let handle = Handle(r);
 
@@ -130,11 +192,39 @@ impl Client {
         Err(io::Error::new(
             io::ErrorKind::Other,
             "failed to find a unique name for a semaphore",
-        ))
+        )
+        .into())
     }
 
-    pub(crate) unsafe fn open(s: &str, _check_pipe: bool) -> Result<Client, FromEnvErrorInner> {
-        let name = match CString::new(s) {
+    /// Wraps the semaphore `handle` of a jobserver created elsewhere, which
+    /// child processes open by `name`.
+    pub unsafe fn from_semaphore_handle(handle: RawHandle, name: &str) -> io::Result<Client> {
+        check_name(name)?;
+        Ok(Client {
+            sem: Handle(handle as HANDLE),
+            name: name.to_string(),
+        })
+    }
+
+    pub(crate) unsafe fn open(
+        s: &[u8],
+        _check_pipe: bool,
+        style: Option<JobserverStyle>,
+    ) -> Result<Client, FromEnvErrorInner> {
+        let unsupported = match style {
+            Some(JobserverStyle::Pipe) => Some("pipe"),
+            Some(JobserverStyle::Fifo) => Some("fifo"),
+            Some(JobserverStyle::Sem) | None => None,
+        };
+        if let Some(style) = unsupported {
+            return Err(FromEnvErrorInner::CannotParse(format!(
+                "`--jobserver-style={}` is not supported on Windows",
+                style
+            )));
+        }
+        // `from_env_ext` has already made sure that this is valid UTF-8.
+        let s = String::from_utf8_lossy(s);
+        let name = match CString::new(&*s) {
             Ok(s) => s,
             Err(e) => return Err(FromEnvErrorInner::CannotParse(e.to_string())),
         };
@@ -165,7 +255,21 @@ impl Client {
     }
 
     pub fn try_acquire(&self) -> io::Result<Option<Acquired>> {
-        match unsafe { WaitForSingleObject(self.sem.0, 0) } {
+        self.acquire_timeout_millis(0)
+    }
+
+    pub fn acquire_timeout(&self, timeout: Duration) -> io::Result<Option<Acquired>> {
+        // Round up so that we don't spin for the last fraction of a
+        // millisecond, and wait forever rather than for less than asked.
+        let millis = (timeout.as_nanos() + 999_999) / 1_000_000;
+        if millis >= u128::from(INFINITE) {
+            return self.acquire().map(Some);
+        }
+        self.acquire_timeout_millis(millis as DWORD)
+    }
+
+    fn acquire_timeout_millis(&self, millis: DWORD) -> io::Result<Option<Acquired>> {
+        match unsafe { WaitForSingleObject(self.sem.0, millis) } {
             WAIT_OBJECT_0 => Ok(Some(Acquired)),
             WAIT_TIMEOUT => Ok(None),
             WAIT_FAILED => Err(io::Error::last_os_error()),
@@ -179,6 +283,17 @@ impl Client {
         }
     }
 
+    pub fn reset_to(&self, _limit: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "only jobservers backed by a fifo can be reset",
+        ))
+    }
+
+    pub fn supports_try_acquire(&self) -> bool {
+        true
+    }
+
     pub fn release(&self, _data: Option<&Acquired>) -> io::Result<()> {
         unsafe {
             let r = ReleaseSemaphore(self.sem.0, 1, ptr::null_mut());
@@ -190,14 +305,56 @@ impl Client {
         }
     }
 
-    pub fn string_arg(&self) -> String {
-        self.name.clone()
+    pub fn string_arg(&self) -> OsString {
+        self.name.clone().into()
+    }
+
+    pub fn set_read_ahead(&self, _tokens: usize) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "read-ahead is only supported for jobservers backed by a pipe",
+        ))
+    }
+
+    pub fn read_ahead_tokens(&self) -> usize {
+        0
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn clone_for_thread(&self) -> io::Result<Client> {
+        self.clone_independent()
+    }
+
+    pub fn clone_independent(&self) -> io::Result<Client> {
+        let mut sem = ptr::null_mut();
+        let r = unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                self.sem.0,
+                GetCurrentProcess(),
+                &mut sem,
+                0,
+                FALSE,
+                DUPLICATE_SAME_ACCESS,
+            )
+        };
+        if r == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Client {
+            sem: Handle(sem),
+            name: self.name.clone(),
+        })
     }
 
     pub fn available(&self) -> io::Result<usize> {
         // Can't read value of a semaphore on Windows, so
         // try to acquire without sleeping, since we can find out the
-        // old value on release. If acquisiton fails, then available is 0.
+        // old value on release. If acquisition fails, then available is 0.
+        // `NtQuerySemaphore` would read it directly, but it's undocumented.
         unsafe {
             let r = WaitForSingleObject(self.sem.0, 0);
             if r != WAIT_OBJECT_0 {
@@ -214,10 +371,14 @@ impl Client {
         }
     }
 
-    pub fn configure(&self, _cmd: &mut Command) {
+    pub fn configure(&self, _cmd: &mut impl ConfigurableCommand) {
         // nothing to do here, we gave the name of our semaphore to the
         // child above
     }
+
+    pub fn deconfigure(&self, _cmd: &mut Command) {
+        // nothing to do here either, no handles are inherited
+    }
 }
 
 #[derive(Debug)]
@@ -234,6 +395,98 @@ impl Drop for Handle {
     }
 }
 
+#[repr(C)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+struct JOBOBJECT_BASIC_LIMIT_INFORMATION {
+    PerProcessUserTimeLimit: i64,
+    PerJobUserTimeLimit: i64,
+    LimitFlags: DWORD,
+    MinimumWorkingSetSize: usize,
+    MaximumWorkingSetSize: usize,
+    ActiveProcessLimit: DWORD,
+    Affinity: usize,
+    PriorityClass: DWORD,
+    SchedulingClass: DWORD,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+struct IO_COUNTERS {
+    ReadOperationCount: u64,
+    WriteOperationCount: u64,
+    OtherOperationCount: u64,
+    ReadTransferCount: u64,
+    WriteTransferCount: u64,
+    OtherTransferCount: u64,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types, non_snake_case, dead_code)]
+struct JOBOBJECT_EXTENDED_LIMIT_INFORMATION {
+    BasicLimitInformation: JOBOBJECT_BASIC_LIMIT_INFORMATION,
+    IoInfo: IO_COUNTERS,
+    ProcessMemoryLimit: usize,
+    JobMemoryLimit: usize,
+    PeakProcessMemoryUsed: usize,
+    PeakJobMemoryUsed: usize,
+}
+
+/// A Windows job object which terminates all of its processes once it is
+/// closed.
+///
+/// Processes spawned with [`Client::configure_with_job_object`] are assigned
+/// to the job object, so they're killed by the OS once the job object is
+/// dropped or when this process exits, even abnormally. Since a process
+/// holding tokens from a jobserver can't release them after it's been killed,
+/// this makes sure that no orphaned process keeps holding tokens of a
+/// jobserver which has been torn down.
+///
+/// Processes spawned by a process in a job object are part of the job object
+/// as well by default.
+///
+/// [`Client::configure_with_job_object`]: crate::Client::configure_with_job_object
+#[derive(Debug)]
+pub struct JobObject {
+    handle: Handle,
+}
+
+impl JobObject {
+    /// Creates a new anonymous job object which kills all of its processes
+    /// once it is closed.
+    pub fn new() -> io::Result<JobObject> {
+        unsafe {
+            let r = CreateJobObjectA(ptr::null_mut(), ptr::null());
+            if r.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let handle = Handle(r);
+
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            let r = SetInformationJobObject(
+                handle.0,
+                JOB_OBJECT_EXTENDED_LIMIT_INFORMATION_CLASS,
+                &mut info as *mut _ as *mut u8,
+                mem::size_of_val(&info) as DWORD,
+            );
+            if r == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(JobObject { handle })
+        }
+    }
+
+    /// Assigns an already spawned process to this job object.
+    pub fn assign(&self, child: &Child) -> io::Result<()> {
+        let r = unsafe { AssignProcessToJobObject(self.handle.0, child.as_raw_handle() as HANDLE) };
+        if r == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Helper {
     event: Arc<Handle>,
@@ -256,16 +509,16 @@ pub(crate) fn spawn_helper(
     let event = Arc::new(event);
     let event2 = event.clone();
     let thread = Builder::new().spawn(move || {
-        let objects = [event2.0, client.inner.sem.0];
+        // The shutdown event comes first: `WaitForMultipleObjects` reports the
+        // lowest signalled index, so once the event is set no more tokens are
+        // taken from the semaphore, and as it's manual-reset it stays set for
+        // every wait until the thread exits.
+        let objects = [event2.0, client.backend().os().sem.0];
         state.for_each_request(|_| {
             const WAIT_OBJECT_1: u32 = WAIT_OBJECT_0 + 1;
             match unsafe { WaitForMultipleObjects(2, objects.as_ptr(), FALSE, INFINITE) } {
                 WAIT_OBJECT_0 => {}
-                WAIT_OBJECT_1 => f(Ok(crate::Acquired {
-                    client: client.inner.clone(),
-                    data: Acquired,
-                    disabled: false,
-                })),
+                WAIT_OBJECT_1 => f(Ok(crate::Acquired::new(&client, Some(Acquired)))),
                 _ => f(Err(io::Error::last_os_error())),
             }
         });
@@ -93,6 +93,36 @@ const TESTS: &[Test] = &[
             assert!(hit.load(Ordering::SeqCst));
         },
     },
+    Test {
+        name: "total tokens",
+        make_args: &["-j3"],
+        rule: &|me| format!("+{}", me),
+        f: &|| {
+            let c = unsafe { Client::from_env().unwrap() };
+            assert_eq!(t!(c.total_tokens()), Some(2));
+        },
+    },
+    Test {
+        name: "protocol",
+        make_args: &["-j2"],
+        rule: &|me| format!("+{}", me),
+        f: &|| {
+            // Which flavor depends on the version of make running the test.
+            let from_env = unsafe { Client::from_env_ext(false) };
+            assert!(from_env.client.is_ok());
+            assert!(from_env.protocol.is_some());
+        },
+    },
+    #[cfg(unix)]
+    Test {
+        name: "cloexec",
+        make_args: &["-j2"],
+        rule: &|me| format!("+{}", me),
+        f: &|| {
+            let c = unsafe { Client::from_env().unwrap() };
+            assert_eq!(c.cloexec_state(), Some((true, true)));
+        },
+    },
     Test {
         name: "acquire_raw",
         make_args: &["-j2"],
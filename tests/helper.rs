@@ -74,3 +74,76 @@ fn prompt_shutdown() {
         assert!(d.as_secs_f64() < 0.5);
     }
 }
+
+#[test]
+fn counting_prompt_shutdown() {
+    let (tx, rx) = mpsc::channel();
+    let client = Client::counting(1);
+    let helper = client
+        .into_helper_thread(move |a| drop(tx.send(a)))
+        .unwrap();
+    helper.request_token();
+    let token = rx.recv().unwrap().unwrap();
+
+    // The second request blocks in the helper until shutdown.
+    helper.request_token();
+    let t = std::time::Instant::now();
+    drop(helper);
+    assert!(t.elapsed().as_secs_f64() < 0.5);
+    drop(token);
+}
+
+#[test]
+fn many_requests() {
+    let (tx, rx) = mpsc::channel();
+    let client = t!(Client::new(1));
+    let helper = client
+        .into_helper_thread(move |a| drop(tx.send(a)))
+        .unwrap();
+    for _ in 0..1000 {
+        helper.request_token();
+    }
+    for _ in 0..1000 {
+        rx.recv().unwrap().unwrap();
+    }
+    drop(helper);
+}
+
+#[test]
+fn capped() {
+    let (tx, rx) = mpsc::channel();
+    let client = t!(Client::new(4)).capped(1);
+    let helper = client
+        .into_helper_thread(move |a| drop(tx.send(a)))
+        .unwrap();
+    helper.request_token();
+    helper.request_token();
+    let a = rx.recv().unwrap().unwrap();
+    assert!(rx
+        .recv_timeout(std::time::Duration::from_millis(100))
+        .is_err());
+    drop(a);
+    rx.recv().unwrap().unwrap();
+
+    // Shutting down while blocked on the cap must not hang.
+    helper.request_token();
+    drop(helper);
+}
+
+#[test]
+fn no_signals() {
+    let (tx, rx) = mpsc::channel();
+    let client = t!(Client::new(1));
+    let helper = client
+        .into_helper_thread_no_signals(move |a| drop(tx.send(a)))
+        .unwrap();
+    helper.request_token();
+    let token = rx.recv().unwrap().unwrap();
+
+    // The second request blocks in the helper until shutdown.
+    helper.request_token();
+    let t = std::time::Instant::now();
+    drop(helper);
+    assert!(t.elapsed().as_secs_f64() < 0.5);
+    drop(token);
+}
@@ -21,7 +21,7 @@ fn main() {
             Command::new(env::var_os("MAKE").unwrap())
                 .env("MAKEFLAGS", env::var_os("CARGO_MAKEFLAGS").unwrap())
                 .env_remove("_DO_THE_TEST")
-                .args(&env::args_os().skip(1).collect::<Vec<_>>())
+                .args(env::args_os().skip(1).collect::<Vec<_>>())
                 .status()
                 .unwrap()
                 .code()
@@ -43,7 +43,7 @@ fn main() {
     let me = t!(env::current_exe());
     let me = me.to_str().unwrap();
 
-    let mut cmd = Command::new(&me);
+    let mut cmd = Command::new(me);
     cmd.current_dir(td.path());
     cmd.env("MAKE", prog);
     cmd.env("_DO_THE_TEST", "1");
@@ -0,0 +1,86 @@
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::process::Command;
+
+use jobserver::Client;
+
+macro_rules! t {
+    ($e:expr) => {
+        match $e {
+            Ok(e) => e,
+            Err(e) => panic!("{} failed with {}", stringify!($e), e),
+        }
+    };
+}
+
+/// Values of `-jN` to run `make` with. `-j1` is left out as `make` doesn't
+/// set up a jobserver at all then.
+const JOBS: &[usize] = &[2, 3, 8];
+
+fn main() {
+    if let Ok(jobs) = env::var("INTEROP_JOBS") {
+        return check_tokens(t!(jobs.parse()));
+    }
+
+    let prog = env::var("MAKE").unwrap_or_else(|_| "make".to_string());
+    if Command::new(&prog).arg("--version").output().is_err() {
+        println!("\nskipping make interop tests: `{}` not found\n", prog);
+        return;
+    }
+
+    let me = t!(env::current_exe());
+    let me = me.to_str().unwrap();
+
+    println!("\nrunning {} tests\n", JOBS.len());
+
+    let mut failures = 0;
+    for &jobs in JOBS {
+        let td = t!(tempfile::tempdir());
+        let makefile = format!(
+            "\
+all: export INTEROP_JOBS={}
+all:
+\t+{}
+",
+            jobs, me
+        );
+        t!(t!(File::create(td.path().join("Makefile"))).write_all(makefile.as_bytes()));
+
+        let output = t!(Command::new(&prog)
+            .arg(format!("-j{}", jobs))
+            .current_dir(td.path())
+            .output());
+        if output.status.success() {
+            println!("test make -j{} ... ok", jobs);
+        } else {
+            failures += 1;
+            println!("test make -j{} ... FAIL", jobs);
+            println!("\texit status: {}", output.status);
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                println!("\t\t{}", line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                println!("\t\t{}", line);
+            }
+        }
+    }
+
+    if failures > 0 {
+        panic!("{} make interop tests failed", failures);
+    }
+    println!("\ntest result: ok\n");
+}
+
+/// Runs under `make -j{jobs}` as its only job, so every token but the
+/// implicit one we're running with should be sitting in the jobserver.
+fn check_tokens(jobs: usize) {
+    let c = t!(unsafe { Client::from_env_ext(true) }.client);
+    assert_eq!(t!(c.total_tokens()), Some(jobs - 1));
+    assert_eq!(t!(c.available()), jobs - 1);
+
+    let tokens = (0..jobs - 1).map(|_| t!(c.acquire())).collect::<Vec<_>>();
+    assert_eq!(t!(c.available()), 0);
+    drop(tokens);
+    assert_eq!(t!(c.available()), jobs - 1);
+}
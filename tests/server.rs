@@ -1,4 +1,6 @@
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsString;
 use std::fs::File;
 use std::io::prelude::*;
 use std::process::Command;
@@ -6,8 +8,9 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use jobserver::Client;
+use jobserver::{Client, TokenPool};
 
 macro_rules! t {
     ($e:expr) => {
@@ -179,3 +182,1917 @@ fn zero_client() {
         assert!(rx.try_recv().is_err());
     }
 }
+
+#[test]
+fn counting_available() {
+    let c = Client::counting(2);
+    assert_eq!(c.available().unwrap(), 2);
+    let a = c.acquire().unwrap();
+    let b = c.try_acquire().unwrap().unwrap();
+    assert_eq!(c.available().unwrap(), 0);
+    assert!(c.try_acquire().unwrap().is_none());
+    drop((a, b));
+    assert_eq!(c.available().unwrap(), 2);
+}
+
+#[test]
+#[should_panic(expected = "cannot be inherited")]
+fn counting_configure_panics() {
+    let c = Client::counting(1);
+    c.configure(&mut Command::new("make"));
+}
+
+#[test]
+fn drain() {
+    // Use an in-process client as non-blocking acquire isn't supported for
+    // anonymous pipes on all platforms.
+    let c = Client::counting(3);
+    let a = c.acquire().unwrap();
+    let tokens = t!(c.drain());
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(c.available().unwrap(), 0);
+    assert!(t!(c.drain()).is_empty());
+    drop((a, tokens));
+    assert_eq!(c.available().unwrap(), 3);
+}
+
+#[test]
+fn implicit_token() {
+    let c = t!(Client::new(1));
+    let a = c.acquire().unwrap();
+    t!(c.release_implicit());
+    t!(c.clone().release_implicit());
+    assert_eq!(c.available().unwrap(), 1);
+    t!(c.reacquire_implicit());
+    t!(c.reacquire_implicit());
+    assert_eq!(c.available().unwrap(), 0);
+    drop(a);
+    assert_eq!(c.available().unwrap(), 1);
+}
+
+#[test]
+fn supports_try_acquire() {
+    assert!(Client::counting(1).supports_try_acquire());
+
+    // Support for anonymous pipes may only be known after the first attempt.
+    let c = t!(Client::new(1));
+    let unsupported = match c.try_acquire() {
+        Ok(_) => false,
+        Err(e) => e.kind() == std::io::ErrorKind::Unsupported,
+    };
+    assert_eq!(c.supports_try_acquire(), !unsupported);
+}
+
+#[test]
+fn pool_reuses_tokens() {
+    let c = t!(Client::new(2));
+    let pool = t!(TokenPool::new(c.clone(), 1, Duration::from_secs(60)));
+    let a = t!(pool.get());
+    let b = t!(pool.get());
+    assert_eq!(c.available().unwrap(), 0);
+    drop((a, b));
+    // Only one token is kept around, the other one is released.
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(c.available().unwrap(), 1);
+    let a = t!(pool.get());
+    assert_eq!(pool.idle(), 0);
+    drop(a.into_inner());
+    assert_eq!(c.available().unwrap(), 2);
+
+    drop(t!(pool.get()));
+    pool.flush();
+    assert_eq!(c.available().unwrap(), 2);
+    drop(t!(pool.get()));
+    drop(pool);
+    assert_eq!(c.available().unwrap(), 2);
+}
+
+#[test]
+fn pool_releases_idle_tokens() {
+    let c = t!(Client::new(1));
+    let pool = t!(TokenPool::new(c.clone(), 1, Duration::from_millis(10)));
+    drop(t!(pool.get()));
+    let start = Instant::now();
+    while c.available().unwrap() == 0 {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(pool.idle(), 0);
+}
+
+#[cfg(windows)]
+#[test]
+fn configure_with_job_object() {
+    let c = t!(Client::new(1));
+    let job = t!(jobserver::JobObject::new());
+    let mut cmd = Command::new("cmd");
+    cmd.args(["/c", "exit 0"]);
+    let mut child = t!(c.configure_with_job_object(&mut cmd, &job));
+    assert!(t!(child.wait()).success());
+}
+
+#[test]
+fn leak_and_reclaim() {
+    let c = t!(Client::new(1));
+    let raw = c.acquire().unwrap().leak();
+    assert_eq!(c.available().unwrap(), 0);
+    let raw = jobserver::AcquiredRaw::from_usize(raw.into_usize());
+    drop(c.reclaim_raw(raw));
+    assert_eq!(c.available().unwrap(), 1);
+}
+
+#[test]
+fn leak_and_reclaim_implicit() {
+    let c = t!(Client::new(0)).with_implicit_token();
+    let raw = t!(c.acquire()).leak();
+    // The implicit token stays in use while leaked.
+    assert!(t!(c.try_acquire()).is_none());
+    let err = c.raw_client().release_raw(raw).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // Reclaiming it doesn't add a token to the jobserver.
+    drop(c.reclaim_raw(raw));
+    assert_eq!(t!(c.available()), 0);
+    drop(t!(c.acquire()));
+}
+
+#[cfg(windows)]
+#[test]
+fn new_named() {
+    let c = t!(Client::new_named(1, "my_tool"));
+    let mut cmd = Command::new("cmd");
+    c.configure(&mut cmd);
+    let flags = cmd
+        .get_envs()
+        .find(|(k, _)| *k == "CARGO_MAKEFLAGS")
+        .and_then(|(_, v)| v)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(flags.contains("--jobserver-auth=my_tool_"));
+    drop(c.acquire().unwrap());
+
+    assert_eq!(
+        Client::new_named(1, "my tool").unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_read_fd() {
+    assert!(Client::counting(1).as_raw_read_fd().is_none());
+
+    let c = t!(Client::new(1));
+    let fd = c.as_raw_read_fd().unwrap();
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 1);
+    let a = c.acquire().unwrap();
+    assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 0) }, 0);
+    drop(a);
+}
+
+#[test]
+fn total_tokens() {
+    let c = t!(Client::new(3));
+    assert_eq!(t!(c.total_tokens()), Some(3));
+    let _a = c.acquire().unwrap();
+    assert_eq!(t!(c.total_tokens()), Some(3));
+    assert_eq!(t!(Client::counting(2).total_tokens()), Some(2));
+
+    // Without a known limit the tokens are only counted on request,
+    // including those held.
+    #[cfg(unix)]
+    {
+        let (read, write) = c.raw_fds().unwrap();
+        let c2 = t!(unsafe { Client::from_pipe_fds(read, write, false) });
+        let _b = t!(c2.acquire());
+        assert_eq!(t!(c2.total_tokens()), None);
+        assert_eq!(t!(c.available()), 1);
+        assert_eq!(t!(c2.count_tokens_by_draining()), Some(2));
+        assert_eq!(t!(c.available()), 1);
+    }
+}
+
+#[test]
+fn limit_too_large() {
+    const _: () = assert!(Client::is_valid_limit(4));
+
+    let e = Client::new_checked(usize::MAX).unwrap_err();
+    assert!(matches!(e.kind(), jobserver::LimitErrorKind::TooLarge));
+    assert_eq!(
+        Client::new(usize::MAX).unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+    assert!(Client::new_checked(0).is_ok());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn limit_larger_than_default_pipe() {
+    // More tokens than the 64KiB a pipe holds by default, which needs the
+    // pipe to be grown.
+    let c = t!(Client::new(100_000));
+    assert_eq!(t!(c.available()), 100_000);
+
+    let e = Client::new_checked(usize::MAX).unwrap_err();
+    assert!(e.max_limit().is_some());
+}
+
+#[test]
+fn is_inheritable() {
+    assert!(!Client::counting(1).is_inheritable());
+    assert_eq!(
+        t!(Client::new(1)).is_inheritable(),
+        cfg!(any(unix, windows))
+    );
+}
+
+#[test]
+fn configure_generic() {
+    use std::ffi::{OsStr, OsString};
+
+    #[derive(Default)]
+    struct Recorder {
+        env: Vec<(OsString, OsString)>,
+        #[cfg(unix)]
+        fds: Vec<std::os::unix::io::RawFd>,
+    }
+
+    impl jobserver::ConfigurableCommand for Recorder {
+        fn env(&mut self, key: &OsStr, value: &OsStr) {
+            self.env.push((key.to_owned(), value.to_owned()));
+        }
+
+        #[cfg(unix)]
+        fn inherit_fd(&mut self, fd: std::os::unix::io::RawFd) {
+            self.fds.push(fd);
+        }
+    }
+
+    let c = t!(Client::new(1));
+    let mut cmd = Recorder::default();
+    c.configure_generic(&mut cmd);
+    assert_eq!(cmd.env.len(), 1);
+    assert_eq!(cmd.env[0].0, "CARGO_MAKEFLAGS");
+    #[cfg(unix)]
+    assert_eq!(cmd.fds.len(), 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn raw_fds() {
+    use std::os::unix::io::FromRawFd;
+
+    assert!(Client::counting(1).raw_fds().is_none());
+
+    let c = t!(Client::new(0));
+    let (read, write) = c.raw_fds().unwrap();
+    assert_eq!(c.as_raw_read_fd(), Some(read));
+
+    // Writing a token through the raw fd makes it available to the client.
+    let write = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(write) });
+    t!((&*write).write_all(b"+"));
+    assert_eq!(t!(c.available()), 1);
+    drop(t!(c.acquire()));
+}
+
+#[cfg(unix)]
+#[test]
+fn release_preserves_token_byte() {
+    use std::os::unix::io::FromRawFd;
+
+    let c = t!(Client::new(0));
+    let (read, write) = c.raw_fds().unwrap();
+    let mut read = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(read) });
+    let mut write = std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(write) });
+    let mut byte = [0];
+
+    t!(write.write_all(b"x"));
+    drop(t!(c.acquire()));
+    t!(read.read_exact(&mut byte));
+    assert_eq!(&byte, b"x");
+
+    // Also when going through a raw token.
+    t!(write.write_all(b"y"));
+    let raw = t!(c.acquire()).leak();
+    drop(c.reclaim_raw(raw));
+    t!(read.read_exact(&mut byte));
+    assert_eq!(&byte, b"y");
+}
+
+#[test]
+fn capped() {
+    let c = t!(Client::new(4)).capped(2);
+    let a = t!(c.acquire());
+    let b = t!(c.acquire());
+    assert_eq!(t!(c.available()), 0);
+    assert!(t!(c.try_acquire()).is_none());
+    drop(a);
+    assert_eq!(t!(c.available()), 1);
+    let _a = t!(c.acquire());
+    drop(b);
+
+    // A cap larger than the jobserver doesn't get in the way, and failing to
+    // get a token from the jobserver gives back room under the cap.
+    let c = Client::counting(1).capped(4);
+    let a = t!(c.acquire());
+    assert!(t!(c.try_acquire()).is_none());
+    drop(a);
+    for _ in 0..4 {
+        drop(t!(c.acquire()));
+    }
+}
+
+#[test]
+fn try_acquire_many() {
+    let c = Client::counting(3);
+    assert_eq!(t!(c.try_acquire_many(0)).unwrap().len(), 0);
+
+    let tokens = t!(c.try_acquire_many(2)).unwrap();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(t!(c.available()), 1);
+
+    // Not enough tokens left, so the one that was available is rolled back.
+    assert!(t!(c.try_acquire_many(2)).is_none());
+    assert_eq!(t!(c.available()), 1);
+
+    drop(tokens);
+    assert_eq!(t!(c.try_acquire_many(3)).unwrap().len(), 3);
+    assert_eq!(t!(c.available()), 3);
+
+    assert!(t!(c.try_acquire_many(usize::MAX)).is_none());
+    assert_eq!(t!(c.available()), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn send_over_socket() {
+    use std::os::unix::net::UnixStream;
+
+    let (a, b) = t!(UnixStream::pair());
+    let err = Client::counting(1).send_over_socket(&a).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    let c = t!(Client::new(2));
+    t!(c.send_over_socket(&a));
+    let received = t!(Client::recv_from_socket(&b));
+    assert_eq!(t!(received.total_tokens()), Some(2));
+    assert!(received.raw_fds().is_none());
+    assert!(!received.is_owner());
+
+    // Both clients share the same jobserver.
+    let token = t!(received.acquire());
+    assert_eq!(t!(c.available()), 1);
+    drop(token);
+    assert_eq!(t!(c.available()), 2);
+
+    // Garbage on the socket isn't mistaken for a jobserver.
+    t!((&a).write_all(&[0; 13]));
+    let err = Client::recv_from_socket(&b).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "leak-check")]
+#[test]
+fn check_leaks() {
+    let c = t!(Client::new(3));
+    assert!(c.check_leaks().is_none());
+
+    let a = t!(c.acquire());
+    let b = t!(c.clone().acquire());
+    let report = c.check_leaks().unwrap();
+    assert_eq!(report.len(), 2);
+    assert!(report
+        .to_string()
+        .starts_with("2 jobserver token(s) still held"));
+
+    drop(a);
+    assert_eq!(c.check_leaks().unwrap().len(), 1);
+    b.drop_without_releasing();
+    assert!(c.check_leaks().is_none());
+    t!(c.release_raw());
+}
+
+#[test]
+fn acquire_labeled() {
+    let c = t!(Client::new(2));
+    let a = t!(c.acquire_labeled("compile foo.c"));
+    let b = t!(c.acquire());
+    assert_eq!(a.label(), Some("compile foo.c"));
+    assert_eq!(b.label(), None);
+    assert!(format!("{:?}", a).contains("compile foo.c"));
+
+    #[cfg(feature = "leak-check")]
+    {
+        let report = c.check_leaks().unwrap();
+        let labels = report.labels().collect::<Vec<_>>();
+        assert_eq!(labels, [Some("compile foo.c"), None]);
+        assert!(report
+            .to_string()
+            .contains("token 0 (compile foo.c) acquired at"));
+    }
+    drop((a, b));
+    assert_eq!(t!(c.available()), 2);
+}
+
+#[test]
+fn gnumakeflags() {
+    // Runs again in a child process with only `GNUMAKEFLAGS` set.
+    if env::var_os("JOBSERVER_GNUMAKEFLAGS_CHILD").is_some() {
+        let from_env = unsafe { Client::from_env_ext(false) };
+        assert_eq!(from_env.var.unwrap().0, "GNUMAKEFLAGS");
+        t!(from_env.client);
+        return;
+    }
+
+    let c = t!(Client::new(1));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "gnumakeflags"]);
+    c.configure_make(&mut cmd);
+    cmd.env_remove("CARGO_MAKEFLAGS")
+        .env_remove("MAKEFLAGS")
+        .env_remove("MFLAGS")
+        .env("JOBSERVER_GNUMAKEFLAGS_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+}
+
+#[test]
+fn from_env_all() {
+    // Runs again in a child process with `CARGO_MAKEFLAGS` naming one
+    // jobserver and the other variables naming another.
+    if env::var_os("JOBSERVER_FROM_ENV_ALL_CHILD").is_some() {
+        let all = unsafe { Client::from_env_all(false) };
+        let found = all
+            .into_iter()
+            .map(|from_env| {
+                let var = from_env.var.unwrap().0;
+                (var, t!(t!(from_env.client).available()))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("CARGO_MAKEFLAGS", 1), ("MAKEFLAGS", 3)]);
+        return;
+    }
+
+    let a = t!(Client::new(1));
+    let b = t!(Client::new(3));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "from_env_all"]);
+    b.configure_make(&mut cmd);
+    a.configure(&mut cmd);
+    cmd.env("JOBSERVER_FROM_ENV_ALL_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+}
+
+#[test]
+#[cfg(unix)]
+fn from_env_cached() {
+    // Runs again in a child process, which is free to change its environment
+    // and keep the cached clients around.
+    if env::var_os("JOBSERVER_FROM_ENV_CACHED_CHILD").is_some() {
+        let dir = t!(tempfile::tempdir());
+        let fifo_a = dir.path().join("a");
+        let fifo_b = dir.path().join("b");
+        for fifo in [&fifo_a, &fifo_b] {
+            t!(nix::unistd::mkfifo(fifo, nix::sys::stat::Mode::S_IRWXU));
+        }
+        let connect = |fifo: &std::path::Path| {
+            let flags = format!("-j --jobserver-auth=fifo:{}", fifo.display());
+            env::set_var("CARGO_MAKEFLAGS", flags);
+            let c = t!(unsafe { Client::from_env_ext(false) }.client);
+            c.as_raw_read_fd().unwrap()
+        };
+
+        // The same jobserver is connected to only once.
+        let a = connect(&fifo_a);
+        assert_eq!(a, connect(&fifo_a));
+        let b = connect(&fifo_b);
+        assert_ne!(a, b);
+        assert_eq!(b, connect(&fifo_b));
+
+        // Each call still gets a client of its own, so shutting one down
+        // leaves the others, and later calls, working.
+        let a = t!(unsafe { Client::from_env_ext(false) }.client);
+        let b = t!(unsafe { Client::from_env_ext(false) }.client);
+        a.pause();
+        assert!(!b.is_paused());
+        t!(b.release_raw());
+        t!(a.shutdown());
+        let c = t!(unsafe { Client::from_env_ext(false) }.client);
+        drop(t!(c.acquire()));
+        drop(t!(b.acquire()));
+        return;
+    }
+
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "from_env_cached"]);
+    cmd.env("JOBSERVER_FROM_ENV_CACHED_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+}
+
+#[test]
+fn cooperative() {
+    let c = t!(Client::new(2));
+    assert!(!c.is_cooperative());
+    c.set_cooperative(true);
+    assert!(c.clone().is_cooperative());
+
+    let threads = (0..4)
+        .map(|_| {
+            let c = c.clone();
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    drop(t!(c.acquire()));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    t!(c.acquire_raw());
+    t!(c.release_raw());
+    assert_eq!(t!(c.available()), 2);
+}
+
+#[test]
+fn display() {
+    let c = t!(Client::new(2));
+    let _a = t!(c.acquire());
+    let display = c.to_string();
+    assert!(display.starts_with("jobserver("), "{}", display);
+    assert!(display.ends_with(", limit=2)"), "{}", display);
+    assert!(display.contains(c.descriptor().unwrap().auth()));
+
+    let c = Client::counting(3);
+    assert_eq!(c.to_string(), "jobserver(in-process, limit=3)");
+    t!(c.clone().shutdown());
+    assert_eq!(c.to_string(), "jobserver(closed, limit=3)");
+}
+
+#[test]
+fn pause_resume() {
+    let c = t!(Client::new(2));
+    let held = t!(c.acquire());
+    c.pause();
+    assert!(c.is_paused());
+    assert!(t!(c.try_acquire()).is_none());
+
+    let (tx, rx) = mpsc::channel();
+    let c2 = c.clone();
+    let t = thread::spawn(move || {
+        let token = t!(c2.acquire());
+        tx.send(()).unwrap();
+        drop(token);
+    });
+    // Tokens held from before the pause are released as usual.
+    drop(held);
+    assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    assert_eq!(t!(c.available()), 2);
+
+    c.resume();
+    assert!(!c.is_paused());
+    rx.recv().unwrap();
+    t.join().unwrap();
+
+    // Shutting down wakes threads waiting for the client to be resumed.
+    c.pause();
+    let c2 = c.clone();
+    let t = thread::spawn(move || c2.acquire().map(drop));
+    thread::sleep(Duration::from_millis(50));
+    t!(c.shutdown());
+    assert!(t.join().unwrap().is_err());
+}
+
+#[test]
+fn pause_acquire_timeout() {
+    let c = t!(Client::new(2));
+    c.pause();
+    let err = c.acquire_timeout(Duration::from_millis(10)).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    c.set_default_acquire_timeout(Some(Duration::from_millis(10)));
+    assert_eq!(
+        c.acquire().unwrap_err().kind(),
+        std::io::ErrorKind::TimedOut
+    );
+    assert_eq!(
+        c.acquire_raw().unwrap_err().kind(),
+        std::io::ErrorKind::TimedOut
+    );
+    c.resume();
+    assert_eq!(t!(c.available()), 2);
+}
+
+#[test]
+fn pause_acquire_deadline() {
+    let c = t!(Client::new(2));
+    c.pause();
+    let start = Instant::now();
+    assert!(t!(c.acquire_deadline(Instant::now())).is_none());
+    let deadline = Instant::now() + Duration::from_millis(10);
+    assert!(t!(c.acquire_deadline(deadline)).is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    c.resume();
+    assert!(t!(c.acquire_deadline(Instant::now())).is_some());
+}
+
+#[test]
+fn from_env_map() {
+    let c = t!(Client::new(2));
+    let auth = c.descriptor().unwrap().auth().to_string();
+    let mut env = HashMap::new();
+    env.insert(
+        OsString::from("MAKEFLAGS"),
+        OsString::from(format!("-j3 --jobserver-auth={}", auth)),
+    );
+    let from_env = unsafe { Client::from_env_map(&env, false) };
+    assert_eq!(from_env.var.as_ref().unwrap().0, "MAKEFLAGS");
+    let c2 = t!(from_env.client);
+    assert_eq!(t!(c2.total_tokens()), Some(2));
+    drop(t!(c2.acquire()));
+
+    env.insert(
+        OsString::from("CARGO_MAKEFLAGS"),
+        OsString::from("--jobserver-auth=fifo:/nonexistent/jobserver"),
+    );
+    let from_env = unsafe { Client::from_env_map(&env, false) };
+    assert_eq!(from_env.var.unwrap().0, "CARGO_MAKEFLAGS");
+    assert!(from_env.client.is_err());
+}
+
+#[test]
+#[cfg(unix)]
+fn jobserver_style() {
+    let c = t!(Client::new(1));
+    let auth = c.descriptor().unwrap().auth().to_string();
+    let from_env_with = |flags: String| {
+        let mut env = HashMap::new();
+        env.insert(OsString::from("MAKEFLAGS"), OsString::from(flags));
+        unsafe { Client::from_env_map(&env, false) }.client
+    };
+
+    let c2 = t!(from_env_with(format!(
+        "--jobserver-style=pipe --jobserver-auth={}",
+        auth
+    )));
+    drop(t!(c2.acquire()));
+    let err =
+        from_env_with(format!("--jobserver-style=fifo --jobserver-auth={}", auth)).unwrap_err();
+    assert!(err.to_string().contains("--jobserver-style=fifo"));
+    assert!(from_env_with(format!("--jobserver-style=sem --jobserver-auth={}", auth)).is_err());
+    assert!(from_env_with("--jobserver-style=pipe --jobserver-auth=fifo:/tmp/x".into()).is_err());
+}
+
+#[test]
+fn jobserver_depth() {
+    let mut env = HashMap::new();
+    assert_eq!(unsafe { Client::from_env_map(&env, false) }.depth, 0);
+    env.insert(OsString::from("JOBSERVER_DEPTH"), OsString::from("3"));
+    let from_env = unsafe { Client::from_env_map(&env, false) };
+    assert!(from_env.client.is_err());
+    assert_eq!(from_env.depth, 3);
+    env.insert(OsString::from("JOBSERVER_DEPTH"), OsString::from("deep"));
+    assert_eq!(unsafe { Client::from_env_map(&env, false) }.depth, 0);
+
+    // The depth is only passed down to children if asked for.
+    let c = t!(Client::new(1));
+    let depth = |c: &Client| {
+        let mut cmd = Command::new("true");
+        c.configure(&mut cmd);
+        cmd.get_envs()
+            .find(|(name, _)| *name == "JOBSERVER_DEPTH")
+            .and_then(|(_, value)| value?.to_str().map(str::to_owned))
+    };
+    assert!(!c.tracks_depth());
+    assert_eq!(depth(&c), None);
+
+    c.set_track_depth(true);
+    assert!(c.clone().tracks_depth());
+    let expected = match env::var("JOBSERVER_DEPTH") {
+        Ok(depth) => depth.parse::<usize>().unwrap_or(0) + 1,
+        Err(_) => 1,
+    };
+    assert_eq!(depth(&c), Some(expected.to_string()));
+}
+
+#[test]
+#[cfg(unix)]
+fn not_a_pipe() {
+    use jobserver::{FdKind, FromEnvErrorKind};
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+
+    let (sock, _peer) = t!(UnixStream::pair());
+    let dir = t!(File::open(env!("CARGO_MANIFEST_DIR")));
+    let file = t!(File::open(file!()));
+    let cases = [
+        (sock.as_raw_fd(), FdKind::Socket),
+        (dir.as_raw_fd(), FdKind::Directory),
+        (file.as_raw_fd(), FdKind::Regular),
+    ];
+    for (fd, kind) in cases {
+        let mut env = HashMap::new();
+        env.insert(
+            OsString::from("MAKEFLAGS"),
+            OsString::from(format!("--jobserver-auth={},{}", fd, fd)),
+        );
+        let err = unsafe { Client::from_env_map(&env, true) }
+            .client
+            .unwrap_err();
+        match err.kind() {
+            FromEnvErrorKind::NotAPipe { actual } => assert_eq!(actual, kind),
+            other => panic!("unexpected error kind {:?}", other),
+        }
+        assert!(err
+            .to_string()
+            .ends_with(&format!("not a pipe but a {}", kind)));
+    }
+}
+
+#[test]
+#[cfg(all(unix, feature = "async-io"))]
+fn acquire_async_io() {
+    let c = t!(Client::new(1));
+    let held = t!(c.acquire());
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(held);
+    });
+    let start = Instant::now();
+    let token = t!(async_io::block_on(c.acquire_async_io()));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    t.join().unwrap();
+    drop(token);
+    assert_eq!(t!(c.available()), 1);
+
+    let err = async_io::block_on(Client::counting(1).acquire_async_io()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+#[cfg(all(unix, feature = "async-io"))]
+fn acquire_async_io_with_read_ahead() {
+    let c = t!(Client::new(1));
+    t!(c.set_read_ahead(1));
+    let held = t!(c.acquire());
+    let c2 = c.clone();
+    let (tx, rx) = mpsc::channel();
+    let t = thread::spawn(move || {
+        let token = t!(async_io::block_on(c2.acquire_async_io()));
+        tx.send(()).unwrap();
+        drop(token);
+    });
+    thread::sleep(Duration::from_millis(50));
+    // The released token has to go through the pipe to wake the poller up.
+    drop(held);
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    t.join().unwrap();
+    assert_eq!(c.read_ahead(), 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn as_raw_read_fd_turns_off_read_ahead() {
+    let c = t!(Client::new(2));
+    t!(c.set_read_ahead(2));
+    drop(t!(c.acquire()));
+    assert!(c.as_raw_read_fd().is_some());
+    assert_eq!(c.read_ahead(), 0);
+    let other = t!(c.clone_independent());
+    assert_eq!(t!(other.available()), 2);
+}
+
+#[test]
+#[cfg(unix)]
+fn deconfigure() {
+    // Runs again in a child process with the jobserver hidden from it, given
+    // the numbers and inodes of the file descriptors of the pipe.
+    if let Some(fds) = env::var_os("JOBSERVER_DECONFIGURE_CHILD") {
+        let from_env = unsafe { Client::from_env_ext(false) };
+        assert!(from_env.client.unwrap_err().is_no_env_var());
+        for fd in fds.to_str().unwrap().split(',') {
+            let (fd, ino) = fd.split_once(':').unwrap();
+            if let Ok(stat) = nix::sys::stat::fstat(fd.parse().unwrap()) {
+                assert_ne!(stat.st_ino.to_string(), ino);
+            }
+        }
+        return;
+    }
+
+    let c = t!(Client::new(1));
+    let (read, write) = c.raw_fds().unwrap();
+    let fds = [read, write]
+        .iter()
+        .map(|&fd| format!("{}:{}", fd, t!(nix::sys::stat::fstat(fd)).st_ino))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "deconfigure"]);
+    c.configure_make(&mut cmd);
+    c.deconfigure(&mut cmd);
+    cmd.env("JOBSERVER_DECONFIGURE_CHILD", fds);
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+}
+
+#[test]
+fn configure_argv() {
+    // Runs again in a child process without any environment, finding the
+    // jobserver on its command line, where the test harness takes it for
+    // another filter.
+    if env::var_os("JOBSERVER_ARGV_CHILD").is_some() {
+        let c = t!(unsafe { Client::from_argv("test-jobserver", env::args_os()) });
+        assert!(unsafe { Client::from_env_ext(false) }
+            .client
+            .unwrap_err()
+            .is_no_env_var());
+        drop(t!(c.acquire()));
+        let err = unsafe { Client::from_argv("other", env::args_os()) }.unwrap_err();
+        assert!(err.is_no_jobserver());
+        return;
+    }
+
+    let c = t!(Client::new(1));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "configure_argv", "--"]);
+    cmd.env_clear();
+    c.configure_argv(&mut cmd, "test-jobserver");
+    cmd.env("JOBSERVER_ARGV_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn from_env_or_new() {
+    // Runs again in child processes, with and without a jobserver to inherit.
+    if let Some(case) = env::var_os("JOBSERVER_OR_NEW_CHILD") {
+        let c = t!(unsafe { Client::from_env_or_new(3) });
+        if case == "inherited" {
+            assert!(!c.is_owner());
+            assert_eq!(t!(c.total_tokens()), Some(1));
+        } else {
+            assert!(c.is_owner());
+            assert_eq!(t!(c.total_tokens()), Some(3));
+        }
+        drop(t!(c.acquire()));
+        return;
+    }
+
+    let c = t!(Client::new(1));
+    for case in ["inherited", "new"] {
+        let mut cmd = Command::new(t!(env::current_exe()));
+        cmd.args(["--exact", "from_env_or_new"]);
+        cmd.env_clear();
+        if case == "inherited" {
+            c.configure(&mut cmd);
+        }
+        cmd.env("JOBSERVER_OR_NEW_CHILD", case);
+        let output = t!(cmd.output());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn default_acquire_timeout() {
+    for c in [
+        t!(Client::new(1)),
+        Client::counting(1),
+        t!(Client::new(2)).capped(1),
+    ] {
+        let token = t!(c.acquire_timeout(Duration::from_secs(1)));
+        assert_eq!(c.default_acquire_timeout(), None);
+        c.clone()
+            .set_default_acquire_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(c.default_acquire_timeout(), Some(Duration::from_millis(50)));
+
+        let start = Instant::now();
+        let err = c.acquire().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let err = c.acquire_raw().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+        let c2 = c.clone();
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(token);
+        });
+        c2.set_default_acquire_timeout(Some(Duration::from_secs(10)));
+        drop(t!(c.acquire()));
+        t.join().unwrap();
+        c.set_default_acquire_timeout(None);
+        assert_eq!(t!(c.available()), 1);
+    }
+}
+
+#[test]
+fn watch_available() {
+    let c = t!(Client::new(2));
+    let mut watch = c.watch_available();
+    assert_eq!(t!(watch.next().unwrap()), 2);
+
+    let c2 = c.clone();
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        let a = t!(c2.acquire());
+        let b = t!(c2.acquire());
+        thread::sleep(Duration::from_millis(100));
+        drop((a, b));
+    });
+    // The intermediate count of 1 may be missed.
+    let mut seen = t!(watch.next().unwrap());
+    if seen == 1 {
+        seen = t!(watch.next().unwrap());
+    }
+    assert_eq!(seen, 0);
+    assert_eq!(t!(watch.next().unwrap()), 2);
+    t.join().unwrap();
+}
+
+#[cfg(feature = "rayon")]
+#[test]
+fn bind_rayon() {
+    use std::sync::atomic::AtomicUsize;
+
+    let c = t!(Client::new(2));
+    let pool = t!(rayon::ThreadPoolBuilder::new().num_threads(4).build());
+    let bound = c.bind_rayon(&pool);
+
+    // At most two of the tasks run at once.
+    let running = Arc::new(AtomicUsize::new(0));
+    let max = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..8 {
+        let (running, max, tx) = (running.clone(), max.clone(), tx.clone());
+        bound.spawn(move |token| {
+            let token = t!(token);
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            running.fetch_sub(1, Ordering::SeqCst);
+            // Release the token before reporting back, so it's available to
+            // what runs next.
+            drop(token);
+            tx.send(()).unwrap();
+        });
+    }
+    for _ in 0..8 {
+        rx.recv().unwrap();
+    }
+    assert_eq!(max.load(Ordering::SeqCst), 2);
+
+    assert_eq!(t!(bound.install(|| t!(c.available()))), 1);
+
+    // Both halves of a join hold a token of their own, so with a single token
+    // they never overlap.
+    let c = t!(Client::new(1));
+    let bound = c.bind_rayon(&pool);
+    let running = AtomicUsize::new(0);
+    let half = || {
+        let overlapping = running.fetch_add(1, Ordering::SeqCst) > 0;
+        thread::sleep(Duration::from_millis(10));
+        running.fetch_sub(1, Ordering::SeqCst);
+        overlapping
+    };
+    assert_eq!(t!(bound.join(half, half)), (false, false));
+    assert_eq!(t!(c.available()), 1);
+
+    // Nested calls run under the token of the task they're called from
+    // instead of waiting for more.
+    let nested = t!(bound.install(|| t!(bound.join(|| 1, || t!(bound.install(|| 2))))));
+    assert_eq!(nested, (1, 2));
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn is_owner() {
+    let c = t!(Client::new(2));
+    assert!(c.is_owner());
+    assert!(c.clone().capped(1).is_owner());
+    assert!(t!(c.clone_independent()).is_owner());
+    assert!(Client::counting(2).is_owner());
+
+    let desc = c.descriptor().unwrap();
+    let connected = unsafe { t!(Client::from_descriptor(&desc)) };
+    assert!(!connected.is_owner());
+    assert!(!t!(connected.clone_independent()).is_owner());
+}
+
+#[cfg(unix)]
+#[test]
+fn fd_actions() {
+    let c = t!(Client::new(1));
+    let (read, write) = c.raw_fds().unwrap();
+    assert_eq!(c.fd_actions(), [(read, read), (write, write)]);
+    assert_eq!(c.clone().capped(1).fd_actions(), c.fd_actions());
+}
+
+#[test]
+fn jobserver_for_each() {
+    use jobserver::JobserverIteratorExt;
+    use std::sync::atomic::AtomicUsize;
+
+    let c = t!(Client::new(2));
+    let running = AtomicUsize::new(0);
+    let max = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    t!((0..8).jobserver_for_each(&c, |i| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        max.fetch_max(now, Ordering::SeqCst);
+        thread::sleep(Duration::from_millis(10));
+        running.fetch_sub(1, Ordering::SeqCst);
+        done.fetch_add(i, Ordering::SeqCst);
+    }));
+    assert_eq!(done.load(Ordering::SeqCst), 28);
+    assert_eq!(max.load(Ordering::SeqCst), 2);
+    assert_eq!(t!(c.available()), 2);
+}
+
+#[test]
+fn stats() {
+    let c = t!(Client::new(2));
+    let a = t!(c.acquire());
+    let b = t!(c.clone().acquire());
+    drop(a);
+    let stats = c.stats();
+    assert_eq!((stats.acquired(), stats.released()), (2, 1));
+    assert_eq!((stats.held(), stats.peak_held()), (1, 2));
+
+    // Blocked waiting for `b` to be released.
+    let c2 = c.clone();
+    let t = thread::spawn(move || {
+        let _a = t!(c2.acquire());
+        let _b = t!(c2.acquire());
+    });
+    thread::sleep(Duration::from_millis(50));
+    drop(b);
+    t.join().unwrap();
+    t!(c.acquire_raw());
+    t!(c.release_raw());
+    c.acquire().unwrap().drop_without_releasing();
+    t!(c.release_raw());
+
+    let token = t!(c.acquire());
+    thread::sleep(Duration::from_millis(10));
+    assert!(token.held_for() >= Duration::from_millis(10));
+    drop(token);
+
+    let stats = c.stats();
+    assert_eq!((stats.acquired(), stats.released()), (7, 7));
+    assert!(stats.held_time() >= Duration::from_millis(60));
+    assert_eq!((stats.held(), stats.peak_held()), (0, 2));
+    assert!(stats.blocked() >= Duration::from_millis(10));
+    assert!(stats
+        .to_string()
+        .starts_with("7 jobserver token(s) acquired, 7 released"));
+}
+
+#[test]
+fn wait_available() {
+    let c = t!(Client::new(2));
+    let a = t!(c.acquire());
+    let b = t!(c.acquire());
+    assert!(t!(c.wait_available(0, Some(Duration::ZERO))));
+    assert!(!t!(c.wait_available(1, Some(Duration::from_millis(50)))));
+
+    let t = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        drop(a);
+        thread::sleep(Duration::from_millis(50));
+        drop(b);
+    });
+    assert!(t!(c.wait_available(2, Some(Duration::from_secs(10)))));
+    assert_eq!(t!(c.available()), 2);
+    t.join().unwrap();
+}
+
+#[test]
+fn available_cached() {
+    let c = t!(Client::new(3));
+    let forever = Duration::from_secs(3600);
+    assert_eq!(t!(c.available_cached(forever)), 3);
+
+    // Tokens taken through this client, or its clones, are noticed...
+    let a = t!(c.clone().acquire());
+    assert_eq!(t!(c.available_cached(forever)), 2);
+    t!(c.acquire_raw());
+    assert_eq!(t!(c.available_cached(forever)), 1);
+    t!(c.release_raw());
+    drop(a);
+    assert_eq!(t!(c.available_cached(forever)), 3);
+
+    // ... but ones taken by anyone else only once the cache is refreshed.
+    let other = t!(c.clone_independent());
+    let b = t!(other.acquire());
+    assert_eq!(t!(c.available_cached(forever)), 3);
+    assert_eq!(t!(c.available_cached(Duration::ZERO)), 2);
+    drop(b);
+    assert_eq!(t!(c.available_cached(forever)), 2);
+    assert_eq!(t!(c.refresh_available()), 3);
+    assert_eq!(t!(c.available_cached(forever)), 3);
+}
+
+#[test]
+fn configure_twice() {
+    // Runs again in a child process configured twice with the jobserver.
+    if env::var_os("JOBSERVER_CONFIGURE_TWICE_CHILD").is_some() {
+        let c = t!(unsafe { Client::from_env_ext(true) }.client);
+        drop(t!(c.acquire()));
+        return;
+    }
+
+    let c = t!(Client::new(1));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "configure_twice"]);
+    c.configure(&mut cmd);
+    c.clone().configure(&mut cmd);
+    c.configure_make(&mut cmd);
+    cmd.env("JOBSERVER_CONFIGURE_TWICE_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn reconnect() {
+    // Runs again in a child process whose jobserver is then replaced.
+    if env::var_os("JOBSERVER_RECONNECT_CHILD").is_some() {
+        let c = t!(unsafe { Client::from_env_ext(false) }.client);
+        let clone = c.clone();
+        assert_eq!(t!(c.total_tokens()), Some(1));
+        let token = t!(c.acquire());
+        assert!(unsafe { c.reconnect() }.is_err());
+        drop(token);
+
+        let server = t!(Client::new(3));
+        let mut cmd = Command::new("unused");
+        server.configure(&mut cmd);
+        for (key, value) in cmd.get_envs() {
+            env::set_var(key, value.unwrap());
+        }
+        t!(unsafe { c.reconnect() });
+        assert_eq!(t!(c.total_tokens()), Some(3));
+        assert_eq!(t!(clone.total_tokens()), Some(3));
+        assert_eq!(t!(clone.available()), 3);
+        let tokens = (0..3).map(|_| t!(c.acquire())).collect::<Vec<_>>();
+        assert_eq!(t!(server.available()), 0);
+        drop(tokens);
+        return;
+    }
+
+    let err = unsafe { t!(Client::new(1)).reconnect() }.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+
+    let c = t!(Client::new(1));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.args(["--exact", "reconnect"]);
+    c.configure(&mut cmd);
+    cmd.env("JOBSERVER_RECONNECT_CHILD", "1");
+    let output = t!(cmd.output());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("1 passed"), "{}", stdout);
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn spawn_limited() {
+    let c = t!(Client::new(1));
+    let mut cmd = Command::new(t!(env::current_exe()));
+    cmd.arg("--list").stdout(std::process::Stdio::null());
+    let mut child = t!(c.spawn_limited(&mut cmd));
+    assert!(child.holds_token());
+    assert_eq!(t!(c.available()), 0);
+    assert!(t!(child.wait()).success());
+    assert!(!child.holds_token());
+    assert_eq!(t!(c.available()), 1);
+
+    // The token is released if spawning fails.
+    assert!(c
+        .spawn_limited(&mut Command::new("does-not-exist"))
+        .is_err());
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn deadlock_checked() {
+    let c = t!(Client::new_deadlock_checked(2));
+    let a = t!(c.acquire());
+    let b = t!(c.clone().acquire());
+    let err = c.acquire().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Other);
+
+    // Another thread may still block waiting for one of our tokens.
+    let c2 = c.clone();
+    let t = thread::spawn(move || drop(t!(c2.acquire())));
+    thread::sleep(Duration::from_millis(50));
+    drop(a);
+    t.join().unwrap();
+
+    let a = t!(c.acquire());
+    drop((a, b));
+
+    // Two threads each holding a token and waiting for another: whichever
+    // blocks last is told, and the other gets its token once it gives up.
+    let (tx, rx) = mpsc::channel();
+    let threads = (0..2)
+        .map(|_| {
+            let c = c.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let token = t!(c.acquire());
+                tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                let ret = c.acquire().map(drop);
+                drop(token);
+                ret
+            })
+        })
+        .collect::<Vec<_>>();
+    rx.recv().unwrap();
+    rx.recv().unwrap();
+    let errors = threads
+        .into_iter()
+        .filter_map(|t| t.join().unwrap().err())
+        .collect::<Vec<_>>();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind(), std::io::ErrorKind::Other);
+}
+
+#[test]
+fn descriptor() {
+    let c = t!(Client::new(2));
+    let descriptor = c.descriptor().unwrap();
+    assert_eq!(descriptor.limit(), Some(2));
+    let c2 = t!(unsafe { Client::from_descriptor(&descriptor) });
+    assert_eq!(t!(c2.total_tokens()), Some(2));
+    let a = t!(c2.acquire());
+    assert_eq!(t!(c.available()), 1);
+    drop(a);
+
+    assert!(Client::counting(2).descriptor().is_none());
+}
+
+#[test]
+fn shutdown() {
+    let c = t!(Client::new(2));
+    let c2 = c.clone();
+    let a = t!(c.acquire());
+    t!(c.shutdown());
+
+    let broken = |r: std::io::Result<_>| r.unwrap_err().kind() == std::io::ErrorKind::BrokenPipe;
+    assert!(broken(c2.acquire().map(drop)));
+    assert!(broken(c2.try_acquire().map(drop)));
+    assert!(broken(c2.available().map(drop)));
+    assert!(broken(c2.release_raw()));
+    assert!(broken(c2.clone().into_helper_thread(|_| ()).map(drop)));
+    // Tokens acquired before are still released as usual.
+    drop(a);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn shutdown_closes_handles() {
+    let c = t!(Client::new(2));
+    let c2 = c.clone();
+    let fd = c.as_raw_read_fd().unwrap();
+    let path = format!("/proc/self/fd/{}", fd);
+    let pipe = t!(std::fs::read_link(&path));
+    t!(c.shutdown());
+
+    // The pipe is closed even though a clone is still around, so the fd is
+    // either gone or reused for something else.
+    assert_ne!(std::fs::read_link(&path).ok(), Some(pipe));
+    drop(c2);
+}
+
+#[test]
+fn split() {
+    let c = t!(Client::new(1));
+    let mut shares = t!(c.acquire()).split(3);
+    assert_eq!(shares.len(), 3);
+    assert_eq!(t!(c.available()), 0);
+
+    drop(shares.pop());
+    assert!(shares.pop().unwrap().merge().is_none());
+    assert_eq!(t!(c.available()), 0);
+    let token = shares.pop().unwrap().merge().unwrap();
+    assert_eq!(t!(c.available()), 0);
+
+    // The token is released once every share is dropped.
+    let shares = token.split(2);
+    drop(shares);
+    assert_eq!(t!(c.available()), 1);
+
+    assert!(t!(c.acquire()).split(0).is_empty());
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[test]
+fn acquire_spin() {
+    let c = t!(Client::new(1));
+    let a = t!(c.acquire_spin(100));
+    assert_eq!(t!(c.available()), 0);
+
+    // Falls back to blocking until the token is released.
+    let c2 = c.clone();
+    let t = thread::spawn(move || drop(t!(c2.acquire_spin(10))));
+    thread::sleep(Duration::from_millis(50));
+    drop(a);
+    t.join().unwrap();
+    assert_eq!(t!(c.available()), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn cloexec_state() {
+    let c = t!(Client::new(1));
+    assert_eq!(c.cloexec_state(), Some((true, true)));
+    assert_eq!(Client::counting(1).cloexec_state(), None);
+}
+
+#[test]
+fn with_implicit_token() {
+    let c = t!(Client::new(0)).with_implicit_token();
+    let a = t!(c.clone().acquire());
+    assert!(t!(c.try_acquire()).is_none());
+    // The implicit token is in use, so there's nothing to release.
+    t!(c.release_implicit());
+    assert_eq!(t!(c.available()), 0);
+
+    // Dropping it doesn't write it to the jobserver.
+    drop(a);
+    assert_eq!(t!(c.available()), 0);
+    drop(t!(c.try_acquire()).unwrap());
+
+    t!(c.release_implicit());
+    assert_eq!(t!(c.available()), 1);
+    let b = t!(c.acquire());
+    assert_eq!(t!(c.available()), 0);
+    drop(b);
+    t!(c.reacquire_implicit());
+    assert_eq!(t!(c.available()), 0);
+}
+
+#[test]
+fn audit_with_implicit_token() {
+    let c = t!(Client::new(2)).with_implicit_token();
+    let implicit = t!(c.acquire());
+    let token = t!(c.acquire());
+    // The implicit token isn't one of the jobserver's, so it isn't held.
+    let report = t!(c.audit(2));
+    assert_eq!((report.found(), report.held()), (1, 1));
+    assert!(report.is_consistent());
+    drop((implicit, token));
+}
+
+#[cfg(unix)]
+#[test]
+fn set_nonblocking() {
+    let c = t!(Client::new(1));
+    t!(c.set_nonblocking(true));
+    assert!(c.supports_try_acquire());
+    let a = t!(c.try_acquire()).unwrap();
+    assert!(t!(c.try_acquire()).is_none());
+
+    // Blocking acquisition still works.
+    let c2 = c.clone();
+    let t = thread::spawn(move || drop(t!(c2.acquire())));
+    thread::sleep(Duration::from_millis(50));
+    drop(a);
+    t.join().unwrap();
+
+    t!(c.set_nonblocking(false));
+    drop(t!(c.acquire()));
+    assert!(Client::counting(1).set_nonblocking(true).is_err());
+}
+
+#[test]
+fn clone_independent() {
+    let c = t!(Client::new(2));
+    let c2 = t!(c.clone_independent());
+    assert_eq!(t!(c2.total_tokens()), Some(2));
+    #[cfg(unix)]
+    assert_ne!(c.raw_fds(), c2.raw_fds());
+
+    let a = t!(c2.acquire());
+    assert_eq!(t!(c.available()), 1);
+    t!(c2.shutdown());
+    drop(a);
+    assert_eq!(t!(c.available()), 2);
+    drop(t!(c.acquire()));
+}
+
+#[test]
+fn try_clone_for_thread() {
+    let c = t!(Client::new(2));
+    #[cfg(unix)]
+    t!(c.set_read_ahead(1));
+    let clones = (0..4)
+        .map(|_| t!(c.try_clone_for_thread()))
+        .collect::<Vec<_>>();
+    #[cfg(unix)]
+    for clone in &clones {
+        assert_ne!(c.raw_fds(), clone.raw_fds());
+        assert_eq!(clone.read_ahead(), 1);
+    }
+
+    let threads = clones
+        .into_iter()
+        .map(|clone| {
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    drop(t!(clone.acquire()));
+                }
+                t!(clone.flush());
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(t!(c.available()), 2);
+
+    // The clones share the rest of the client's state.
+    let clone = t!(c.try_clone_for_thread());
+    c.pause();
+    assert!(clone.is_paused());
+    c.resume();
+    assert!(c.stats().acquired() >= 400);
+}
+
+#[test]
+#[cfg(unix)]
+fn try_clone_for_thread_shares_read_ahead() {
+    let c = t!(Client::new(1));
+    t!(c.set_read_ahead(1));
+    let token = t!(c.acquire());
+    let clone = t!(c.try_clone_for_thread());
+    let (tx, rx) = mpsc::channel();
+    let waiter = thread::spawn(move || {
+        let token = t!(clone.acquire());
+        tx.send(()).unwrap();
+        drop(token);
+    });
+    thread::sleep(Duration::from_millis(50));
+    // The token must go to the thread blocked on the clone rather than be
+    // kept back by read-ahead.
+    drop(token);
+    rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    waiter.join().unwrap();
+}
+
+#[test]
+fn acquire_weighted() {
+    let c = Client::counting(4);
+    let heavy = t!(c.acquire_weighted(3));
+    assert_eq!(heavy.weight(), 3);
+    assert_eq!(c.available().unwrap(), 1);
+
+    // A second weighted acquisition waits for the whole bundle.
+    let c2 = c.clone();
+    let t = thread::spawn(move || t!(c2.acquire_weighted(2)).weight());
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(c.available().unwrap(), 0);
+    drop(heavy);
+    assert_eq!(t.join().unwrap(), 2);
+    assert_eq!(c.available().unwrap(), 4);
+
+    assert_eq!(t!(c.acquire_weighted(0)).weight(), 0);
+    let err = c.acquire_weighted(5).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(t!(c.acquire_weighted(4)).into_tokens().len(), 4);
+    assert_eq!(c.available().unwrap(), 4);
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn acquire_weighted_rolls_back() {
+    // The implicit token is handed out first, then acquiring from the
+    // jobserver fails, which has to give the implicit token back.
+    let c = Client::counting(4).with_implicit_token();
+    c.set_fail_next_acquire(std::io::ErrorKind::Other);
+    assert!(c.acquire_weighted(2).is_err());
+    assert_eq!(c.available().unwrap(), 4);
+    assert_eq!(t!(c.acquire_weighted(5)).weight(), 5);
+}
+
+#[test]
+fn replace_inner() {
+    let parent = Client::counting(1);
+    // Has state of its own, so it's left on the old jobserver.
+    let old = t!(parent.clone_independent());
+    let client = parent.clone().capped(1);
+    let clone = client.clone();
+    let token = t!(clone.acquire());
+
+    let new = t!(Client::new(3));
+    t!(client.replace_inner(new.clone()));
+    assert_eq!(t!(clone.total_tokens()), Some(3));
+    assert_eq!(clone.available().unwrap(), 0);
+    // The client the capped one was derived from is switched over as well.
+    assert_eq!(t!(parent.total_tokens()), Some(3));
+    assert_eq!(parent.available().unwrap(), 3);
+
+    // The token held goes back to the old jobserver, making room under the
+    // cap, which is kept, for a token from the new one.
+    drop(token);
+    assert_eq!(old.available().unwrap(), 1);
+    let token = t!(clone.acquire());
+    assert_eq!(new.available().unwrap(), 2);
+    assert!(t!(clone.try_acquire()).is_none());
+    drop(token);
+    assert_eq!(new.available().unwrap(), 3);
+
+    let closed = Client::counting(1);
+    t!(closed.clone().shutdown());
+    let err = client.replace_inner(closed).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn acquire_deadline() {
+    for c in [t!(Client::new(1)), Client::counting(1)] {
+        // A deadline in the past still takes a token which is available.
+        let past = Instant::now();
+        thread::sleep(Duration::from_millis(1));
+        let token = t!(c.acquire_deadline(past)).unwrap();
+        assert!(t!(c.acquire_deadline(past)).is_none());
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(50);
+        assert!(t!(c.acquire_deadline(deadline)).is_none());
+        assert!(Instant::now() >= deadline);
+
+        let c2 = c.clone();
+        let t = thread::spawn(move || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            t!(c2.acquire_deadline(deadline)).is_some()
+        });
+        thread::sleep(Duration::from_millis(50));
+        drop(token);
+        assert!(t.join().unwrap());
+    }
+}
+
+#[test]
+fn pipe_capacity() {
+    let c = t!(Client::new(1));
+    assert_eq!(Client::counting(1).pipe_capacity().unwrap(), None);
+    if cfg!(target_os = "linux") {
+        let capacity = t!(c.pipe_capacity()).unwrap();
+        assert!(capacity >= 4096, "capacity {}", capacity);
+        // Large limits grow the pipe to fit.
+        let c = t!(Client::new(capacity + 1));
+        assert!(t!(c.pipe_capacity()).unwrap() > capacity);
+    } else {
+        assert_eq!(t!(c.pipe_capacity()), None);
+    }
+}
+
+#[test]
+fn boost() {
+    let mut clients = vec![Client::counting(1)];
+    #[cfg(unix)]
+    clients.push(t!(Client::new(1)));
+    for client in clients {
+        let boost = t!(client.boost(2));
+        assert_eq!(boost.extra(), 2);
+        assert_eq!(t!(client.total_tokens()), Some(3));
+        assert_eq!(client.available().unwrap(), 3);
+
+        // Dropping the guard waits for the held tokens to be released.
+        let tokens = (0..3).map(|_| t!(client.acquire())).collect::<Vec<_>>();
+        let (tx, rx) = mpsc::channel();
+        let t = thread::spawn(move || {
+            drop(boost);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(tokens);
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(t!(client.total_tokens()), Some(1));
+        assert_eq!(client.available().unwrap(), 1);
+
+        // The tokens are removed when unwinding from a panic too.
+        let client2 = client.clone();
+        let ret = std::panic::catch_unwind(move || {
+            let _boost = client2.boost(4).unwrap();
+            panic!("burst failed");
+        });
+        assert!(ret.is_err());
+        assert_eq!(t!(client.total_tokens()), Some(1));
+        assert_eq!(client.available().unwrap(), 1);
+        t!(t!(client.boost(1)).end());
+    }
+
+    let c = t!(Client::new(1));
+    let mut env = HashMap::new();
+    env.insert(
+        OsString::from("MAKEFLAGS"),
+        OsString::from(format!(
+            "--jobserver-auth={}",
+            c.descriptor().unwrap().auth()
+        )),
+    );
+    let inherited = t!(unsafe { Client::from_env_map(&env, false) }.client);
+    let err = inherited.boost(1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn with_cancellation() {
+    for client in [t!(Client::new(1)), Client::counting(1)] {
+        let (client, cancel) = t!(client.with_cancellation());
+        let token = t!(client.acquire());
+        let client2 = client.clone();
+        let waiter = thread::spawn(move || client2.acquire().map(drop));
+        thread::sleep(Duration::from_millis(50));
+        assert!(!cancel.is_cancelled());
+        cancel.cancel();
+        let err = waiter.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert!(cancel.is_cancelled());
+
+        // Later acquisitions fail too, even with tokens to spare.
+        drop(token);
+        let err = client.acquire_timeout(Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+        assert_eq!(client.available().unwrap(), 1);
+    }
+
+    // Waiting for room under the cap of a capped client made from it can be
+    // cancelled as well.
+    let (client, cancel) = t!(t!(Client::new(2)).with_cancellation());
+    let client = client.capped(1);
+    let _token = t!(client.acquire());
+    let client2 = client.clone();
+    let waiter = thread::spawn(move || client2.acquire().map(drop));
+    thread::sleep(Duration::from_millis(50));
+    cancel.cancel();
+    let err = waiter.join().unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+}
+
+#[test]
+fn weak_acquired() {
+    let c = Client::counting(1);
+    let token = t!(c.acquire());
+    let weak = token.downgrade();
+    assert_eq!(c.available().unwrap(), 0);
+
+    // An upgraded handle keeps the token held after its owner is dropped.
+    let shared = weak.upgrade().unwrap();
+    drop(token);
+    assert_eq!(c.available().unwrap(), 0);
+    drop(shared);
+    assert_eq!(c.available().unwrap(), 1);
+    assert!(weak.upgrade().is_none());
+
+    let token = t!(c.acquire());
+    let weak = token.downgrade();
+    weak.upgrade().unwrap().drop_without_releasing();
+    drop(token);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(c.available().unwrap(), 0);
+}
+
+#[test]
+fn read_write_halves() {
+    let from = t!(Client::new(2));
+    let to = t!(Client::new(0));
+    let read = from.read_half();
+    let write = to.write_half();
+    let (from_check, to_check) = (t!(from.clone_independent()), t!(to.clone_independent()));
+    // The halves keep the jobservers open on their own.
+    drop((from, to));
+
+    let token = t!(read.acquire());
+    t!(write.release());
+    token.drop_without_releasing();
+    assert_eq!(t!(from_check.available()), 1);
+    assert_eq!(t!(to_check.available()), 1);
+
+    let read = Client::counting(1).read_half();
+    let token = t!(read.try_acquire()).unwrap();
+    assert!(t!(read.try_acquire()).is_none());
+    drop(token);
+    assert!(t!(read.try_acquire()).is_some());
+}
+
+#[test]
+fn bridge() {
+    use jobserver::JobserverBridge;
+
+    let outer = t!(Client::new(3));
+    let inner = t!(Client::new(0));
+    // Taking tokens back from an anonymous pipe needs nonblocking reads.
+    #[cfg(unix)]
+    t!(inner.set_nonblocking(true));
+    let bridge = t!(JobserverBridge::new(
+        t!(outer.clone_independent()).capped(2),
+        inner.clone()
+    ));
+
+    let a = t!(inner.acquire_timeout(Duration::from_secs(5)));
+    let b = t!(inner.acquire_timeout(Duration::from_secs(5)));
+    assert_eq!(bridge.forwarded(), 2);
+    assert_eq!(t!(outer.available()), 1);
+    let err = inner
+        .acquire_timeout(Duration::from_millis(100))
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+
+    // One idle token is left downstream, the other one goes back up.
+    drop((a, b));
+    let start = Instant::now();
+    while bridge.forwarded() > 1 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(t!(outer.available()), 2);
+    assert_eq!(t!(inner.available()), 1);
+
+    t!(bridge.stop());
+    assert_eq!(t!(outer.available()), 3);
+    assert_eq!(t!(inner.available()), 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn from_pipe_fds() {
+    // Borrowed file descriptors are duplicated and left open.
+    let outer = t!(Client::new(2));
+    let (read, write) = outer.raw_fds().unwrap();
+    let c = t!(unsafe { Client::from_pipe_fds(read, write, false) });
+    assert!(c.is_owner());
+    assert_eq!(t!(c.total_tokens()), None);
+    assert_ne!(c.raw_fds(), Some((read, write)));
+    let a = t!(c.acquire());
+    assert_eq!(t!(outer.available()), 1);
+    drop((a, c));
+    assert_eq!(t!(outer.available()), 2);
+
+    // Owned ones are closed along with the client, which a duplicate of
+    // the read end sees as the pipe hanging up once drained. The pipe must
+    // be close-on-exec right away, or children spawned by other tests may
+    // hold it open.
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        let (read, write) = t!(nix::unistd::pipe2(nix::fcntl::OFlag::O_CLOEXEC));
+        let probe = File::from(t!(read.try_clone()));
+        t!(nix::fcntl::fcntl(
+            probe.as_raw_fd(),
+            nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK)
+        ));
+        t!(nix::unistd::write(&write, b"++"));
+        let (read, write) = (read.into_raw_fd(), write.into_raw_fd());
+        let c = t!(unsafe { Client::from_pipe_fds(read, write, true) });
+        assert_eq!(c.raw_fds(), Some((read, write)));
+        drop(t!(c.acquire()));
+        drop(c);
+        let mut tokens = Vec::new();
+        t!((&probe).read_to_end(&mut tokens));
+        assert_eq!(tokens, b"++");
+    }
+}
+
+#[test]
+#[cfg(all(unix, feature = "testing"))]
+fn set_release_order() {
+    use jobserver::{AcquiredRaw, ReleaseOrder};
+
+    // Releases tokens carrying the bytes `a` to `d`, in that order, and
+    // returns the order they come back out of the pipe in.
+    fn released(order: ReleaseOrder) -> Vec<u8> {
+        let c = t!(Client::new(1));
+        let _only = t!(c.acquire());
+        let tokens = (b'a'..=b'd')
+            .map(|b| c.reclaim_raw(AcquiredRaw::from_usize(b.into())))
+            .collect::<Vec<_>>();
+        c.set_release_order(order);
+        drop(tokens);
+        if order != ReleaseOrder::Immediate {
+            assert_eq!(t!(c.available()), 0);
+        }
+        t!(c.flush());
+        (0..4)
+            .map(|_| t!(c.acquire()).leak().into_usize() as u8)
+            .collect()
+    }
+
+    assert_eq!(released(ReleaseOrder::Immediate), b"abcd");
+    assert_eq!(released(ReleaseOrder::Fifo), b"abcd");
+    assert_eq!(released(ReleaseOrder::Lifo), b"dcba");
+    let seeded = released(ReleaseOrder::Seeded(7));
+    assert_eq!(released(ReleaseOrder::Seeded(7)), seeded);
+    let mut sorted = seeded.clone();
+    sorted.sort_unstable();
+    assert_eq!(sorted, b"abcd");
+}
+
+#[test]
+#[cfg(feature = "testing")]
+fn set_release_order_wakes_waiter() {
+    let c = Client::counting(1);
+    c.set_release_order(jobserver::ReleaseOrder::Fifo);
+    let token = t!(c.acquire());
+    let (tx, rx) = mpsc::channel();
+    let c2 = c.clone();
+    let waiter = thread::spawn(move || {
+        tx.send(()).unwrap();
+        drop(t!(c2.acquire()));
+    });
+    rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    // Queueing the release would leave the waiter blocked forever.
+    drop(token);
+    waiter.join().unwrap();
+}
+
+#[test]
+fn raw_client() {
+    let c = Client::counting(2);
+    let raw = c.raw_client();
+    let a = t!(raw.acquire_raw());
+    let b = t!(raw.acquire_raw());
+    assert_eq!(t!(c.available()), 0);
+    // Raw tokens aren't counted as acquired through the client.
+    assert_eq!(c.stats().acquired(), 0);
+    // Raw tokens interoperate with the RAII ones.
+    drop(c.reclaim_raw(a));
+    assert_eq!(t!(c.available()), 1);
+    t!(raw.release_raw(b));
+    assert_eq!(t!(c.available()), 2);
+
+    let c = t!(Client::new(1));
+    let raw = c.raw_client();
+    let token = t!(raw.acquire_raw());
+    t!(raw.release_raw(token));
+    drop(t!(c.acquire()));
+}
+
+#[test]
+fn for_each_scoped() {
+    let c = Client::counting(2);
+    let running = std::sync::atomic::AtomicUsize::new(0);
+    let peak = std::sync::atomic::AtomicUsize::new(0);
+    let mut seen = [false; 6];
+    thread::scope(|scope| {
+        let items = seen.iter_mut();
+        t!(c.for_each_scoped(scope, items, |seen| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(10));
+            *seen = true;
+            running.fetch_sub(1, Ordering::SeqCst);
+        }));
+        // Other threads can still be spawned into the scope.
+        t!(c.spawn_scoped(scope, || ()));
+    });
+    assert!(seen.iter().all(|&seen| seen));
+    assert!(peak.into_inner() <= 2);
+    assert_eq!(t!(c.available()), 2);
+
+    // The tokens count towards the spawned threads, so the spawning thread
+    // isn't taken for deadlocking itself.
+    use jobserver::JobserverIteratorExt;
+    let c = t!(Client::new_deadlock_checked(2));
+    thread::scope(|scope| {
+        t!(c.for_each_scoped(scope, 0..4, |_| {
+            thread::sleep(Duration::from_millis(10))
+        }));
+    });
+    let items = [0; 4];
+    t!(items
+        .iter()
+        .jobserver_for_each(&c, |_| thread::sleep(Duration::from_millis(10))));
+}